@@ -15,6 +15,7 @@ fn l_insert_benchmark(c: &mut Criterion) {
             side: Side::Sell,
             request_type: Type::Limit,
             user_id: i,
+            ..Default::default()
         };
         book.match_request(&request.clone());
     }
@@ -24,6 +25,7 @@ fn l_insert_benchmark(c: &mut Criterion) {
         side: Side::Sell,
         request_type: Type::Limit,
         user_id: 10000,
+        ..Default::default()
     };
     c.bench_function("Limit inserting in prepared book", move |b| {
         b.iter_batched_ref(
@@ -43,6 +45,7 @@ fn l_insert_worst_case_benchmark(c: &mut Criterion) {
             side: Side::Sell,
             request_type: Type::Limit,
             user_id: i,
+            ..Default::default()
         };
         book.match_request(&request.clone());
     }
@@ -52,6 +55,7 @@ fn l_insert_worst_case_benchmark(c: &mut Criterion) {
         side: Side::Sell,
         request_type: Type::Limit,
         user_id: 1,
+        ..Default::default()
     };
     book.match_request(&request.clone());
     let request = Request {
@@ -60,6 +64,7 @@ fn l_insert_worst_case_benchmark(c: &mut Criterion) {
         side: Side::Sell,
         request_type: Type::Limit,
         user_id: 10000,
+        ..Default::default()
     };
     c.bench_function(
         "Limit inserting in prepared book all with the same prices (worst case)",
@@ -81,6 +86,7 @@ fn l_benchmark(c: &mut Criterion) {
             side: Side::Sell,
             request_type: Type::Limit,
             user_id: i,
+            ..Default::default()
         };
         book.match_request(&request.clone());
     }
@@ -90,6 +96,7 @@ fn l_benchmark(c: &mut Criterion) {
         side: Side::Buy,
         request_type: Type::Limit,
         user_id: 10000,
+        ..Default::default()
     };
     c.bench_function("Limit matching", move |b| {
         b.iter_batched_ref(
@@ -100,6 +107,36 @@ fn l_benchmark(c: &mut Criterion) {
     });
 }
 
+fn l_u32_benchmark(c: &mut Criterion) {
+    let mut book = OrderBook::<u32, u32, u32>::new();
+    for i in 0..7000 {
+        let request = Request {
+            price: 1,
+            size: 1,
+            side: Side::Sell,
+            request_type: Type::Limit,
+            user_id: i,
+            ..Default::default()
+        };
+        book.match_request(&request.clone());
+    }
+    let request = Request {
+        price: 1,
+        size: 20,
+        side: Side::Buy,
+        request_type: Type::Limit,
+        user_id: 10000,
+        ..Default::default()
+    };
+    c.bench_function("Limit matching with u32 prices, sizes and users", move |b| {
+        b.iter_batched_ref(
+            || (book.clone(), request.clone()),
+            |(book, request)| book.match_request(black_box(request)),
+            BatchSize::SmallInput,
+        );
+    });
+}
+
 fn lq_benchmark(c: &mut Criterion) {
     let mut book = OrderBook::default();
     for i in 0..7000 {
@@ -109,6 +146,7 @@ fn lq_benchmark(c: &mut Criterion) {
             side: Side::Sell,
             request_type: Type::Limit,
             user_id: i,
+            ..Default::default()
         };
         book.match_request(&request.clone());
     }
@@ -118,6 +156,7 @@ fn lq_benchmark(c: &mut Criterion) {
         side: Side::Buy,
         request_type: Type::Limit,
         user_id: 10000,
+        ..Default::default()
     };
     c.bench_function("Limit quiet matching", move |b| {
         b.iter_batched_ref(
@@ -137,6 +176,7 @@ fn ic_benchmark(c: &mut Criterion) {
             side: Side::Sell,
             request_type: Type::Limit,
             user_id: i,
+            ..Default::default()
         };
         book.match_request(&request.clone());
     }
@@ -146,6 +186,7 @@ fn ic_benchmark(c: &mut Criterion) {
         side: Side::Buy,
         request_type: Type::ImmediateOrCancel,
         user_id: 10000,
+        ..Default::default()
     };
     c.bench_function("ImmediateOrCancel matching", move |b| {
         b.iter_batched_ref(
@@ -165,6 +206,7 @@ fn fk_benchmark(c: &mut Criterion) {
             side: Side::Sell,
             request_type: Type::Limit,
             user_id: i,
+            ..Default::default()
         };
         book.match_request(&request.clone());
     }
@@ -174,6 +216,7 @@ fn fk_benchmark(c: &mut Criterion) {
         side: Side::Buy,
         request_type: Type::FillOrKill,
         user_id: 10000,
+        ..Default::default()
     };
     c.bench_function("FillOrKill matching", move |b| {
         b.iter_batched_ref(
@@ -184,15 +227,58 @@ fn fk_benchmark(c: &mut Criterion) {
     });
 }
 
-// criterion_group!(
-//     benches,
-//     l_benchmark,
-//     ic_benchmark,
-//     fk_benchmark,
-//     l_insert_benchmark,
-//     l_insert_worst_case_benchmark
-// );
+fn ouch_benchmark(c: &mut Criterion) {
+    let mut book = OrderBook::default();
+    for i in 0..7000 {
+        let request = Request {
+            price: 1,
+            size: 1,
+            side: Side::Sell,
+            request_type: Type::Limit,
+            user_id: i,
+            ..Default::default()
+        };
+        book.match_request(&request.clone());
+    }
+    let enter = OuchInbound::Enter(EnterOrder {
+        token: 1,
+        side: Side::Buy,
+        size: 20,
+        symbol: wire_symbol("AAA").unwrap(),
+        price: 1,
+        time_in_force: Type::Limit,
+        hidden: false,
+    });
+    let mut message = [0; OUCH_MAX_LEN];
+    let len = enter.encode(&mut message);
+    c.bench_function("Limit matching from and to OUCH", move |b| {
+        b.iter_batched_ref(
+            || book.clone(),
+            |book| {
+                let view = match OuchInboundView::decode(black_box(&message[..len])) {
+                    Ok(Some((OuchInboundView::Enter(view), _))) => view,
+                    _ => unreachable!(),
+                };
+                let result = book.match_request(&view.request(10000));
+                let order = view.to_order();
+                let mut buffer = [0; OUCH_MAX_LEN];
+                let mut match_number = 0;
+                for (_, response) in ouch_responses(0, &order, 10000, &result, &mut match_number) {
+                    black_box(response.encode(&mut buffer));
+                }
+            },
+            BatchSize::SmallInput,
+        );
+    });
+}
+
 criterion_group!(benches,
                  l_benchmark,
-                 lq_benchmark);
+                 ic_benchmark,
+                 fk_benchmark,
+                 l_insert_benchmark,
+                 l_insert_worst_case_benchmark,
+                 l_u32_benchmark,
+                 lq_benchmark,
+                 ouch_benchmark);
 criterion_main!(benches);
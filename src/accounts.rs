@@ -0,0 +1,365 @@
+use serde::{Deserialize, Serialize};
+use std::cmp;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::exchange::*;
+use crate::fees::*;
+use crate::matcher::*;
+use crate::price::Overflow;
+
+/// Assets an instrument is traded in: sizes are in `base`, prices in `quote`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Instrument {
+    pub base: String,
+    pub quote: String,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Balance {
+    pub available: u64,
+    /// Held by requests which are in a book.
+    pub reserved: u64,
+}
+
+// Funds held by one request: quote asset at `price` per piece plus the highest
+// fee for buyers, one piece of base asset per piece for sellers.
+#[derive(Debug, Clone)]
+struct Reservation {
+    side: Side,
+    price: u64,
+    size: u64,
+    fee_rate: i64,
+    amount: u64,
+}
+
+impl Reservation {
+    // Part of the reserved amount which pays for `size` pieces.
+    fn amount_for(&self, size: u64) -> u64 {
+        if size >= self.size {
+            return self.amount;
+        }
+        let amount = match self.side {
+            Side::Buy => buyer_amount(self.price, size, self.fee_rate).unwrap_or(u64::MAX),
+            Side::Sell => size,
+        };
+        cmp::min(amount, self.amount)
+    }
+}
+
+fn buyer_amount(price: u64, size: u64, fee_rate: i64) -> Option<u64> {
+    let cost = price.checked_mul(size)?;
+    cost.checked_add(cmp::max(fee(cost, fee_rate).ok()?, 0) as u64)
+}
+
+/// Why an execution could not be settled by the accounts.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum SettlementError {
+    /// The user has not enough of the asset to pay, outside of reservations.
+    InsufficientFunds {
+        user_id: u64,
+        asset: String,
+    },
+    Overflow(Overflow),
+}
+
+impl fmt::Display for SettlementError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SettlementError::InsufficientFunds { user_id, asset } => {
+                write!(f, "user #{} has not enough {}", user_id, asset)
+            }
+            SettlementError::Overflow(overflow) => write!(f, "{}", overflow),
+        }
+    }
+}
+
+impl std::error::Error for SettlementError {}
+
+/// An execution the accounts could not pay for, the venue has to settle it by hand.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct UnsettledExecution {
+    pub symbol: String,
+    pub action: MarketAction,
+    pub error: SettlementError,
+}
+
+/// Balances of users, with funds reserved for every request they have in a book.
+///
+/// A buyer reserves price times size of quote asset and the fee on top of it,
+/// a pegged buyer does it at its limit, and a seller reserves size of base asset.
+/// Executions are paid from the reservations, whatever is left is released once
+/// the request leaves the book. Fees are paid in quote asset.
+#[derive(Default, Debug, Clone)]
+pub struct Accounts {
+    instruments: HashMap<String, Instrument>,
+    balances: HashMap<(u64, String), Balance>,
+    // keyed by symbol, user and request id
+    reservations: HashMap<(String, u64, u64), Reservation>,
+    collected_fees: HashMap<String, i64>,
+}
+
+impl Accounts {
+    pub fn add_instrument(&mut self, symbol: &str, instrument: Instrument) {
+        self.instruments.insert(symbol.to_string(), instrument);
+    }
+
+    pub fn balance(&self, user_id: u64, asset: &str) -> Balance {
+        self.balances
+            .get(&(user_id, asset.to_string()))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Adds the amount to available funds, returns `false` if the balance would overflow.
+    pub fn deposit(&mut self, user_id: u64, asset: &str, amount: u64) -> bool {
+        let balance = self.balance_mut(user_id, asset);
+        // reserved funds return to available ones, so the sum of both has to fit
+        let total = balance.available.checked_add(balance.reserved);
+        match total.and_then(|total| total.checked_add(amount)) {
+            Some(_) => {
+                balance.available += amount;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Takes the amount from available funds, returns `false` if there is not enough.
+    pub fn withdraw(&mut self, user_id: u64, asset: &str, amount: u64) -> bool {
+        let balance = self.balance_mut(user_id, asset);
+        if balance.available < amount {
+            return false;
+        }
+        balance.available -= amount;
+        true
+    }
+
+    fn balance_mut(&mut self, user_id: u64, asset: &str) -> &mut Balance {
+        self.balances
+            .entry((user_id, asset.to_string()))
+            .or_default()
+    }
+
+    /// Net fees the venue has collected in the asset.
+    pub fn collected_fees(&self, asset: &str) -> i64 {
+        self.collected_fees.get(asset).copied().unwrap_or(0)
+    }
+
+    /// Reserves funds for requests of one user which are sent together, taking into
+    /// account funds of the `replaced` requests which are released at the same time.
+    /// Buyers reserve fees at `fee_rate`, which should be the highest rate they may pay.
+    pub(crate) fn reserve(
+        &mut self,
+        symbol: &str,
+        requests: &[Request],
+        replaced: &[Request],
+        fee_rate: i64,
+    ) -> Result<(), Reject> {
+        let instrument = self.instruments.get(symbol).ok_or(Reject::UnknownBook)?;
+        let mut reservations = Vec::new();
+        let (mut base, mut quote) = (0u64, 0u64);
+        for request in requests {
+            if request.id == 0 {
+                return Err(Reject::MissingId);
+            }
+            // a second reservation under the same key would hide the first one
+            let key = (symbol.to_string(), request.user_id, request.id);
+            let is_replaced = replaced.iter().any(|old| old.id == request.id);
+            if self.reservations.contains_key(&key) && !is_replaced {
+                return Err(Reject::DuplicateId);
+            }
+            let reservation = match request.side {
+                Side::Buy => {
+                    let price = match request.peg {
+                        Some(peg) => peg.limit.ok_or(Reject::PegWithoutLimit)?,
+                        None => request.price,
+                    };
+                    let amount = buyer_amount(price, request.size, fee_rate)
+                        .ok_or(Reject::Overflow(Overflow::Notional))?;
+                    quote = quote
+                        .checked_add(amount)
+                        .ok_or(Reject::Overflow(Overflow::Balance))?;
+                    Reservation {
+                        side: Side::Buy,
+                        price,
+                        size: request.size,
+                        fee_rate,
+                        amount,
+                    }
+                }
+                Side::Sell => {
+                    base = base
+                        .checked_add(request.size)
+                        .ok_or(Reject::Overflow(Overflow::Balance))?;
+                    Reservation {
+                        side: Side::Sell,
+                        price: request.price,
+                        size: request.size,
+                        fee_rate,
+                        amount: request.size,
+                    }
+                }
+            };
+            reservations.push((request.user_id, request.id, reservation));
+        }
+        let user_id = match requests.first() {
+            Some(request) => request.user_id,
+            None => return Ok(()),
+        };
+        let (mut released_base, mut released_quote) = (0, 0);
+        for request in replaced {
+            let key = (symbol.to_string(), request.user_id, request.id);
+            if let Some(reservation) = self.reservations.get(&key) {
+                match reservation.side {
+                    Side::Buy => released_quote += reservation.amount,
+                    Side::Sell => released_base += reservation.amount,
+                }
+            }
+        }
+        let (base_asset, quote_asset) = (instrument.base.clone(), instrument.quote.clone());
+        let available = |asset: &str, released: u64| {
+            self.balance(user_id, asset)
+                .available
+                .saturating_add(released)
+        };
+        if available(&base_asset, released_base) < base
+            || available(&quote_asset, released_quote) < quote
+        {
+            return Err(Reject::InsufficientFunds);
+        }
+
+        for request in replaced {
+            self.release(symbol, request.user_id, request.id);
+        }
+        self.balance_mut(user_id, &base_asset).available -= base;
+        self.balance_mut(user_id, &base_asset).reserved += base;
+        self.balance_mut(user_id, &quote_asset).available -= quote;
+        self.balance_mut(user_id, &quote_asset).reserved += quote;
+        for (user_id, id, reservation) in reservations {
+            self.reservations
+                .insert((symbol.to_string(), user_id, id), reservation);
+        }
+        Ok(())
+    }
+
+    /// Returns funds still held by the request to its user.
+    pub(crate) fn release(&mut self, symbol: &str, user_id: u64, id: u64) {
+        let reservation = match self.reservations.remove(&(symbol.to_string(), user_id, id)) {
+            Some(reservation) => reservation,
+            None => return,
+        };
+        let instrument = &self.instruments[symbol];
+        let asset = match reservation.side {
+            Side::Buy => instrument.quote.clone(),
+            Side::Sell => instrument.base.clone(),
+        };
+        let balance = self.balance_mut(user_id, &asset);
+        balance.reserved -= reservation.amount;
+        balance.available += reservation.amount;
+    }
+
+    /// Moves funds of an execution between the buyer and the seller and charges their fees.
+    /// Requests without a reservation pay from available funds; if somebody cannot pay
+    /// or a balance would overflow, nothing is moved and the error tells why.
+    pub(crate) fn settle(
+        &mut self,
+        symbol: &str,
+        action: &MarketAction,
+    ) -> Result<(), SettlementError> {
+        let instrument = match self.instruments.get(symbol) {
+            Some(instrument) => instrument.clone(),
+            None => return Ok(()),
+        };
+        let cost = i128::from(action.price) * i128::from(action.size);
+        let buyer = (action.buyer_user_id, action.buyer_request_id);
+        let seller = (action.seller_user_id, action.seller_request_id);
+        let buyer_reserved = self.reserved_for(symbol, buyer, action.size);
+        let seller_reserved = self.reserved_for(symbol, seller, action.size);
+
+        // balances are changed on copies, so that a failed settlement leaves them as they were
+        let mut changed: Vec<((u64, String), Balance)> = Vec::new();
+        let mut change = |user_id: u64, asset: &str, reserved: u64, available: i128| {
+            let key = (user_id, asset.to_string());
+            let index = match changed.iter().position(|(changed, _)| *changed == key) {
+                Some(index) => index,
+                None => {
+                    let balance = self.balances.get(&key).copied().unwrap_or_default();
+                    changed.push((key, balance));
+                    changed.len() - 1
+                }
+            };
+            let balance = &mut changed[index].1;
+            balance.reserved = balance
+                .reserved
+                .checked_sub(reserved)
+                .ok_or(SettlementError::Overflow(Overflow::Balance))?;
+            balance.available = add_signed(balance.available, i128::from(reserved) + available)
+                .ok_or_else(|| {
+                    if available < 0 {
+                        SettlementError::InsufficientFunds {
+                            user_id,
+                            asset: asset.to_string(),
+                        }
+                    } else {
+                        SettlementError::Overflow(Overflow::Balance)
+                    }
+                })?;
+            Ok(())
+        };
+        // the execution may happen at a better price than the reserved one
+        let paid = cost + i128::from(action.buyer_fee);
+        change(
+            buyer.0,
+            &instrument.quote,
+            buyer_reserved.unwrap_or(0),
+            -paid,
+        )?;
+        change(buyer.0, &instrument.base, 0, i128::from(action.size))?;
+        match seller_reserved {
+            Some(amount) => change(seller.0, &instrument.base, amount, -i128::from(amount))?,
+            None => change(seller.0, &instrument.base, 0, -i128::from(action.size))?,
+        }
+        let received = cost - i128::from(action.seller_fee);
+        change(seller.0, &instrument.quote, 0, received)?;
+        let collected = self.collected_fees(&instrument.quote);
+        let collected = collected
+            .checked_add(action.buyer_fee)
+            .and_then(|collected| collected.checked_add(action.seller_fee))
+            .ok_or(SettlementError::Overflow(Overflow::Balance))?;
+
+        self.balances.extend(changed);
+        self.collected_fees.insert(instrument.quote, collected);
+        self.take_reserved(symbol, buyer, action.size);
+        self.take_reserved(symbol, seller, action.size);
+        Ok(())
+    }
+
+    // Part of the reservation of a request which pays for an executed size.
+    fn reserved_for(&self, symbol: &str, (user_id, id): (u64, u64), size: u64) -> Option<u64> {
+        let key = (symbol.to_string(), user_id, id);
+        self.reservations
+            .get(&key)
+            .map(|reservation| reservation.amount_for(size))
+    }
+
+    // Shrinks the reservation of a request by an executed size and returns the amount
+    // which pays for it.
+    fn take_reserved(&mut self, symbol: &str, (user_id, id): (u64, u64), size: u64) -> Option<u64> {
+        let key = (symbol.to_string(), user_id, id);
+        let reservation = self.reservations.get_mut(&key)?;
+        let amount = reservation.amount_for(size);
+        reservation.amount -= amount;
+        reservation.size = reservation.size.saturating_sub(size);
+        if reservation.size == 0 {
+            self.reservations.remove(&key);
+        }
+        Some(amount)
+    }
+}
+
+// `None` if the result is negative or does not fit into `u64`.
+fn add_signed(value: u64, delta: i128) -> Option<u64> {
+    u64::try_from(i128::from(value) + delta).ok()
+}
@@ -0,0 +1,129 @@
+extern crate market_matcher;
+
+use std::env;
+use std::fs;
+use std::process;
+
+use market_matcher::*;
+
+const USAGE: &str = "usage: market_server [--address ADDRESS] [--config PATH] [--symbol SYMBOL]...
+                     [--api-key KEY=USER_ID]...
+
+Serves the HTTP API of the books of the symbols, matched by a single thread:
+GET /books, GET /books/SYMBOL, GET /books/SYMBOL/trades,
+POST /books/SYMBOL/requests, DELETE /books/SYMBOL/requests/ID and
+GET /users/USER_ID/requests. A request is only cancelled by its user,
+whose KEY is sent as `Authorization: Bearer KEY`.
+Trades and level updates of a book are streamed over WebSocket at
+/books/SYMBOL/stream. Unless ADDRESS is given it is taken from the
+section of the config, `Rocket.toml` by default, named by ROCKET_ENV,
+`development` by default.";
+
+fn main() {
+    let mut address = None;
+    let mut config = "Rocket.toml".to_string();
+    let mut symbols = Vec::new();
+    let mut api_keys = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--address" => {
+                address = Some(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("missing address")),
+                )
+            }
+            "--config" => config = args.next().unwrap_or_else(|| usage_error("missing path")),
+            "--symbol" => {
+                symbols.push(args.next().unwrap_or_else(|| usage_error("missing symbol")))
+            }
+            "--api-key" => {
+                let key = args
+                    .next()
+                    .unwrap_or_else(|| usage_error("missing API key"));
+                let (key, user_id) = key
+                    .split_once('=')
+                    .and_then(|(key, user_id)| Some((key.to_string(), user_id.parse().ok()?)))
+                    .unwrap_or_else(|| usage_error("expected KEY=USER_ID"));
+                api_keys.push((key, user_id));
+            }
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => usage_error(&format!("unexpected argument '{}'", arg)),
+        }
+    }
+    let address = address.unwrap_or_else(|| {
+        let environment = env::var("ROCKET_ENV").unwrap_or_else(|_| "development".to_string());
+        match fs::read_to_string(&config) {
+            Ok(config) => configured_address(&config, &environment),
+            Err(error) => fail(&format!("{}: {}", config, error)),
+        }
+    });
+
+    let mut exchange = Exchange::default();
+    for symbol in symbols.iter() {
+        exchange.add_book(symbol);
+    }
+    let engine = MatchingEngine::new(exchange).spawn();
+    let mut server = HttpServer::bind(address.as_str(), engine)
+        .unwrap_or_else(|error| fail(&format!("{}: {}", address, error)));
+    for (key, user_id) in api_keys.iter() {
+        server.add_api_key(key, *user_id);
+    }
+    if let Ok(address) = server.local_addr() {
+        eprintln!("market_server: listening on {}", address);
+    }
+    if let Err(error) = server.run() {
+        fail(&error.to_string());
+    }
+}
+
+// Address and port of the section of the config, which is TOML with plain `key = value` lines;
+// like Rocket, the port is 8000 unless it is set.
+fn configured_address(config: &str, environment: &str) -> String {
+    let section = format!("[{}]", environment);
+    let mut in_section = false;
+    let mut host = "0.0.0.0".to_string();
+    let mut port = "8000".to_string();
+    for line in config.lines().map(|line| without_comment(line).trim()) {
+        if line.starts_with('[') {
+            in_section = line == section;
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) if in_section => (key.trim(), value.trim()),
+            _ => continue,
+        };
+        match key {
+            "address" => host = value.trim_matches('"').to_string(),
+            "port" => port = value.to_string(),
+            _ => {}
+        }
+    }
+    format!("{}:{}", host, port)
+}
+
+// The line up to a `#` which is not inside a string.
+fn without_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("market_server: {}\n\n{}", message, USAGE);
+    process::exit(2);
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("market_server: {}", message);
+    process::exit(1);
+}
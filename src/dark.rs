@@ -0,0 +1,298 @@
+use std::cmp;
+
+use crate::matcher::*;
+use crate::price::{Price, Quantity, UserId};
+
+/// A book without displayed prices where every execution happens at the midpoint
+/// of a reference BBO supplied from outside, usually `OrderBook::bbo` of a lit book.
+///
+/// Requests queue by time only, their prices are limits on the midpoint: buyers
+/// execute at or below their price and sellers at or above it. `min_size` of both
+/// sides is honoured on every execution, pegs and the hidden flag are ignored.
+/// A midpoint between two price points is rounded in favour of the passive side.
+#[derive(Debug, Clone)]
+pub struct DarkBook<P = u64, Q = u64, U = u64> {
+    pub(crate) buyers: Vec<DarkRequest<P, Q, U>>,
+    pub(crate) sellers: Vec<DarkRequest<P, Q, U>>,
+    reference: Bbo<P>,
+    arrivals: u64,
+}
+
+// only the unsigned book is the default one, like `OrderBook`
+impl Default for DarkBook {
+    fn default() -> Self {
+        DarkBook::new()
+    }
+}
+
+/// A request to the dark book, which may refuse executions smaller than `min_size`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DarkOrder<P = u64, Q = u64, U = u64> {
+    pub request: Request<P, Q, U>,
+    pub min_size: Q,
+}
+
+/// A resting order with the number telling when it arrived to the book.
+#[derive(Debug, Clone)]
+pub(crate) struct DarkRequest<P, Q, U> {
+    pub(crate) request: Request<P, Q, U>,
+    pub(crate) min_size: Q,
+    pub(crate) arrival: u64,
+}
+
+// Prices the midpoint is rounded to, equal unless it falls between two price points.
+#[derive(Debug, Clone, Copy)]
+struct Midpoint<P> {
+    below: P,
+    above: P,
+}
+
+impl<P: Price> Midpoint<P> {
+    // the aggressor pays for the rounding
+    fn price(self, aggressor_side: Side) -> P {
+        match aggressor_side {
+            Side::Buy => self.above,
+            Side::Sell => self.below,
+        }
+    }
+}
+
+impl<P: Price, Q: Quantity, U: UserId> DarkBook<P, Q, U> {
+    pub fn new() -> Self {
+        DarkBook {
+            buyers: Vec::new(),
+            sellers: Vec::new(),
+            reference: Bbo::default(),
+            arrivals: 0,
+        }
+    }
+
+    /// Midpoint of the reference BBO rounded down, `None` while it is one-sided or crossed.
+    pub fn midpoint(&self) -> Option<P> {
+        self.rounded_midpoint().map(|midpoint| midpoint.below)
+    }
+
+    /// Price of executions with an aggressor on the side, `None` while the reference BBO
+    /// is one-sided or crossed.
+    pub fn execution_price(&self, aggressor_side: Side) -> Option<P> {
+        self.rounded_midpoint()
+            .map(|midpoint| midpoint.price(aggressor_side))
+    }
+
+    fn rounded_midpoint(&self) -> Option<Midpoint<P>> {
+        let (bid, ask) = (self.reference.bid?, self.reference.ask?);
+        if bid > ask {
+            return None;
+        }
+        Some(Midpoint {
+            below: P::middle(bid, ask, false),
+            above: P::middle(bid, ask, true),
+        })
+    }
+
+    /// Sets a new reference BBO and executes resting requests which are now able
+    /// to trade with each other, the one which arrived later is the aggressor.
+    pub fn update_reference(&mut self, reference: Bbo<P>) -> Vec<MarketAction<P, Q, U>> {
+        self.reference = reference;
+        let mut market_actions = Vec::new();
+        let midpoint = match self.rounded_midpoint() {
+            Some(midpoint) => midpoint,
+            None => return market_actions,
+        };
+        let mut index = 0;
+        while index < self.buyers.len() {
+            let buyer = self.buyers[index].clone();
+            let left = execute(&buyer, midpoint, &mut self.sellers, &mut market_actions);
+            if left == Q::ZERO {
+                self.buyers.remove(index);
+            } else {
+                self.buyers[index].request.size = left;
+                index += 1;
+            }
+        }
+        market_actions
+    }
+
+    /// Matches a request which takes executions of any size.
+    pub fn match_request(&mut self, request: &Request<P, Q, U>) -> MatchingResult<P, Q, U> {
+        self.match_order(&DarkOrder {
+            request: request.clone(),
+            min_size: Q::ZERO,
+        })
+    }
+
+    pub fn match_order(&mut self, order: &DarkOrder<P, Q, U>) -> MatchingResult<P, Q, U> {
+        let request = &order.request;
+        let mut market_actions = Vec::new();
+        let mut request_actions = Vec::new();
+        let midpoint = self.rounded_midpoint();
+        self.arrivals += 1;
+        let incoming = DarkRequest {
+            request: request.clone(),
+            min_size: order.min_size,
+            arrival: self.arrivals,
+        };
+        let opposite_vec = match request.side {
+            Side::Buy => &mut self.sellers,
+            Side::Sell => &mut self.buyers,
+        };
+        let left = match midpoint {
+            Some(midpoint) => {
+                let fillable = fillable_size(&incoming, midpoint, opposite_vec);
+                if request.request_type == Type::FillOrKill && fillable < request.size {
+                    request.size
+                } else {
+                    execute(&incoming, midpoint, opposite_vec, &mut market_actions)
+                }
+            }
+            None => request.size,
+        };
+
+        if left > Q::ZERO {
+            if left != request.size {
+                request_actions.push(RequestAction::FilledPartially);
+            }
+            match request.request_type {
+                Type::Limit | Type::Quote => {
+                    let mut leftover_request = incoming;
+                    leftover_request.request.size = left;
+                    match request.side {
+                        Side::Buy => self.buyers.push(leftover_request),
+                        Side::Sell => self.sellers.push(leftover_request),
+                    }
+                    request_actions.push(RequestAction::AddedToBook);
+                }
+                Type::FillOrKill | Type::ImmediateOrCancel => {
+                    request_actions.push(RequestAction::Cancelled)
+                }
+            }
+        } else {
+            request_actions.push(RequestAction::Filled);
+        }
+        MatchingResult {
+            market_actions,
+            request_actions,
+            book_events: Vec::new(),
+        }
+    }
+
+    /// Removes the resting request with the id, requests without an id cannot be cancelled.
+    pub fn cancel_request(&mut self, id: u64) -> Vec<BookEvent<P, Q, U>> {
+        if id == 0 {
+            return Vec::new();
+        }
+        for queue in [&mut self.buyers, &mut self.sellers].iter_mut() {
+            if let Some(index) = queue.iter().position(|resting| resting.request.id == id) {
+                let request = queue.remove(index).request;
+                return vec![BookEvent::Cancelled { request }];
+            }
+        }
+        Vec::new()
+    }
+}
+
+impl<P: Price, Q: Quantity, U: UserId> Matcher<P, Q, U> for DarkBook<P, Q, U> {
+    fn match_request(&mut self, request: &Request<P, Q, U>) -> MatchingResult<P, Q, U> {
+        DarkBook::match_request(self, request)
+    }
+}
+
+fn accepts_price<P: Price, Q, U>(request: &Request<P, Q, U>, price: P) -> bool {
+    match request.side {
+        Side::Buy => price <= request.price,
+        Side::Sell => price >= request.price,
+    }
+}
+
+// Size of an execution between the incoming request with `left` pieces to go and
+// a passive one, or `None` if they cannot trade with each other.
+fn execution_size<P, Q: Quantity, U: UserId>(
+    incoming: &DarkRequest<P, Q, U>,
+    left: Q,
+    passive: &DarkRequest<P, Q, U>,
+) -> Option<Q> {
+    if passive.request.user_id == incoming.request.user_id {
+        return None;
+    }
+    let size = cmp::min(left, passive.request.size);
+    // the last execution of a request is allowed to be smaller than its minimum
+    if size < cmp::min(incoming.min_size, left)
+        || size < cmp::min(passive.min_size, passive.request.size)
+    {
+        return None;
+    }
+    Some(size)
+}
+
+fn fillable_size<P: Price, Q: Quantity, U: UserId>(
+    incoming: &DarkRequest<P, Q, U>,
+    midpoint: Midpoint<P>,
+    opposite_vec: &[DarkRequest<P, Q, U>],
+) -> Q {
+    let request = &incoming.request;
+    let price = midpoint.price(request.side);
+    if !accepts_price(request, price) {
+        return Q::ZERO;
+    }
+    let mut left = request.size;
+    for passive in opposite_vec.iter() {
+        if left == Q::ZERO {
+            break;
+        }
+        if !accepts_price(&passive.request, price) {
+            continue;
+        }
+        if let Some(size) = execution_size(incoming, left, passive) {
+            left -= size;
+        }
+    }
+    request.size - left
+}
+
+// Executes the request against the opposite queue at the midpoint, returning the size left.
+fn execute<P: Price, Q: Quantity, U: UserId>(
+    incoming: &DarkRequest<P, Q, U>,
+    midpoint: Midpoint<P>,
+    opposite_vec: &mut Vec<DarkRequest<P, Q, U>>,
+    market_actions: &mut Vec<MarketAction<P, Q, U>>,
+) -> Q {
+    let request = &incoming.request;
+    let mut left = request.size;
+    for resting in opposite_vec.iter_mut() {
+        if left == Q::ZERO {
+            break;
+        }
+        let aggressor_side = if incoming.arrival > resting.arrival {
+            request.side
+        } else {
+            resting.request.side
+        };
+        let price = midpoint.price(aggressor_side);
+        if !accepts_price(request, price) || !accepts_price(&resting.request, price) {
+            continue;
+        }
+        let size = match execution_size(incoming, left, resting) {
+            Some(size) => size,
+            None => continue,
+        };
+        let passive_request = &mut resting.request;
+        let (seller, buyer) = match request.side {
+            Side::Buy => (&*passive_request, request),
+            Side::Sell => (request, &*passive_request),
+        };
+        market_actions.push(MarketAction {
+            size,
+            price,
+            seller_user_id: seller.user_id,
+            buyer_user_id: buyer.user_id,
+            seller_request_id: seller.id,
+            buyer_request_id: buyer.id,
+            aggressor_side,
+            ..Default::default()
+        });
+        left -= size;
+        passive_request.size -= size;
+    }
+    opposite_vec.retain(|resting| resting.request.size > Q::ZERO);
+    left
+}
@@ -0,0 +1,425 @@
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use std::cmp::{self, Ordering};
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::{Add, AddAssign, Sub, SubAssign};
+use std::str::FromStr;
+
+use crate::matcher::*;
+use crate::pegging::*;
+use crate::price::{Price, Quantity};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecimalError {
+    Malformed,
+    /// The number has more decimal places than the instrument allows.
+    TooPrecise,
+    OutOfRange,
+}
+
+impl fmt::Display for DecimalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let res_str = match self {
+            DecimalError::Malformed => "not a decimal number",
+            DecimalError::TooPrecise => "too many decimal places",
+            DecimalError::OutOfRange => "the number is out of range",
+        };
+        write!(f, "{}", res_str)
+    }
+}
+
+impl std::error::Error for DecimalError {}
+
+/// Exact decimal number equal to `units / 10^scale`.
+///
+/// Numbers are compared by value, so 1.0 equals 1.00; the scale only tells how
+/// many decimal places are displayed. As a price of a book, a price point is one
+/// unit of the last decimal place.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct Decimal {
+    pub units: i128,
+    pub scale: u32,
+}
+
+impl Decimal {
+    pub fn new(units: i128, scale: u32) -> Decimal {
+        Decimal { units, scale }
+    }
+
+    /// The same number with another amount of decimal places, which fails rather
+    /// than rounding.
+    pub fn rescale(self, scale: u32) -> Result<Decimal, DecimalError> {
+        let units = if scale >= self.scale {
+            10i128
+                .checked_pow(scale - self.scale)
+                .and_then(|factor| self.units.checked_mul(factor))
+                .ok_or(DecimalError::OutOfRange)?
+        } else {
+            let divisor = 10i128
+                .checked_pow(self.scale - scale)
+                .ok_or(DecimalError::TooPrecise)?;
+            if self.units % divisor != 0 {
+                return Err(DecimalError::TooPrecise);
+            }
+            self.units / divisor
+        };
+        Ok(Decimal { units, scale })
+    }
+
+    pub fn checked_mul(self, other: Decimal) -> Option<Decimal> {
+        Some(Decimal {
+            units: self.units.checked_mul(other.units)?,
+            scale: self.scale.checked_add(other.scale)?,
+        })
+    }
+
+    /// The same number without trailing zeros in its decimal places.
+    pub fn normalize(self) -> Decimal {
+        let mut normalized = self;
+        while normalized.scale > 0 && normalized.units % 10 == 0 {
+            normalized.units /= 10;
+            normalized.scale -= 1;
+        }
+        normalized
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Decimal) -> bool {
+        let (left, right) = (self.normalize(), other.normalize());
+        left.units == right.units && left.scale == right.scale
+    }
+}
+
+impl Eq for Decimal {}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Decimal) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Decimal) -> Ordering {
+        let scale = cmp::max(self.scale, other.scale);
+        match (self.rescale(scale), other.rescale(scale)) {
+            (Ok(left), Ok(right)) => left.units.cmp(&right.units),
+            // a number which does not fit with more places is further from zero than the other
+            (Err(_), _) if self.units != 0 => self.units.cmp(&0),
+            (_, Err(_)) if other.units != 0 => 0.cmp(&other.units),
+            _ => self.units.signum().cmp(&other.units.signum()),
+        }
+    }
+}
+
+// Units of both numbers with the same amount of decimal places.
+fn aligned(left: Decimal, right: Decimal) -> Option<(i128, i128, u32)> {
+    let scale = cmp::max(left.scale, right.scale);
+    let (left, right) = (left.rescale(scale).ok()?, right.rescale(scale).ok()?);
+    Some((left.units, right.units, scale))
+}
+
+impl Price for Decimal {
+    fn offset(self, ticks: i64) -> Option<Decimal> {
+        Some(Decimal::new(
+            self.units.checked_add(i128::from(ticks))?,
+            self.scale,
+        ))
+    }
+
+    fn middle(low: Decimal, high: Decimal, round_up: bool) -> Decimal {
+        // the middle is exact with one more decimal place, which is dropped unless needed
+        let middle = |places: u32| {
+            let scale = cmp::max(low.scale, high.scale).checked_add(places)?;
+            let (low, high) = (low.rescale(scale).ok()?, high.rescale(scale).ok()?);
+            let difference = high.units.checked_sub(low.units)?;
+            let half = if round_up {
+                difference - difference / 2
+            } else {
+                difference / 2
+            };
+            Some(Decimal::new(low.units + half, scale))
+        };
+        match middle(1) {
+            Some(exact) if exact.units % 10 == 0 => Decimal::new(exact.units / 10, exact.scale - 1),
+            Some(exact) => exact,
+            None => middle(0).unwrap_or(low),
+        }
+    }
+}
+
+impl Quantity for Decimal {
+    const ZERO: Decimal = Decimal { units: 0, scale: 0 };
+    const MAX: Decimal = Decimal {
+        units: i128::MAX,
+        scale: 0,
+    };
+
+    fn checked_add(self, other: Decimal) -> Option<Decimal> {
+        let (left, right, scale) = aligned(self, other)?;
+        Some(Decimal::new(left.checked_add(right)?, scale))
+    }
+
+    fn checked_sub(self, other: Decimal) -> Option<Decimal> {
+        let (left, right, scale) = aligned(self, other)?;
+        Some(Decimal::new(left.checked_sub(right)?, scale))
+    }
+
+    fn to_f64(self) -> f64 {
+        self.units as f64 / 10f64.powi(self.scale as i32)
+    }
+}
+
+impl Add for Decimal {
+    type Output = Decimal;
+
+    fn add(self, other: Decimal) -> Decimal {
+        Quantity::checked_add(self, other).expect("decimal addition overflowed")
+    }
+}
+
+impl Sub for Decimal {
+    type Output = Decimal;
+
+    fn sub(self, other: Decimal) -> Decimal {
+        Quantity::checked_sub(self, other).expect("decimal subtraction overflowed")
+    }
+}
+
+impl AddAssign for Decimal {
+    fn add_assign(&mut self, other: Decimal) {
+        *self = *self + other;
+    }
+}
+
+impl SubAssign for Decimal {
+    fn sub_assign(&mut self, other: Decimal) {
+        *self = *self - other;
+    }
+}
+
+impl Hash for Decimal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let normalized = self.normalize();
+        normalized.units.hash(state);
+        normalized.scale.hash(state);
+    }
+}
+
+impl FromStr for Decimal {
+    type Err = DecimalError;
+
+    fn from_str(s: &str) -> Result<Decimal, DecimalError> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, s),
+        };
+        let (whole, fraction) = match digits.split_once('.') {
+            Some((whole, fraction)) => (whole, fraction),
+            None => (digits, ""),
+        };
+        let is_digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+        if whole.is_empty()
+            || !is_digits(whole)
+            || !is_digits(fraction)
+            || (fraction.is_empty() && digits.contains('.'))
+        {
+            return Err(DecimalError::Malformed);
+        }
+        let mut units: i128 = 0;
+        for byte in whole.bytes().chain(fraction.bytes()) {
+            units = units
+                .checked_mul(10)
+                .and_then(|units| units.checked_add(i128::from(byte - b'0')))
+                .ok_or(DecimalError::OutOfRange)?;
+        }
+        Ok(Decimal {
+            units: if negative { -units } else { units },
+            scale: fraction.len() as u32,
+        })
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.units < 0 { "-" } else { "" };
+        let digits = self.units.unsigned_abs().to_string();
+        let scale = self.scale as usize;
+        if scale == 0 {
+            return write!(f, "{}{}", sign, digits);
+        }
+        let digits = format!("{:0>width$}", digits, width = scale + 1);
+        let (whole, fraction) = digits.split_at(digits.len() - scale);
+        write!(f, "{}{}.{}", sign, whole, fraction)
+    }
+}
+
+impl Serialize for Decimal {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+struct DecimalVisitor;
+
+impl<'de> Visitor<'de> for DecimalVisitor {
+    type Value = Decimal;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a decimal number in a string or a number")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Decimal, E> {
+        value
+            .parse()
+            .map_err(|_| E::invalid_value(de::Unexpected::Str(value), &self))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Decimal, E> {
+        Ok(Decimal::new(i128::from(value), 0))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Decimal, E> {
+        Ok(Decimal::new(i128::from(value), 0))
+    }
+
+    fn visit_u128<E: de::Error>(self, value: u128) -> Result<Decimal, E> {
+        i128::try_from(value)
+            .map(|units| Decimal::new(units, 0))
+            .map_err(|_| E::invalid_value(de::Unexpected::Other("a too large integer"), &self))
+    }
+
+    fn visit_i128<E: de::Error>(self, value: i128) -> Result<Decimal, E> {
+        Ok(Decimal::new(value, 0))
+    }
+
+    // the shortest decimal which reads back as the same float, which is what was written
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<Decimal, E> {
+        value
+            .to_string()
+            .parse()
+            .map_err(|_| E::invalid_value(de::Unexpected::Float(value), &self))
+    }
+}
+
+impl<'de> Deserialize<'de> for Decimal {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Decimal, D::Error> {
+        deserializer.deserialize_any(DecimalVisitor)
+    }
+}
+
+/// Decimal places of prices and sizes of an instrument; the engine works in
+/// integer units of `10^-price` and `10^-size`.
+#[derive(Deserialize, Serialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scale {
+    pub price: u32,
+    pub size: u32,
+}
+
+impl Scale {
+    pub fn price(&self, units: u64) -> Decimal {
+        Decimal::new(i128::from(units), self.price)
+    }
+
+    pub fn size(&self, units: u64) -> Decimal {
+        Decimal::new(i128::from(units), self.size)
+    }
+
+    /// Amounts which are price times size, such as notionals and fees.
+    pub fn amount(&self, units: i64) -> Decimal {
+        Decimal::new(i128::from(units), self.price + self.size)
+    }
+
+    pub fn price_units(&self, price: Decimal) -> Result<u64, DecimalError> {
+        to_units(price, self.price)
+    }
+
+    pub fn size_units(&self, size: Decimal) -> Result<u64, DecimalError> {
+        to_units(size, self.size)
+    }
+
+    /// Exact price times size, `None` if it does not fit into a `Decimal`.
+    pub fn notional(&self, price: u64, size: u64) -> Option<Decimal> {
+        self.price(price).checked_mul(self.size(size))
+    }
+
+    pub fn request(&self, request: &DecimalRequest) -> Result<Request, DecimalError> {
+        let peg = match request.peg {
+            Some(peg) => Some(Peg {
+                reference: peg.reference,
+                offset: peg.offset,
+                limit: peg.limit.map(|limit| self.price_units(limit)).transpose()?,
+            }),
+            None => None,
+        };
+        Ok(Request {
+            id: request.id,
+            side: request.side,
+            price: self.price_units(request.price)?,
+            size: self.size_units(request.size)?,
+            user_id: request.user_id,
+            request_type: request.request_type,
+            peg,
+            hidden: request.hidden,
+        })
+    }
+
+    pub fn decimal_request(&self, request: &Request) -> DecimalRequest {
+        DecimalRequest {
+            id: request.id,
+            side: request.side,
+            price: self.price(request.price),
+            size: self.size(request.size),
+            user_id: request.user_id,
+            request_type: request.request_type,
+            peg: request.peg.map(|peg| Peg {
+                reference: peg.reference,
+                offset: peg.offset,
+                limit: peg.limit.map(|limit| self.price(limit)),
+            }),
+            hidden: request.hidden,
+        }
+    }
+
+    /// The execution with decimal price and size; fees stay integer amounts, which
+    /// `Scale::amount` turns into decimals.
+    pub fn market_action(&self, action: &MarketAction) -> MarketAction<Decimal, Decimal> {
+        MarketAction {
+            size: self.size(action.size),
+            price: self.price(action.price),
+            seller_user_id: action.seller_user_id,
+            buyer_user_id: action.buyer_user_id,
+            seller_request_id: action.seller_request_id,
+            buyer_request_id: action.buyer_request_id,
+            aggressor_side: action.aggressor_side,
+            buyer_fee: action.buyer_fee,
+            seller_fee: action.seller_fee,
+        }
+    }
+
+    /// Displays prices and sizes of the value as decimals.
+    #[cfg(feature = "display")]
+    pub fn display<'a, T>(&self, value: &'a T) -> Scaled<'a, T> {
+        Scaled {
+            value,
+            scale: *self,
+        }
+    }
+}
+
+fn to_units(value: Decimal, scale: u32) -> Result<u64, DecimalError> {
+    u64::try_from(value.rescale(scale)?.units).map_err(|_| DecimalError::OutOfRange)
+}
+
+/// A value shown with prices and sizes of an instrument.
+#[cfg(feature = "display")]
+pub struct Scaled<'a, T> {
+    pub value: &'a T,
+    pub scale: Scale,
+}
+
+/// `Request` with decimal prices and sizes, see `Scale::request`; offsets of pegs
+/// stay in price points of the instrument.
+pub type DecimalRequest = Request<Decimal, Decimal>;
@@ -0,0 +1,191 @@
+use serde::{Deserialize, Serialize};
+
+use crate::matcher::*;
+use crate::price::*;
+
+/// Displayed liquidity at a single price.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PriceLevel<P = u64, Q = u64> {
+    pub price: P,
+    pub size: Q,
+    pub orders: usize,
+}
+
+/// Aggregated displayed liquidity, best prices first.
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+pub struct Depth<P = u64, Q = u64> {
+    pub bids: Vec<PriceLevel<P, Q>>,
+    pub asks: Vec<PriceLevel<P, Q>>,
+}
+
+/// Book drawn as a price ladder for terminals, asks above bids with a bar for the size
+/// of every level. Shows displayed liquidity only, like `Depth`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ladder<P = u64, Q = u64> {
+    pub depth: Depth<P, Q>,
+    /// Levels which differ from these are marked, see `highlight_changes`.
+    pub previous: Option<Depth<P, Q>>,
+    /// Length of the bar of the largest level.
+    pub bar_width: usize,
+}
+
+impl<P: Price, Q: Quantity> Ladder<P, Q> {
+    /// Marks levels which are new or changed since the depth taken before the last request.
+    pub fn highlight_changes(mut self, previous: Depth<P, Q>) -> Self {
+        self.previous = Some(previous);
+        self
+    }
+
+    /// Whether the level on the side is new or changed since the previous depth.
+    pub fn is_changed(&self, side: Side, level: &PriceLevel<P, Q>) -> bool {
+        let previous = match self.previous {
+            Some(ref previous) => previous,
+            None => return false,
+        };
+        let levels = match side {
+            Side::Buy => &previous.bids,
+            Side::Sell => &previous.asks,
+        };
+        !levels.contains(level)
+    }
+}
+
+impl<P: Price, Q: Quantity, U: UserId> OrderBook<P, Q, U> {
+    /// Up to `levels` displayed price levels per side; hidden requests are left out.
+    /// Sizes of levels stop at `Q::MAX`, `try_depth` tells when they would not fit.
+    pub fn depth(&self, levels: usize) -> Depth<P, Q> {
+        Depth {
+            bids: aggregate(self.buyers.active(), levels).0,
+            asks: aggregate(self.sellers.active(), levels).0,
+        }
+    }
+
+    /// Like `depth`, but fails if the size of a level does not fit into `Q`, which never
+    /// happens to books only fed through `try_match_request`.
+    pub fn try_depth(&self, levels: usize) -> Result<Depth<P, Q>, Overflow> {
+        let (bids, bids_overflow) = aggregate(self.buyers.active(), levels);
+        let (asks, asks_overflow) = aggregate(self.sellers.active(), levels);
+        if bids_overflow || asks_overflow {
+            return Err(Overflow::Volume);
+        }
+        Ok(Depth { bids, asks })
+    }
+
+    /// Up to `levels` displayed price levels per side as a ladder.
+    pub fn ladder(&self, levels: usize) -> Ladder<P, Q> {
+        Ladder {
+            depth: self.depth(levels),
+            previous: None,
+            bar_width: 20,
+        }
+    }
+
+    /// Displayed liquidity at the price on the side, with no orders if there is none.
+    /// The size stops at `Q::MAX` like the ones of `depth`.
+    pub fn level(&self, side: Side, price: P) -> PriceLevel<P, Q> {
+        let queue = match side {
+            Side::Buy => &self.buyers,
+            Side::Sell => &self.sellers,
+        };
+        let mut level = PriceLevel {
+            price,
+            size: Q::ZERO,
+            orders: 0,
+        };
+        let requests = queue.active().iter();
+        for request in requests.filter(|request| !request.hidden && request.price == price) {
+            level.size = level.size.checked_add(request.size).unwrap_or(Q::MAX);
+            level.orders += 1;
+        }
+        level
+    }
+
+    /// Best displayed bid and ask.
+    pub fn bbo(&self) -> Bbo<P> {
+        let best = |queue: &RequestQueue<P, Q, U>| {
+            queue
+                .active()
+                .iter()
+                .find(|request| !request.hidden)
+                .map(|request| request.price)
+        };
+        Bbo {
+            bid: best(&self.buyers),
+            ask: best(&self.sellers),
+        }
+    }
+
+    /// Total size resting on the side of the book, hidden requests included;
+    /// `None` if it does not fit into `Q`.
+    pub fn volume(&self, side: Side) -> Option<Q> {
+        let queue = match side {
+            Side::Buy => &self.buyers,
+            Side::Sell => &self.sellers,
+        };
+        queue
+            .active()
+            .iter()
+            .try_fold(Q::ZERO, |volume, request| volume.checked_add(request.size))
+    }
+
+    /// Tells whether the request may rest in the book without the total size on its side
+    /// overflowing, which keeps every aggregate of the book within `Q`.
+    pub fn check_request(&self, request: &Request<P, Q, U>) -> Result<(), Overflow> {
+        self.volume(request.side)
+            .and_then(|volume| volume.checked_add(request.size))
+            .map(|_| ())
+            .ok_or(Overflow::Volume)
+    }
+
+    /// Requests of the user which are resting in the book, hidden ones included.
+    pub fn user_requests(&self, user_id: U) -> impl Iterator<Item = &Request<P, Q, U>> {
+        self.buyers
+            .active()
+            .iter()
+            .chain(self.sellers.active())
+            .filter(move |request| request.user_id == user_id)
+    }
+
+    /// The resting request with the id, hidden ones included.
+    pub fn resting_request(&self, id: u64) -> Option<&Request<P, Q, U>> {
+        self.buyers
+            .active()
+            .iter()
+            .chain(self.sellers.active())
+            .find(|request| request.id == id)
+    }
+}
+
+// Levels with sizes stopped at `Q::MAX`, and whether any of them had to be stopped.
+fn aggregate<P: Price, Q: Quantity, U: UserId>(
+    requests: &[Request<P, Q, U>],
+    levels: usize,
+) -> (Vec<PriceLevel<P, Q>>, bool) {
+    let mut result: Vec<PriceLevel<P, Q>> = Vec::new();
+    let mut overflow = false;
+    for request in requests.iter().filter(|request| !request.hidden) {
+        match result.last_mut() {
+            Some(level) if level.price == request.price => {
+                level.size = match level.size.checked_add(request.size) {
+                    Some(size) => size,
+                    None => {
+                        overflow = true;
+                        Q::MAX
+                    }
+                };
+                level.orders += 1;
+            }
+            _ => {
+                if result.len() == levels {
+                    break;
+                }
+                result.push(PriceLevel {
+                    price: request.price,
+                    size: request.size,
+                    orders: 1,
+                });
+            }
+        }
+    }
+    (result, overflow)
+}
@@ -1,72 +1,559 @@
+use serde::{Deserialize, Serialize};
+use std::cmp;
 use std::fmt::*;
-use std::string::ToString;
 
+use crate::decimal::*;
+use crate::depth::*;
 use crate::matcher::*;
+use crate::pegging::*;
+use crate::price::*;
 
-impl Display for MarketAction {
+#[derive(Deserialize, Serialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    #[default]
+    English,
+    Russian,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verbosity {
+    /// Sentences, as the binary prints them.
+    #[default]
+    Verbose,
+    /// A short line per value, for logs.
+    Compact,
+}
+
+/// How requests, results and books are written by `Formatted`; their own `Display`
+/// is English and verbose. Rejects and other errors are always in English.
+#[derive(Deserialize, Serialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayOptions {
+    pub language: Language,
+    pub verbosity: Verbosity,
+}
+
+impl DisplayOptions {
+    pub fn format<T>(self, value: &T) -> Formatted<'_, T> {
+        Formatted {
+            value,
+            options: self,
+        }
+    }
+
+    fn pick<'a>(&self, english: &'a str, russian: &'a str) -> &'a str {
+        match self.language {
+            Language::English => english,
+            Language::Russian => russian,
+        }
+    }
+
+    fn is_compact(&self) -> bool {
+        self.verbosity == Verbosity::Compact
+    }
+}
+
+/// A value written with the options, see `DisplayOptions::format`.
+pub struct Formatted<'a, T> {
+    pub value: &'a T,
+    pub options: DisplayOptions,
+}
+
+impl<P: Price, Q: Quantity, U: UserId> Display for MarketAction<P, Q, U> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write_market_action(f, self, &Plain, DisplayOptions::default())
+    }
+}
+
+impl<P: Price, Q: Quantity, U: UserId> Display for Formatted<'_, MarketAction<P, Q, U>> {
     fn fmt(&self, f: &mut Formatter) -> Result {
+        write_market_action(f, self.value, &Plain, self.options)
+    }
+}
+
+impl Display for Scaled<'_, MarketAction> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write_market_action(f, self.value, &self.scale, DisplayOptions::default())
+    }
+}
+
+fn write_market_action<P: Copy, Q: Quantity, U: UserId>(
+    f: &mut Formatter,
+    action: &MarketAction<P, Q, U>,
+    units: &impl Units<P, Q>,
+    options: DisplayOptions,
+) -> Result {
+    if options.is_compact() {
+        write!(
+            f,
+            "#{} -> #{} {} @ {}",
+            action.seller_user_id,
+            action.buyer_user_id,
+            units.size(action.size),
+            units.price(action.price)
+        )?;
+    } else {
         write!(
             f,
-            "User #{} sold {} pieces at price point '{}' to user #{}",
-            self.seller_user_id, self.size, self.price, self.buyer_user_id
-        )
+            "{} #{} {} {} {} '{}' {} #{}",
+            options.pick("User", "Пользователь"),
+            action.seller_user_id,
+            options.pick("sold", "продал"),
+            units.size(action.size),
+            options.pick("pieces at price point", "шт. по цене"),
+            units.price(action.price),
+            options.pick("to user", "пользователю"),
+            action.buyer_user_id
+        )?;
+    }
+    if action.seller_fee != 0 || action.buyer_fee != 0 {
+        let seller_fee = units.amount(action.seller_fee);
+        let buyer_fee = units.amount(action.buyer_fee);
+        if options.is_compact() {
+            let fees = options.pick("fees", "комиссии");
+            write!(f, " {} {}/{}", fees, seller_fee, buyer_fee)?;
+        } else {
+            write!(
+                f,
+                ", {} {} {} {} {}",
+                options.pick("paying", "заплатив"),
+                seller_fee,
+                options.pick("and", "и"),
+                buyer_fee,
+                options.pick("in fees", "комиссии")
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// Prices and sizes are shown in integer units unless the scale of the instrument is known.
+trait Units<P, Q> {
+    fn price(&self, price: P) -> String;
+    fn size(&self, size: Q) -> String;
+    fn amount(&self, amount: i64) -> String;
+    fn offset(&self, ticks: i64) -> String;
+}
+
+struct Plain;
+
+impl<P: Price, Q: Quantity> Units<P, Q> for Plain {
+    fn price(&self, price: P) -> String {
+        price.to_string()
+    }
+
+    fn size(&self, size: Q) -> String {
+        size.to_string()
+    }
+
+    fn amount(&self, amount: i64) -> String {
+        amount.to_string()
+    }
+
+    fn offset(&self, ticks: i64) -> String {
+        ticks.to_string()
+    }
+}
+
+impl Units<u64, u64> for Scale {
+    fn price(&self, price: u64) -> String {
+        Scale::price(self, price).to_string()
+    }
+
+    fn size(&self, size: u64) -> String {
+        Scale::size(self, size).to_string()
+    }
+
+    fn amount(&self, amount: i64) -> String {
+        Scale::amount(self, amount).to_string()
+    }
+
+    fn offset(&self, ticks: i64) -> String {
+        Decimal::new(i128::from(ticks), self.price).to_string()
     }
 }
 
 impl Display for RequestAction {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        let res_str = match self {
-            RequestAction::Filled => "satisfied",
-            RequestAction::FilledPartially => "satisfied partially",
-            RequestAction::Cancelled => "cancelled",
-            RequestAction::AddedToBook => "added to the market",
-        };
-        write!(f, "{}", res_str)
+        write_request_action(f, self, DisplayOptions::default())
     }
 }
 
-impl Display for MatchingResult {
+impl Display for Formatted<'_, RequestAction> {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        let mut message = String::new();
-        let request_actions = self
-            .request_actions
-            .iter()
-            .map(ToString::to_string)
-            .collect::<Vec<_>>()
-            .join(", ");
-        message += &format!("Request was {}", request_actions);
+        write_request_action(f, self.value, self.options)
+    }
+}
 
-        if !self.market_actions.is_empty() {
-            let market_actions = self
-                .market_actions
-                .iter()
-                .map(ToString::to_string)
-                .collect::<Vec<_>>()
-                .join("\n");
-            message += &format!(
-                " and the following actions were performed on the market:\n{}",
-                market_actions
-            );
+fn write_request_action(
+    f: &mut Formatter,
+    action: &RequestAction,
+    options: DisplayOptions,
+) -> Result {
+    let res_str = match (action, options.verbosity) {
+        (RequestAction::Filled, Verbosity::Verbose) => options.pick("satisfied", "исполнена"),
+        (RequestAction::Filled, Verbosity::Compact) => options.pick("filled", "исполнена"),
+        (RequestAction::FilledPartially, Verbosity::Verbose) => {
+            options.pick("satisfied partially", "исполнена частично")
+        }
+        (RequestAction::FilledPartially, Verbosity::Compact) => {
+            options.pick("partially filled", "частично исполнена")
         }
-        write!(f, "{}", message)
+        (RequestAction::Cancelled, _) => options.pick("cancelled", "отменена"),
+        (RequestAction::AddedToBook, Verbosity::Verbose) => {
+            options.pick("added to the market", "добавлена на рынок")
+        }
+        (RequestAction::AddedToBook, Verbosity::Compact) => options.pick("added", "добавлена"),
+    };
+    write!(f, "{}", res_str)
+}
+
+impl<P: Price, Q: Quantity, U: UserId> Display for MatchingResult<P, Q, U> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write_matching_result(f, self, &Plain, DisplayOptions::default())
     }
 }
 
-impl Display for Request {
+impl<P: Price, Q: Quantity, U: UserId> Display for Formatted<'_, MatchingResult<P, Q, U>> {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        let side_str = match self.side {
-            Side::Sell => "sell",
-            Side::Buy => "buy",
+        write_matching_result(f, self.value, &Plain, self.options)
+    }
+}
+
+impl Display for Scaled<'_, MatchingResult> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write_matching_result(f, self.value, &self.scale, DisplayOptions::default())
+    }
+}
+
+fn write_matching_result<P: Copy, Q: Quantity, U: UserId>(
+    f: &mut Formatter,
+    result: &MatchingResult<P, Q, U>,
+    units: &impl Units<P, Q>,
+    options: DisplayOptions,
+) -> Result {
+    let request_actions = result
+        .request_actions
+        .iter()
+        .map(|action| options.format(action).to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if options.is_compact() {
+        write!(f, "{}", request_actions)?;
+        for action in result.market_actions.iter() {
+            write!(f, "; ")?;
+            write_market_action(f, action, units, options)?;
+        }
+        return Ok(());
+    }
+    let request_str = options.pick("Request was", "Заявка");
+    write!(f, "{} {}", request_str, request_actions)?;
+
+    if !result.market_actions.is_empty() {
+        write!(
+            f,
+            " {}:",
+            options.pick(
+                "and the following actions were performed on the market",
+                "и на рынке были совершены следующие действия"
+            )
+        )?;
+        for action in result.market_actions.iter() {
+            writeln!(f)?;
+            write_market_action(f, action, units, options)?;
+        }
+    }
+    Ok(())
+}
+
+impl<P: Price, Q: Quantity, U: UserId> Display for Request<P, Q, U> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write_request(f, self, &Plain, DisplayOptions::default())
+    }
+}
+
+impl<P: Price, Q: Quantity, U: UserId> Display for Formatted<'_, Request<P, Q, U>> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write_request(f, self.value, &Plain, self.options)
+    }
+}
+
+impl Display for Scaled<'_, Request> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write_request(f, self.value, &self.scale, DisplayOptions::default())
+    }
+}
+
+fn write_request<P: Copy, Q: Quantity, U: UserId>(
+    f: &mut Formatter,
+    request: &Request<P, Q, U>,
+    units: &impl Units<P, Q>,
+    options: DisplayOptions,
+) -> Result {
+    if options.is_compact() {
+        return write_compact_request(f, request, units, options);
+    }
+    let side_str = match request.side {
+        Side::Sell => options.pick("sell", "продажу"),
+        Side::Buy => options.pick("buy", "покупку"),
+    };
+    let type_str = match request.request_type {
+        Type::Limit => options.pick("Limit", "лимитная"),
+        Type::ImmediateOrCancel => options.pick("Immediate or cancel", "немедленно или отменить"),
+        Type::FillOrKill => options.pick("Fill or kill", "исполнить или отменить"),
+        Type::Quote => options.pick("Quote", "котировка"),
+    };
+    let hidden_str = if request.hidden {
+        options.pick("hidden ", "скрытая ")
+    } else {
+        ""
+    };
+    match options.language {
+        Language::English => write!(
+            f,
+            "Incoming {}{} request from user #{} to {} {} pieces ",
+            hidden_str,
+            type_str,
+            request.user_id,
+            side_str,
+            units.size(request.size)
+        )?,
+        Language::Russian => write!(
+            f,
+            "Входящая {}заявка типа «{}» от пользователя #{} на {} {} шт. ",
+            hidden_str,
+            type_str,
+            request.user_id,
+            side_str,
+            units.size(request.size)
+        )?,
+    }
+    match request.peg {
+        None => {
+            let price_str = options.pick("at price point", "по цене");
+            write!(f, "{} '{}'", price_str, units.price(request.price))?
+        }
+        Some(peg) => write_peg(f, &peg, units, options)?,
+    }
+    Ok(())
+}
+
+fn write_compact_request<P: Copy, Q: Quantity, U: UserId>(
+    f: &mut Formatter,
+    request: &Request<P, Q, U>,
+    units: &impl Units<P, Q>,
+    options: DisplayOptions,
+) -> Result {
+    if request.id != 0 {
+        write!(f, "#{} ", request.id)?;
+    }
+    let side_str = match request.side {
+        Side::Sell => options.pick("sell", "продажа"),
+        Side::Buy => options.pick("buy", "покупка"),
+    };
+    write!(f, "{} {} @ ", side_str, units.size(request.size))?;
+    match request.peg {
+        None => write!(f, "{}", units.price(request.price))?,
+        Some(peg) => write_peg(f, &peg, units, options)?,
+    }
+    let type_str = match request.request_type {
+        Type::Limit => options.pick("limit", "лимитная"),
+        Type::ImmediateOrCancel => "ioc",
+        Type::FillOrKill => "fok",
+        Type::Quote => options.pick("quote", "котировка"),
+    };
+    let user_str = options.pick("user", "польз.");
+    write!(f, " {} {} #{}", type_str, user_str, request.user_id)?;
+    if request.hidden {
+        write!(f, " {}", options.pick("hidden", "скрытая"))?;
+    }
+    Ok(())
+}
+
+impl<P: Price> Display for Peg<P> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write_peg::<P, u64>(f, self, &Plain, DisplayOptions::default())
+    }
+}
+
+fn write_peg<P: Copy, Q>(
+    f: &mut Formatter,
+    peg: &Peg<P>,
+    units: &impl Units<P, Q>,
+    options: DisplayOptions,
+) -> Result {
+    if options.is_compact() {
+        let reference_str = match peg.reference {
+            PegReference::Primary => "primary",
+            PegReference::Market => "market",
+            PegReference::Midpoint => "midpoint",
         };
-        let type_str = match self.request_type {
-            Type::Limit => "Limit",
-            Type::ImmediateOrCancel => "Immediate or cancel",
-            Type::FillOrKill => "Fill or kill",
+        write!(f, "{} {}", options.pick("peg", "привязка"), reference_str)?;
+        if peg.offset > 0 {
+            write!(f, "+")?;
+        }
+        if peg.offset != 0 {
+            write!(f, "{}", units.offset(peg.offset))?;
+        }
+        if let Some(limit) = peg.limit {
+            let limit_str = options.pick("limit", "предел");
+            write!(f, " {} {}", limit_str, units.price(limit))?;
+        }
+        return Ok(());
+    }
+    let reference_str = match peg.reference {
+        PegReference::Primary => {
+            options.pick("the same side of the market", "той же стороне рынка")
+        }
+        PegReference::Market => options.pick(
+            "the opposite side of the market",
+            "противоположной стороне рынка",
+        ),
+        PegReference::Midpoint => options.pick("the middle of the market", "середине рынка"),
+    };
+    let pegged_str = options.pick("pegged to", "с привязкой к");
+    write!(f, "{} {}", pegged_str, reference_str)?;
+    if peg.offset != 0 {
+        let offset_str = options.pick("with offset", "со смещением");
+        write!(f, " {} {}", offset_str, units.offset(peg.offset))?;
+    }
+    if let Some(limit) = peg.limit {
+        let limit_str = options.pick("limited by price point", "с ограничением цены");
+        write!(f, " {} '{}'", limit_str, units.price(limit))?;
+    }
+    Ok(())
+}
+
+impl<P: Price, Q: Quantity, U: UserId> Display for OrderBook<P, Q, U> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write_ladder(f, &self.ladder(usize::MAX), DisplayOptions::default())
+    }
+}
+
+impl<P: Price, Q: Quantity, U: UserId> Display for Formatted<'_, OrderBook<P, Q, U>> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write_ladder(f, &self.value.ladder(usize::MAX), self.options)
+    }
+}
+
+impl<P: Price, Q: Quantity> Display for Ladder<P, Q> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write_ladder(f, self, DisplayOptions::default())
+    }
+}
+
+impl<P: Price, Q: Quantity> Display for Formatted<'_, Ladder<P, Q>> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write_ladder(f, self.value, self.options)
+    }
+}
+
+fn write_ladder<P: Price, Q: Quantity>(
+    f: &mut Formatter,
+    ladder: &Ladder<P, Q>,
+    options: DisplayOptions,
+) -> Result {
+    let asks = ladder
+        .depth
+        .asks
+        .iter()
+        .rev()
+        .map(|level| (Side::Sell, level));
+    let bids = ladder.depth.bids.iter().map(|level| (Side::Buy, level));
+    let rows: Vec<_> = asks.chain(bids).collect();
+    if rows.is_empty() {
+        let empty_str = options.pick("The book is empty", "Книга заявок пуста");
+        return write!(f, "{}", empty_str);
+    }
+    let marker = |side: Side, level: &PriceLevel<P, Q>| {
+        if ladder.is_changed(side, level) {
+            "*"
+        } else {
+            ""
+        }
+    };
+    if options.is_compact() {
+        let levels = |side: Side, levels: &[PriceLevel<P, Q>]| {
+            if levels.is_empty() {
+                return "-".to_string();
+            }
+            levels
+                .iter()
+                .map(|level| format!("{}{}@{}", marker(side, level), level.size, level.price))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        return write!(
+            f,
+            "{} {}; {} {}",
+            options.pick("bids", "покупка"),
+            levels(Side::Buy, &ladder.depth.bids),
+            options.pick("asks", "продажа"),
+            levels(Side::Sell, &ladder.depth.asks)
+        );
+    }
+
+    let headers = [
+        options.pick("side", "сторона"),
+        options.pick("price", "цена"),
+        options.pick("size", "объём"),
+        options.pick("orders", "заявки"),
+    ];
+    let ask_str = options.pick("ask", "прод.");
+    let bid_str = options.pick("bid", "пок.");
+    // widths are counted in characters, as Russian words take two bytes a letter
+    let width = |header: &str, value: &dyn Fn(&PriceLevel<P, Q>) -> String| {
+        rows.iter()
+            .map(|(_, level)| value(level).chars().count())
+            .fold(header.chars().count(), cmp::max)
+    };
+    let side_width = [ask_str, bid_str]
+        .iter()
+        .map(|side_str| side_str.chars().count())
+        .fold(headers[0].chars().count(), cmp::max);
+    let price_width = width(headers[1], &|level| level.price.to_string());
+    let size_width = width(headers[2], &|level| level.size.to_string());
+    let orders_width = width(headers[3], &|level| level.orders.to_string());
+    let largest = rows
+        .iter()
+        .map(|(_, level)| level.size.to_f64())
+        .fold(0.0, f64::max);
+
+    write!(
+        f,
+        "  {:<ew$} {:>pw$} {:>sw$} {:>ow$}",
+        headers[0],
+        headers[1],
+        headers[2],
+        headers[3],
+        ew = side_width,
+        pw = price_width,
+        sw = size_width,
+        ow = orders_width
+    )?;
+    for (index, (side, level)) in rows.iter().enumerate() {
+        if index == ladder.depth.asks.len() && index != 0 {
+            let line_width = side_width + price_width + size_width + orders_width + 5;
+            write!(f, "\n{}", "-".repeat(line_width))?;
+        }
+        let side_str = match side {
+            Side::Buy => bid_str,
+            Side::Sell => ask_str,
         };
+        let bar = (level.size.to_f64() / largest * ladder.bar_width as f64).ceil() as usize;
         write!(
             f,
-            "Incoming {} request from user #{} to {} {} pieces at price point '{}'",
-            type_str, self.user_id, side_str, self.size, self.price
-        )
+            "\n{:<1} {:<ew$} {:>pw$} {:>sw$} {:>ow$} {}",
+            marker(*side, level),
+            side_str,
+            level.price,
+            level.size,
+            level.orders,
+            "#".repeat(bar),
+            ew = side_width,
+            pw = price_width,
+            sw = size_width,
+            ow = orders_width
+        )?;
     }
+    Ok(())
 }
@@ -0,0 +1,598 @@
+use serde::{Deserialize, Serialize};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+use crate::accounts::*;
+use crate::decimal::*;
+use crate::fees::*;
+use crate::matcher::*;
+use crate::mmp::*;
+use crate::price::*;
+use crate::quotes::*;
+use crate::risk::*;
+
+/// Why a request was refused before reaching a book.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum Reject {
+    UnknownBook,
+    UserBlocked,
+    UnknownSession,
+    /// The request belongs to another user than the session it was sent through.
+    UserMismatch,
+    /// Market-maker protection of the user was triggered in the book and not reset yet,
+    /// so no quotes are accepted.
+    MmpTriggered,
+    Risk(RiskBreach),
+    InsufficientFunds,
+    /// Funds cannot be reserved for requests without an id, and quotes need them too.
+    MissingId,
+    /// Another request with the id is already in the book, or both sides of a quote have it.
+    DuplicateId,
+    /// The request has no size.
+    ZeroSize,
+    /// Funds cannot be reserved for a pegged buyer which has no limit.
+    PegWithoutLimit,
+    /// Prices or sizes do not fit the scale of the instrument.
+    Decimal(DecimalError),
+    /// The request would overflow the book or the accounts.
+    Overflow(Overflow),
+    /// The bid of the quote is not below its ask.
+    CrossedQuote,
+}
+
+impl fmt::Display for Reject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let res_str = match self {
+            Reject::UnknownBook => "there is no such book",
+            Reject::UserBlocked => "the user is blocked",
+            Reject::UnknownSession => "there is no such session",
+            Reject::UserMismatch => "the session belongs to another user",
+            Reject::MmpTriggered => "market-maker protection was triggered",
+            Reject::InsufficientFunds => "the user has not enough funds",
+            Reject::MissingId => "the request has no id",
+            Reject::DuplicateId => "a request with the id is already in the book",
+            Reject::ZeroSize => "the request has no size",
+            Reject::PegWithoutLimit => "the pegged buyer has no limit",
+            Reject::CrossedQuote => "the bid of the quote is not below its ask",
+            Reject::Risk(breach) => return write!(f, "Request was rejected: {}", breach),
+            Reject::Decimal(error) => return write!(f, "Request was rejected: {}", error),
+            Reject::Overflow(overflow) => return write!(f, "Request was rejected: {}", overflow),
+        };
+        write!(f, "Request was rejected: {}", res_str)
+    }
+}
+
+impl std::error::Error for Reject {}
+
+#[derive(Debug, Clone)]
+struct Session {
+    user_id: u64,
+    cancel_on_disconnect: bool,
+    // symbols and ids of the requests which were added to a book through the session
+    requests: Vec<(String, u64)>,
+}
+
+/// Order books of a venue keyed by instrument symbol, with venue-wide controls
+/// in front of them.
+#[derive(Default, Debug, Clone)]
+pub struct Exchange {
+    books: BTreeMap<String, OrderBook>,
+    scales: HashMap<String, Scale>,
+    blocked_users: HashSet<u64>,
+    sessions: HashMap<u64, Session>,
+    next_session_id: u64,
+    mmp_configs: HashMap<u64, MmpConfig>,
+    mmp_trackers: HashMap<(String, u64), MmpTracker>,
+    risk_limits: HashMap<u64, RiskLimits>,
+    risk_trackers: HashMap<u64, RiskTracker>,
+    accounts: Option<Accounts>,
+    unsettled: Vec<UnsettledExecution>,
+    fee_schedule: Option<FeeSchedule>,
+    // milliseconds, as set by the gateway
+    time: u64,
+}
+
+impl Exchange {
+    /// Adds an empty book, an existing book with the same symbol is kept.
+    pub fn add_book(&mut self, symbol: &str) {
+        self.books.entry(symbol.to_string()).or_default();
+    }
+
+    pub fn book(&self, symbol: &str) -> Option<&OrderBook> {
+        self.books.get(symbol)
+    }
+
+    /// Sets decimal places of prices and sizes in the book, which are zero by default.
+    pub fn set_scale(&mut self, symbol: &str, scale: Scale) {
+        self.scales.insert(symbol.to_string(), scale);
+    }
+
+    pub fn scale(&self, symbol: &str) -> Scale {
+        self.scales.get(symbol).copied().unwrap_or_default()
+    }
+
+    pub fn symbols(&self) -> impl Iterator<Item = &str> {
+        self.books.keys().map(String::as_str)
+    }
+
+    /// Sets the current time in milliseconds; it is expected to never go back.
+    pub fn set_time(&mut self, timestamp: u64) {
+        self.time = timestamp;
+    }
+
+    pub fn submit(&mut self, symbol: &str, request: &Request) -> Result<MatchingResult, Reject> {
+        self.check_user(symbol, request.user_id, request.request_type == Type::Quote)?;
+        if request.size == 0 {
+            return Err(Reject::ZeroSize);
+        }
+        if request.id != 0 && self.books[symbol].resting_request(request.id).is_some() {
+            return Err(Reject::DuplicateId);
+        }
+        let requests = std::slice::from_ref(request);
+        self.check_overflow(symbol, requests)?;
+        self.check_risk(symbol, request.user_id, requests, false)?;
+        let fee_rate = self.highest_fee_rate(request.user_id);
+        if let Some(accounts) = self.accounts.as_mut() {
+            accounts.reserve(symbol, requests, &[], fee_rate)?;
+        }
+        let book = self.books.get_mut(symbol).ok_or(Reject::UnknownBook)?;
+        let mut result = book.match_request(request);
+        self.charge_fees(symbol, result.market_actions.iter_mut());
+        self.record_executions(symbol, result.market_actions.iter());
+        let events = self.apply_mmp(symbol, result.market_actions.iter());
+        result.book_events.extend(events);
+        self.settle(
+            symbol,
+            result.market_actions.iter(),
+            &result.book_events,
+            &[(request, &result)],
+        );
+        Ok(result)
+    }
+
+    /// Submits a request with decimal prices and sizes, which have to fit the scale of the book.
+    pub fn submit_decimal(
+        &mut self,
+        symbol: &str,
+        request: &DecimalRequest,
+    ) -> Result<MatchingResult, Reject> {
+        let request = self
+            .scale(symbol)
+            .request(request)
+            .map_err(Reject::Decimal)?;
+        self.submit(symbol, &request)
+    }
+
+    /// Replaces the quote of the user in the book, see `OrderBook::quote`.
+    pub fn quote(&mut self, symbol: &str, quote: &Quote) -> Result<QuoteResult, Reject> {
+        self.check_user(symbol, quote.user_id, true)?;
+        if quote.is_crossed() {
+            return Err(Reject::CrossedQuote);
+        }
+        let (bid, ask) = quote.requests();
+        let requests: Vec<_> = bid.iter().chain(ask.iter()).cloned().collect();
+        self.check_quote_ids(symbol, quote.user_id, &requests)?;
+        self.check_overflow(symbol, &requests)?;
+        self.check_risk(symbol, quote.user_id, &requests, true)?;
+        let fee_rate = self.highest_fee_rate(quote.user_id);
+        if let Some(accounts) = self.accounts.as_mut() {
+            let replaced: Vec<_> = self.books[symbol]
+                .user_requests(quote.user_id)
+                .filter(|request| request.request_type == Type::Quote)
+                .cloned()
+                .collect();
+            accounts.reserve(symbol, &requests, &replaced, fee_rate)?;
+        }
+        let book = self.books.get_mut(symbol).ok_or(Reject::UnknownBook)?;
+        let mut result = book.quote(quote).map_err(|_| Reject::CrossedQuote)?;
+        let market_actions = result.bid.iter_mut().chain(result.ask.iter_mut());
+        let market_actions = market_actions.flat_map(|result| result.market_actions.iter_mut());
+        self.charge_fees(symbol, market_actions);
+        self.record_executions(symbol, result.market_actions());
+        let events = self.apply_mmp(symbol, result.market_actions());
+        result.book_events.extend(events);
+        let entered: Vec<_> = bid
+            .iter()
+            .zip(result.bid.iter())
+            .chain(ask.iter().zip(result.ask.iter()))
+            .collect();
+        self.settle(
+            symbol,
+            result.market_actions(),
+            &result.book_events,
+            &entered,
+        );
+        Ok(result)
+    }
+
+    fn check_user(&self, symbol: &str, user_id: u64, is_quote: bool) -> Result<(), Reject> {
+        if !self.books.contains_key(symbol) {
+            return Err(Reject::UnknownBook);
+        }
+        if self.blocked_users.contains(&user_id) {
+            return Err(Reject::UserBlocked);
+        }
+        if is_quote && !self.mmp_trackers.is_empty() {
+            let mmp_key = (symbol.to_string(), user_id);
+            if self.mmp_trackers.get(&mmp_key).is_some_and(|t| t.triggered) {
+                return Err(Reject::MmpTriggered);
+            }
+        }
+        Ok(())
+    }
+
+    // Legs of a quote need ids of their own, which only the quote they replace may have.
+    fn check_quote_ids(
+        &self,
+        symbol: &str,
+        user_id: u64,
+        requests: &[Request],
+    ) -> Result<(), Reject> {
+        if requests.iter().any(|request| request.id == 0) {
+            return Err(Reject::MissingId);
+        }
+        if requests.len() == 2 && requests[0].id == requests[1].id {
+            return Err(Reject::DuplicateId);
+        }
+        let book = &self.books[symbol];
+        for request in requests {
+            if let Some(resting) = book.resting_request(request.id) {
+                if resting.user_id != user_id || resting.request_type != Type::Quote {
+                    return Err(Reject::DuplicateId);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Keeps the resting size of the book and the notional of every request within `u64`,
+    // so that neither aggregates of the book nor amounts of the accounts wrap.
+    fn check_overflow(&self, symbol: &str, requests: &[Request]) -> Result<(), Reject> {
+        let book = self.books.get(symbol).ok_or(Reject::UnknownBook)?;
+        for request in requests {
+            book.check_request(request).map_err(Reject::Overflow)?;
+            notional(entry_price(book, request), request.size).map_err(Reject::Overflow)?;
+        }
+        Ok(())
+    }
+
+    // Checks requests which are sent together against the limits of their user and
+    // counts them towards the rate limit if they pass.
+    fn check_risk(
+        &mut self,
+        symbol: &str,
+        user_id: u64,
+        requests: &[Request],
+        replaces_quotes: bool,
+    ) -> Result<(), Reject> {
+        let limits = match self.risk_limits.get(&user_id) {
+            Some(limits) => *limits,
+            None => return Ok(()),
+        };
+        let book = self.books.get(symbol).ok_or(Reject::UnknownBook)?;
+        let tracker = self.risk_trackers.entry(user_id).or_default();
+        for request in requests {
+            tracker
+                .check_request(&limits, symbol, request, entry_price(book, request))
+                .map_err(Reject::Risk)?;
+        }
+        if let Some(limit) = limits.max_open_orders {
+            let open = self
+                .books
+                .iter()
+                .flat_map(|(book_symbol, book)| {
+                    // a new quote takes the place of the previous one
+                    let replaced = replaces_quotes && book_symbol == symbol;
+                    book.user_requests(user_id)
+                        .filter(move |request| !replaced || request.request_type != Type::Quote)
+                })
+                .count();
+            let resting = requests.iter().filter(|request| {
+                request.request_type == Type::Limit || request.request_type == Type::Quote
+            });
+            let value = (open + resting.count()) as u64;
+            if value > limit {
+                return Err(Reject::Risk(RiskBreach::OpenOrders { limit, value }));
+            }
+        }
+        tracker
+            .check_rate(&limits, self.time)
+            .map_err(Reject::Risk)?;
+        tracker.record_request(self.time);
+        Ok(())
+    }
+
+    fn record_executions<'a>(
+        &mut self,
+        symbol: &str,
+        market_actions: impl Iterator<Item = &'a MarketAction>,
+    ) {
+        if self.risk_trackers.is_empty() {
+            return;
+        }
+        for action in market_actions {
+            if let Some(tracker) = self.risk_trackers.get_mut(&action.buyer_user_id) {
+                tracker.record_execution(symbol, Side::Buy, action.size);
+            }
+            if let Some(tracker) = self.risk_trackers.get_mut(&action.seller_user_id) {
+                tracker.record_execution(symbol, Side::Sell, action.size);
+            }
+        }
+    }
+
+    // Executions whose fees do not fit are left without fees and reported as unsettled.
+    fn charge_fees<'a>(
+        &mut self,
+        symbol: &str,
+        market_actions: impl Iterator<Item = &'a mut MarketAction>,
+    ) {
+        let schedule = match &self.fee_schedule {
+            Some(schedule) => schedule,
+            None => return,
+        };
+        for action in market_actions {
+            if let Err(overflow) = schedule.apply(action) {
+                self.unsettled.push(UnsettledExecution {
+                    symbol: symbol.to_string(),
+                    action: action.clone(),
+                    error: SettlementError::Overflow(overflow),
+                });
+            }
+        }
+    }
+
+    fn highest_fee_rate(&self, user_id: u64) -> i64 {
+        self.fee_schedule.as_ref().map_or(0, |schedule| {
+            let tier = schedule.tier(user_id);
+            cmp::max(tier.maker, tier.taker)
+        })
+    }
+
+    // Pays for executions from reserved funds and releases funds of requests which
+    // left the book or were never added to it.
+    fn settle<'a>(
+        &mut self,
+        symbol: &str,
+        market_actions: impl Iterator<Item = &'a MarketAction>,
+        book_events: &[BookEvent],
+        entered: &[(&Request, &MatchingResult)],
+    ) {
+        let accounts = match self.accounts.as_mut() {
+            Some(accounts) => accounts,
+            None => return,
+        };
+        for action in market_actions {
+            if let Err(error) = accounts.settle(symbol, action) {
+                self.unsettled.push(UnsettledExecution {
+                    symbol: symbol.to_string(),
+                    action: action.clone(),
+                    error,
+                });
+            }
+        }
+        for (request, result) in entered {
+            if !result.request_actions.contains(&RequestAction::AddedToBook) {
+                accounts.release(symbol, request.user_id, request.id);
+            }
+        }
+        self.release_cancelled(symbol, book_events);
+    }
+
+    fn release_cancelled(&mut self, symbol: &str, book_events: &[BookEvent]) {
+        let accounts = match self.accounts.as_mut() {
+            Some(accounts) => accounts,
+            None => return,
+        };
+        for event in book_events {
+            if let BookEvent::Cancelled { request } = event {
+                accounts.release(symbol, request.user_id, request.id);
+            }
+        }
+    }
+
+    // Records passive executions for market-maker protection and pulls the quotes
+    // of users whose protection has been triggered.
+    fn apply_mmp<'a>(
+        &mut self,
+        symbol: &str,
+        market_actions: impl Iterator<Item = &'a MarketAction>,
+    ) -> Vec<BookEvent> {
+        let mut events = Vec::new();
+        if self.mmp_configs.is_empty() {
+            return events;
+        }
+        let mut triggered = Vec::new();
+        for action in market_actions {
+            let user_id = action.passive_user_id();
+            let config = match self.mmp_configs.get(&user_id) {
+                Some(config) => config,
+                None => continue,
+            };
+            let tracker = self
+                .mmp_trackers
+                .entry((symbol.to_string(), user_id))
+                .or_default();
+            if tracker.record(config, self.time, action.size) {
+                triggered.push(user_id);
+            }
+        }
+        let filter = CancelFilter {
+            request_type: Some(Type::Quote),
+            ..Default::default()
+        };
+        if let Some(book) = self.books.get_mut(symbol) {
+            for user_id in triggered {
+                events.extend(book.cancel_user_requests(user_id, &filter));
+            }
+        }
+        events
+    }
+
+    pub fn cancel_request(&mut self, symbol: &str, id: u64) -> Result<Vec<BookEvent>, Reject> {
+        let book = self.books.get_mut(symbol).ok_or(Reject::UnknownBook)?;
+        let events = book.cancel_request(id);
+        self.release_cancelled(symbol, &events);
+        Ok(events)
+    }
+
+    /// Cancels requests of the user in the given book, or in every book if `symbol` is `None`.
+    /// Events are grouped by the symbol of the book they happened in.
+    pub fn cancel_user_requests(
+        &mut self,
+        symbol: Option<&str>,
+        user_id: u64,
+        filter: &CancelFilter,
+    ) -> Result<BTreeMap<String, Vec<BookEvent>>, Reject> {
+        let mut events = BTreeMap::new();
+        match symbol {
+            Some(symbol) => {
+                let book = self.books.get_mut(symbol).ok_or(Reject::UnknownBook)?;
+                events.insert(
+                    symbol.to_string(),
+                    book.cancel_user_requests(user_id, filter),
+                );
+            }
+            None => {
+                for (symbol, book) in self.books.iter_mut() {
+                    events.insert(symbol.clone(), book.cancel_user_requests(user_id, filter));
+                }
+            }
+        }
+        events.retain(|_, events| !events.is_empty());
+        for (symbol, events) in events.iter() {
+            self.release_cancelled(symbol, events);
+        }
+        Ok(events)
+    }
+
+    /// Cancels everything the user has in every book and refuses their requests
+    /// until `enable_user` is called.
+    pub fn kill_switch(&mut self, user_id: u64) -> BTreeMap<String, Vec<BookEvent>> {
+        self.blocked_users.insert(user_id);
+        self.cancel_user_requests(None, user_id, &CancelFilter::default())
+            .unwrap_or_default()
+    }
+
+    pub fn enable_user(&mut self, user_id: u64) {
+        self.blocked_users.remove(&user_id);
+    }
+
+    pub fn is_blocked(&self, user_id: u64) -> bool {
+        self.blocked_users.contains(&user_id)
+    }
+
+    /// Opens a session for the user and returns its id.
+    pub fn connect(&mut self, user_id: u64, cancel_on_disconnect: bool) -> u64 {
+        self.next_session_id += 1;
+        self.sessions.insert(
+            self.next_session_id,
+            Session {
+                user_id,
+                cancel_on_disconnect,
+                requests: Vec::new(),
+            },
+        );
+        self.next_session_id
+    }
+
+    /// Submits a request on behalf of a session; requests which should be cancelled
+    /// on disconnect need an id.
+    pub fn submit_from_session(
+        &mut self,
+        session_id: u64,
+        symbol: &str,
+        request: &Request,
+    ) -> Result<MatchingResult, Reject> {
+        let session = self
+            .sessions
+            .get(&session_id)
+            .ok_or(Reject::UnknownSession)?;
+        if session.user_id != request.user_id {
+            return Err(Reject::UserMismatch);
+        }
+        let result = self.submit(symbol, request)?;
+        let session = self.sessions.get_mut(&session_id).unwrap();
+        if session.cancel_on_disconnect
+            && request.id != 0
+            && result.request_actions.contains(&RequestAction::AddedToBook)
+        {
+            session.requests.push((symbol.to_string(), request.id));
+        }
+        Ok(result)
+    }
+
+    /// Closes the session, cancelling its requests which are still in the books
+    /// if it was opened with `cancel_on_disconnect`.
+    pub fn disconnect(&mut self, session_id: u64) -> BTreeMap<String, Vec<BookEvent>> {
+        let mut events = BTreeMap::new();
+        let session = match self.sessions.remove(&session_id) {
+            Some(session) => session,
+            None => return events,
+        };
+        for (symbol, id) in session.requests {
+            if let Some(book) = self.books.get_mut(&symbol) {
+                let cancelled = book.cancel_request(id);
+                self.release_cancelled(&symbol, &cancelled);
+                if !cancelled.is_empty() {
+                    events
+                        .entry(symbol)
+                        .or_insert_with(Vec::new)
+                        .extend(cancelled);
+                }
+            }
+        }
+        events
+    }
+
+    /// Enables market-maker protection for the user in every book.
+    pub fn set_mmp(&mut self, user_id: u64, config: MmpConfig) {
+        self.mmp_configs.insert(user_id, config);
+    }
+
+    /// Lets the user trade in the book again after the protection was triggered.
+    pub fn reset_mmp(&mut self, symbol: &str, user_id: u64) {
+        self.mmp_trackers.remove(&(symbol.to_string(), user_id));
+    }
+
+    /// Makes every request reserve funds of its user, which should happen before
+    /// any request is submitted.
+    pub fn set_accounts(&mut self, accounts: Accounts) {
+        self.accounts = Some(accounts);
+    }
+
+    pub fn accounts(&self) -> Option<&Accounts> {
+        self.accounts.as_ref()
+    }
+
+    pub fn accounts_mut(&mut self) -> Option<&mut Accounts> {
+        self.accounts.as_mut()
+    }
+
+    /// Executions which happened but could not be paid for through the accounts
+    /// since the last call.
+    pub fn take_unsettled(&mut self) -> Vec<UnsettledExecution> {
+        std::mem::take(&mut self.unsettled)
+    }
+
+    /// Charges fees on every execution, they are paid through the accounts if the
+    /// venue has them.
+    pub fn set_fee_schedule(&mut self, schedule: FeeSchedule) {
+        self.fee_schedule = Some(schedule);
+    }
+
+    /// Sets pre-trade limits of the user; net positions are counted from the first
+    /// time limits are set.
+    pub fn set_risk_limits(&mut self, user_id: u64, limits: RiskLimits) {
+        self.risk_limits.insert(user_id, limits);
+        self.risk_trackers.entry(user_id).or_default();
+    }
+}
+
+// Price the request enters the book at, pegged requests follow the current touch.
+fn entry_price(book: &OrderBook, request: &Request) -> u64 {
+    request
+        .peg
+        .and_then(|peg| peg.price(request.side, book.reference_bbo()))
+        .unwrap_or(request.price)
+}
@@ -0,0 +1,61 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use std::convert::TryFrom;
+
+use crate::matcher::*;
+use crate::price::*;
+
+/// Rates in hundredths of a percent of the notional, negative rates are rebates.
+#[derive(Deserialize, Serialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeeTier {
+    pub maker: i64,
+    pub taker: i64,
+}
+
+/// Fee tiers of users, everybody else pays the default tier.
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+pub struct FeeSchedule {
+    pub default: FeeTier,
+    #[serde(default)]
+    pub users: HashMap<u64, FeeTier>,
+}
+
+impl FeeSchedule {
+    pub fn tier(&self, user_id: u64) -> FeeTier {
+        self.users.get(&user_id).copied().unwrap_or(self.default)
+    }
+
+    /// Fills in fees of both sides of the execution, the passive side pays the maker rate;
+    /// they are left as they were if the notional or a fee does not fit.
+    pub fn apply(&self, action: &mut MarketAction) -> Result<(), Overflow> {
+        let notional = notional(action.price, action.size)?;
+        let (buyer_rate, seller_rate) = match action.aggressor_side {
+            Side::Buy => (
+                self.tier(action.buyer_user_id).taker,
+                self.tier(action.seller_user_id).maker,
+            ),
+            Side::Sell => (
+                self.tier(action.buyer_user_id).maker,
+                self.tier(action.seller_user_id).taker,
+            ),
+        };
+        let buyer_fee = fee(notional, buyer_rate)?;
+        action.seller_fee = fee(notional, seller_rate)?;
+        action.buyer_fee = buyer_fee;
+        Ok(())
+    }
+}
+
+/// Fee on the notional at the rate, fees are rounded up and rebates towards zero
+/// so that the venue never pays out more than its rates say. Fails if the fee does
+/// not fit into `i64`.
+pub fn fee(notional: u64, rate: i64) -> Result<i64, Overflow> {
+    let amount = i128::from(notional) * i128::from(rate);
+    let fee = if amount > 0 {
+        (amount + 9999) / 10000
+    } else {
+        amount / 10000
+    };
+    i64::try_from(fee).map_err(|_| Overflow::Balance)
+}
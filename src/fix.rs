@@ -0,0 +1,649 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::mem;
+use std::str;
+
+/// The only version spoken, sent as BeginString.
+pub const FIX_BEGIN_STRING: &str = "FIX.4.4";
+
+/// Longest BodyLength of the messages which are read.
+pub const FIX_MAX_BODY_LENGTH: usize = 64 * 1024;
+
+const SOH: u8 = 1;
+
+/// Tags of the fields used by the engine.
+pub mod tag {
+    pub const AVG_PX: u32 = 6;
+    pub const BEGIN_SEQ_NO: u32 = 7;
+    pub const BEGIN_STRING: u32 = 8;
+    pub const BODY_LENGTH: u32 = 9;
+    pub const CHECK_SUM: u32 = 10;
+    pub const CL_ORD_ID: u32 = 11;
+    pub const CUM_QTY: u32 = 14;
+    pub const END_SEQ_NO: u32 = 16;
+    pub const EXEC_ID: u32 = 17;
+    pub const LAST_PX: u32 = 31;
+    pub const LAST_QTY: u32 = 32;
+    pub const MSG_SEQ_NUM: u32 = 34;
+    pub const MSG_TYPE: u32 = 35;
+    pub const NEW_SEQ_NO: u32 = 36;
+    pub const ORDER_ID: u32 = 37;
+    pub const ORDER_QTY: u32 = 38;
+    pub const ORD_STATUS: u32 = 39;
+    pub const ORD_TYPE: u32 = 40;
+    pub const ORIG_CL_ORD_ID: u32 = 41;
+    pub const POSS_DUP_FLAG: u32 = 43;
+    pub const PRICE: u32 = 44;
+    pub const REF_SEQ_NUM: u32 = 45;
+    pub const SENDER_COMP_ID: u32 = 49;
+    pub const SENDING_TIME: u32 = 52;
+    pub const SIDE: u32 = 54;
+    pub const SYMBOL: u32 = 55;
+    pub const TARGET_COMP_ID: u32 = 56;
+    pub const TEXT: u32 = 58;
+    pub const TIME_IN_FORCE: u32 = 59;
+    pub const ENCRYPT_METHOD: u32 = 98;
+    pub const CXL_REJ_REASON: u32 = 102;
+    pub const ORD_REJ_REASON: u32 = 103;
+    pub const HEART_BT_INT: u32 = 108;
+    pub const TEST_REQ_ID: u32 = 112;
+    pub const ORIG_SENDING_TIME: u32 = 122;
+    pub const GAP_FILL_FLAG: u32 = 123;
+    pub const RESET_SEQ_NUM_FLAG: u32 = 141;
+    pub const EXEC_TYPE: u32 = 150;
+    pub const LEAVES_QTY: u32 = 151;
+    pub const REF_TAG_ID: u32 = 371;
+    pub const SESSION_REJECT_REASON: u32 = 373;
+    pub const CXL_REJ_RESPONSE_TO: u32 = 434;
+}
+
+/// Values of MsgType used by the engine.
+pub mod msg_type {
+    pub const HEARTBEAT: &str = "0";
+    pub const TEST_REQUEST: &str = "1";
+    pub const RESEND_REQUEST: &str = "2";
+    pub const REJECT: &str = "3";
+    pub const SEQUENCE_RESET: &str = "4";
+    pub const LOGOUT: &str = "5";
+    pub const EXECUTION_REPORT: &str = "8";
+    pub const ORDER_CANCEL_REJECT: &str = "9";
+    pub const LOGON: &str = "A";
+    pub const NEW_ORDER_SINGLE: &str = "D";
+    pub const ORDER_CANCEL_REQUEST: &str = "F";
+    pub const ORDER_CANCEL_REPLACE_REQUEST: &str = "G";
+}
+
+// Fields which are put in front of the body by the session.
+const HEADER: [u32; 8] = [
+    tag::BEGIN_STRING,
+    tag::BODY_LENGTH,
+    tag::CHECK_SUM,
+    tag::MSG_TYPE,
+    tag::SENDER_COMP_ID,
+    tag::TARGET_COMP_ID,
+    tag::MSG_SEQ_NUM,
+    tag::SENDING_TIME,
+];
+
+/// Bytes which cannot be read as a FIX message; the stream cannot be trusted after them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FixError {
+    Malformed(String),
+    UnsupportedVersion(String),
+    BadChecksum { expected: u8, found: u8 },
+}
+
+impl fmt::Display for FixError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FixError::Malformed(message) => write!(f, "malformed message: {}", message),
+            FixError::UnsupportedVersion(version) => write!(f, "unsupported version {}", version),
+            FixError::BadChecksum { expected, found } => {
+                write!(f, "checksum is {:03}, expected {:03}", found, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FixError {}
+
+/// A message as its fields in order. BeginString, BodyLength and CheckSum are not kept,
+/// `encode` adds them.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FixMessage {
+    pub fields: Vec<(u32, String)>,
+}
+
+impl FixMessage {
+    pub fn new(msg_type: &str) -> FixMessage {
+        FixMessage {
+            fields: vec![(tag::MSG_TYPE, msg_type.to_string())],
+        }
+    }
+
+    pub fn msg_type(&self) -> &str {
+        self.get(tag::MSG_TYPE).unwrap_or("")
+    }
+
+    pub fn get(&self, tag: u32) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| *field == tag)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Value of the field as a number, `None` if it is missing or is not one.
+    pub fn number(&self, tag: u32) -> Option<u64> {
+        self.get(tag)?.parse().ok()
+    }
+
+    /// Replaces the value of the field, adding it to the end if it is not there.
+    pub fn set(&mut self, tag: u32, value: impl ToString) {
+        let value = value.to_string();
+        match self.fields.iter_mut().find(|(field, _)| *field == tag) {
+            Some(field) => field.1 = value,
+            None => self.fields.push((tag, value)),
+        }
+    }
+
+    pub fn with(mut self, tag: u32, value: impl ToString) -> FixMessage {
+        self.set(tag, value);
+        self
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (tag, value) in self.fields.iter() {
+            if [tag::BEGIN_STRING, tag::BODY_LENGTH, tag::CHECK_SUM].contains(tag) {
+                continue;
+            }
+            body.extend_from_slice(format!("{}={}", tag, value).as_bytes());
+            body.push(SOH);
+        }
+        let mut message = format!("8={}\u{1}9={}\u{1}", FIX_BEGIN_STRING, body.len()).into_bytes();
+        message.extend(body);
+        let checksum = checksum(&message);
+        message.extend_from_slice(format!("10={:03}\u{1}", checksum).as_bytes());
+        message
+    }
+
+    /// Reads the message at the start of the buffer, returning it together with the number
+    /// of bytes it took, or `None` if the buffer does not hold the whole message yet.
+    pub fn decode(buffer: &[u8]) -> Result<Option<(FixMessage, usize)>, FixError> {
+        let (tag, version, at) = match field_at(buffer, 0)? {
+            Some(field) => field,
+            None => return Ok(None),
+        };
+        if tag != tag::BEGIN_STRING {
+            return Err(malformed("the message does not start with BeginString"));
+        }
+        if version != FIX_BEGIN_STRING {
+            return Err(FixError::UnsupportedVersion(version.to_string()));
+        }
+        let (tag, length, body_start) = match field_at(buffer, at)? {
+            Some(field) => field,
+            None => return Ok(None),
+        };
+        if tag != tag::BODY_LENGTH {
+            return Err(malformed("BodyLength does not follow BeginString"));
+        }
+        let length: usize = length
+            .parse()
+            .map_err(|_| malformed("BodyLength is not a number"))?;
+        if length > FIX_MAX_BODY_LENGTH {
+            return Err(malformed("BodyLength is too large"));
+        }
+        let body_end = body_start
+            .checked_add(length)
+            .ok_or_else(|| malformed("BodyLength is too large"))?;
+        if body_end > buffer.len() {
+            return Ok(None);
+        }
+        let (tag, found, end) = match field_at(buffer, body_end)? {
+            Some(field) => field,
+            None => return Ok(None),
+        };
+        if tag != tag::CHECK_SUM {
+            return Err(malformed("CheckSum does not follow the body"));
+        }
+        let found: u8 = found
+            .parse()
+            .map_err(|_| malformed("CheckSum is not a number"))?;
+        let expected = checksum(&buffer[..body_end]);
+        if found != expected {
+            return Err(FixError::BadChecksum { expected, found });
+        }
+        let mut fields = Vec::new();
+        let mut at = body_start;
+        while at < body_end {
+            let (tag, value, next) = field_at(&buffer[..body_end], at)?
+                .ok_or_else(|| malformed("unterminated field"))?;
+            fields.push((tag, value.to_string()));
+            at = next;
+        }
+        Ok(Some((FixMessage { fields }, end)))
+    }
+}
+
+fn malformed(message: &str) -> FixError {
+    FixError::Malformed(message.to_string())
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+// Tag and value of the field starting at `at`, and where the next field starts;
+// `None` if the field is not terminated yet.
+fn field_at(buffer: &[u8], at: usize) -> Result<Option<(u32, &str, usize)>, FixError> {
+    let end = match buffer[at..].iter().position(|byte| *byte == SOH) {
+        Some(end) => at + end,
+        None => return Ok(None),
+    };
+    let field = str::from_utf8(&buffer[at..end]).map_err(|_| malformed("a field is not UTF-8"))?;
+    let (tag, value) = field
+        .split_once('=')
+        .ok_or_else(|| malformed("a field has no '='"))?;
+    let tag = tag
+        .parse()
+        .map_err(|_| FixError::Malformed(format!("'{}' is not a tag", tag)))?;
+    Ok(Some((tag, value, end + 1)))
+}
+
+/// UTCTimestamp of the time in milliseconds since the Unix epoch, as in SendingTime.
+pub fn fix_timestamp(millis: u64) -> String {
+    let seconds = millis / 1000;
+    let (year, month, day) = civil_date(seconds / 86400);
+    format!(
+        "{:04}{:02}{:02}-{:02}:{:02}:{:02}.{:03}",
+        year,
+        month,
+        day,
+        seconds / 3600 % 24,
+        seconds / 60 % 60,
+        seconds % 60,
+        millis % 1000
+    )
+}
+
+// Year, month and day of the day counted from 1970-01-01.
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn is_admin(msg_type: &str) -> bool {
+    [
+        msg_type::HEARTBEAT,
+        msg_type::TEST_REQUEST,
+        msg_type::RESEND_REQUEST,
+        msg_type::REJECT,
+        msg_type::SEQUENCE_RESET,
+        msg_type::LOGOUT,
+        msg_type::LOGON,
+    ]
+    .contains(&msg_type)
+}
+
+/// Session layer with one counterparty, independent of the transport: incoming messages
+/// are passed to `receive`, the passing time to `on_timer`, and encoded messages to be
+/// sent are collected with `take_outgoing`.
+///
+/// Sequence numbers are checked on every message; a gap is answered with a ResendRequest
+/// and messages are dropped until it is filled. Application messages sent are kept,
+/// so they can be resent with PossDupFlag, while session messages are replaced by
+/// a SequenceReset-GapFill. Times are in milliseconds.
+#[derive(Debug, Clone)]
+pub struct FixSession {
+    sender_comp_id: String,
+    target_comp_id: String,
+    initiator: bool,
+    /// In seconds, 0 turns heartbeats off.
+    heartbeat_interval: u64,
+    logged_on: bool,
+    next_outgoing: u64,
+    next_incoming: u64,
+    sent: BTreeMap<u64, FixMessage>,
+    outgoing: Vec<Vec<u8>>,
+    disconnect: bool,
+    last_sent: u64,
+    last_received: u64,
+    test_request_sent: bool,
+    // sequence number which revealed a gap, a resend up to it was requested
+    resend_until: Option<u64>,
+}
+
+impl FixSession {
+    /// Session of an acceptor, which waits for a Logon and takes the heartbeat interval from it.
+    pub fn new(sender_comp_id: &str, target_comp_id: &str) -> FixSession {
+        FixSession {
+            sender_comp_id: sender_comp_id.to_string(),
+            target_comp_id: target_comp_id.to_string(),
+            initiator: false,
+            heartbeat_interval: 30,
+            logged_on: false,
+            next_outgoing: 1,
+            next_incoming: 1,
+            sent: BTreeMap::new(),
+            outgoing: Vec::new(),
+            disconnect: false,
+            last_sent: 0,
+            last_received: 0,
+            test_request_sent: false,
+            resend_until: None,
+        }
+    }
+
+    /// Session of an initiator, which starts with `logon`.
+    pub fn initiator(
+        sender_comp_id: &str,
+        target_comp_id: &str,
+        heartbeat_interval: u64,
+    ) -> FixSession {
+        FixSession {
+            initiator: true,
+            heartbeat_interval,
+            ..FixSession::new(sender_comp_id, target_comp_id)
+        }
+    }
+
+    pub fn sender_comp_id(&self) -> &str {
+        &self.sender_comp_id
+    }
+
+    pub fn target_comp_id(&self) -> &str {
+        &self.target_comp_id
+    }
+
+    pub fn is_logged_on(&self) -> bool {
+        self.logged_on
+    }
+
+    pub fn next_outgoing(&self) -> u64 {
+        self.next_outgoing
+    }
+
+    pub fn next_incoming(&self) -> u64 {
+        self.next_incoming
+    }
+
+    /// The connection has to be closed once the outgoing messages are sent.
+    pub fn should_disconnect(&self) -> bool {
+        self.disconnect
+    }
+
+    pub fn take_outgoing(&mut self) -> Vec<Vec<u8>> {
+        mem::take(&mut self.outgoing)
+    }
+
+    /// Sends a Logon, asking to start both sequences from 1 if `reset` is set.
+    pub fn logon(&mut self, reset: bool, now: u64) {
+        if reset {
+            self.next_outgoing = 1;
+            self.next_incoming = 1;
+            self.sent.clear();
+        }
+        let mut logon = FixMessage::new(msg_type::LOGON)
+            .with(tag::ENCRYPT_METHOD, 0)
+            .with(tag::HEART_BT_INT, self.heartbeat_interval);
+        if reset {
+            logon.set(tag::RESET_SEQ_NUM_FLAG, "Y");
+        }
+        self.send(logon, now);
+    }
+
+    /// Sends a Logout and closes the connection after it.
+    pub fn logout(&mut self, text: &str, now: u64) {
+        self.send(FixMessage::new(msg_type::LOGOUT).with(tag::TEXT, text), now);
+        self.disconnect = true;
+    }
+
+    /// Gives the message the next sequence number and queues it. Application messages are
+    /// only queued while logged on, the counterparty gets them later through a resend.
+    pub fn send(&mut self, message: FixMessage, now: u64) {
+        let seq = self.next_outgoing;
+        self.next_outgoing += 1;
+        let stamped = self.stamp(&message, seq, now);
+        let admin = is_admin(message.msg_type());
+        if !admin {
+            self.sent.insert(seq, stamped.clone());
+        }
+        if self.logged_on || admin {
+            self.outgoing.push(stamped.encode());
+            self.last_sent = now;
+        }
+    }
+
+    /// Handles an incoming message and returns it if it is an application message
+    /// to be processed, which happens exactly once per sequence number. Session-level
+    /// Rejects are returned as well, they refer to messages of the application.
+    pub fn receive(&mut self, message: FixMessage, now: u64) -> Option<FixMessage> {
+        self.last_received = now;
+        self.test_request_sent = false;
+        let seq = match message.number(tag::MSG_SEQ_NUM) {
+            Some(seq) => seq,
+            None => {
+                self.logout("MsgSeqNum is missing", now);
+                return None;
+            }
+        };
+        if !self.logged_on {
+            self.receive_logon(message, seq, now);
+            return None;
+        }
+        if message.msg_type() == msg_type::SEQUENCE_RESET
+            && message.get(tag::GAP_FILL_FLAG) != Some("Y")
+        {
+            // reset mode ignores the sequence number of the message itself
+            self.skip_to(message.number(tag::NEW_SEQ_NO));
+            return None;
+        }
+        if seq > self.next_incoming {
+            self.request_resend(seq, now);
+            return None;
+        }
+        if seq < self.next_incoming {
+            if message.get(tag::POSS_DUP_FLAG) != Some("Y") {
+                let text = format!(
+                    "MsgSeqNum too low, expecting {} but received {}",
+                    self.next_incoming, seq
+                );
+                self.logout(&text, now);
+            }
+            return None;
+        }
+        self.next_incoming += 1;
+        if self
+            .resend_until
+            .is_some_and(|until| self.next_incoming > until)
+        {
+            self.resend_until = None;
+        }
+        match message.msg_type() {
+            msg_type::HEARTBEAT | msg_type::LOGON => None,
+            msg_type::TEST_REQUEST => {
+                let mut heartbeat = FixMessage::new(msg_type::HEARTBEAT);
+                if let Some(id) = message.get(tag::TEST_REQ_ID) {
+                    heartbeat.set(tag::TEST_REQ_ID, id);
+                }
+                self.send(heartbeat, now);
+                None
+            }
+            msg_type::RESEND_REQUEST => {
+                let begin = message.number(tag::BEGIN_SEQ_NO).unwrap_or(1);
+                let end = message.number(tag::END_SEQ_NO).unwrap_or(0);
+                self.resend(begin, end, now);
+                None
+            }
+            msg_type::SEQUENCE_RESET => {
+                self.skip_to(message.number(tag::NEW_SEQ_NO));
+                None
+            }
+            msg_type::LOGOUT => {
+                if !self.disconnect {
+                    self.send(FixMessage::new(msg_type::LOGOUT), now);
+                }
+                self.disconnect = true;
+                None
+            }
+            _ => Some(message),
+        }
+    }
+
+    /// Keeps the session alive: sends heartbeats when nothing else was sent, a TestRequest
+    /// when the counterparty is quiet for too long, and gives up on it after two intervals.
+    pub fn on_timer(&mut self, now: u64) {
+        let interval = self.heartbeat_interval * 1000;
+        if !self.logged_on || self.disconnect || interval == 0 {
+            return;
+        }
+        if now >= self.last_received + 2 * interval {
+            self.logout("Heartbeat timeout", now);
+            return;
+        }
+        if !self.test_request_sent && now >= self.last_received + interval + interval / 5 {
+            self.send(
+                FixMessage::new(msg_type::TEST_REQUEST).with(tag::TEST_REQ_ID, now),
+                now,
+            );
+            self.test_request_sent = true;
+        }
+        if now >= self.last_sent + interval {
+            self.send(FixMessage::new(msg_type::HEARTBEAT), now);
+        }
+    }
+
+    /// Forgets about the connection, keeping the sequence numbers for the next Logon.
+    pub fn disconnected(&mut self) {
+        self.logged_on = false;
+        self.disconnect = false;
+        self.outgoing.clear();
+        self.test_request_sent = false;
+        self.resend_until = None;
+    }
+
+    fn receive_logon(&mut self, message: FixMessage, seq: u64, now: u64) {
+        if message.msg_type() != msg_type::LOGON
+            || message.get(tag::SENDER_COMP_ID) != Some(&self.target_comp_id)
+            || message.get(tag::TARGET_COMP_ID) != Some(&self.sender_comp_id)
+        {
+            self.disconnect = true;
+            return;
+        }
+        let reset = message.get(tag::RESET_SEQ_NUM_FLAG) == Some("Y");
+        if reset {
+            self.next_incoming = 1;
+        }
+        if seq < self.next_incoming {
+            let text = format!(
+                "MsgSeqNum too low, expecting {} but received {}",
+                self.next_incoming, seq
+            );
+            self.logout(&text, now);
+            return;
+        }
+        self.logged_on = true;
+        if !self.initiator {
+            if reset {
+                self.next_outgoing = 1;
+                self.sent.clear();
+            }
+            self.heartbeat_interval = message
+                .number(tag::HEART_BT_INT)
+                .unwrap_or(self.heartbeat_interval);
+            let mut logon = FixMessage::new(msg_type::LOGON)
+                .with(tag::ENCRYPT_METHOD, 0)
+                .with(tag::HEART_BT_INT, self.heartbeat_interval);
+            if reset {
+                logon.set(tag::RESET_SEQ_NUM_FLAG, "Y");
+            }
+            self.send(logon, now);
+        }
+        if seq > self.next_incoming {
+            self.request_resend(seq, now);
+        } else {
+            self.next_incoming += 1;
+        }
+    }
+
+    fn skip_to(&mut self, new_seq: Option<u64>) {
+        if let Some(new_seq) = new_seq {
+            self.next_incoming = self.next_incoming.max(new_seq);
+        }
+    }
+
+    fn request_resend(&mut self, seq: u64, now: u64) {
+        if self.resend_until.is_some() {
+            return;
+        }
+        self.resend_until = Some(seq);
+        let request = FixMessage::new(msg_type::RESEND_REQUEST)
+            .with(tag::BEGIN_SEQ_NO, self.next_incoming)
+            .with(tag::END_SEQ_NO, 0);
+        self.send(request, now);
+    }
+
+    // Resends kept application messages, filling the gaps between them.
+    fn resend(&mut self, begin: u64, end: u64, now: u64) {
+        let last = self.next_outgoing - 1;
+        let end = if end == 0 || end > last { last } else { end };
+        let mut gap_start = None;
+        for seq in begin.max(1)..=end {
+            let original = match self.sent.get(&seq) {
+                Some(original) => original.clone(),
+                None => {
+                    gap_start = gap_start.or(Some(seq));
+                    continue;
+                }
+            };
+            if let Some(start) = gap_start.take() {
+                self.gap_fill(start, seq, now);
+            }
+            let mut resent = self.stamp(&original, seq, now);
+            resent
+                .fields
+                .insert(4, (tag::POSS_DUP_FLAG, "Y".to_string()));
+            if let Some(time) = original.get(tag::SENDING_TIME) {
+                resent
+                    .fields
+                    .insert(6, (tag::ORIG_SENDING_TIME, time.to_string()));
+            }
+            self.outgoing.push(resent.encode());
+        }
+        if let Some(start) = gap_start {
+            self.gap_fill(start, end + 1, now);
+        }
+        self.last_sent = now;
+    }
+
+    fn gap_fill(&mut self, seq: u64, new_seq: u64, now: u64) {
+        let mut gap_fill = self.stamp(&FixMessage::new(msg_type::SEQUENCE_RESET), seq, now);
+        gap_fill.set(tag::POSS_DUP_FLAG, "Y");
+        gap_fill.set(tag::GAP_FILL_FLAG, "Y");
+        gap_fill.set(tag::NEW_SEQ_NO, new_seq);
+        self.outgoing.push(gap_fill.encode());
+    }
+
+    fn stamp(&self, message: &FixMessage, seq: u64, now: u64) -> FixMessage {
+        let mut stamped = FixMessage::new(message.msg_type())
+            .with(tag::SENDER_COMP_ID, &self.sender_comp_id)
+            .with(tag::TARGET_COMP_ID, &self.target_comp_id)
+            .with(tag::MSG_SEQ_NUM, seq)
+            .with(tag::SENDING_TIME, fix_timestamp(now));
+        let body = message
+            .fields
+            .iter()
+            .filter(|(tag, _)| !HEADER.contains(tag) && *tag != tag::POSS_DUP_FLAG);
+        stamped.fields.extend(body.cloned());
+        stamped
+    }
+}
@@ -0,0 +1,775 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::exchange::*;
+use crate::fix::*;
+use crate::matcher::*;
+
+// How long reads wait before timers and messages queued by other connections are looked at.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+const RECEIVE_TIMEOUT: u64 = 5000;
+
+mod exec_type {
+    pub const NEW: &str = "0";
+    pub const CANCELED: &str = "4";
+    pub const REPLACED: &str = "5";
+    pub const REJECTED: &str = "8";
+    pub const TRADE: &str = "F";
+}
+
+mod ord_status {
+    pub const NEW: &str = "0";
+    pub const PARTIALLY_FILLED: &str = "1";
+    pub const FILLED: &str = "2";
+    pub const CANCELED: &str = "4";
+    pub const REJECTED: &str = "8";
+}
+
+#[derive(Debug, Clone)]
+struct FixOrder {
+    client: String,
+    cl_ord_id: String,
+    symbol: String,
+    side: Side,
+    price: u64,
+    order_qty: u64,
+    cum_qty: u64,
+    // sum of price times size of the fills
+    cum_notional: u128,
+}
+
+// A field which makes a message unusable, answered with a session-level Reject.
+struct FieldError {
+    tag: u32,
+    reason: u32,
+    text: &'static str,
+}
+
+fn missing(tag: u32) -> FieldError {
+    FieldError {
+        tag,
+        reason: 1,
+        text: "Required tag missing",
+    }
+}
+
+fn incorrect(tag: u32) -> FieldError {
+    FieldError {
+        tag,
+        reason: 5,
+        text: "Value is incorrect (out of range) for this tag",
+    }
+}
+
+type Reports = Vec<(String, FixMessage)>;
+
+/// Application layer of the FIX engine: orders of the clients go to the books of the exchange,
+/// and what happens to them is reported back as ExecutionReports, to the owners of resting
+/// orders as well.
+///
+/// Only limit orders are accepted, with prices and quantities in the units of the books.
+/// Every order gets a request id from the gateway, sent as OrderID, so the books should
+/// not be given requests with ids from elsewhere. A replace takes the order out of the book
+/// and enters what is left of it again, losing its time priority. Orders are cancelled
+/// when the connection of their client is gone.
+#[derive(Debug)]
+pub struct FixGateway {
+    comp_id: String,
+    exchange: Exchange,
+    users: HashMap<String, u64>,
+    sessions: HashMap<String, FixSession>,
+    // sessions of the exchange by client, opened with the first order of a connection
+    exchange_sessions: HashMap<String, u64>,
+    orders: HashMap<u64, FixOrder>,
+    // ids of the open orders by client and ClOrdID
+    cl_ord_ids: HashMap<(String, String), u64>,
+    next_order_id: u64,
+    next_exec_id: u64,
+}
+
+impl FixGateway {
+    pub fn new(comp_id: &str, exchange: Exchange) -> FixGateway {
+        FixGateway {
+            comp_id: comp_id.to_string(),
+            exchange,
+            users: HashMap::new(),
+            sessions: HashMap::new(),
+            exchange_sessions: HashMap::new(),
+            orders: HashMap::new(),
+            cl_ord_ids: HashMap::new(),
+            next_order_id: 0,
+            next_exec_id: 0,
+        }
+    }
+
+    /// Lets the client with the SenderCompID log on and trade as the user.
+    pub fn add_client(&mut self, comp_id: &str, user_id: u64) {
+        self.users.insert(comp_id.to_string(), user_id);
+        let gateway = &self.comp_id;
+        self.sessions
+            .entry(comp_id.to_string())
+            .or_insert_with(|| FixSession::new(gateway, comp_id));
+    }
+
+    pub fn exchange(&self) -> &Exchange {
+        &self.exchange
+    }
+
+    pub fn exchange_mut(&mut self) -> &mut Exchange {
+        &mut self.exchange
+    }
+
+    pub fn session(&self, client: &str) -> Option<&FixSession> {
+        self.sessions.get(client)
+    }
+
+    /// The client a new connection belongs to, if its first message is a Logon
+    /// of a known client which is not logged on already.
+    pub fn accept_logon(&self, message: &FixMessage) -> Option<String> {
+        let client = message.get(tag::SENDER_COMP_ID)?;
+        let session = self.sessions.get(client)?;
+        if message.msg_type() != msg_type::LOGON
+            || message.get(tag::TARGET_COMP_ID) != Some(&self.comp_id)
+            || session.is_logged_on()
+        {
+            return None;
+        }
+        Some(client.to_string())
+    }
+
+    /// Handles a message of the client, `now` is in milliseconds since the Unix epoch.
+    pub fn receive(&mut self, client: &str, message: FixMessage, now: u64) {
+        let session = match self.sessions.get_mut(client) {
+            Some(session) => session,
+            None => return,
+        };
+        let message = match session.receive(message, now) {
+            Some(message) => message,
+            None => return,
+        };
+        self.exchange.set_time(now);
+        let handled = match message.msg_type() {
+            msg_type::NEW_ORDER_SINGLE => self.new_order(client, &message),
+            msg_type::ORDER_CANCEL_REQUEST => self.cancel_order(client, &message),
+            msg_type::ORDER_CANCEL_REPLACE_REQUEST => self.replace_order(client, &message),
+            msg_type::REJECT => Ok(Vec::new()),
+            _ => Err(FieldError {
+                tag: tag::MSG_TYPE,
+                reason: 11,
+                text: "Invalid MsgType",
+            }),
+        };
+        let reports = handled.unwrap_or_else(|error| {
+            let reject = FixMessage::new(msg_type::REJECT)
+                .with(
+                    tag::REF_SEQ_NUM,
+                    message.get(tag::MSG_SEQ_NUM).unwrap_or("0"),
+                )
+                .with(tag::REF_TAG_ID, error.tag)
+                .with(tag::SESSION_REJECT_REASON, error.reason)
+                .with(tag::TEXT, error.text);
+            vec![(client.to_string(), reject)]
+        });
+        for (client, report) in reports {
+            if let Some(session) = self.sessions.get_mut(&client) {
+                session.send(report, now);
+            }
+        }
+    }
+
+    pub fn on_timer(&mut self, now: u64) {
+        for session in self.sessions.values_mut() {
+            session.on_timer(now);
+        }
+    }
+
+    /// Encoded messages to send to the client, and whether its connection has to be
+    /// closed after them.
+    pub fn take_outgoing(&mut self, client: &str) -> (Vec<Vec<u8>>, bool) {
+        match self.sessions.get_mut(client) {
+            Some(session) => (session.take_outgoing(), session.should_disconnect()),
+            None => (Vec::new(), true),
+        }
+    }
+
+    /// The connection of the client is gone, and so are its orders in the books.
+    pub fn disconnected(&mut self, client: &str, now: u64) {
+        if let Some(session) = self.sessions.get_mut(client) {
+            session.disconnected();
+        }
+        let session_id = match self.exchange_sessions.remove(client) {
+            Some(session_id) => session_id,
+            None => return,
+        };
+        self.exchange.set_time(now);
+        let events = self.exchange.disconnect(session_id);
+        let mut reports = Vec::new();
+        for events in events.values() {
+            reports.extend(self.apply_events(events));
+        }
+        for (client, report) in reports {
+            match self.sessions.get_mut(&client) {
+                Some(session) if session.is_logged_on() => session.send(report, now),
+                _ => {}
+            }
+        }
+    }
+
+    fn submit(
+        &mut self,
+        client: &str,
+        symbol: &str,
+        request: &Request,
+    ) -> Result<MatchingResult, Reject> {
+        let user_id = self.users[client];
+        let exchange = &mut self.exchange;
+        let session_id = *self
+            .exchange_sessions
+            .entry(client.to_string())
+            .or_insert_with(|| exchange.connect(user_id, true));
+        self.exchange
+            .submit_from_session(session_id, symbol, request)
+    }
+
+    fn new_order(&mut self, client: &str, message: &FixMessage) -> Result<Reports, FieldError> {
+        let cl_ord_id = required(message, tag::CL_ORD_ID)?;
+        let symbol = required(message, tag::SYMBOL)?;
+        let side = side(message)?;
+        let order_qty = quantity(message)?;
+        let ord_type = required(message, tag::ORD_TYPE)?;
+        let request_type = match message.get(tag::TIME_IN_FORCE) {
+            None | Some("0") | Some("1") => Type::Limit,
+            Some("3") => Type::ImmediateOrCancel,
+            Some("4") => Type::FillOrKill,
+            Some(_) => return Err(incorrect(tag::TIME_IN_FORCE)),
+        };
+        let mut order = FixOrder {
+            client: client.to_string(),
+            cl_ord_id: cl_ord_id.to_string(),
+            symbol: symbol.to_string(),
+            side,
+            price: 0,
+            order_qty,
+            cum_qty: 0,
+            cum_notional: 0,
+        };
+        if ord_type != "2" {
+            let report = self.rejection(0, &order, 11, "Only limit orders are accepted");
+            return Ok(vec![(order.client, report)]);
+        }
+        order.price = required_number(message, tag::PRICE)?;
+        let key = (order.client.clone(), order.cl_ord_id.clone());
+        if self.cl_ord_ids.contains_key(&key) {
+            let report = self.rejection(0, &order, 6, "ClOrdID is in use by an open order");
+            return Ok(vec![(order.client, report)]);
+        }
+
+        self.next_order_id += 1;
+        let id = self.next_order_id;
+        let request = Request {
+            id,
+            side,
+            price: order.price,
+            size: order_qty,
+            user_id: self.users[client],
+            request_type,
+            ..Default::default()
+        };
+        match self.submit(client, symbol, &request) {
+            Err(reject) => {
+                let report =
+                    self.rejection(id, &order, rejection_reason(&reject), &reject.to_string());
+                Ok(vec![(order.client, report)])
+            }
+            Ok(result) => {
+                let report = self.report(id, &order, exec_type::NEW, ord_status::NEW);
+                self.orders.insert(id, order);
+                self.cl_ord_ids.insert(key, id);
+                let mut reports = vec![(client.to_string(), report)];
+                reports.extend(self.apply_result(id, &result));
+                Ok(reports)
+            }
+        }
+    }
+
+    fn cancel_order(&mut self, client: &str, message: &FixMessage) -> Result<Reports, FieldError> {
+        let cl_ord_id = required(message, tag::CL_ORD_ID)?;
+        let orig_cl_ord_id = required(message, tag::ORIG_CL_ORD_ID)?;
+        let key = (client.to_string(), orig_cl_ord_id.to_string());
+        let id = match self.cl_ord_ids.get(&key) {
+            Some(id) => *id,
+            None => {
+                let reject = cancel_reject(None, message, "1", 1, "Unknown order");
+                return Ok(vec![(key.0, reject)]);
+            }
+        };
+        let symbol = self.orders[&id].symbol.clone();
+        let events = self
+            .exchange
+            .cancel_request(&symbol, id)
+            .unwrap_or_default();
+        if !is_cancelled(&events, id) {
+            let reject = cancel_reject(Some(id), message, "1", 0, "Too late to cancel");
+            return Ok(vec![(key.0, reject)]);
+        }
+        let order = self.orders[&id].clone();
+        let mut report = self.report(id, &order, exec_type::CANCELED, ord_status::CANCELED);
+        report.set(tag::CL_ORD_ID, cl_ord_id);
+        report.set(tag::ORIG_CL_ORD_ID, orig_cl_ord_id);
+        self.close(id);
+        let mut reports = vec![(key.0, report)];
+        reports.extend(self.apply_events(&events));
+        Ok(reports)
+    }
+
+    fn replace_order(&mut self, client: &str, message: &FixMessage) -> Result<Reports, FieldError> {
+        let cl_ord_id = required(message, tag::CL_ORD_ID)?;
+        let orig_cl_ord_id = required(message, tag::ORIG_CL_ORD_ID)?;
+        let order_qty = quantity(message)?;
+        let price = required_number(message, tag::PRICE)?;
+        let client = client.to_string();
+        let id = match self
+            .cl_ord_ids
+            .get(&(client.clone(), orig_cl_ord_id.to_string()))
+        {
+            Some(id) => *id,
+            None => {
+                let reject = cancel_reject(None, message, "2", 1, "Unknown order");
+                return Ok(vec![(client, reject)]);
+            }
+        };
+        if order_qty <= self.orders[&id].cum_qty {
+            let reject = cancel_reject(Some(id), message, "2", 99, "OrderQty is not above CumQty");
+            return Ok(vec![(client, reject)]);
+        }
+        let new_key = (client.clone(), cl_ord_id.to_string());
+        if cl_ord_id != orig_cl_ord_id && self.cl_ord_ids.contains_key(&new_key) {
+            let reject = cancel_reject(
+                Some(id),
+                message,
+                "2",
+                6,
+                "ClOrdID is in use by an open order",
+            );
+            return Ok(vec![(client, reject)]);
+        }
+        let symbol = self.orders[&id].symbol.clone();
+        let events = self
+            .exchange
+            .cancel_request(&symbol, id)
+            .unwrap_or_default();
+        if !is_cancelled(&events, id) {
+            let reject = cancel_reject(Some(id), message, "2", 0, "Too late to replace");
+            return Ok(vec![(client, reject)]);
+        }
+        // taken out while the other events are reported, so it is not reported as cancelled
+        let mut order = self.orders.remove(&id).unwrap();
+        let mut reports = self.apply_events(&events);
+
+        self.cl_ord_ids
+            .remove(&(client.clone(), orig_cl_ord_id.to_string()));
+        self.cl_ord_ids.insert(new_key, id);
+        order.cl_ord_id = cl_ord_id.to_string();
+        order.order_qty = order_qty;
+        order.price = price;
+        let request = Request {
+            id,
+            side: order.side,
+            price,
+            size: order_qty - order.cum_qty,
+            user_id: self.users[&client],
+            request_type: Type::Limit,
+            ..Default::default()
+        };
+        self.orders.insert(id, order.clone());
+        match self.submit(&client, &symbol, &request) {
+            Err(reject) => {
+                // the order has already left the book, so it ends up cancelled
+                let mut report = self.report(id, &order, exec_type::CANCELED, ord_status::CANCELED);
+                report.set(tag::ORIG_CL_ORD_ID, orig_cl_ord_id);
+                report.set(tag::TEXT, reject.to_string());
+                self.close(id);
+                reports.push((client, report));
+            }
+            Ok(result) => {
+                let status = if order.cum_qty > 0 {
+                    ord_status::PARTIALLY_FILLED
+                } else {
+                    ord_status::NEW
+                };
+                let mut report = self.report(id, &order, exec_type::REPLACED, status);
+                report.set(tag::ORIG_CL_ORD_ID, orig_cl_ord_id);
+                reports.push((client, report));
+                reports.extend(self.apply_result(id, &result));
+            }
+        }
+        Ok(reports)
+    }
+
+    // Reports fills of both sides, and whatever the book cancelled on the way.
+    fn apply_result(&mut self, id: u64, result: &MatchingResult) -> Reports {
+        let mut reports = Vec::new();
+        for action in result.market_actions.iter() {
+            for order_id in [action.buyer_request_id, action.seller_request_id] {
+                reports.extend(self.fill(order_id, action.size, action.price));
+            }
+        }
+        if result.request_actions.contains(&RequestAction::Cancelled) {
+            reports.extend(self.cancelled(id));
+        }
+        reports.extend(self.apply_events(&result.book_events));
+        reports
+    }
+
+    fn apply_events(&mut self, events: &[BookEvent]) -> Reports {
+        let mut reports = Vec::new();
+        for event in events {
+            if let BookEvent::Cancelled { request } = event {
+                reports.extend(self.cancelled(request.id));
+            }
+        }
+        reports
+    }
+
+    fn fill(&mut self, id: u64, size: u64, price: u64) -> Option<(String, FixMessage)> {
+        let order = self.orders.get_mut(&id)?;
+        order.cum_qty += size;
+        order.cum_notional += price as u128 * size as u128;
+        let order = order.clone();
+        let status = if order.cum_qty >= order.order_qty {
+            ord_status::FILLED
+        } else {
+            ord_status::PARTIALLY_FILLED
+        };
+        let report = self
+            .report(id, &order, exec_type::TRADE, status)
+            .with(tag::LAST_QTY, size)
+            .with(tag::LAST_PX, price);
+        if status == ord_status::FILLED {
+            self.close(id);
+        }
+        Some((order.client, report))
+    }
+
+    fn cancelled(&mut self, id: u64) -> Option<(String, FixMessage)> {
+        let order = self.orders.get(&id)?.clone();
+        let report = self.report(id, &order, exec_type::CANCELED, ord_status::CANCELED);
+        self.close(id);
+        Some((order.client, report))
+    }
+
+    fn close(&mut self, id: u64) {
+        if let Some(order) = self.orders.remove(&id) {
+            self.cl_ord_ids.remove(&(order.client, order.cl_ord_id));
+        }
+    }
+
+    fn rejection(&mut self, id: u64, order: &FixOrder, reason: u32, text: &str) -> FixMessage {
+        self.report(id, order, exec_type::REJECTED, ord_status::REJECTED)
+            .with(tag::ORD_REJ_REASON, reason)
+            .with(tag::TEXT, text)
+    }
+
+    fn report(&mut self, id: u64, order: &FixOrder, exec_type: &str, status: &str) -> FixMessage {
+        self.next_exec_id += 1;
+        let done = [
+            ord_status::FILLED,
+            ord_status::CANCELED,
+            ord_status::REJECTED,
+        ];
+        let leaves_qty = if done.contains(&status) {
+            0
+        } else {
+            order.order_qty - order.cum_qty
+        };
+        let avg_px = if order.cum_qty == 0 {
+            0.0
+        } else {
+            order.cum_notional as f64 / order.cum_qty as f64
+        };
+        FixMessage::new(msg_type::EXECUTION_REPORT)
+            .with(tag::ORDER_ID, id)
+            .with(tag::CL_ORD_ID, &order.cl_ord_id)
+            .with(tag::EXEC_ID, self.next_exec_id)
+            .with(tag::EXEC_TYPE, exec_type)
+            .with(tag::ORD_STATUS, status)
+            .with(tag::SYMBOL, &order.symbol)
+            .with(tag::SIDE, side_code(order.side))
+            .with(tag::ORD_TYPE, 2)
+            .with(tag::ORDER_QTY, order.order_qty)
+            .with(tag::PRICE, order.price)
+            .with(tag::LEAVES_QTY, leaves_qty)
+            .with(tag::CUM_QTY, order.cum_qty)
+            .with(tag::AVG_PX, avg_px)
+    }
+}
+
+fn required(message: &FixMessage, tag: u32) -> Result<&str, FieldError> {
+    message.get(tag).ok_or_else(|| missing(tag))
+}
+
+fn required_number(message: &FixMessage, tag: u32) -> Result<u64, FieldError> {
+    required(message, tag)?.parse().map_err(|_| incorrect(tag))
+}
+
+fn quantity(message: &FixMessage) -> Result<u64, FieldError> {
+    match required_number(message, tag::ORDER_QTY)? {
+        0 => Err(incorrect(tag::ORDER_QTY)),
+        quantity => Ok(quantity),
+    }
+}
+
+fn side(message: &FixMessage) -> Result<Side, FieldError> {
+    match required(message, tag::SIDE)? {
+        "1" => Ok(Side::Buy),
+        "2" => Ok(Side::Sell),
+        _ => Err(incorrect(tag::SIDE)),
+    }
+}
+
+fn side_code(side: Side) -> &'static str {
+    match side {
+        Side::Buy => "1",
+        Side::Sell => "2",
+    }
+}
+
+fn is_cancelled(events: &[BookEvent], id: u64) -> bool {
+    events
+        .iter()
+        .any(|event| matches!(event, BookEvent::Cancelled { request } if request.id == id))
+}
+
+fn cancel_reject(
+    id: Option<u64>,
+    message: &FixMessage,
+    response_to: &str,
+    reason: u32,
+    text: &str,
+) -> FixMessage {
+    let id = id.map_or("NONE".to_string(), |id| id.to_string());
+    FixMessage::new(msg_type::ORDER_CANCEL_REJECT)
+        .with(tag::ORDER_ID, id)
+        .with(tag::CL_ORD_ID, message.get(tag::CL_ORD_ID).unwrap_or(""))
+        .with(
+            tag::ORIG_CL_ORD_ID,
+            message.get(tag::ORIG_CL_ORD_ID).unwrap_or(""),
+        )
+        .with(tag::ORD_STATUS, ord_status::REJECTED)
+        .with(tag::CXL_REJ_RESPONSE_TO, response_to)
+        .with(tag::CXL_REJ_REASON, reason)
+        .with(tag::TEXT, text)
+}
+
+// OrdRejReason of a request refused by the exchange.
+fn rejection_reason(reject: &Reject) -> u32 {
+    match reject {
+        Reject::UnknownBook => 1,
+        Reject::Risk(_) | Reject::InsufficientFunds => 3,
+        Reject::DuplicateId => 6,
+        Reject::ZeroSize => 13,
+        _ => 99,
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as u64)
+}
+
+fn invalid_data(error: FixError) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, error)
+}
+
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// Accepts FIX connections over TCP and serves each of them on its own thread;
+/// all of them share the gateway, so clients trade with each other.
+pub struct FixAcceptor {
+    listener: TcpListener,
+    gateway: Arc<Mutex<FixGateway>>,
+}
+
+impl FixAcceptor {
+    pub fn bind(address: impl ToSocketAddrs, gateway: FixGateway) -> io::Result<FixAcceptor> {
+        Ok(FixAcceptor {
+            listener: TcpListener::bind(address)?,
+            gateway: Arc::new(Mutex::new(gateway)),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn gateway(&self) -> Arc<Mutex<FixGateway>> {
+        Arc::clone(&self.gateway)
+    }
+
+    /// Serves connections until accepting one fails.
+    pub fn run(self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let mut stream = stream?;
+            let gateway = Arc::clone(&self.gateway);
+            thread::spawn(move || {
+                let mut client = None;
+                // errors only end the connection
+                let _ = serve(&mut stream, &gateway, &mut client);
+                if let Some(client) = client {
+                    // a poisoned gateway has its orders cancelled all the same
+                    let mut gateway = gateway.lock().unwrap_or_else(PoisonError::into_inner);
+                    gateway.disconnected(&client, now_millis());
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+fn serve(
+    stream: &mut TcpStream,
+    gateway: &Mutex<FixGateway>,
+    client: &mut Option<String>,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    stream.set_nodelay(true)?;
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 4096];
+    loop {
+        match stream.read(&mut chunk) {
+            Ok(0) => return Ok(()),
+            Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+            Err(ref error) if is_timeout(error) => {}
+            Err(error) => return Err(error),
+        }
+        // the gateway stays usable after a panic of another connection
+        let mut gateway = gateway.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = now_millis();
+        while let Some((message, used)) = FixMessage::decode(&buffer).map_err(invalid_data)? {
+            buffer.drain(..used);
+            if client.is_none() {
+                let accepted = gateway.accept_logon(&message).ok_or_else(|| {
+                    io::Error::new(ErrorKind::PermissionDenied, "the logon is refused")
+                })?;
+                *client = Some(accepted);
+            }
+            gateway.receive(client.as_deref().unwrap(), message, now);
+        }
+        if buffer.len() > MAX_MESSAGE_SIZE {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "the message is too long",
+            ));
+        }
+        gateway.on_timer(now);
+        let (outgoing, disconnect) = match client.as_deref() {
+            Some(client) => gateway.take_outgoing(client),
+            None => (Vec::new(), false),
+        };
+        drop(gateway);
+        for message in outgoing {
+            stream.write_all(&message)?;
+        }
+        if disconnect {
+            return Ok(());
+        }
+    }
+}
+
+/// A FIX initiator over TCP, enough to trade through a `FixAcceptor` from tests and tools.
+/// Session messages are taken care of while waiting for application ones.
+pub struct FixClient {
+    stream: TcpStream,
+    session: FixSession,
+    buffer: Vec<u8>,
+}
+
+impl FixClient {
+    /// Connects and logs on, starting both sequences from 1.
+    pub fn connect(
+        address: impl ToSocketAddrs,
+        sender_comp_id: &str,
+        target_comp_id: &str,
+    ) -> io::Result<FixClient> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        stream.set_nodelay(true)?;
+        let mut client = FixClient {
+            stream,
+            session: FixSession::initiator(sender_comp_id, target_comp_id, 30),
+            buffer: Vec::new(),
+        };
+        let now = now_millis();
+        client.session.logon(true, now);
+        client.flush()?;
+        while !client.session.is_logged_on() {
+            client.poll(now + RECEIVE_TIMEOUT)?;
+        }
+        Ok(client)
+    }
+
+    pub fn session(&self) -> &FixSession {
+        &self.session
+    }
+
+    pub fn send(&mut self, message: FixMessage) -> io::Result<()> {
+        self.session.send(message, now_millis());
+        self.flush()
+    }
+
+    /// Waits for the next application message, for five seconds at most.
+    pub fn receive(&mut self) -> io::Result<FixMessage> {
+        let deadline = now_millis() + RECEIVE_TIMEOUT;
+        loop {
+            if let Some(message) = self.poll(deadline)? {
+                return Ok(message);
+            }
+        }
+    }
+
+    // Handles one incoming message, returning it if it is an application message.
+    fn poll(&mut self, deadline: u64) -> io::Result<Option<FixMessage>> {
+        loop {
+            if let Some((message, used)) = FixMessage::decode(&self.buffer).map_err(invalid_data)? {
+                self.buffer.drain(..used);
+                let message = self.session.receive(message, now_millis());
+                self.flush()?;
+                if self.session.should_disconnect() {
+                    return Err(io::Error::new(ErrorKind::ConnectionAborted, "logged out"));
+                }
+                return Ok(message);
+            }
+            let now = now_millis();
+            if now >= deadline {
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    "no message from the acceptor",
+                ));
+            }
+            self.session.on_timer(now);
+            self.flush()?;
+            let mut chunk = [0; 4096];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(read) => self.buffer.extend_from_slice(&chunk[..read]),
+                Err(ref error) if is_timeout(error) => {}
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for message in self.session.take_outgoing() {
+            self.stream.write_all(&message)?;
+        }
+        Ok(())
+    }
+}
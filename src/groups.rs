@@ -0,0 +1,387 @@
+use serde::{Deserialize, Serialize};
+use std::cmp;
+use std::fmt;
+
+use crate::matcher::*;
+use crate::price::*;
+
+/// What makes one leg of a one-cancels-other pair cancel the other leg.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OcoTrigger {
+    AnyFill,
+    FullFill,
+}
+
+/// Why a linked group was refused before any of its requests was entered.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupError {
+    /// Requests of a group are linked by their ids, so none of them may go without one.
+    MissingId,
+    /// Two requests of the group share an id, or one is in the book or linked already.
+    DuplicateId,
+}
+
+impl fmt::Display for GroupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let res_str = match self {
+            GroupError::MissingId => "a request of the group has no id",
+            GroupError::DuplicateId => "an id of the group is already in use",
+        };
+        write!(f, "Group was rejected: {}", res_str)
+    }
+}
+
+impl std::error::Error for GroupError {}
+
+/// An entry request with a take-profit limit and a stop-loss, both of which
+/// are activated once the entry is completely filled. If the entry is cancelled
+/// after being filled in part, exits are activated for the filled size instead.
+///
+/// The stop-loss request is entered into the book as soon as a trade prints at
+/// `stop_price` or through it. Exits cancel each other: triggering the stop-loss
+/// cancels the take-profit, while every fill of the take-profit shrinks the
+/// stop-loss by the same size, disarming it once nothing is left.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(bound(
+    deserialize = "P: Deserialize<'de>, Q: Deserialize<'de> + Default, U: Deserialize<'de>"
+))]
+pub struct Bracket<P = u64, Q = u64, U = u64> {
+    pub entry: Request<P, Q, U>,
+    pub take_profit: Request<P, Q, U>,
+    pub stop_loss: Request<P, Q, U>,
+    pub stop_price: P,
+}
+
+#[derive(Debug, Clone)]
+struct OcoLink<Q> {
+    ids: [u64; 2],
+    sizes: [Q; 2],
+    filled: [Q; 2],
+    trigger: OcoTrigger,
+}
+
+#[derive(Debug, Clone)]
+struct PendingBracket<P, Q, U> {
+    entry_id: u64,
+    entry_size: Q,
+    entry_left: Q,
+    take_profit: Request<P, Q, U>,
+    stop_loss: Request<P, Q, U>,
+    stop_price: P,
+}
+
+#[derive(Debug, Clone)]
+struct ArmedStop<P, Q, U> {
+    request: Request<P, Q, U>,
+    stop_price: P,
+    take_profit_id: u64,
+}
+
+impl<P: Price, Q: Quantity, U: UserId> ArmedStop<P, Q, U> {
+    fn is_triggered_by(&self, price: P) -> bool {
+        match self.request.side {
+            Side::Sell => price <= self.stop_price,
+            Side::Buy => price >= self.stop_price,
+        }
+    }
+}
+
+// Linked groups of an `OrderBook`, keyed by request ids.
+#[derive(Debug, Clone)]
+pub(crate) struct Links<P, Q, U> {
+    oco: Vec<OcoLink<Q>>,
+    brackets: Vec<PendingBracket<P, Q, U>>,
+    stops: Vec<ArmedStop<P, Q, U>>,
+}
+
+impl<P, Q, U> Default for Links<P, Q, U> {
+    fn default() -> Self {
+        Links {
+            oco: Vec::new(),
+            brackets: Vec::new(),
+            stops: Vec::new(),
+        }
+    }
+}
+
+impl<P, Q, U> Links<P, Q, U> {
+    pub fn is_empty(&self) -> bool {
+        self.oco.is_empty() && self.brackets.is_empty() && self.stops.is_empty()
+    }
+
+    /// Drops every link of a request which left the book on its own.
+    pub(crate) fn forget(&mut self, id: u64) {
+        self.oco.retain(|link| !link.ids.contains(&id));
+        self.brackets.retain(|bracket| bracket.entry_id != id);
+        // the stop-loss stays armed when the take-profit is gone
+        for stop in self.stops.iter_mut() {
+            if stop.take_profit_id == id {
+                stop.take_profit_id = 0;
+            }
+        }
+    }
+
+    /// Removes armed stop-losses whose requests pass the filter and returns those requests.
+    pub(crate) fn disarm_stops(
+        &mut self,
+        filter: impl Fn(&Request<P, Q, U>) -> bool,
+    ) -> Vec<Request<P, Q, U>> {
+        if self.stops.is_empty() {
+            return Vec::new();
+        }
+        let (disarmed, armed) = self
+            .stops
+            .drain(..)
+            .partition::<Vec<_>, _>(|stop| filter(&stop.request));
+        self.stops = armed;
+        disarmed.into_iter().map(|stop| stop.request).collect()
+    }
+
+    fn contains(&self, id: u64) -> bool {
+        self.oco.iter().any(|link| link.ids.contains(&id))
+            || self.brackets.iter().any(|bracket| bracket.entry_id == id)
+    }
+
+    // Whether a request with the id is linked or waits to be activated.
+    fn uses(&self, id: u64) -> bool {
+        self.contains(id)
+            || self
+                .brackets
+                .iter()
+                .any(|bracket| bracket.take_profit.id == id || bracket.stop_loss.id == id)
+            || self
+                .stops
+                .iter()
+                .any(|stop| stop.request.id == id || stop.take_profit_id == id)
+    }
+}
+
+// Results of both legs of an OCO pair, the second one is absent if it was never entered.
+type OcoResults<P, Q, U> = (MatchingResult<P, Q, U>, Option<MatchingResult<P, Q, U>>);
+
+impl<P: Price, Q: Quantity, U: UserId> OrderBook<P, Q, U> {
+    /// Enters two requests which cancel each other; the second one is not entered
+    /// at all if the first one triggers the cancellation straight away.
+    pub fn submit_oco(
+        &mut self,
+        first: &Request<P, Q, U>,
+        second: &Request<P, Q, U>,
+        trigger: OcoTrigger,
+    ) -> Result<OcoResults<P, Q, U>, GroupError> {
+        self.check_group(&[first.id, second.id])?;
+        self.links.oco.push(OcoLink {
+            ids: [first.id, second.id],
+            sizes: [first.size, second.size],
+            filled: [Q::ZERO, Q::ZERO],
+            trigger,
+        });
+        let first_result = self.match_request(first);
+        if !self.links.contains(first.id) {
+            return Ok((first_result, None));
+        }
+        let second_result = self.match_request(second);
+        if !is_resting(&first_result) || !is_resting(&second_result) {
+            self.links.forget(first.id);
+        }
+        Ok((first_result, Some(second_result)))
+    }
+
+    pub fn submit_bracket(
+        &mut self,
+        bracket: &Bracket<P, Q, U>,
+    ) -> Result<MatchingResult<P, Q, U>, GroupError> {
+        let entry_id = bracket.entry.id;
+        self.check_group(&[entry_id, bracket.take_profit.id, bracket.stop_loss.id])?;
+        self.links.brackets.push(PendingBracket {
+            entry_id,
+            entry_size: bracket.entry.size,
+            entry_left: bracket.entry.size,
+            take_profit: bracket.take_profit.clone(),
+            stop_loss: bracket.stop_loss.clone(),
+            stop_price: bracket.stop_price,
+        });
+        let mut result = self.match_request(&bracket.entry);
+        // an entry which is not in the book is done with, exits are activated for what it got
+        if !is_resting(&result) {
+            let released = self.release_bracket(entry_id);
+            result.book_events.extend(released);
+        }
+        Ok(result)
+    }
+
+    fn check_group(&self, ids: &[u64]) -> Result<(), GroupError> {
+        if ids.contains(&0) {
+            return Err(GroupError::MissingId);
+        }
+        for (index, &id) in ids.iter().enumerate() {
+            if ids[..index].contains(&id)
+                || self.links.uses(id)
+                || self.resting_request(id).is_some()
+            {
+                return Err(GroupError::DuplicateId);
+            }
+        }
+        Ok(())
+    }
+
+    // Activates exits of the bracket of a cancelled entry for the size filled before.
+    // A cancel has no executions to report, so the take-profit is only entered if it
+    // would rest, otherwise the stop-loss is left to close the position alone.
+    pub(crate) fn release_bracket(&mut self, entry_id: u64) -> Vec<BookEvent<P, Q, U>> {
+        let index = match self
+            .links
+            .brackets
+            .iter()
+            .position(|b| b.entry_id == entry_id)
+        {
+            Some(index) => index,
+            None => return Vec::new(),
+        };
+        let bracket = self.links.brackets.remove(index);
+        let filled = bracket
+            .entry_size
+            .checked_sub(bracket.entry_left)
+            .unwrap_or(Q::ZERO);
+        if filled == Q::ZERO {
+            return Vec::new();
+        }
+        let take_profit = Request {
+            size: cmp::min(bracket.take_profit.size, filled),
+            ..bracket.take_profit
+        };
+        let stop_loss = Request {
+            size: cmp::min(bracket.stop_loss.size, filled),
+            ..bracket.stop_loss
+        };
+        let rests = !self.would_trade(&take_profit);
+        self.links.stops.push(ArmedStop {
+            request: stop_loss,
+            stop_price: bracket.stop_price,
+            take_profit_id: if rests { take_profit.id } else { 0 },
+        });
+        if !rests {
+            return Vec::new();
+        }
+        let activated = self.enter_request(&take_profit);
+        vec![BookEvent::Activated {
+            request: take_profit,
+            request_actions: activated.request_actions,
+        }]
+    }
+
+    fn would_trade(&self, request: &Request<P, Q, U>) -> bool {
+        let price = match request.peg {
+            None => request.price,
+            Some(peg) => match peg.price(request.side, self.reference_bbo()) {
+                Some(price) => price,
+                None => return false,
+            },
+        };
+        match request.side {
+            Side::Buy => self
+                .sellers
+                .active()
+                .first()
+                .map(|best| best.price <= price),
+            Side::Sell => self.buyers.active().first().map(|best| best.price >= price),
+        }
+        .unwrap_or(false)
+    }
+
+    // Goes through the market actions of the result, including the ones caused by
+    // activated requests along the way, and applies the links they trigger.
+    pub(crate) fn process_links(&mut self, result: &mut MatchingResult<P, Q, U>) {
+        let mut processed = 0;
+        while processed < result.market_actions.len() {
+            let action = result.market_actions[processed].clone();
+            processed += 1;
+            self.on_fill(action.buyer_request_id, action.size, result);
+            self.on_fill(action.seller_request_id, action.size, result);
+            self.on_trade(action.price, result);
+        }
+    }
+
+    fn on_fill(&mut self, id: u64, size: Q, result: &mut MatchingResult<P, Q, U>) {
+        // requests without an id cannot be linked
+        if id == 0 {
+            return;
+        }
+        let mut cancelled = Vec::new();
+        self.links.oco.retain_mut(|link| {
+            let leg = match link.ids.iter().position(|&leg_id| leg_id == id) {
+                Some(leg) => leg,
+                None => return true,
+            };
+            link.filled[leg] = link.filled[leg].checked_add(size).unwrap_or(Q::MAX);
+            let triggered = match link.trigger {
+                OcoTrigger::AnyFill => true,
+                OcoTrigger::FullFill => link.filled[leg] >= link.sizes[leg],
+            };
+            if triggered {
+                cancelled.push(link.ids[1 - leg]);
+            }
+            !triggered
+        });
+        for id in cancelled {
+            self.cancel_linked(id, result);
+        }
+
+        if let Some(index) = self.links.brackets.iter().position(|b| b.entry_id == id) {
+            let bracket = &mut self.links.brackets[index];
+            bracket.entry_left = bracket.entry_left.checked_sub(size).unwrap_or(Q::ZERO);
+            if bracket.entry_left == Q::ZERO {
+                let bracket = self.links.brackets.remove(index);
+                self.links.stops.push(ArmedStop {
+                    request: bracket.stop_loss,
+                    stop_price: bracket.stop_price,
+                    take_profit_id: bracket.take_profit.id,
+                });
+                self.activate(&bracket.take_profit, result);
+            }
+        }
+
+        if let Some(index) = self.links.stops.iter().position(|s| s.take_profit_id == id) {
+            let stop = &mut self.links.stops[index];
+            stop.request.size = stop.request.size.checked_sub(size).unwrap_or(Q::ZERO);
+            if stop.request.size == Q::ZERO {
+                self.links.stops.remove(index);
+            }
+        }
+    }
+
+    fn on_trade(&mut self, price: P, result: &mut MatchingResult<P, Q, U>) {
+        let (triggered, armed) = self
+            .links
+            .stops
+            .drain(..)
+            .partition::<Vec<_>, _>(|stop| stop.is_triggered_by(price));
+        self.links.stops = armed;
+        for stop in triggered {
+            self.cancel_linked(stop.take_profit_id, result);
+            self.activate(&stop.request, result);
+        }
+    }
+
+    fn cancel_linked(&mut self, id: u64, result: &mut MatchingResult<P, Q, U>) {
+        if id == 0 {
+            return;
+        }
+        if let Some(request) = self.remove_request(id) {
+            self.links.forget(id);
+            result.book_events.push(BookEvent::Cancelled { request });
+        }
+    }
+
+    fn activate(&mut self, request: &Request<P, Q, U>, result: &mut MatchingResult<P, Q, U>) {
+        let activated = self.enter_request(request);
+        result.market_actions.extend(activated.market_actions);
+        result.book_events.push(BookEvent::Activated {
+            request: request.clone(),
+            request_actions: activated.request_actions,
+        });
+    }
+}
+
+fn is_resting<P, Q, U>(result: &MatchingResult<P, Q, U>) -> bool {
+    result.request_actions.contains(&RequestAction::AddedToBook)
+}
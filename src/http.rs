@@ -0,0 +1,561 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::exchange::*;
+use crate::matcher::*;
+use crate::websocket::*;
+
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+const MAX_BODY_SIZE: usize = 64 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// Trades kept per book for `GET /books/{symbol}/trades`.
+pub const MAX_TRADES: usize = 1000;
+
+/// A request read from a connection; only what the API needs is kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    /// Names are lowercased.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Reads the next request, `None` if the connection was closed before it.
+    pub fn read(reader: &mut impl BufRead) -> io::Result<Option<HttpRequest>> {
+        let mut line = String::new();
+        if read_line(reader, &mut line)? == 0 {
+            return Ok(None);
+        }
+        let mut parts = line.split_whitespace();
+        let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+                (method.to_string(), target.to_string())
+            }
+            _ => return Err(bad_request("malformed request line")),
+        };
+        let mut headers = Vec::new();
+        let mut head_size = line.len();
+        loop {
+            line.clear();
+            head_size += read_line(reader, &mut line)?;
+            if head_size > MAX_HEAD_SIZE {
+                return Err(bad_request("the head is too long"));
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let (name, value) = header
+                .split_once(':')
+                .ok_or_else(|| bad_request("malformed header"))?;
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), parse_query(query)),
+            None => (target, Vec::new()),
+        };
+        let mut request = HttpRequest {
+            method,
+            path,
+            query,
+            headers,
+            body: Vec::new(),
+        };
+        let length = match request.header("content-length") {
+            Some(length) => length
+                .parse()
+                .map_err(|_| bad_request("invalid Content-Length"))?,
+            None => 0,
+        };
+        if length > MAX_BODY_SIZE {
+            return Err(bad_request("the body is too long"));
+        }
+        request.body.resize(length, 0);
+        reader.read_exact(&mut request.body)?;
+        Ok(Some(request))
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> io::Result<usize> {
+    let read = reader.take(MAX_HEAD_SIZE as u64 + 1).read_line(line)?;
+    if read > MAX_HEAD_SIZE {
+        return Err(bad_request("the line is too long"));
+    }
+    Ok(read)
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => (pair.to_string(), String::new()),
+        })
+        .collect()
+}
+
+fn bad_request(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/// A JSON response, `body` is left out for 204.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: Value,
+}
+
+impl HttpResponse {
+    pub fn json(status: u16, body: impl Serialize) -> HttpResponse {
+        HttpResponse {
+            status,
+            body: serde_json::to_value(body).expect("responses are always serializable"),
+        }
+    }
+
+    pub fn error(status: u16, message: &str) -> HttpResponse {
+        HttpResponse::json(status, json!({ "error": message }))
+    }
+
+    /// Writes the response and asks the client to close the connection.
+    pub fn write(&self, output: &mut impl Write) -> io::Result<()> {
+        let body = match self.status {
+            204 => String::new(),
+            _ => self.body.to_string(),
+        };
+        write!(
+            output,
+            "HTTP/1.1 {} {}\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\
+             Access-Control-Allow-Origin: *\r\n\
+             Access-Control-Allow-Methods: GET, POST, DELETE, OPTIONS\r\n\
+             Access-Control-Allow-Headers: Authorization, Content-Type\r\n\
+             Connection: close\r\n\r\n{}",
+            self.status,
+            reason(self.status),
+            body.len(),
+            body
+        )?;
+        output.flush()
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        422 => "Unprocessable Entity",
+        _ => "Internal Server Error",
+    }
+}
+
+/// What the matching thread is asked to do, see `route` for the endpoints.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EngineCommand {
+    Symbols,
+    Depth { symbol: String, levels: usize },
+    Trades { symbol: String, limit: usize },
+    Submit { symbol: String, request: Request },
+    Cancel { symbol: String, id: u64, owner: u64 },
+    UserRequests { user_id: u64 },
+}
+
+/// Maps a request onto a command, or onto the response if it has none:
+///
+/// * `GET /books` - symbols of the books;
+/// * `GET /books/{symbol}?levels=10` - depth of the book;
+/// * `GET /books/{symbol}/trades?limit=100` - the latest trades, oldest first;
+/// * `POST /books/{symbol}/requests` - submits the `Request` in the body;
+/// * `DELETE /books/{symbol}/requests/{id}` - cancels the resting request of the user
+///   whose API key is sent as `Authorization: Bearer {key}`;
+/// * `GET /users/{user_id}/requests` - requests of the user resting in any book.
+///
+/// `api_keys` are the users by their keys.
+pub fn route(
+    request: &HttpRequest,
+    api_keys: &HashMap<String, u64>,
+) -> Result<EngineCommand, HttpResponse> {
+    if request.method == "OPTIONS" {
+        return Err(HttpResponse::json(204, Value::Null));
+    }
+    let segments: Vec<&str> = request.path.split('/').filter(|s| !s.is_empty()).collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["books"]) => Ok(EngineCommand::Symbols),
+        ("GET", ["books", book]) => Ok(EngineCommand::Depth {
+            symbol: book.to_string(),
+            levels: number(request.query("levels"), 10)?,
+        }),
+        ("GET", ["books", book, "trades"]) => Ok(EngineCommand::Trades {
+            symbol: book.to_string(),
+            limit: number(request.query("limit"), 100)?,
+        }),
+        ("POST", ["books", book, "requests"]) => {
+            let body = serde_json::from_slice(&request.body)
+                .map_err(|error| HttpResponse::error(400, &error.to_string()))?;
+            Ok(EngineCommand::Submit {
+                symbol: book.to_string(),
+                request: body,
+            })
+        }
+        ("DELETE", ["books", book, "requests", id]) => Ok(EngineCommand::Cancel {
+            symbol: book.to_string(),
+            id: number(Some(id), 0)?,
+            owner: authenticated(request, api_keys)?,
+        }),
+        ("GET", ["users", user, "requests"]) => Ok(EngineCommand::UserRequests {
+            user_id: number(Some(user), 0)?,
+        }),
+        (_, ["books"])
+        | (_, ["books", _])
+        | (_, ["books", _, "trades"])
+        | (_, ["books", _, "requests"])
+        | (_, ["books", _, "requests", _])
+        | (_, ["users", _, "requests"]) => Err(HttpResponse::error(405, "method not allowed")),
+        _ => Err(HttpResponse::error(404, "not found")),
+    }
+}
+
+fn number<T: FromStr>(value: Option<&str>, default: T) -> Result<T, HttpResponse> {
+    match value {
+        Some(value) => value
+            .parse()
+            .map_err(|_| HttpResponse::error(400, "expected a number")),
+        None => Ok(default),
+    }
+}
+
+// The user of the API key of the request.
+fn authenticated(
+    request: &HttpRequest,
+    api_keys: &HashMap<String, u64>,
+) -> Result<u64, HttpResponse> {
+    request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|key| api_keys.get(key.trim()))
+        .copied()
+        .ok_or_else(|| HttpResponse::error(401, "expected the API key of a user"))
+}
+
+/// An execution kept for `GET /books/{symbol}/trades`; sequence numbers count trades
+/// of the book from 1.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Trade {
+    pub sequence: u64,
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub action: MarketAction,
+}
+
+// A submitted request as returned by `POST /books/{symbol}/requests`.
+#[derive(Serialize)]
+struct Submitted<'a> {
+    request: &'a Request,
+    #[serde(flatten)]
+    result: &'a MatchingResult,
+}
+
+/// State of the matching thread: the books and what is kept about them for queries.
+#[derive(Debug, Default)]
+pub struct MatchingEngine {
+    exchange: Exchange,
+    trades: HashMap<String, VecDeque<Trade>>,
+    trade_sequences: HashMap<String, u64>,
+    streams: HashMap<String, BookStream>,
+}
+
+impl MatchingEngine {
+    pub fn new(exchange: Exchange) -> MatchingEngine {
+        MatchingEngine {
+            exchange,
+            ..Default::default()
+        }
+    }
+
+    pub fn exchange(&self) -> &Exchange {
+        &self.exchange
+    }
+
+    /// Runs the command at the time in milliseconds.
+    pub fn execute(&mut self, command: EngineCommand, now: u64) -> HttpResponse {
+        match command {
+            EngineCommand::Symbols => {
+                HttpResponse::json(200, self.exchange.symbols().collect::<Vec<_>>())
+            }
+            EngineCommand::Depth { symbol, levels } => match self.exchange.book(&symbol) {
+                Some(book) => HttpResponse::json(200, book.depth(levels)),
+                None => reject(Reject::UnknownBook),
+            },
+            EngineCommand::Trades { symbol, limit } => {
+                if self.exchange.book(&symbol).is_none() {
+                    return reject(Reject::UnknownBook);
+                }
+                let trades = self.trades.get(&symbol);
+                let trades = trades.iter().flat_map(|trades| trades.iter());
+                let skipped = trades.clone().count().saturating_sub(limit);
+                HttpResponse::json(200, trades.skip(skipped).collect::<Vec<_>>())
+            }
+            EngineCommand::Submit { symbol, request } => {
+                self.exchange.set_time(now);
+                match self.exchange.submit(&symbol, &request) {
+                    Ok(result) => {
+                        self.record_trades(&symbol, &result, now);
+                        self.publish(&symbol, Some(&request), &result, now);
+                        let submitted = Submitted {
+                            request: &request,
+                            result: &result,
+                        };
+                        HttpResponse::json(200, submitted)
+                    }
+                    Err(error) => reject(error),
+                }
+            }
+            EngineCommand::Cancel { symbol, id, owner } => {
+                let owned = self
+                    .exchange
+                    .book(&symbol)
+                    .and_then(|book| book.resting_request(id))
+                    .map(|request| request.user_id == owner);
+                // requests of others are as good as missing
+                if owned == Some(false) {
+                    return HttpResponse::error(404, "no resting request of the user with the id");
+                }
+                self.exchange.set_time(now);
+                match self.exchange.cancel_request(&symbol, id) {
+                    Ok(ref events) if events.is_empty() => {
+                        HttpResponse::error(404, "no resting request of the user with the id")
+                    }
+                    Ok(book_events) => {
+                        let result = MatchingResult {
+                            book_events,
+                            ..Default::default()
+                        };
+                        self.publish(&symbol, None, &result, now);
+                        HttpResponse::json(200, json!({ "book_events": result.book_events }))
+                    }
+                    Err(error) => reject(error),
+                }
+            }
+            EngineCommand::UserRequests { user_id } => {
+                let mut requests = Vec::new();
+                for symbol in self.exchange.symbols() {
+                    let book = self.exchange.book(symbol).expect("symbols have books");
+                    for request in book.user_requests(user_id) {
+                        requests.push(json!({ "symbol": symbol, "request": request }));
+                    }
+                }
+                HttpResponse::json(200, requests)
+            }
+        }
+    }
+
+    /// Streams the book to the connection, see `BookStream`; false if there is no such book.
+    pub fn subscribe(&mut self, symbol: &str, frames: SyncSender<StreamFrame>) -> bool {
+        let book = match self.exchange.book(symbol) {
+            Some(book) => book,
+            None => return false,
+        };
+        let stream = self.streams.entry(symbol.to_string()).or_default();
+        stream.subscribe(symbol, book, frames);
+        true
+    }
+
+    fn publish(
+        &mut self,
+        symbol: &str,
+        request: Option<&Request>,
+        result: &MatchingResult,
+        now: u64,
+    ) {
+        if let (Some(stream), Some(book)) =
+            (self.streams.get_mut(symbol), self.exchange.book(symbol))
+        {
+            stream.publish(symbol, book, request, result, now);
+        }
+    }
+
+    fn record_trades(&mut self, symbol: &str, result: &MatchingResult, now: u64) {
+        if result.market_actions.is_empty() {
+            return;
+        }
+        let sequence = self.trade_sequences.entry(symbol.to_string()).or_insert(0);
+        let trades = self.trades.entry(symbol.to_string()).or_default();
+        for action in result.market_actions.iter() {
+            *sequence += 1;
+            if trades.len() == MAX_TRADES {
+                trades.pop_front();
+            }
+            trades.push_back(Trade {
+                sequence: *sequence,
+                timestamp: now,
+                action: action.clone(),
+            });
+        }
+    }
+
+    /// Moves the engine to a thread of its own, which runs commands one at a time
+    /// in the order they are sent through the returned handle.
+    pub fn spawn(mut self) -> EngineHandle {
+        let (sender, jobs) = mpsc::channel();
+        thread::spawn(move || {
+            // the connections may have gone away, the jobs are done anyway
+            for job in jobs {
+                match job {
+                    Job::Command(command, reply) => {
+                        let _ = reply.send(self.execute(command, now_millis()));
+                    }
+                    Job::Subscribe(symbol, frames, reply) => {
+                        let _ = reply.send(self.subscribe(&symbol, frames));
+                    }
+                }
+            }
+        });
+        EngineHandle { sender }
+    }
+}
+
+fn reject(reject: Reject) -> HttpResponse {
+    let status = match reject {
+        Reject::UnknownBook => 404,
+        _ => 422,
+    };
+    HttpResponse::json(status, json!({ "reject": reject }))
+}
+
+#[derive(Debug)]
+enum Job {
+    Command(EngineCommand, Sender<HttpResponse>),
+    Subscribe(String, SyncSender<StreamFrame>, Sender<bool>),
+}
+
+/// Sends commands to the matching thread, cloned for every connection.
+#[derive(Debug, Clone)]
+pub struct EngineHandle {
+    sender: Sender<Job>,
+}
+
+impl EngineHandle {
+    /// Waits for the command to be run; 500 if the matching thread has stopped.
+    pub fn execute(&self, command: EngineCommand) -> HttpResponse {
+        let (reply, response): (_, Receiver<HttpResponse>) = mpsc::channel();
+        self.sender
+            .send(Job::Command(command, reply))
+            .ok()
+            .and_then(|_| response.recv().ok())
+            .unwrap_or_else(|| HttpResponse::error(500, "the matching engine has stopped"))
+    }
+
+    /// Streams the book to the connection; false if there is no such book
+    /// or the matching thread has stopped.
+    pub fn subscribe(&self, symbol: &str, frames: SyncSender<StreamFrame>) -> bool {
+        let (reply, subscribed) = mpsc::channel();
+        self.sender
+            .send(Job::Subscribe(symbol.to_string(), frames, reply))
+            .ok()
+            .and_then(|_| subscribed.recv().ok())
+            .unwrap_or(false)
+    }
+}
+
+/// Serves the API over HTTP/1.1, a connection per request, and streams of the books
+/// over WebSocket at `GET /books/{symbol}/stream`.
+pub struct HttpServer {
+    listener: TcpListener,
+    engine: EngineHandle,
+    api_keys: HashMap<String, u64>,
+}
+
+impl HttpServer {
+    pub fn bind(address: impl ToSocketAddrs, engine: EngineHandle) -> io::Result<HttpServer> {
+        Ok(HttpServer {
+            listener: TcpListener::bind(address)?,
+            engine,
+            api_keys: HashMap::new(),
+        })
+    }
+
+    /// Lets the requests with the key act for the user, see `route`.
+    pub fn add_api_key(&mut self, key: &str, user_id: u64) {
+        self.api_keys.insert(key.to_string(), user_id);
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serves connections until accepting one fails.
+    pub fn run(self) -> io::Result<()> {
+        let api_keys = Arc::new(self.api_keys);
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let engine = self.engine.clone();
+            let api_keys = Arc::clone(&api_keys);
+            thread::spawn(move || {
+                // errors only end the connection
+                let _ = serve(stream, &engine, &api_keys);
+            });
+        }
+        Ok(())
+    }
+}
+
+fn serve(
+    mut stream: TcpStream,
+    engine: &EngineHandle,
+    api_keys: &HashMap<String, u64>,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let request = match HttpRequest::read(&mut BufReader::new(&mut stream)) {
+        Ok(Some(request)) => request,
+        Ok(None) => return Ok(()),
+        Err(ref error) if error.kind() == ErrorKind::InvalidData => {
+            return HttpResponse::error(400, &error.to_string()).write(&mut stream);
+        }
+        Err(error) => return Err(error),
+    };
+    if let Some(symbol) = stream_symbol(&request) {
+        return serve_stream(stream, &request, symbol, engine);
+    }
+    let response = match route(&request, api_keys) {
+        Ok(command) => engine.execute(command),
+        Err(response) => response,
+    };
+    response.write(&mut stream)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as u64)
+}
@@ -0,0 +1,171 @@
+use serde_json::{Map, Value};
+use std::fmt;
+use std::io::BufRead;
+use std::vec;
+
+use crate::matcher::*;
+
+/// How requests are laid out in the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    /// A single JSON array of requests.
+    Json,
+    /// One JSON request per line.
+    JsonLines,
+    /// A header line with names of request fields, then one request per line.
+    /// Fields are separated by commas and cannot contain them, so pegs are left out;
+    /// empty fields take their default values.
+    Csv,
+}
+
+impl InputFormat {
+    /// Format named on the command line: `json`, `jsonl` or `csv`.
+    pub fn from_name(name: &str) -> Option<InputFormat> {
+        match name {
+            "json" => Some(InputFormat::Json),
+            "jsonl" | "ndjson" => Some(InputFormat::JsonLines),
+            "csv" => Some(InputFormat::Csv),
+            _ => None,
+        }
+    }
+
+    /// Format implied by the extension of the file.
+    pub fn from_path(path: &str) -> Option<InputFormat> {
+        let (_, extension) = path.rsplit_once('.')?;
+        InputFormat::from_name(&extension.to_ascii_lowercase())
+    }
+}
+
+/// Input which cannot be read as requests, `line` counts from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for InputError {}
+
+/// Reads requests one at a time, so that lines may be matched as soon as they arrive.
+/// The whole input is read first only for JSON arrays. Reading stops at the first error.
+pub struct RequestReader<R> {
+    input: R,
+    format: InputFormat,
+    line: usize,
+    columns: Option<Vec<String>>,
+    parsed: Option<vec::IntoIter<Request>>,
+    failed: bool,
+}
+
+impl<R: BufRead> RequestReader<R> {
+    pub fn new(input: R, format: InputFormat) -> RequestReader<R> {
+        RequestReader {
+            input,
+            format,
+            line: 0,
+            columns: None,
+            parsed: None,
+            failed: false,
+        }
+    }
+
+    fn error(&mut self, line: usize, message: String) -> InputError {
+        self.failed = true;
+        InputError { line, message }
+    }
+
+    fn next_from_array(&mut self) -> Option<Result<Request, InputError>> {
+        if self.parsed.is_none() {
+            let mut data = String::new();
+            if let Err(error) = self.input.read_to_string(&mut data) {
+                return Some(Err(self.error(0, error.to_string())));
+            }
+            match serde_json::from_str::<Vec<Request>>(&data) {
+                Ok(requests) => self.parsed = Some(requests.into_iter()),
+                Err(error) => return Some(Err(self.error(error.line(), json_message(&error)))),
+            }
+        }
+        self.parsed.as_mut()?.next().map(Ok)
+    }
+
+    fn next_from_line(&mut self) -> Option<Result<Request, InputError>> {
+        loop {
+            let mut data = String::new();
+            self.line += 1;
+            match self.input.read_line(&mut data) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(error) => return Some(Err(self.error(self.line, error.to_string()))),
+            }
+            let data = data.trim();
+            if data.is_empty() {
+                continue;
+            }
+            let parsed = match self.format {
+                InputFormat::Csv => match self.columns {
+                    None => {
+                        self.columns =
+                            Some(data.split(',').map(|c| c.trim().to_string()).collect());
+                        continue;
+                    }
+                    Some(ref columns) => csv_request(columns, data),
+                },
+                _ => serde_json::from_str(data).map_err(|error| json_message(&error)),
+            };
+            return Some(parsed.map_err(|message| self.error(self.line, message)));
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for RequestReader<R> {
+    type Item = Result<Request, InputError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.format {
+            InputFormat::Json => self.next_from_array(),
+            InputFormat::JsonLines | InputFormat::Csv => self.next_from_line(),
+        }
+    }
+}
+
+// serde_json puts the position at the end of its messages, the line is reported separately.
+fn json_message(error: &serde_json::Error) -> String {
+    let message = error.to_string();
+    let position = format!(" at line {} column {}", error.line(), error.column());
+    message
+        .strip_suffix(&position)
+        .unwrap_or(&message)
+        .to_string()
+}
+
+fn csv_request(columns: &[String], data: &str) -> Result<Request, String> {
+    let fields: Vec<_> = data.split(',').map(str::trim).collect();
+    if fields.len() != columns.len() {
+        return Err(format!(
+            "expected {} fields, found {}",
+            columns.len(),
+            fields.len()
+        ));
+    }
+    let mut request = Map::new();
+    for (column, field) in columns.iter().zip(fields) {
+        if field.is_empty() {
+            continue;
+        }
+        // numbers and booleans are taken as they are, anything else is a name of a variant
+        let value = match serde_json::from_str(field) {
+            Ok(value @ Value::Number(_)) | Ok(value @ Value::Bool(_)) => value,
+            _ => Value::String(field.to_string()),
+        };
+        request.insert(column.clone(), value);
+    }
+    serde_json::from_value(Value::Object(request)).map_err(|error| error.to_string())
+}
@@ -0,0 +1,666 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+use crate::depth::*;
+use crate::matcher::*;
+use crate::ouch::*;
+
+/// Length of the longest message, a buffer of it fits any message.
+pub const ITCH_MAX_LEN: usize = 50;
+
+// Datagrams are kept below the usual MTU.
+const MAX_DATAGRAM: usize = 1400;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SystemEvent {
+    StartOfMessages,
+    EndOfMessages,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItchBody {
+    System(SystemEvent),
+    /// A displayed request was added to the book.
+    AddOrder {
+        symbol: WireSymbol,
+        order_id: u64,
+        side: Side,
+        size: u64,
+        price: u64,
+    },
+    /// A resting order was executed at its price.
+    OrderExecuted {
+        symbol: WireSymbol,
+        order_id: u64,
+        size: u64,
+        match_number: u64,
+    },
+    /// A resting order left the book without being executed.
+    OrderCancel {
+        symbol: WireSymbol,
+        order_id: u64,
+        size: u64,
+    },
+    /// An execution of a resting request which is not published as an order;
+    /// `side` is the side of that request.
+    Trade {
+        symbol: WireSymbol,
+        side: Side,
+        size: u64,
+        price: u64,
+        match_number: u64,
+    },
+}
+
+/// A message of the feed; sequence numbers start from 1 and have no gaps,
+/// `timestamp` is the time of the exchange in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ItchMessage {
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub body: ItchBody,
+}
+
+impl ItchMessage {
+    /// Reads the message at the start of the buffer, returning it together with its length,
+    /// or `None` if the buffer does not hold all of it yet.
+    pub fn decode(buffer: &[u8]) -> Result<Option<(ItchMessage, usize)>, WireError> {
+        let lengths = [(b'S', 18), (b'A', 50), (b'E', 49), (b'X', 41), (b'P', 50)];
+        let len = match message_len(buffer, &lengths)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        let mut reader = Reader {
+            bytes: buffer,
+            at: 1,
+        };
+        let sequence = reader.u64();
+        let timestamp = reader.u64();
+        let body = match buffer[0] {
+            b'S' => ItchBody::System(match reader.u8() {
+                b'O' => SystemEvent::StartOfMessages,
+                b'C' => SystemEvent::EndOfMessages,
+                _ => return Err(WireError::InvalidField("event code")),
+            }),
+            b'A' => ItchBody::AddOrder {
+                symbol: reader.symbol(),
+                order_id: reader.u64(),
+                side: reader.side()?,
+                size: reader.u64(),
+                price: reader.u64(),
+            },
+            b'E' => ItchBody::OrderExecuted {
+                symbol: reader.symbol(),
+                order_id: reader.u64(),
+                size: reader.u64(),
+                match_number: reader.u64(),
+            },
+            b'X' => ItchBody::OrderCancel {
+                symbol: reader.symbol(),
+                order_id: reader.u64(),
+                size: reader.u64(),
+            },
+            _ => ItchBody::Trade {
+                symbol: reader.symbol(),
+                side: reader.side()?,
+                size: reader.u64(),
+                price: reader.u64(),
+                match_number: reader.u64(),
+            },
+        };
+        let message = ItchMessage {
+            sequence,
+            timestamp,
+            body,
+        };
+        Ok(Some((message, len)))
+    }
+
+    /// Writes the message to the start of the buffer and returns its length;
+    /// panics if the buffer is shorter than `ITCH_MAX_LEN`.
+    pub fn encode(&self, buffer: &mut [u8]) -> usize {
+        let mut writer = Writer {
+            bytes: buffer,
+            at: 0,
+        };
+        let message_type = match self.body {
+            ItchBody::System(_) => b'S',
+            ItchBody::AddOrder { .. } => b'A',
+            ItchBody::OrderExecuted { .. } => b'E',
+            ItchBody::OrderCancel { .. } => b'X',
+            ItchBody::Trade { .. } => b'P',
+        };
+        writer
+            .u8(message_type)
+            .u64(self.sequence)
+            .u64(self.timestamp);
+        match self.body {
+            ItchBody::System(event) => writer.u8(match event {
+                SystemEvent::StartOfMessages => b'O',
+                SystemEvent::EndOfMessages => b'C',
+            }),
+            ItchBody::AddOrder {
+                ref symbol,
+                order_id,
+                side,
+                size,
+                price,
+            } => writer
+                .symbol(symbol)
+                .u64(order_id)
+                .u8(side_code(side))
+                .u64(size)
+                .u64(price),
+            ItchBody::OrderExecuted {
+                ref symbol,
+                order_id,
+                size,
+                match_number,
+            } => writer
+                .symbol(symbol)
+                .u64(order_id)
+                .u64(size)
+                .u64(match_number),
+            ItchBody::OrderCancel {
+                ref symbol,
+                order_id,
+                size,
+            } => writer.symbol(symbol).u64(order_id).u64(size),
+            ItchBody::Trade {
+                ref symbol,
+                side,
+                size,
+                price,
+                match_number,
+            } => writer
+                .symbol(symbol)
+                .u8(side_code(side))
+                .u64(size)
+                .u64(price)
+                .u64(match_number),
+        };
+        writer.at
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PublishedOrder {
+    side: Side,
+    price: u64,
+    size: u64,
+}
+
+/// Turns what happens in the books into a sequenced stream of messages.
+///
+/// Orders are published under the ids of requests, so a book published in full needs
+/// an id on every request. Requests without an id and hidden ones are not published
+/// as orders, executions against them are published as `Trade` messages instead.
+#[derive(Debug, Default, Clone)]
+pub struct ItchPublisher {
+    sequence: u64,
+    match_number: u64,
+    orders: HashMap<(WireSymbol, u64), PublishedOrder>,
+}
+
+impl ItchPublisher {
+    pub fn new() -> ItchPublisher {
+        ItchPublisher::default()
+    }
+
+    /// Sequence number of the last message published.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn system_event(&mut self, event: SystemEvent, timestamp: u64) -> ItchMessage {
+        self.message(timestamp, ItchBody::System(event))
+    }
+
+    /// Messages for the result of the request matched in the book with the symbol;
+    /// the book is looked at after matching to find where requests rest.
+    pub fn publish_result(
+        &mut self,
+        symbol: &WireSymbol,
+        book: &OrderBook,
+        request: &Request,
+        result: &MatchingResult,
+        timestamp: u64,
+    ) -> Vec<ItchMessage> {
+        let mut messages = Vec::new();
+        for action in result.market_actions.iter() {
+            self.match_number += 1;
+            let (order_id, side) = match action.aggressor_side {
+                Side::Buy => (action.seller_request_id, Side::Sell),
+                Side::Sell => (action.buyer_request_id, Side::Buy),
+            };
+            let body = match self.orders.get_mut(&(*symbol, order_id)) {
+                Some(order) if order_id != 0 => {
+                    order.size = order.size.saturating_sub(action.size);
+                    if order.size == 0 {
+                        self.orders.remove(&(*symbol, order_id));
+                    }
+                    ItchBody::OrderExecuted {
+                        symbol: *symbol,
+                        order_id,
+                        size: action.size,
+                        match_number: self.match_number,
+                    }
+                }
+                _ => ItchBody::Trade {
+                    symbol: *symbol,
+                    side,
+                    size: action.size,
+                    price: action.price,
+                    match_number: self.match_number,
+                },
+            };
+            messages.push(self.message(timestamp, body));
+        }
+        messages.extend(self.publish_events(symbol, book, &result.book_events, timestamp));
+        if result.request_actions.contains(&RequestAction::AddedToBook) {
+            messages.extend(self.add(symbol, book, request, timestamp));
+        }
+        messages
+    }
+
+    /// Messages for events of the book with the symbol, e.g. from cancels.
+    pub fn publish_events(
+        &mut self,
+        symbol: &WireSymbol,
+        book: &OrderBook,
+        events: &[BookEvent],
+        timestamp: u64,
+    ) -> Vec<ItchMessage> {
+        let mut messages = Vec::new();
+        for event in events {
+            match event {
+                BookEvent::Cancelled { request } => {
+                    messages.extend(self.cancel(symbol, request.id, timestamp));
+                }
+                BookEvent::Repriced { id, new_price, .. } => {
+                    // published as a new order at the new price, which has lost its priority
+                    let order = match self.orders.get(&(*symbol, *id)) {
+                        Some(order) => *order,
+                        None => continue,
+                    };
+                    messages.extend(self.cancel(symbol, *id, timestamp));
+                    let order = PublishedOrder {
+                        price: *new_price,
+                        ..order
+                    };
+                    messages.push(self.add_order(symbol, *id, order, timestamp));
+                }
+                BookEvent::Activated {
+                    request,
+                    request_actions,
+                } => {
+                    if request_actions.contains(&RequestAction::AddedToBook) {
+                        messages.extend(self.add(symbol, book, request, timestamp));
+                    }
+                }
+            }
+        }
+        messages
+    }
+
+    fn add(
+        &mut self,
+        symbol: &WireSymbol,
+        book: &OrderBook,
+        request: &Request,
+        timestamp: u64,
+    ) -> Option<ItchMessage> {
+        let id = request.id;
+        if id == 0 || self.orders.contains_key(&(*symbol, id)) {
+            return None;
+        }
+        let request = book.resting_request(id).filter(|request| !request.hidden)?;
+        let order = PublishedOrder {
+            side: request.side,
+            price: request.price,
+            size: request.size,
+        };
+        Some(self.add_order(symbol, id, order, timestamp))
+    }
+
+    fn add_order(
+        &mut self,
+        symbol: &WireSymbol,
+        id: u64,
+        order: PublishedOrder,
+        timestamp: u64,
+    ) -> ItchMessage {
+        self.orders.insert((*symbol, id), order);
+        let body = ItchBody::AddOrder {
+            symbol: *symbol,
+            order_id: id,
+            side: order.side,
+            size: order.size,
+            price: order.price,
+        };
+        self.message(timestamp, body)
+    }
+
+    fn cancel(&mut self, symbol: &WireSymbol, id: u64, timestamp: u64) -> Option<ItchMessage> {
+        let order = self.orders.remove(&(*symbol, id))?;
+        let body = ItchBody::OrderCancel {
+            symbol: *symbol,
+            order_id: id,
+            size: order.size,
+        };
+        Some(self.message(timestamp, body))
+    }
+
+    fn message(&mut self, timestamp: u64, body: ItchBody) -> ItchMessage {
+        self.sequence += 1;
+        ItchMessage {
+            sequence: self.sequence,
+            timestamp,
+            body,
+        }
+    }
+}
+
+/// A message which does not follow the last one applied, the ones in between were lost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ItchGap {
+    pub expected: u64,
+    pub found: u64,
+}
+
+impl fmt::Display for ItchGap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "expected message {}, found {}",
+            self.expected, self.found
+        )
+    }
+}
+
+impl std::error::Error for ItchGap {}
+
+#[derive(Debug, Default, Clone)]
+struct ReplicaLevels {
+    // size and number of orders by price
+    bids: BTreeMap<u64, (u64, usize)>,
+    asks: BTreeMap<u64, (u64, usize)>,
+}
+
+impl ReplicaLevels {
+    fn change(&mut self, order: &PublishedOrder, size: u64, added: bool) {
+        let levels = match order.side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        };
+        let level = levels.entry(order.price).or_insert((0, 0));
+        if added {
+            level.0 = level.0.saturating_add(size);
+            level.1 += 1;
+            return;
+        }
+        level.0 = level.0.saturating_sub(size);
+        if size == order.size {
+            level.1 -= 1;
+        }
+        if level.1 == 0 {
+            levels.remove(&order.price);
+        }
+    }
+}
+
+/// Books of a consumer of the feed, rebuilt from its messages.
+#[derive(Debug, Default, Clone)]
+pub struct ItchReplica {
+    next_sequence: u64,
+    orders: HashMap<(WireSymbol, u64), PublishedOrder>,
+    books: HashMap<WireSymbol, ReplicaLevels>,
+    volumes: HashMap<WireSymbol, u64>,
+}
+
+impl ItchReplica {
+    pub fn new() -> ItchReplica {
+        ItchReplica {
+            next_sequence: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Sequence number of the message expected next.
+    pub fn next_sequence(&self) -> u64 {
+        self.next_sequence
+    }
+
+    /// Applies the message if it is the next one; messages seen already are ignored,
+    /// so the same messages may be received twice.
+    pub fn apply(&mut self, message: &ItchMessage) -> Result<(), ItchGap> {
+        if message.sequence < self.next_sequence {
+            return Ok(());
+        }
+        if message.sequence > self.next_sequence {
+            return Err(ItchGap {
+                expected: self.next_sequence,
+                found: message.sequence,
+            });
+        }
+        self.next_sequence += 1;
+        match message.body {
+            ItchBody::System(_) => {}
+            ItchBody::AddOrder {
+                symbol,
+                order_id,
+                side,
+                size,
+                price,
+            } => {
+                let order = PublishedOrder { side, price, size };
+                self.books
+                    .entry(symbol)
+                    .or_default()
+                    .change(&order, size, true);
+                self.orders.insert((symbol, order_id), order);
+            }
+            ItchBody::OrderExecuted {
+                symbol,
+                order_id,
+                size,
+                ..
+            } => {
+                self.take(symbol, order_id, size);
+                *self.volumes.entry(symbol).or_insert(0) += size;
+            }
+            ItchBody::OrderCancel {
+                symbol,
+                order_id,
+                size,
+            } => self.take(symbol, order_id, size),
+            ItchBody::Trade { symbol, size, .. } => {
+                *self.volumes.entry(symbol).or_insert(0) += size;
+            }
+        }
+        Ok(())
+    }
+
+    /// Up to `levels` price levels per side of the book with the symbol.
+    pub fn depth(&self, symbol: &str, levels: usize) -> Depth {
+        let book = match wire_symbol(symbol).and_then(|symbol| self.books.get(&symbol)) {
+            Some(book) => book,
+            None => return Depth::default(),
+        };
+        let level = |(price, (size, orders)): (&u64, &(u64, usize))| PriceLevel {
+            price: *price,
+            size: *size,
+            orders: *orders,
+        };
+        Depth {
+            bids: book.bids.iter().rev().take(levels).map(level).collect(),
+            asks: book.asks.iter().take(levels).map(level).collect(),
+        }
+    }
+
+    /// Total size traded in the book with the symbol.
+    pub fn volume(&self, symbol: &str) -> u64 {
+        wire_symbol(symbol)
+            .and_then(|symbol| self.volumes.get(&symbol))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn take(&mut self, symbol: WireSymbol, order_id: u64, size: u64) {
+        let order = match self.orders.get_mut(&(symbol, order_id)) {
+            Some(order) => order,
+            None => return,
+        };
+        let size = size.min(order.size);
+        let before = *order;
+        order.size -= size;
+        if order.size == 0 {
+            self.orders.remove(&(symbol, order_id));
+        }
+        if let Some(book) = self.books.get_mut(&symbol) {
+            book.change(&before, size, false);
+        }
+    }
+}
+
+// Messages are stored and sent after their length, as two big-endian bytes.
+fn frame(message: &ItchMessage, output: &mut Vec<u8>) {
+    let mut buffer = [0; ITCH_MAX_LEN];
+    let len = message.encode(&mut buffer);
+    output.extend_from_slice(&(len as u16).to_be_bytes());
+    output.extend_from_slice(&buffer[..len]);
+}
+
+fn invalid_data(error: WireError) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, error)
+}
+
+/// Writes messages to a file, or any other stream, for a later replay with `ItchReader`.
+pub struct ItchWriter<W> {
+    output: W,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> ItchWriter<W> {
+    pub fn new(output: W) -> ItchWriter<W> {
+        ItchWriter {
+            output,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn write(&mut self, messages: &[ItchMessage]) -> io::Result<()> {
+        self.buffer.clear();
+        for message in messages {
+            frame(message, &mut self.buffer);
+        }
+        self.output.write_all(&self.buffer)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+/// Reads back messages written by `ItchWriter`.
+pub struct ItchReader<R> {
+    input: R,
+}
+
+impl<R: Read> ItchReader<R> {
+    pub fn new(input: R) -> ItchReader<R> {
+        ItchReader { input }
+    }
+
+    fn read_message(&mut self) -> io::Result<Option<ItchMessage>> {
+        let mut len = [0; 2];
+        match self.input.read(&mut len[..1])? {
+            0 => return Ok(None),
+            _ => self.input.read_exact(&mut len[1..])?,
+        }
+        let mut buffer = [0; ITCH_MAX_LEN];
+        let len = u16::from_be_bytes(len) as usize;
+        let message = buffer
+            .get_mut(..len)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "the message is too long"))?;
+        self.input.read_exact(message)?;
+        match ItchMessage::decode(message).map_err(invalid_data)? {
+            Some((message, used)) if used == len => Ok(Some(message)),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "the length does not match the message",
+            )),
+        }
+    }
+}
+
+impl<R: Read> Iterator for ItchReader<R> {
+    type Item = io::Result<ItchMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_message().transpose()
+    }
+}
+
+/// Sends messages to a UDP address, as many of them in a datagram as fit.
+pub struct ItchUdpSender {
+    socket: UdpSocket,
+}
+
+impl ItchUdpSender {
+    /// Sends from an address picked by the system.
+    pub fn connect(destination: impl ToSocketAddrs) -> io::Result<ItchUdpSender> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(destination)?;
+        Ok(ItchUdpSender { socket })
+    }
+
+    pub fn send(&self, messages: &[ItchMessage]) -> io::Result<()> {
+        let mut datagram = Vec::with_capacity(MAX_DATAGRAM);
+        for message in messages {
+            if datagram.len() + 2 + ITCH_MAX_LEN > MAX_DATAGRAM {
+                self.socket.send(&datagram)?;
+                datagram.clear();
+            }
+            frame(message, &mut datagram);
+        }
+        if !datagram.is_empty() {
+            self.socket.send(&datagram)?;
+        }
+        Ok(())
+    }
+}
+
+/// Receives datagrams of `ItchUdpSender`.
+pub struct ItchUdpReceiver {
+    socket: UdpSocket,
+}
+
+impl ItchUdpReceiver {
+    pub fn bind(address: impl ToSocketAddrs) -> io::Result<ItchUdpReceiver> {
+        Ok(ItchUdpReceiver {
+            socket: UdpSocket::bind(address)?,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Waits for the next datagram and returns its messages.
+    pub fn receive(&self) -> io::Result<Vec<ItchMessage>> {
+        let mut datagram = [0; MAX_DATAGRAM];
+        let len = self.socket.recv(&mut datagram)?;
+        ItchReader::new(&datagram[..len]).collect()
+    }
+}
@@ -1,2 +1,53 @@
+pub mod accounts;
+pub mod dark;
+pub mod decimal;
+pub mod depth;
+#[cfg(feature = "display")]
+pub mod displayers;
+pub mod exchange;
+pub mod fees;
+pub mod fix;
+pub mod fix_gateway;
+pub mod groups;
+pub mod http;
+pub mod input;
+pub mod itch;
 pub mod matcher;
+pub mod mmp;
+pub mod ouch;
+pub mod pegging;
+pub mod positions;
+pub mod price;
+pub mod quotes;
+#[cfg(feature = "display")]
+pub mod repl;
+pub mod risk;
+pub mod websocket;
+#[cfg(test)]
+mod tests;
+
+pub use accounts::*;
+pub use dark::*;
+pub use decimal::*;
+pub use depth::*;
+#[cfg(feature = "display")]
+pub use displayers::*;
+pub use exchange::*;
+pub use fees::*;
+pub use fix::*;
+pub use fix_gateway::*;
+pub use groups::*;
+pub use http::*;
+pub use input::*;
+pub use itch::*;
 pub use matcher::*;
+pub use mmp::*;
+pub use ouch::*;
+pub use pegging::*;
+pub use positions::*;
+pub use price::*;
+pub use quotes::*;
+#[cfg(feature = "display")]
+pub use repl::*;
+pub use risk::*;
+pub use websocket::*;
@@ -1,22 +1,286 @@
+extern crate market_matcher;
 extern crate serde;
 extern crate serde_json;
 
-mod displayers;
-mod matcher;
-#[cfg(test)]
-mod tests;
+use serde::Serialize;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::matcher::*;
+use market_matcher::*;
+
+const USAGE: &str = "usage: market_matcher [--format json|jsonl|csv] [--output text|json]
+                      [--language en|ru] [--compact] [--itch-file PATH]
+                      [--itch-udp ADDRESS] [--symbol SYMBOL] [PATH]
+       market_matcher --repl
+       market_matcher --fix ADDRESS [--comp-id ID] [--symbol SYMBOL]...
+                      [--client COMP_ID=USER_ID]...
+
+Matches requests from PATH, or from stdin if it is absent or `-`, and prints
+every request with its result as soon as it is matched. The format is taken
+from the extension of PATH and is JSON Lines for stdin unless it is given.
+With `--output json` every request and its result are printed as one JSON
+object per line, otherwise text is printed in the language, one line per
+request with `--compact`. Book changes and trades are also published as an ITCH feed
+of the symbol, `BOOK` by default, to a file with `--itch-file` and over UDP with
+`--itch-udp`. With `--repl` requests are typed in by hand, see `help` there.
+With `--fix` a FIX 4.4 acceptor listens on ADDRESS, trading the symbols for the clients,
+which log on with their CompIDs and trade as the given users.";
+
+// Outputs of the market data feed of the book.
+struct Feed {
+    symbol: WireSymbol,
+    publisher: ItchPublisher,
+    file: Option<ItchWriter<io::BufWriter<File>>>,
+    udp: Option<ItchUdpSender>,
+}
+
+impl Feed {
+    fn publish(&mut self, messages: &[ItchMessage]) {
+        if let Some(ref mut file) = self.file {
+            file.write(messages)
+                .unwrap_or_else(|error| fail(&error.to_string()));
+        }
+        if let Some(ref udp) = self.udp {
+            udp.send(messages)
+                .unwrap_or_else(|error| fail(&error.to_string()));
+        }
+    }
+
+    fn system_event(&mut self, event: SystemEvent) {
+        let message = self.publisher.system_event(event, now_millis());
+        self.publish(&[message]);
+    }
+}
+
+// A processed request as printed by `--output json`.
+#[derive(Serialize)]
+struct Processed<'a> {
+    request: &'a Request,
+    #[serde(flatten)]
+    result: &'a MatchingResult,
+}
 
 fn main() {
-    let data = include_str!("../requests.json");
-    let requests: Vec<Request> = serde_json::from_str(data).expect("was expecting valid JSON file");
+    let mut format = None;
+    let mut json_output = false;
+    let mut options = DisplayOptions::default();
+    let mut repl = false;
+    let mut fix_address = None;
+    let mut comp_id = "MATCHER".to_string();
+    let mut symbols = Vec::new();
+    let mut clients = Vec::new();
+    let mut itch_file = None;
+    let mut itch_udp = None;
+    let mut path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" | "-f" => {
+                let name = args.next().unwrap_or_else(|| usage_error("missing format"));
+                format = Some(
+                    InputFormat::from_name(&name)
+                        .unwrap_or_else(|| usage_error(&format!("unknown format '{}'", name))),
+                );
+            }
+            "--output" | "-o" => match args.next().as_deref() {
+                Some("text") => json_output = false,
+                Some("json") => json_output = true,
+                Some(name) => usage_error(&format!("unknown output '{}'", name)),
+                None => usage_error("missing output"),
+            },
+            "--language" | "-l" => match args.next().as_deref() {
+                Some("en") => options.language = Language::English,
+                Some("ru") => options.language = Language::Russian,
+                Some(name) => usage_error(&format!("unknown language '{}'", name)),
+                None => usage_error("missing language"),
+            },
+            "--compact" | "-c" => options.verbosity = Verbosity::Compact,
+            "--repl" | "-i" => repl = true,
+            "--fix" => {
+                fix_address = Some(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("missing address")),
+                )
+            }
+            "--comp-id" => comp_id = args.next().unwrap_or_else(|| usage_error("missing CompID")),
+            "--symbol" => {
+                symbols.push(args.next().unwrap_or_else(|| usage_error("missing symbol")))
+            }
+            "--client" => {
+                let client = args.next().unwrap_or_else(|| usage_error("missing client"));
+                let parsed = client
+                    .split_once('=')
+                    .and_then(|(comp_id, user)| Some((comp_id.to_string(), user.parse().ok()?)));
+                clients.push(parsed.unwrap_or_else(|| {
+                    usage_error(&format!("expected COMP_ID=USER_ID, found '{}'", client))
+                }));
+            }
+            "--itch-file" => {
+                itch_file = Some(args.next().unwrap_or_else(|| usage_error("missing path")))
+            }
+            "--itch-udp" => {
+                itch_udp = Some(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("missing address")),
+                )
+            }
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if arg.starts_with('-') && arg != "-" => {
+                usage_error(&format!("unknown option '{}'", arg))
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => usage_error(&format!("unexpected argument '{}'", arg)),
+        }
+    }
+
+    if repl {
+        run_repl();
+        return;
+    }
+    if let Some(address) = fix_address {
+        run_fix(&address, &comp_id, &symbols, &clients);
+        return;
+    }
+
+    let input: Box<dyn BufRead> = match path.as_deref() {
+        None | Some("-") => Box::new(BufReader::new(io::stdin())),
+        Some(path) => match File::open(path) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(error) => fail(&format!("{}: {}", path, error)),
+        },
+    };
+    let format = format
+        .or_else(|| path.as_deref().and_then(InputFormat::from_path))
+        .unwrap_or(InputFormat::JsonLines);
+
+    let symbol = symbols.first().map_or("BOOK", |symbol| symbol.as_str());
+    let mut feed = Feed {
+        symbol: wire_symbol(symbol)
+            .unwrap_or_else(|| usage_error(&format!("symbol '{}' is too long", symbol))),
+        publisher: ItchPublisher::new(),
+        file: itch_file.map(|path| match File::create(&path) {
+            Ok(file) => ItchWriter::new(io::BufWriter::new(file)),
+            Err(error) => fail(&format!("{}: {}", path, error)),
+        }),
+        udp: itch_udp.map(|address| {
+            ItchUdpSender::connect(address.as_str())
+                .unwrap_or_else(|error| fail(&format!("{}: {}", address, error)))
+        }),
+    };
+    feed.system_event(SystemEvent::StartOfMessages);
+
     let mut order_book = OrderBook::default();
-    let results_iterator = requests
-        .iter()
-        .map(|request| (request, order_book.match_request(request)));
-    for (request, result) in results_iterator {
-        println!("{}", request);
-        println!("{}\n", result);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for request in RequestReader::new(input, format) {
+        let request = request.unwrap_or_else(|error| fail(&error.to_string()));
+        let published = feed.file.is_some() || feed.udp.is_some();
+        if published && request.id == 0 {
+            // orders of the feed are referenced by the ids of requests
+            fail("requests published to the ITCH feed need an id");
+        }
+        let result = order_book.match_request(&request);
+        if published {
+            let messages = feed.publisher.publish_result(
+                &feed.symbol,
+                &order_book,
+                &request,
+                &result,
+                now_millis(),
+            );
+            feed.publish(&messages);
+        }
+        let written = if json_output {
+            let processed = Processed {
+                request: &request,
+                result: &result,
+            };
+            let line = serde_json::to_string(&processed).expect("results are always serializable");
+            writeln!(out, "{}", line)
+        } else if options.verbosity == Verbosity::Compact {
+            let (request, result) = (options.format(&request), options.format(&result));
+            writeln!(out, "{}: {}", request, result)
+        } else {
+            let (request, result) = (options.format(&request), options.format(&result));
+            writeln!(out, "{}\n{}\n", request, result)
+        };
+        let written = written.and_then(|_| out.flush());
+        if written.is_err() {
+            // the reader of the output has gone away
+            break;
+        }
+    }
+    feed.system_event(SystemEvent::EndOfMessages);
+    if let Some(ref mut file) = feed.file {
+        file.flush()
+            .unwrap_or_else(|error| fail(&error.to_string()));
+    }
+}
+
+fn run_repl() {
+    let mut repl = Repl::default();
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("> ");
+        io::stdout().flush().ok();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            Some(Err(error)) => fail(&error.to_string()),
+            None => {
+                println!();
+                break;
+            }
+        };
+        let output = match repl.parse(&line) {
+            Ok(Command::Quit) => break,
+            Ok(command) => repl.execute(command),
+            Err(error) => Err(error),
+        };
+        match output {
+            Ok(output) => println!("{}", output),
+            Err(error) => println!("{}", error),
+        }
+    }
+}
+
+fn run_fix(address: &str, comp_id: &str, symbols: &[String], clients: &[(String, u64)]) {
+    let mut exchange = Exchange::default();
+    for symbol in symbols {
+        exchange.add_book(symbol);
+    }
+    let mut gateway = FixGateway::new(comp_id, exchange);
+    for (client, user_id) in clients {
+        gateway.add_client(client, *user_id);
     }
+    let acceptor =
+        FixAcceptor::bind(address, gateway).unwrap_or_else(|error| fail(&error.to_string()));
+    if let Ok(address) = acceptor.local_addr() {
+        eprintln!("market_matcher: accepting FIX connections on {}", address);
+    }
+    if let Err(error) = acceptor.run() {
+        fail(&error.to_string());
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("market_matcher: {}\n\n{}", message, USAGE);
+    process::exit(2);
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("market_matcher: {}", message);
+    process::exit(1);
 }
@@ -1,37 +1,69 @@
 use serde::{Deserialize, Serialize};
 use std::cmp;
+use std::fmt;
 
-#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+use crate::groups::Links;
+use crate::pegging::Peg;
+use crate::price::{Overflow, Price, Quantity, UserId};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
 pub enum Side {
+    #[default]
     Buy,
     Sell,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum Type {
+    #[default]
     Limit,
     FillOrKill,
     ImmediateOrCancel,
+    /// One side of a two-sided quote, behaves like `Limit`, see `OrderBook::quote`.
+    Quote,
 }
 
-#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
-pub struct Request {
+/// A request to the book. Narrower types make it smaller, on 64-bit targets it takes
+/// 72 bytes with the default `u64`s and 48 bytes with `u32`s.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(bound(
+    deserialize = "P: Deserialize<'de>, Q: Deserialize<'de> + Default, U: Deserialize<'de>"
+))]
+pub struct Request<P = u64, Q = u64, U = u64> {
+    /// Identifier used to refer to a resting request, unique among the requests of a book;
+    /// 0 is for requests nobody refers to.
+    #[serde(default)]
+    pub id: u64,
     pub side: Side,
-    pub price: u64,
-    pub size: u64,
-    pub user_id: u64,
+    pub price: P,
+    pub size: Q,
+    pub user_id: U,
     pub request_type: Type,
+    /// Pegged requests ignore `price` and follow the touch, see `Peg`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peg: Option<Peg<P>>,
+    /// Hidden requests are left out of the depth and queue behind displayed ones.
+    #[serde(default)]
+    pub hidden: bool,
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct MarketAction {
-    pub size: u64,
-    pub price: u64,
-    pub seller_user_id: u64,
-    pub buyer_user_id: u64,
+#[derive(Deserialize, Serialize, Default, Debug, PartialEq, Eq, Clone)]
+pub struct MarketAction<P = u64, Q = u64, U = u64> {
+    pub size: Q,
+    pub price: P,
+    pub seller_user_id: U,
+    pub buyer_user_id: U,
+    pub seller_request_id: u64,
+    pub buyer_request_id: u64,
+    /// Side of the incoming request, the other side was resting in the book.
+    pub aggressor_side: Side,
+    /// Fees in the price currency, zero unless the venue has a fee schedule;
+    /// negative fees are rebates.
+    pub buyer_fee: i64,
+    pub seller_fee: i64,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
 pub enum RequestAction {
     Filled,
     FilledPartially,
@@ -39,55 +71,186 @@ pub enum RequestAction {
     AddedToBook,
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct MatchingResult {
-    pub market_actions: Vec<MarketAction>,
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+#[serde(bound(
+    deserialize = "P: Deserialize<'de>, Q: Deserialize<'de> + Default, U: Deserialize<'de>"
+))]
+pub enum BookEvent<P = u64, Q = u64, U = u64> {
+    /// A pegged request followed the touch to a new price and lost its time priority.
+    Repriced {
+        id: u64,
+        side: Side,
+        user_id: U,
+        size: Q,
+        old_price: P,
+        new_price: P,
+    },
+    /// A resting request was taken out of the book without being filled.
+    Cancelled { request: Request<P, Q, U> },
+    /// A contingent request of a linked group was entered into the book.
+    Activated {
+        request: Request<P, Q, U>,
+        request_actions: Vec<RequestAction>,
+    },
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, PartialEq, Eq)]
+#[serde(bound(
+    deserialize = "P: Deserialize<'de>, Q: Deserialize<'de> + Default, U: Deserialize<'de>"
+))]
+pub struct MatchingResult<P = u64, Q = u64, U = u64> {
+    pub market_actions: Vec<MarketAction<P, Q, U>>,
     pub request_actions: Vec<RequestAction>,
+    pub book_events: Vec<BookEvent<P, Q, U>>,
+}
+
+/// Selects requests for a mass cancel, every request passes the default filter.
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+pub struct CancelFilter<P = u64> {
+    pub side: Option<Side>,
+    pub request_type: Option<Type>,
+    pub min_price: Option<P>,
+    pub max_price: Option<P>,
+}
+
+impl<P: Price> CancelFilter<P> {
+    pub fn matches<Q, U>(&self, request: &Request<P, Q, U>) -> bool {
+        self.side.is_none_or(|side| side == request.side)
+            && self.request_type.is_none_or(|t| t == request.request_type)
+            && self.min_price.is_none_or(|price| request.price >= price)
+            && self.max_price.is_none_or(|price| request.price <= price)
+    }
 }
 
-#[derive(Default, Debug, Clone)]
-pub struct OrderBook {
-    pub buyers: RequestQueue,
-    pub sellers: RequestQueue,
+/// Best bid and best ask prices, either of which may be missing.
+#[derive(Deserialize, Serialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bbo<P = u64> {
+    pub bid: Option<P>,
+    pub ask: Option<P>,
+}
+
+/// Common interface of the lit `OrderBook` and the `DarkBook`.
+pub trait Matcher<P = u64, Q = u64, U = u64> {
+    fn match_request(&mut self, request: &Request<P, Q, U>) -> MatchingResult<P, Q, U>;
+}
+
+#[derive(Debug, Clone)]
+pub struct OrderBook<P = u64, Q = u64, U = u64> {
+    pub(crate) buyers: RequestQueue<P, Q, U>,
+    pub(crate) sellers: RequestQueue<P, Q, U>,
+    // the touch pegged requests were last priced against
+    pub(crate) peg_reference: Bbo<P>,
+    // no fewer than the pegged requests in the book, counted exactly by every repricing
+    pub(crate) pegged: usize,
+    pub(crate) links: Links<P, Q, U>,
+}
+
+// only the unsigned book is the default one, so that `OrderBook::default()` needs no annotations
+impl Default for OrderBook {
+    fn default() -> Self {
+        OrderBook::new()
+    }
 }
 
-#[derive(Default, Debug, Clone)]
-pub struct RequestQueue {
-    pub vec: Vec<Request>,
-    pub start_from: usize
+#[derive(Default, Clone)]
+pub struct RequestQueue<P = u64, Q = u64, U = u64> {
+    vec: Vec<Request<P, Q, U>>,
+    pub(crate) start_from: usize,
 }
 
 use std::ops::{Deref, DerefMut};
 
-impl RequestQueue {
+impl<P, Q, U> RequestQueue<P, Q, U> {
+    /// Requests that are logically in the book, i.e. starting from `start_from`.
+    pub fn active(&self) -> &[Request<P, Q, U>] {
+        &self.vec[self.start_from..]
+    }
+
     fn flush_vec(&mut self) {
         self.vec.drain(0..self.start_from);
         self.start_from = 0;
     }
 }
 
-impl Deref for RequestQueue {
-    type Target = Vec<Request>;
+// requests before `start_from` are already gone from the book, so they are left out
+impl<P: fmt::Debug, Q: fmt::Debug, U: fmt::Debug> fmt::Debug for RequestQueue<P, Q, U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.active()).finish()
+    }
+}
+
+impl<P, Q, U> Deref for RequestQueue<P, Q, U> {
+    type Target = Vec<Request<P, Q, U>>;
 
     fn deref(&self) -> &Self::Target {
         &self.vec
     }
 }
 
-impl DerefMut for RequestQueue {
+impl<P, Q, U> DerefMut for RequestQueue<P, Q, U> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.vec
     }
 }
 
-impl OrderBook {
+// `i` points somewhere inside the price level of `request`; the request is queued at the
+// end of the level, but displayed requests go in front of the hidden ones
+fn level_insertion_index<P: Price, Q: Quantity, U: UserId>(
+    queue: &[Request<P, Q, U>],
+    i: usize,
+    request: &Request<P, Q, U>,
+) -> usize {
+    let mut index = i + 1;
+    while index < queue.len() && queue[index].price == request.price {
+        index += 1;
+    }
+    if !request.hidden {
+        while index > 0 && queue[index - 1].hidden && queue[index - 1].price == request.price {
+            index -= 1;
+        }
+    }
+    index
+}
+
+impl<P: Price, Q: Quantity, U: UserId> OrderBook<P, Q, U> {
+    pub fn new() -> Self {
+        OrderBook {
+            buyers: RequestQueue::default(),
+            sellers: RequestQueue::default(),
+            peg_reference: Bbo::default(),
+            pegged: 0,
+            links: Links::default(),
+        }
+    }
+
     pub fn flush_request_queues(&mut self) {
         self.sellers.flush_vec();
         self.buyers.flush_vec();
     }
 
-    fn insert_limit_request(&mut self, request: Request) {
+    /// Displayed buy requests from the highest price, in the order they are matched;
+    /// hidden requests are left out.
+    pub fn buyers(&self) -> impl Iterator<Item = &Request<P, Q, U>> {
+        self.buyers
+            .active()
+            .iter()
+            .filter(|request| !request.hidden)
+    }
+
+    /// Displayed sell requests from the lowest price, in the order they are matched;
+    /// hidden requests are left out.
+    pub fn sellers(&self) -> impl Iterator<Item = &Request<P, Q, U>> {
+        self.sellers
+            .active()
+            .iter()
+            .filter(|request| !request.hidden)
+    }
+
+    pub(crate) fn insert_limit_request(&mut self, request: Request<P, Q, U>) {
         self.flush_request_queues();
+        if request.peg.is_some() {
+            self.pegged += 1;
+        }
         match request.side {
             Side::Buy => {
                 // the order for buyers is from the highest to the lowest
@@ -97,11 +260,7 @@ impl OrderBook {
                 match search_result {
                     Err(i) => self.buyers.insert(i, request),
                     Ok(i) => {
-                        let mut index = i + 1;
-                        while index < self.buyers.len() && self.buyers[index].price == request.price
-                        {
-                            index += 1;
-                        }
+                        let index = level_insertion_index(&self.buyers, i, &request);
                         self.buyers.insert(index, request);
                     }
                 }
@@ -114,12 +273,7 @@ impl OrderBook {
                 match search_result {
                     Err(i) => self.sellers.insert(i, request),
                     Ok(i) => {
-                        let mut index = i + 1;
-                        while index < self.sellers.len()
-                            && self.sellers[index].price == request.price
-                        {
-                            index += 1;
-                        }
+                        let index = level_insertion_index(&self.sellers, i, &request);
                         self.sellers.insert(index, request);
                     }
                 }
@@ -127,7 +281,24 @@ impl OrderBook {
         }
     }
 
-    pub fn match_request_quiet<'a>(&mut self, request: &'a Request) {
+    pub fn match_request_quiet(&mut self, request: &Request<P, Q, U>) {
+        // linked groups need to know about every fill
+        if !self.links.is_empty() {
+            self.match_request(request);
+            return;
+        }
+        match request.peg {
+            None => self.execute_request_quiet(request),
+            Some(peg) => {
+                if let Some(price) = peg.price(request.side, self.reference_bbo()) {
+                    self.execute_request_quiet(&Request { price, ..*request });
+                }
+            }
+        }
+        self.reprice_pegged_requests();
+    }
+
+    fn execute_request_quiet(&mut self, request: &Request<P, Q, U>) {
         let mut left = request.size;
         let mut ranges = Vec::with_capacity(10);
         let opposite_vec = match request.side {
@@ -137,8 +308,8 @@ impl OrderBook {
 
         let mut previous_left_border = 0;
         let mut current_index = opposite_vec.start_from;
-        while left > 0 {
-            if let Some(mut passive_request) = opposite_vec.get_mut(current_index) {
+        while left > Q::ZERO {
+            if let Some(passive_request) = opposite_vec.get_mut(current_index) {
                 if passive_request.user_id == request.user_id {
                     if previous_left_border != current_index {
                         ranges.push(previous_left_border..current_index);
@@ -184,7 +355,7 @@ impl OrderBook {
         }
 
         let is_fk = request.request_type == Type::FillOrKill;
-        if left == 0 || !is_fk {
+        if left == Q::ZERO || !is_fk {
             for range in ranges.into_iter().rev() {
                 if range.contains(&0) {
                     opposite_vec.start_from = range.end
@@ -195,19 +366,101 @@ impl OrderBook {
         }
 
         // if there are leftovers from incoming request, save them to the book
-        if left > 0 && request.request_type == Type::Limit {
+        if left > Q::ZERO && matches!(request.request_type, Type::Limit | Type::Quote) {
             let leftover_request = Request {
-                request_type: request.request_type,
-                side: request.side,
                 size: left,
-                price: request.price,
-                user_id: request.user_id,
+                ..*request
             };
             self.insert_limit_request(leftover_request);
         }
     }
 
-    pub fn match_request<'a>(&mut self, request: &'a Request) -> MatchingResult {
+    pub fn match_request(&mut self, request: &Request<P, Q, U>) -> MatchingResult<P, Q, U> {
+        let mut result = self.enter_request(request);
+        if !self.links.is_empty() {
+            self.process_links(&mut result);
+        }
+        let repriced = self.reprice_pegged_requests();
+        result.book_events.extend(repriced);
+        result
+    }
+
+    /// Like `match_request`, but refuses a request which could take the resting size
+    /// of its side beyond `Q::MAX`, so that no aggregate of the book has to saturate.
+    pub fn try_match_request(
+        &mut self,
+        request: &Request<P, Q, U>,
+    ) -> Result<MatchingResult<P, Q, U>, Overflow> {
+        self.check_request(request)?;
+        Ok(self.match_request(request))
+    }
+
+    /// Cancels the resting request with the given id.
+    pub fn cancel_request(&mut self, id: u64) -> Vec<BookEvent<P, Q, U>> {
+        let mut events = Vec::new();
+        if let Some(request) = self.remove_request(id) {
+            let exits = self.release_bracket(id);
+            self.links.forget(id);
+            events.push(BookEvent::Cancelled { request });
+            events.extend(exits);
+            events.extend(self.reprice_pegged_requests());
+        }
+        events
+    }
+
+    /// Cancels every resting request of the user that passes the filter, including
+    /// stop-losses of brackets which are waiting for their trigger.
+    pub fn cancel_user_requests(
+        &mut self,
+        user_id: U,
+        filter: &CancelFilter<P>,
+    ) -> Vec<BookEvent<P, Q, U>> {
+        self.flush_request_queues();
+        let matches =
+            |request: &Request<P, Q, U>| request.user_id == user_id && filter.matches(request);
+        let mut cancelled = Vec::new();
+        for queue in [&mut self.buyers, &mut self.sellers].iter_mut() {
+            if queue.iter().any(matches) {
+                let (removed, kept) = queue.vec.drain(..).partition(matches);
+                queue.vec = kept;
+                cancelled.extend::<Vec<_>>(removed);
+            }
+        }
+        cancelled.extend(self.links.disarm_stops(matches));
+        let mut events = Vec::with_capacity(cancelled.len());
+        for request in cancelled {
+            self.links.forget(request.id);
+            events.push(BookEvent::Cancelled { request });
+        }
+        events.extend(self.reprice_pegged_requests());
+        events
+    }
+
+    pub(crate) fn remove_request(&mut self, id: u64) -> Option<Request<P, Q, U>> {
+        self.flush_request_queues();
+        if let Some(index) = self.buyers.iter().position(|request| request.id == id) {
+            return Some(self.buyers.remove(index));
+        }
+        let index = self.sellers.iter().position(|request| request.id == id)?;
+        Some(self.sellers.remove(index))
+    }
+
+    // matching of a single request, without any of the follow-ups
+    pub(crate) fn enter_request(&mut self, request: &Request<P, Q, U>) -> MatchingResult<P, Q, U> {
+        match request.peg {
+            None => self.execute_request(request),
+            Some(peg) => match peg.price(request.side, self.reference_bbo()) {
+                Some(price) => self.execute_request(&Request { price, ..*request }),
+                // there is nothing to peg to
+                None => MatchingResult {
+                    request_actions: vec![RequestAction::Cancelled],
+                    ..Default::default()
+                },
+            },
+        }
+    }
+
+    fn execute_request(&mut self, request: &Request<P, Q, U>) -> MatchingResult<P, Q, U> {
         let mut left = request.size;
         let mut market_actions = Vec::new();
         let mut request_actions = Vec::with_capacity(20);
@@ -219,9 +472,8 @@ impl OrderBook {
 
         let mut previous_left_border = 0;
         let mut current_index = opposite_vec.start_from;
-        while left > 0 {
-            // println!("left border {}, curr index {}", previous_left_border, current_index);
-            if let Some(mut passive_request) = opposite_vec.get_mut(current_index) {
+        while left > Q::ZERO {
+            if let Some(passive_request) = opposite_vec.get_mut(current_index) {
                 if passive_request.user_id == request.user_id {
                     if previous_left_border != current_index {
                         ranges.push(previous_left_border..current_index);
@@ -242,6 +494,10 @@ impl OrderBook {
                             price: passive_request.price,
                             seller_user_id: request.user_id,
                             buyer_user_id: passive_request.user_id,
+                            seller_request_id: request.id,
+                            buyer_request_id: passive_request.id,
+                            aggressor_side: Side::Sell,
+                            ..Default::default()
                         }
                     }
                     Side::Buy => {
@@ -254,6 +510,10 @@ impl OrderBook {
                             price: passive_request.price,
                             seller_user_id: passive_request.user_id,
                             buyer_user_id: request.user_id,
+                            seller_request_id: passive_request.id,
+                            buyer_request_id: request.id,
+                            aggressor_side: Side::Buy,
+                            ..Default::default()
                         }
                     }
                 };
@@ -275,14 +535,12 @@ impl OrderBook {
                 break;
             }
         }
-        // println!("After left border {}, curr index {}", previous_left_border, current_index);
         if previous_left_border != current_index {
             ranges.push(previous_left_border..current_index);
         }
-        // println!("{:?}", ranges);
 
         let is_fk = request.request_type == Type::FillOrKill;
-        if left == 0 || !is_fk {
+        if left == Q::ZERO || !is_fk {
             for range in ranges.into_iter().rev() {
                 if range.contains(&0) {
                     opposite_vec.start_from = range.end
@@ -293,16 +551,13 @@ impl OrderBook {
         }
 
         // building result
-        if left > 0 {
+        if left > Q::ZERO {
             // if there are leftovers from incoming request, save them to the book
             match request.request_type {
-                Type::Limit => {
+                Type::Limit | Type::Quote => {
                     let leftover_request = Request {
-                        request_type: request.request_type,
-                        side: request.side,
                         size: left,
-                        price: request.price,
-                        user_id: request.user_id,
+                        ..*request
                     };
                     self.insert_limit_request(leftover_request);
                     if left != request.size {
@@ -324,6 +579,23 @@ impl OrderBook {
         MatchingResult {
             market_actions,
             request_actions,
+            book_events: Vec::new(),
         }
     }
 }
+
+impl<P, Q, U: Copy> MarketAction<P, Q, U> {
+    /// User whose request was resting in the book.
+    pub fn passive_user_id(&self) -> U {
+        match self.aggressor_side {
+            Side::Buy => self.seller_user_id,
+            Side::Sell => self.buyer_user_id,
+        }
+    }
+}
+
+impl<P: Price, Q: Quantity, U: UserId> Matcher<P, Q, U> for OrderBook<P, Q, U> {
+    fn match_request(&mut self, request: &Request<P, Q, U>) -> MatchingResult<P, Q, U> {
+        OrderBook::match_request(self, request)
+    }
+}
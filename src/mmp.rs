@@ -0,0 +1,44 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Market-maker protection: once more than `volume_limit` pieces of the user's
+/// resting requests are executed within `window` milliseconds, the user's quotes
+/// in that book are cancelled and new ones are refused until the protection is reset.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MmpConfig {
+    pub window: u64,
+    pub volume_limit: u64,
+}
+
+// Passive executions of one user in one book within the protection window.
+#[derive(Default, Debug, Clone)]
+pub(crate) struct MmpTracker {
+    executions: VecDeque<(u64, u64)>,
+    volume: u64,
+    pub(crate) triggered: bool,
+}
+
+impl MmpTracker {
+    /// Records a passive execution at `timestamp` and tells whether the protection has just
+    /// been triggered by it.
+    pub(crate) fn record(&mut self, config: &MmpConfig, timestamp: u64, size: u64) -> bool {
+        if self.triggered {
+            return false;
+        }
+        while let Some(&(executed_at, executed_size)) = self.executions.front() {
+            if executed_at + config.window > timestamp {
+                break;
+            }
+            self.executions.pop_front();
+            self.volume = self.volume.saturating_sub(executed_size);
+        }
+        self.executions.push_back((timestamp, size));
+        self.volume = self.volume.saturating_add(size);
+        if self.volume > config.volume_limit {
+            self.executions.clear();
+            self.volume = 0;
+            self.triggered = true;
+        }
+        self.triggered
+    }
+}
@@ -0,0 +1,1055 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+
+use crate::exchange::*;
+use crate::matcher::*;
+
+/// Length of the longest message, a buffer of it fits any message.
+pub const OUCH_MAX_LEN: usize = 53;
+
+/// Symbols of the binary protocols are left-aligned ASCII padded with spaces.
+pub type WireSymbol = [u8; 8];
+
+/// Bytes which cannot be read as a message of a binary protocol; the stream cannot be
+/// trusted after them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireError {
+    UnknownType(u8),
+    InvalidField(&'static str),
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WireError::UnknownType(message_type) => {
+                write!(f, "unknown message type {:?}", *message_type as char)
+            }
+            WireError::InvalidField(field) => write!(f, "invalid {}", field),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+/// Enters a limit request; the token is not 0 and is not used by another resting request
+/// of the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnterOrder {
+    pub token: u64,
+    pub side: Side,
+    pub size: u64,
+    pub symbol: WireSymbol,
+    pub price: u64,
+    /// `Limit`, `ImmediateOrCancel` or `FillOrKill`.
+    pub time_in_force: Type,
+    pub hidden: bool,
+}
+
+/// Replaces a resting request with a new one on the same side, which loses time priority;
+/// `size` is what should be left in the book.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplaceOrder {
+    pub existing_token: u64,
+    pub replacement_token: u64,
+    pub size: u64,
+    pub price: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CancelOrder {
+    pub token: u64,
+}
+
+/// Messages of the clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OuchInbound {
+    Enter(EnterOrder),
+    Replace(ReplaceOrder),
+    Cancel(CancelOrder),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Liquidity {
+    Added,
+    Removed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelReason {
+    /// Asked for by the user.
+    User,
+    /// What was left of an immediate-or-cancel or fill-or-kill request.
+    Immediate,
+    /// Cancelled by the venue, e.g. by a linked request or market-maker protection.
+    System,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    UnknownSymbol,
+    /// The token is used by a resting request of the user.
+    DuplicateToken,
+    /// The token is 0.
+    InvalidToken,
+    /// There is no resting request of the user with the token.
+    UnknownToken,
+    Blocked,
+    Risk,
+    Funds,
+    Other,
+}
+
+/// Messages to the clients; `timestamp` is the time of the exchange, in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OuchOutbound {
+    Accepted {
+        timestamp: u64,
+        order: EnterOrder,
+        /// Whether anything of the request is left in the book.
+        resting: bool,
+    },
+    Replaced {
+        timestamp: u64,
+        previous_token: u64,
+        order: EnterOrder,
+        resting: bool,
+    },
+    Executed {
+        timestamp: u64,
+        token: u64,
+        size: u64,
+        price: u64,
+        liquidity: Liquidity,
+        match_number: u64,
+    },
+    Cancelled {
+        timestamp: u64,
+        token: u64,
+        /// Size which was taken out of the book.
+        size: u64,
+        reason: CancelReason,
+    },
+    Rejected {
+        timestamp: u64,
+        token: u64,
+        reason: RejectReason,
+    },
+}
+
+impl EnterOrder {
+    pub fn request(&self, user_id: u64) -> Request {
+        Request {
+            id: self.token,
+            side: self.side,
+            price: self.price,
+            size: self.size,
+            user_id,
+            request_type: self.time_in_force,
+            hidden: self.hidden,
+            ..Default::default()
+        }
+    }
+}
+
+/// Symbol as sent on the wire, `None` if it is longer than 8 bytes.
+pub fn wire_symbol(symbol: &str) -> Option<WireSymbol> {
+    let mut padded = [b' '; 8];
+    padded
+        .get_mut(..symbol.len())?
+        .copy_from_slice(symbol.as_bytes());
+    Some(padded)
+}
+
+/// Symbol without padding, `None` if it is not UTF-8.
+pub fn symbol_str(symbol: &WireSymbol) -> Option<&str> {
+    std::str::from_utf8(symbol)
+        .ok()
+        .map(|s| s.trim_end_matches(' '))
+}
+
+// Fields of the binary protocols are read from and written to the buffers of the callers,
+// big-endian, without allocating.
+pub(crate) struct Reader<'a> {
+    pub(crate) bytes: &'a [u8],
+    pub(crate) at: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn u8(&mut self) -> u8 {
+        self.at += 1;
+        self.bytes[self.at - 1]
+    }
+
+    pub(crate) fn u64(&mut self) -> u64 {
+        self.at += 8;
+        u64::from_be_bytes(self.bytes[self.at - 8..self.at].try_into().unwrap())
+    }
+
+    pub(crate) fn symbol(&mut self) -> WireSymbol {
+        self.at += 8;
+        self.bytes[self.at - 8..self.at].try_into().unwrap()
+    }
+
+    pub(crate) fn side(&mut self) -> Result<Side, WireError> {
+        match self.u8() {
+            b'B' => Ok(Side::Buy),
+            b'S' => Ok(Side::Sell),
+            _ => Err(WireError::InvalidField("side")),
+        }
+    }
+
+    fn flag(&mut self, name: &'static str) -> Result<bool, WireError> {
+        match self.u8() {
+            b'Y' => Ok(true),
+            b'N' => Ok(false),
+            _ => Err(WireError::InvalidField(name)),
+        }
+    }
+
+    fn order(&mut self) -> Result<EnterOrder, WireError> {
+        let token = self.u64();
+        let side = self.side()?;
+        let size = self.u64();
+        let symbol = self.symbol();
+        let price = self.u64();
+        let time_in_force = match self.u8() {
+            b'D' => Type::Limit,
+            b'I' => Type::ImmediateOrCancel,
+            b'F' => Type::FillOrKill,
+            _ => return Err(WireError::InvalidField("time in force")),
+        };
+        let hidden = !self.flag("display")?;
+        Ok(EnterOrder {
+            token,
+            side,
+            size,
+            symbol,
+            price,
+            time_in_force,
+            hidden,
+        })
+    }
+}
+
+pub(crate) struct Writer<'a> {
+    pub(crate) bytes: &'a mut [u8],
+    pub(crate) at: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub(crate) fn u8(&mut self, value: u8) -> &mut Self {
+        self.bytes[self.at] = value;
+        self.at += 1;
+        self
+    }
+
+    pub(crate) fn u64(&mut self, value: u64) -> &mut Self {
+        self.bytes[self.at..self.at + 8].copy_from_slice(&value.to_be_bytes());
+        self.at += 8;
+        self
+    }
+
+    fn flag(&mut self, value: bool) -> &mut Self {
+        self.u8(if value { b'Y' } else { b'N' })
+    }
+
+    fn order(&mut self, order: &EnterOrder) -> &mut Self {
+        let time_in_force = match order.time_in_force {
+            Type::ImmediateOrCancel => b'I',
+            Type::FillOrKill => b'F',
+            Type::Limit | Type::Quote => b'D',
+        };
+        self.u64(order.token)
+            .u8(side_code(order.side))
+            .u64(order.size)
+            .symbol(&order.symbol)
+            .u64(order.price)
+            .u8(time_in_force)
+            .flag(!order.hidden)
+    }
+
+    pub(crate) fn symbol(&mut self, symbol: &WireSymbol) -> &mut Self {
+        self.bytes[self.at..self.at + 8].copy_from_slice(symbol);
+        self.at += 8;
+        self
+    }
+}
+
+pub(crate) fn side_code(side: Side) -> u8 {
+    match side {
+        Side::Buy => b'B',
+        Side::Sell => b'S',
+    }
+}
+
+// Checks that the whole message of the type is in the buffer.
+pub(crate) fn message_len(
+    buffer: &[u8],
+    lengths: &[(u8, usize)],
+) -> Result<Option<usize>, WireError> {
+    let message_type = match buffer.first() {
+        Some(message_type) => *message_type,
+        None => return Ok(None),
+    };
+    let (_, len) = lengths
+        .iter()
+        .find(|(known, _)| *known == message_type)
+        .ok_or(WireError::UnknownType(message_type))?;
+    Ok(if buffer.len() < *len {
+        None
+    } else {
+        Some(*len)
+    })
+}
+
+/// A message of a client left in the buffer of the caller, its fields are read from
+/// the buffer when asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OuchInboundView<'a> {
+    Enter(EnterOrderView<'a>),
+    Replace(ReplaceOrderView<'a>),
+    Cancel(CancelOrderView<'a>),
+}
+
+/// An enter message in the buffer of the caller, its side, time in force and display
+/// are checked when it is read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnterOrderView<'a> {
+    bytes: &'a [u8],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplaceOrderView<'a> {
+    bytes: &'a [u8],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CancelOrderView<'a> {
+    bytes: &'a [u8],
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+impl<'a> OuchInboundView<'a> {
+    /// Reads the message at the start of the buffer, returning it together with its length,
+    /// or `None` if the buffer does not hold all of it yet.
+    pub fn decode(buffer: &'a [u8]) -> Result<Option<(OuchInboundView<'a>, usize)>, WireError> {
+        let len = match message_len(buffer, &[(b'O', 36), (b'U', 33), (b'X', 9)])? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        let bytes = &buffer[..len];
+        let view = match bytes[0] {
+            b'O' => {
+                if !matches!(bytes[9], b'B' | b'S') {
+                    return Err(WireError::InvalidField("side"));
+                }
+                if !matches!(bytes[34], b'D' | b'I' | b'F') {
+                    return Err(WireError::InvalidField("time in force"));
+                }
+                if !matches!(bytes[35], b'Y' | b'N') {
+                    return Err(WireError::InvalidField("display"));
+                }
+                OuchInboundView::Enter(EnterOrderView { bytes })
+            }
+            b'U' => OuchInboundView::Replace(ReplaceOrderView { bytes }),
+            _ => OuchInboundView::Cancel(CancelOrderView { bytes }),
+        };
+        Ok(Some((view, len)))
+    }
+
+    pub fn to_message(&self) -> OuchInbound {
+        match self {
+            OuchInboundView::Enter(order) => OuchInbound::Enter(order.to_order()),
+            OuchInboundView::Replace(replace) => OuchInbound::Replace(ReplaceOrder {
+                existing_token: replace.existing_token(),
+                replacement_token: replace.replacement_token(),
+                size: replace.size(),
+                price: replace.price(),
+            }),
+            OuchInboundView::Cancel(cancel) => OuchInbound::Cancel(CancelOrder {
+                token: cancel.token(),
+            }),
+        }
+    }
+}
+
+impl<'a> EnterOrderView<'a> {
+    pub fn token(&self) -> u64 {
+        u64_at(self.bytes, 1)
+    }
+
+    pub fn side(&self) -> Side {
+        match self.bytes[9] {
+            b'B' => Side::Buy,
+            _ => Side::Sell,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        u64_at(self.bytes, 10)
+    }
+
+    pub fn symbol(&self) -> &'a WireSymbol {
+        self.bytes[18..26].try_into().unwrap()
+    }
+
+    pub fn price(&self) -> u64 {
+        u64_at(self.bytes, 26)
+    }
+
+    pub fn time_in_force(&self) -> Type {
+        match self.bytes[34] {
+            b'D' => Type::Limit,
+            b'I' => Type::ImmediateOrCancel,
+            _ => Type::FillOrKill,
+        }
+    }
+
+    pub fn hidden(&self) -> bool {
+        self.bytes[35] == b'N'
+    }
+
+    /// The request read straight from the message, like `EnterOrder::request`.
+    pub fn request(&self, user_id: u64) -> Request {
+        Request {
+            id: self.token(),
+            side: self.side(),
+            price: self.price(),
+            size: self.size(),
+            user_id,
+            request_type: self.time_in_force(),
+            hidden: self.hidden(),
+            ..Default::default()
+        }
+    }
+
+    pub fn to_order(&self) -> EnterOrder {
+        EnterOrder {
+            token: self.token(),
+            side: self.side(),
+            size: self.size(),
+            symbol: *self.symbol(),
+            price: self.price(),
+            time_in_force: self.time_in_force(),
+            hidden: self.hidden(),
+        }
+    }
+}
+
+impl<'a> ReplaceOrderView<'a> {
+    pub fn existing_token(&self) -> u64 {
+        u64_at(self.bytes, 1)
+    }
+
+    pub fn replacement_token(&self) -> u64 {
+        u64_at(self.bytes, 9)
+    }
+
+    pub fn size(&self) -> u64 {
+        u64_at(self.bytes, 17)
+    }
+
+    pub fn price(&self) -> u64 {
+        u64_at(self.bytes, 25)
+    }
+}
+
+impl<'a> CancelOrderView<'a> {
+    pub fn token(&self) -> u64 {
+        u64_at(self.bytes, 1)
+    }
+}
+
+impl OuchInbound {
+    /// Reads the message at the start of the buffer into a copy of it, see `OuchInboundView`
+    /// for reading it in place.
+    pub fn decode(buffer: &[u8]) -> Result<Option<(OuchInbound, usize)>, WireError> {
+        let decoded = OuchInboundView::decode(buffer)?;
+        Ok(decoded.map(|(view, len)| (view.to_message(), len)))
+    }
+
+    /// Writes the message to the start of the buffer and returns its length;
+    /// panics if the buffer is shorter than `OUCH_MAX_LEN`.
+    pub fn encode(&self, buffer: &mut [u8]) -> usize {
+        let mut writer = Writer {
+            bytes: buffer,
+            at: 0,
+        };
+        match self {
+            OuchInbound::Enter(order) => writer.u8(b'O').order(order),
+            OuchInbound::Replace(replace) => writer
+                .u8(b'U')
+                .u64(replace.existing_token)
+                .u64(replace.replacement_token)
+                .u64(replace.size)
+                .u64(replace.price),
+            OuchInbound::Cancel(cancel) => writer.u8(b'X').u64(cancel.token),
+        };
+        writer.at
+    }
+}
+
+impl OuchOutbound {
+    /// Reads the message at the start of the buffer, returning it together with its length,
+    /// or `None` if the buffer does not hold all of it yet.
+    pub fn decode(buffer: &[u8]) -> Result<Option<(OuchOutbound, usize)>, WireError> {
+        let lengths = [(b'A', 45), (b'U', 53), (b'E', 42), (b'C', 26), (b'J', 18)];
+        let len = match message_len(buffer, &lengths)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        let mut reader = Reader {
+            bytes: buffer,
+            at: 1,
+        };
+        let timestamp = reader.u64();
+        let message = match buffer[0] {
+            b'A' => OuchOutbound::Accepted {
+                timestamp,
+                order: reader.order()?,
+                resting: reader.flag("resting")?,
+            },
+            b'U' => OuchOutbound::Replaced {
+                timestamp,
+                previous_token: reader.u64(),
+                order: reader.order()?,
+                resting: reader.flag("resting")?,
+            },
+            b'E' => OuchOutbound::Executed {
+                timestamp,
+                token: reader.u64(),
+                size: reader.u64(),
+                price: reader.u64(),
+                liquidity: match reader.u8() {
+                    b'A' => Liquidity::Added,
+                    b'R' => Liquidity::Removed,
+                    _ => return Err(WireError::InvalidField("liquidity")),
+                },
+                match_number: reader.u64(),
+            },
+            b'C' => OuchOutbound::Cancelled {
+                timestamp,
+                token: reader.u64(),
+                size: reader.u64(),
+                reason: match reader.u8() {
+                    b'U' => CancelReason::User,
+                    b'I' => CancelReason::Immediate,
+                    b'S' => CancelReason::System,
+                    _ => return Err(WireError::InvalidField("cancel reason")),
+                },
+            },
+            _ => OuchOutbound::Rejected {
+                timestamp,
+                token: reader.u64(),
+                reason: match reader.u8() {
+                    b'S' => RejectReason::UnknownSymbol,
+                    b'D' => RejectReason::DuplicateToken,
+                    b'T' => RejectReason::UnknownToken,
+                    b'I' => RejectReason::InvalidToken,
+                    b'B' => RejectReason::Blocked,
+                    b'R' => RejectReason::Risk,
+                    b'F' => RejectReason::Funds,
+                    b'O' => RejectReason::Other,
+                    _ => return Err(WireError::InvalidField("reject reason")),
+                },
+            },
+        };
+        Ok(Some((message, len)))
+    }
+
+    /// Writes the message to the start of the buffer and returns its length;
+    /// panics if the buffer is shorter than `OUCH_MAX_LEN`.
+    pub fn encode(&self, buffer: &mut [u8]) -> usize {
+        let mut writer = Writer {
+            bytes: buffer,
+            at: 0,
+        };
+        match *self {
+            OuchOutbound::Accepted {
+                timestamp,
+                ref order,
+                resting,
+            } => writer.u8(b'A').u64(timestamp).order(order).flag(resting),
+            OuchOutbound::Replaced {
+                timestamp,
+                previous_token,
+                ref order,
+                resting,
+            } => writer
+                .u8(b'U')
+                .u64(timestamp)
+                .u64(previous_token)
+                .order(order)
+                .flag(resting),
+            OuchOutbound::Executed {
+                timestamp,
+                token,
+                size,
+                price,
+                liquidity,
+                match_number,
+            } => {
+                let liquidity = match liquidity {
+                    Liquidity::Added => b'A',
+                    Liquidity::Removed => b'R',
+                };
+                writer
+                    .u8(b'E')
+                    .u64(timestamp)
+                    .u64(token)
+                    .u64(size)
+                    .u64(price)
+                    .u8(liquidity)
+                    .u64(match_number)
+            }
+            OuchOutbound::Cancelled {
+                timestamp,
+                token,
+                size,
+                reason,
+            } => {
+                let reason = match reason {
+                    CancelReason::User => b'U',
+                    CancelReason::Immediate => b'I',
+                    CancelReason::System => b'S',
+                };
+                writer
+                    .u8(b'C')
+                    .u64(timestamp)
+                    .u64(token)
+                    .u64(size)
+                    .u8(reason)
+            }
+            OuchOutbound::Rejected {
+                timestamp,
+                token,
+                reason,
+            } => {
+                let reason = match reason {
+                    RejectReason::UnknownSymbol => b'S',
+                    RejectReason::DuplicateToken => b'D',
+                    RejectReason::UnknownToken => b'T',
+                    RejectReason::InvalidToken => b'I',
+                    RejectReason::Blocked => b'B',
+                    RejectReason::Risk => b'R',
+                    RejectReason::Funds => b'F',
+                    RejectReason::Other => b'O',
+                };
+                writer.u8(b'J').u64(timestamp).u64(token).u8(reason)
+            }
+        };
+        writer.at
+    }
+}
+
+/// Responses of a result, addressed by user: the request is accepted, then both sides
+/// of every trade get an execution, then everything taken out of the book is cancelled.
+/// Match numbers are counted from `match_number`, which is left at the next free one.
+pub fn ouch_responses(
+    timestamp: u64,
+    order: &EnterOrder,
+    user_id: u64,
+    result: &MatchingResult,
+    match_number: &mut u64,
+) -> Vec<(u64, OuchOutbound)> {
+    let resting = result.request_actions.contains(&RequestAction::AddedToBook);
+    let mut responses = vec![(
+        user_id,
+        OuchOutbound::Accepted {
+            timestamp,
+            order: *order,
+            resting,
+        },
+    )];
+    responses.extend(executions(timestamp, result, match_number));
+    if result.request_actions.contains(&RequestAction::Cancelled) {
+        let filled: u64 = result.market_actions.iter().map(|a| a.size).sum();
+        let cancelled = OuchOutbound::Cancelled {
+            timestamp,
+            token: order.token,
+            size: order.size.saturating_sub(filled),
+            reason: CancelReason::Immediate,
+        };
+        responses.push((user_id, cancelled));
+    }
+    responses.extend(cancellations(timestamp, &result.book_events, None));
+    responses
+}
+
+fn executions<'a>(
+    timestamp: u64,
+    result: &'a MatchingResult,
+    match_number: &'a mut u64,
+) -> impl Iterator<Item = (u64, OuchOutbound)> + 'a {
+    result.market_actions.iter().flat_map(move |action| {
+        *match_number += 1;
+        let sides = [
+            (action.buyer_user_id, action.buyer_request_id, Side::Buy),
+            (action.seller_user_id, action.seller_request_id, Side::Sell),
+        ];
+        let number = *match_number;
+        sides.map(|(user_id, token, side)| {
+            let liquidity = if side == action.aggressor_side {
+                Liquidity::Removed
+            } else {
+                Liquidity::Added
+            };
+            let executed = OuchOutbound::Executed {
+                timestamp,
+                token,
+                size: action.size,
+                price: action.price,
+                liquidity,
+                match_number: number,
+            };
+            (user_id, executed)
+        })
+    })
+}
+
+// Cancellations of the events, `user` is the token cancelled on request of its owner.
+fn cancellations(
+    timestamp: u64,
+    events: &[BookEvent],
+    user: Option<u64>,
+) -> impl Iterator<Item = (u64, OuchOutbound)> + '_ {
+    events.iter().filter_map(move |event| match event {
+        BookEvent::Cancelled { request } => {
+            let reason = if Some(request.id) == user {
+                CancelReason::User
+            } else {
+                CancelReason::System
+            };
+            let cancelled = OuchOutbound::Cancelled {
+                timestamp,
+                token: request.id,
+                size: request.size,
+                reason,
+            };
+            Some((request.user_id, cancelled))
+        }
+        _ => None,
+    })
+}
+
+fn reject_reason(reject: &Reject) -> RejectReason {
+    match reject {
+        Reject::UnknownBook => RejectReason::UnknownSymbol,
+        Reject::UserBlocked | Reject::MmpTriggered => RejectReason::Blocked,
+        Reject::Risk(_) => RejectReason::Risk,
+        Reject::InsufficientFunds => RejectReason::Funds,
+        Reject::DuplicateId => RejectReason::DuplicateToken,
+        _ => RejectReason::Other,
+    }
+}
+
+/// Runs the messages of the clients against the exchange. Every user has tokens of its own:
+/// the gateway enters requests under ids of its own and keeps the tokens of them while
+/// they rest, a token is free again once its request has left the book.
+#[derive(Debug, Default)]
+pub struct OuchGateway {
+    exchange: Exchange,
+    // books and request ids of the tokens, by user and token
+    orders: HashMap<(u64, u64), (String, u64)>,
+    // users and tokens of the requests entered by the gateway, by request id
+    tokens: HashMap<u64, (u64, u64)>,
+    last_id: u64,
+    next_match_number: u64,
+}
+
+impl OuchGateway {
+    pub fn new(exchange: Exchange) -> OuchGateway {
+        OuchGateway {
+            exchange,
+            ..Default::default()
+        }
+    }
+
+    pub fn exchange(&self) -> &Exchange {
+        &self.exchange
+    }
+
+    pub fn exchange_mut(&mut self) -> &mut Exchange {
+        &mut self.exchange
+    }
+
+    /// Handles a message of the user, returning the responses with the users they are for.
+    pub fn handle(
+        &mut self,
+        user_id: u64,
+        message: &OuchInbound,
+        timestamp: u64,
+    ) -> Vec<(u64, OuchOutbound)> {
+        self.exchange.set_time(timestamp);
+        match message {
+            OuchInbound::Enter(order) => self.enter(user_id, order, timestamp),
+            OuchInbound::Replace(replace) => self.replace(user_id, replace, timestamp),
+            OuchInbound::Cancel(cancel) => self.cancel(user_id, cancel, timestamp),
+        }
+    }
+
+    fn enter(
+        &mut self,
+        user_id: u64,
+        order: &EnterOrder,
+        timestamp: u64,
+    ) -> Vec<(u64, OuchOutbound)> {
+        let rejected = |reason| {
+            let rejected = OuchOutbound::Rejected {
+                timestamp,
+                token: order.token,
+                reason,
+            };
+            vec![(user_id, rejected)]
+        };
+        let symbol = match symbol_str(&order.symbol) {
+            Some(symbol) => symbol,
+            None => return rejected(RejectReason::UnknownSymbol),
+        };
+        if self.exchange.book(symbol).is_none() {
+            return rejected(RejectReason::UnknownSymbol);
+        }
+        if let Err(reason) = self.check_token(user_id, order.token) {
+            return rejected(reason);
+        }
+        let entered = EnterOrder {
+            token: self.next_id(symbol),
+            ..*order
+        };
+        match self.exchange.submit(symbol, &entered.request(user_id)) {
+            Ok(result) => {
+                self.track(user_id, order.token, symbol, entered.token);
+                let responses = ouch_responses(
+                    timestamp,
+                    &entered,
+                    user_id,
+                    &result,
+                    &mut self.next_match_number,
+                );
+                self.with_tokens(responses)
+            }
+            Err(reject) => rejected(reject_reason(&reject)),
+        }
+    }
+
+    fn replace(
+        &mut self,
+        user_id: u64,
+        replace: &ReplaceOrder,
+        timestamp: u64,
+    ) -> Vec<(u64, OuchOutbound)> {
+        let rejected = |reason| {
+            let rejected = OuchOutbound::Rejected {
+                timestamp,
+                token: replace.replacement_token,
+                reason,
+            };
+            vec![(user_id, rejected)]
+        };
+        let (symbol, existing) = match self.find(user_id, replace.existing_token) {
+            Some(found) => found,
+            None => return rejected(RejectReason::UnknownToken),
+        };
+        if replace.replacement_token != replace.existing_token {
+            if let Err(reason) = self.check_token(user_id, replace.replacement_token) {
+                return rejected(reason);
+            }
+        }
+        let events = self
+            .exchange
+            .cancel_request(&symbol, existing.id)
+            .unwrap_or_default();
+        let order = EnterOrder {
+            token: self.next_id(&symbol),
+            size: replace.size,
+            price: replace.price,
+            symbol: wire_symbol(&symbol).unwrap(),
+            time_in_force: Type::Limit,
+            side: existing.side,
+            hidden: existing.hidden,
+        };
+        // the existing request is replaced rather than cancelled
+        let others: Vec<_> = events
+            .into_iter()
+            .filter(|event| !matches!(event, BookEvent::Cancelled { request } if request.id == existing.id))
+            .collect();
+        let mut responses: Vec<_> = cancellations(timestamp, &others, None).collect();
+        let reject = match self.exchange.submit(&symbol, &order.request(user_id)) {
+            Ok(result) => {
+                self.track(user_id, replace.replacement_token, &symbol, order.token);
+                let accepted = ouch_responses(
+                    timestamp,
+                    &order,
+                    user_id,
+                    &result,
+                    &mut self.next_match_number,
+                );
+                let resting = result.request_actions.contains(&RequestAction::AddedToBook);
+                let replaced = OuchOutbound::Replaced {
+                    timestamp,
+                    previous_token: existing.id,
+                    order,
+                    resting,
+                };
+                responses.push((user_id, replaced));
+                responses.extend(accepted.into_iter().skip(1));
+                None
+            }
+            Err(reject) => {
+                // the existing request has left the book already
+                let cancelled = OuchOutbound::Cancelled {
+                    timestamp,
+                    token: existing.id,
+                    size: existing.size,
+                    reason: CancelReason::System,
+                };
+                responses.push((user_id, cancelled));
+                Some(reject)
+            }
+        };
+        let mut responses = self.with_tokens(responses);
+        if let Some(reject) = reject {
+            responses.extend(rejected(reject_reason(&reject)));
+        }
+        responses
+    }
+
+    fn cancel(
+        &mut self,
+        user_id: u64,
+        cancel: &CancelOrder,
+        timestamp: u64,
+    ) -> Vec<(u64, OuchOutbound)> {
+        let (symbol, request) = match self.find(user_id, cancel.token) {
+            Some(found) => found,
+            None => {
+                let rejected = OuchOutbound::Rejected {
+                    timestamp,
+                    token: cancel.token,
+                    reason: RejectReason::UnknownToken,
+                };
+                return vec![(user_id, rejected)];
+            }
+        };
+        let events = self
+            .exchange
+            .cancel_request(&symbol, request.id)
+            .unwrap_or_default();
+        let responses = cancellations(timestamp, &events, Some(request.id)).collect();
+        self.with_tokens(responses)
+    }
+
+    // Whether the user may enter a request with the token.
+    fn check_token(&mut self, user_id: u64, token: u64) -> Result<(), RejectReason> {
+        if token == 0 {
+            return Err(RejectReason::InvalidToken);
+        }
+        if let Some(&(_, id)) = self.orders.get(&(user_id, token)) {
+            if self.resting(id) {
+                return Err(RejectReason::DuplicateToken);
+            }
+            // the request has left the book without the gateway, e.g. by a kill switch
+            self.forget(id);
+        }
+        Ok(())
+    }
+
+    // A request id which is not used in the book.
+    fn next_id(&mut self, symbol: &str) -> u64 {
+        loop {
+            self.last_id += 1;
+            let id = self.last_id;
+            let used = self
+                .exchange
+                .book(symbol)
+                .and_then(|book| book.resting_request(id))
+                .is_some();
+            if !used && !self.tokens.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    fn track(&mut self, user_id: u64, token: u64, symbol: &str, id: u64) {
+        self.orders
+            .insert((user_id, token), (symbol.to_string(), id));
+        self.tokens.insert(id, (user_id, token));
+    }
+
+    fn forget(&mut self, id: u64) {
+        if let Some(key) = self.tokens.remove(&id) {
+            if self
+                .orders
+                .get(&key)
+                .is_some_and(|(_, tracked)| *tracked == id)
+            {
+                self.orders.remove(&key);
+            }
+        }
+    }
+
+    // Whether the request entered by the gateway with the id still rests under its token.
+    fn resting(&self, id: u64) -> bool {
+        let key = match self.tokens.get(&id) {
+            Some(key) => key,
+            None => return false,
+        };
+        match self.orders.get(key) {
+            Some((symbol, tracked)) if *tracked == id => self
+                .exchange
+                .book(symbol)
+                .and_then(|book| book.resting_request(id))
+                .is_some(),
+            _ => false,
+        }
+    }
+
+    // Book and request of the user resting with the token.
+    fn find(&self, user_id: u64, token: u64) -> Option<(String, Request)> {
+        let (symbol, id) = self.orders.get(&(user_id, token))?;
+        let request = self.exchange.book(symbol)?.resting_request(*id)?;
+        Some((symbol.clone(), request.clone()))
+    }
+
+    // Puts the tokens of the users in place of the request ids of the gateway, and forgets
+    // the tokens of requests which have left the book.
+    fn with_tokens(&mut self, mut responses: Vec<(u64, OuchOutbound)>) -> Vec<(u64, OuchOutbound)> {
+        let mut ids = Vec::new();
+        for (user_id, message) in responses.iter_mut() {
+            let mut to_token = |id: &mut u64| {
+                if let Some(&(owner, token)) = self.tokens.get(id) {
+                    if owner == *user_id {
+                        ids.push(*id);
+                        *id = token;
+                    }
+                }
+            };
+            match message {
+                OuchOutbound::Accepted { order, .. } => to_token(&mut order.token),
+                OuchOutbound::Replaced {
+                    previous_token,
+                    order,
+                    ..
+                } => {
+                    to_token(previous_token);
+                    to_token(&mut order.token);
+                }
+                OuchOutbound::Executed { token, .. } | OuchOutbound::Cancelled { token, .. } => {
+                    to_token(token)
+                }
+                // rejections are made with the tokens of the users
+                OuchOutbound::Rejected { .. } => {}
+            }
+        }
+        for id in ids {
+            if !self.resting(id) {
+                self.forget(id);
+            }
+        }
+        responses
+    }
+}
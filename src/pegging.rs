@@ -0,0 +1,160 @@
+use serde::{Deserialize, Serialize};
+use std::cmp;
+
+use crate::matcher::*;
+use crate::price::*;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PegReference {
+    /// Same side of the touch: the best bid for buyers, the best ask for sellers.
+    Primary,
+    /// Opposite side of the touch: the best ask for buyers, the best bid for sellers.
+    Market,
+    /// The middle of the touch, rounded away from the opposite side.
+    Midpoint,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(bound(deserialize = "P: Deserialize<'de>"))]
+pub struct Peg<P = u64> {
+    pub reference: PegReference,
+    /// Added to the reference price.
+    #[serde(default)]
+    pub offset: i64,
+    /// The most aggressive price allowed: a cap for buyers and a floor for sellers.
+    #[serde(default)]
+    pub limit: Option<P>,
+}
+
+impl<P: Price> Peg<P> {
+    /// Price of a pegged request, `None` if the needed side of the touch is empty.
+    pub fn price(&self, side: Side, touch: Bbo<P>) -> Option<P> {
+        let reference = match (self.reference, side) {
+            (PegReference::Primary, Side::Buy) | (PegReference::Market, Side::Sell) => touch.bid?,
+            (PegReference::Primary, Side::Sell) | (PegReference::Market, Side::Buy) => touch.ask?,
+            (PegReference::Midpoint, _) => {
+                let (bid, ask) = (touch.bid?, touch.ask?);
+                let (low, high) = (cmp::min(bid, ask), cmp::max(bid, ask));
+                // buyers round down and sellers round up, so neither crosses the other
+                P::middle(low, high, side == Side::Sell)
+            }
+        };
+        let price = reference.offset(self.offset)?;
+        Some(match (side, self.limit) {
+            (Side::Buy, Some(limit)) => cmp::min(price, limit),
+            (Side::Sell, Some(limit)) => cmp::max(price, limit),
+            (_, None) => price,
+        })
+    }
+}
+
+impl<P: Price, Q: Quantity, U: UserId> OrderBook<P, Q, U> {
+    /// The touch formed by displayed non-pegged requests, which is what pegged requests follow.
+    pub fn reference_bbo(&self) -> Bbo<P> {
+        let best = |queue: &RequestQueue<P, Q, U>| {
+            queue
+                .active()
+                .iter()
+                .find(|request| request.peg.is_none() && !request.hidden)
+                .map(|request| request.price)
+        };
+        Bbo {
+            bid: best(&self.buyers),
+            ask: best(&self.sellers),
+        }
+    }
+
+    // Repricing rules:
+    // * nothing happens unless the reference touch has moved since the last call;
+    // * a pegged request which keeps its price keeps its place in the queue;
+    // * a repriced request goes to the back of its new price level, requests repriced
+    //   together keep their relative order;
+    // * repricing never makes a request marketable: buyers are repriced first and kept
+    //   strictly below the best ask, then sellers are kept strictly above the best bid.
+    pub(crate) fn reprice_pegged_requests(&mut self) -> Vec<BookEvent<P, Q, U>> {
+        if self.pegged == 0 {
+            return Vec::new();
+        }
+        let reference = self.reference_bbo();
+        if reference == self.peg_reference {
+            return Vec::new();
+        }
+        self.peg_reference = reference;
+        self.flush_request_queues();
+
+        let mut events = Vec::new();
+        let best_ask = self.sellers.first().map(|request| request.price);
+        let (repriced, pegged_buyers) =
+            take_repriced(&mut self.buyers, Side::Buy, reference, best_ask);
+        self.reinsert_repriced(repriced, &mut events);
+        let best_bid = self.buyers.first().map(|request| request.price);
+        let (repriced, pegged_sellers) =
+            take_repriced(&mut self.sellers, Side::Sell, reference, best_bid);
+        self.reinsert_repriced(repriced, &mut events);
+        self.pegged = pegged_buyers + pegged_sellers;
+        events
+    }
+
+    fn reinsert_repriced(
+        &mut self,
+        repriced: Repriced<P, Q, U>,
+        events: &mut Vec<BookEvent<P, Q, U>>,
+    ) {
+        for (request, old_price) in repriced {
+            events.push(BookEvent::Repriced {
+                id: request.id,
+                side: request.side,
+                user_id: request.user_id,
+                size: request.size,
+                old_price,
+                new_price: request.price,
+            });
+            self.insert_limit_request(request);
+        }
+    }
+}
+
+// Requests with their new prices set, alongside their old prices.
+type Repriced<P, Q, U> = Vec<(Request<P, Q, U>, P)>;
+
+// Removes pegged requests whose price has to change from the queue and returns them,
+// and the number of pegged requests
+// of the queue, the removed ones included.
+fn take_repriced<P: Price, Q: Quantity, U: UserId>(
+    queue: &mut RequestQueue<P, Q, U>,
+    side: Side,
+    reference: Bbo<P>,
+    opposite_best: Option<P>,
+) -> (Repriced<P, Q, U>, usize) {
+    let mut repriced = Vec::new();
+    let pegged = queue.iter().filter(|request| request.peg.is_some()).count();
+    if pegged == 0 {
+        return (repriced, 0);
+    }
+    let mut kept = Vec::with_capacity(queue.len());
+    // the queue is flushed, so all of it is in the book
+    for mut request in queue.drain(..) {
+        let new_price = request
+            .peg
+            .and_then(|peg| peg.price(side, reference))
+            .and_then(|price| passive_price(price, side, opposite_best));
+        match new_price {
+            Some(price) if price != request.price => {
+                let old_price = request.price;
+                request.price = price;
+                repriced.push((request, old_price));
+            }
+            _ => kept.push(request),
+        }
+    }
+    queue.append(&mut kept);
+    (repriced, pegged)
+}
+
+fn passive_price<P: Price>(price: P, side: Side, opposite_best: Option<P>) -> Option<P> {
+    match (side, opposite_best) {
+        (_, None) => Some(price),
+        (Side::Buy, Some(ask)) => Some(cmp::min(price, ask.offset(-1)?)),
+        (Side::Sell, Some(bid)) => Some(cmp::max(price, bid.offset(1)?)),
+    }
+}
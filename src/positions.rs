@@ -0,0 +1,170 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use crate::matcher::*;
+use crate::price::Overflow;
+
+/// What open positions are marked to for unrealized PnL.
+#[derive(Deserialize, Serialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mark {
+    #[default]
+    LastTrade,
+    /// Middle of the BBO last passed to `PositionKeeper::update_bbo`, rounded down.
+    Midpoint,
+}
+
+/// Position of one user, amounts are in the price currency.
+#[derive(Deserialize, Serialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    /// Positive when long, negative when short.
+    pub net: i64,
+    /// Price times size of the open position, with the sign of `net`.
+    pub entry_cost: i64,
+    pub realized_pnl: i64,
+    pub fees: i64,
+}
+
+impl Position {
+    pub fn average_price(&self) -> Option<f64> {
+        if self.net == 0 {
+            return None;
+        }
+        Some(self.entry_cost as f64 / self.net as f64)
+    }
+
+    /// `None` if the PnL does not fit into `i64`.
+    pub fn unrealized_pnl(&self, mark_price: u64) -> Option<i64> {
+        let value = i64::try_from(mark_price).ok()?.checked_mul(self.net)?;
+        value.checked_sub(self.entry_cost)
+    }
+
+    // The position after an execution, `None` if any of its amounts would overflow.
+    fn applied(&self, size: i64, price: u64, fee: i64) -> Option<Position> {
+        let price = i64::try_from(price).ok()?;
+        let mut position = Position {
+            net: self.net.checked_add(size)?,
+            fees: self.fees.checked_add(fee)?,
+            ..*self
+        };
+        if self.net == 0 || self.net.signum() == size.signum() {
+            position.entry_cost = self.entry_cost.checked_add(size.checked_mul(price)?)?;
+            return Some(position);
+        }
+        let closed = size.abs().min(self.net.abs());
+        // at most the entry cost itself, as at most the whole position is closed
+        let closed_cost =
+            (i128::from(self.entry_cost) * i128::from(closed) / i128::from(self.net.abs())) as i64;
+        let proceeds = (self.net.signum() * closed).checked_mul(price)?;
+        position.realized_pnl = self
+            .realized_pnl
+            .checked_add(proceeds.checked_sub(closed_cost)?)?;
+        // whatever is left opens a position on the other side
+        let opened = size.signum() * (size.abs() - closed);
+        position.entry_cost =
+            (self.entry_cost - closed_cost).checked_add(opened.checked_mul(price)?)?;
+        Some(position)
+    }
+}
+
+/// Position and PnL of one user at the current mark price.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct PositionReport {
+    pub user_id: u64,
+    pub net: i64,
+    pub average_price: Option<f64>,
+    pub realized_pnl: i64,
+    pub unrealized_pnl: Option<i64>,
+    pub fees: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct PositionSnapshot {
+    pub mark_price: Option<u64>,
+    pub positions: Vec<PositionReport>,
+}
+
+/// Positions of users in one book, built from its executions.
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+pub struct PositionKeeper {
+    pub mark: Mark,
+    positions: BTreeMap<u64, Position>,
+    last_price: Option<u64>,
+    bbo: Bbo,
+}
+
+impl PositionKeeper {
+    pub fn new(mark: Mark) -> PositionKeeper {
+        PositionKeeper {
+            mark,
+            ..Default::default()
+        }
+    }
+
+    /// Updates positions of both users of the execution, neither of them is changed
+    /// if an amount of either would overflow.
+    pub fn record(&mut self, action: &MarketAction) -> Result<(), Overflow> {
+        let size = i64::try_from(action.size).map_err(|_| Overflow::Position)?;
+        let buyer =
+            self.position(action.buyer_user_id)
+                .applied(size, action.price, action.buyer_fee);
+        let seller =
+            self.position(action.seller_user_id)
+                .applied(-size, action.price, action.seller_fee);
+        let (buyer, seller) = buyer.zip(seller).ok_or(Overflow::Position)?;
+        self.positions.insert(action.buyer_user_id, buyer);
+        self.positions.insert(action.seller_user_id, seller);
+        self.last_price = Some(action.price);
+        Ok(())
+    }
+
+    /// Records executions of the result up to the first one which overflows.
+    pub fn record_result(&mut self, result: &MatchingResult) -> Result<(), Overflow> {
+        result
+            .market_actions
+            .iter()
+            .try_for_each(|action| self.record(action))
+    }
+
+    pub fn update_bbo(&mut self, bbo: Bbo) {
+        self.bbo = bbo;
+    }
+
+    pub fn mark_price(&self) -> Option<u64> {
+        match self.mark {
+            Mark::LastTrade => self.last_price,
+            Mark::Midpoint => {
+                let (bid, ask) = (self.bbo.bid?, self.bbo.ask?);
+                Some(bid / 2 + ask / 2 + (bid % 2 + ask % 2) / 2)
+            }
+        }
+    }
+
+    pub fn position(&self, user_id: u64) -> Position {
+        self.positions.get(&user_id).copied().unwrap_or_default()
+    }
+
+    pub fn unrealized_pnl(&self, user_id: u64) -> Option<i64> {
+        self.position(user_id).unrealized_pnl(self.mark_price()?)
+    }
+
+    pub fn snapshot(&self) -> PositionSnapshot {
+        let mark_price = self.mark_price();
+        let positions = self
+            .positions
+            .iter()
+            .map(|(&user_id, position)| PositionReport {
+                user_id,
+                net: position.net,
+                average_price: position.average_price(),
+                realized_pnl: position.realized_pnl,
+                unrealized_pnl: mark_price.and_then(|price| position.unrealized_pnl(price)),
+                fees: position.fees,
+            })
+            .collect();
+        PositionSnapshot {
+            mark_price,
+            positions,
+        }
+    }
+}
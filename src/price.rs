@@ -0,0 +1,197 @@
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt::{self, Debug, Display};
+use std::hash::Hash;
+use std::ops::{Add, AddAssign, Sub, SubAssign};
+
+/// Price type of a book: `u64` for most instruments, `i64` for spreads and
+/// contracts which may trade below zero.
+pub trait Price: Copy + Ord + Hash + Default + Debug + Display {
+    /// The price `ticks` price points away, `None` if it cannot be represented.
+    fn offset(self, ticks: i64) -> Option<Self>;
+
+    /// Middle of two prices, `low` being at most `high`, rounded down or up.
+    fn middle(low: Self, high: Self, round_up: bool) -> Self;
+}
+
+impl Price for u64 {
+    #[inline]
+    fn offset(self, ticks: i64) -> Option<u64> {
+        if ticks >= 0 {
+            self.checked_add(ticks as u64)
+        } else {
+            self.checked_sub(ticks.unsigned_abs())
+        }
+    }
+
+    #[inline]
+    fn middle(low: u64, high: u64, round_up: bool) -> u64 {
+        if round_up {
+            high - (high - low) / 2
+        } else {
+            low + (high - low) / 2
+        }
+    }
+}
+
+impl Price for i64 {
+    #[inline]
+    fn offset(self, ticks: i64) -> Option<i64> {
+        self.checked_add(ticks)
+    }
+
+    #[inline]
+    fn middle(low: i64, high: i64, round_up: bool) -> i64 {
+        let half = (i128::from(high) - i128::from(low)) / 2;
+        if round_up {
+            (i128::from(high) - half) as i64
+        } else {
+            (i128::from(low) + half) as i64
+        }
+    }
+}
+
+impl Price for u32 {
+    #[inline]
+    fn offset(self, ticks: i64) -> Option<u32> {
+        u32::try_from(u64::from(self).offset(ticks)?).ok()
+    }
+
+    #[inline]
+    fn middle(low: u32, high: u32, round_up: bool) -> u32 {
+        u64::middle(u64::from(low), u64::from(high), round_up) as u32
+    }
+}
+
+impl Price for i32 {
+    #[inline]
+    fn offset(self, ticks: i64) -> Option<i32> {
+        i32::try_from(i64::from(self).offset(ticks)?).ok()
+    }
+
+    #[inline]
+    fn middle(low: i32, high: i32, round_up: bool) -> i32 {
+        i64::middle(i64::from(low), i64::from(high), round_up) as i32
+    }
+}
+
+/// Size type of a book.
+pub trait Quantity:
+    Copy
+    + Ord
+    + Hash
+    + Default
+    + Debug
+    + Display
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + AddAssign
+    + SubAssign
+{
+    const ZERO: Self;
+    const MAX: Self;
+
+    fn checked_add(self, other: Self) -> Option<Self>;
+
+    fn checked_sub(self, other: Self) -> Option<Self>;
+
+    /// Approximate value, for display.
+    fn to_f64(self) -> f64;
+}
+
+impl Quantity for u32 {
+    const ZERO: u32 = 0;
+    const MAX: u32 = u32::MAX;
+
+    #[inline]
+    fn checked_add(self, other: u32) -> Option<u32> {
+        u32::checked_add(self, other)
+    }
+
+    #[inline]
+    fn checked_sub(self, other: u32) -> Option<u32> {
+        u32::checked_sub(self, other)
+    }
+
+    #[inline]
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl Quantity for u64 {
+    const ZERO: u64 = 0;
+    const MAX: u64 = u64::MAX;
+
+    #[inline]
+    fn checked_add(self, other: u64) -> Option<u64> {
+        u64::checked_add(self, other)
+    }
+
+    #[inline]
+    fn checked_sub(self, other: u64) -> Option<u64> {
+        u64::checked_sub(self, other)
+    }
+
+    #[inline]
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl Quantity for u128 {
+    const ZERO: u128 = 0;
+    const MAX: u128 = u128::MAX;
+
+    #[inline]
+    fn checked_add(self, other: u128) -> Option<u128> {
+        u128::checked_add(self, other)
+    }
+
+    #[inline]
+    fn checked_sub(self, other: u128) -> Option<u128> {
+        u128::checked_sub(self, other)
+    }
+
+    #[inline]
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+/// Type of user ids of a book, any plain value or newtype around one will do.
+pub trait UserId: Copy + Eq + Hash + Default + Debug + Display {}
+
+impl<T: Copy + Eq + Hash + Default + Debug + Display> UserId for T {}
+
+/// Arithmetic that does not fit into the types of a book or an account.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Total size resting on one side of a book.
+    Volume,
+    /// Price times size.
+    Notional,
+    /// Balance of an account.
+    Balance,
+    /// Net position or its cost.
+    Position,
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let res_str = match self {
+            Overflow::Volume => "the size in the book would overflow",
+            Overflow::Notional => "price times size would overflow",
+            Overflow::Balance => "the balance would overflow",
+            Overflow::Position => "the position would overflow",
+        };
+        write!(f, "{}", res_str)
+    }
+}
+
+impl std::error::Error for Overflow {}
+
+/// Price times size, the error tells that it does not fit into `u64`.
+pub fn notional(price: u64, size: u64) -> Result<u64, Overflow> {
+    price.checked_mul(size).ok_or(Overflow::Notional)
+}
@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::matcher::*;
+
+/// Bid and ask of a market maker, a side with zero size is not quoted.
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+pub struct Quote {
+    pub user_id: u64,
+    pub bid_id: u64,
+    pub bid_price: u64,
+    pub bid_size: u64,
+    pub ask_id: u64,
+    pub ask_price: u64,
+    pub ask_size: u64,
+}
+
+/// Why a quote was refused before anything happened in the book.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuoteError {
+    /// Both sides are quoted and the bid is not below the ask.
+    Crossed,
+}
+
+impl fmt::Display for QuoteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QuoteError::Crossed => write!(f, "Quote was rejected: the bid is not below the ask"),
+        }
+    }
+}
+
+impl std::error::Error for QuoteError {}
+
+impl Quote {
+    pub fn is_crossed(&self) -> bool {
+        self.bid_size > 0 && self.ask_size > 0 && self.bid_price >= self.ask_price
+    }
+
+    pub(crate) fn requests(&self) -> (Option<Request>, Option<Request>) {
+        let side_request = |id, side, price, size| {
+            if size == 0 {
+                return None;
+            }
+            Some(Request {
+                id,
+                side,
+                price,
+                size,
+                user_id: self.user_id,
+                request_type: Type::Quote,
+                ..Default::default()
+            })
+        };
+        (
+            side_request(self.bid_id, Side::Buy, self.bid_price, self.bid_size),
+            side_request(self.ask_id, Side::Sell, self.ask_price, self.ask_size),
+        )
+    }
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, PartialEq, Eq)]
+pub struct QuoteResult {
+    /// Removal of the previous quote and everything that followed the new one.
+    pub book_events: Vec<BookEvent>,
+    pub bid: Option<MatchingResult>,
+    pub ask: Option<MatchingResult>,
+}
+
+impl QuoteResult {
+    pub fn market_actions(&self) -> impl Iterator<Item = &MarketAction> {
+        self.bid
+            .iter()
+            .chain(self.ask.iter())
+            .flat_map(|result| result.market_actions.iter())
+    }
+}
+
+impl OrderBook {
+    /// Replaces the previous quote of the user on both sides with the new one,
+    /// without anything happening in the book in between.
+    pub fn quote(&mut self, quote: &Quote) -> Result<QuoteResult, QuoteError> {
+        if quote.is_crossed() {
+            return Err(QuoteError::Crossed);
+        }
+        let filter = CancelFilter {
+            request_type: Some(Type::Quote),
+            ..Default::default()
+        };
+        self.flush_request_queues();
+        let mut book_events = Vec::new();
+        for queue in [&mut self.buyers, &mut self.sellers].iter_mut() {
+            queue.retain(|request| {
+                if request.user_id == quote.user_id && filter.matches(request) {
+                    book_events.push(BookEvent::Cancelled {
+                        request: request.clone(),
+                    });
+                    return false;
+                }
+                true
+            });
+        }
+        for event in book_events.iter() {
+            if let BookEvent::Cancelled { request } = event {
+                self.links.forget(request.id);
+            }
+        }
+
+        let (bid, ask) = quote.requests();
+        let mut enter = |request: Request| {
+            let mut result = self.enter_request(&request);
+            if !self.links.is_empty() {
+                self.process_links(&mut result);
+            }
+            book_events.append(&mut result.book_events);
+            result
+        };
+        let bid = bid.map(&mut enter);
+        let ask = ask.map(&mut enter);
+        book_events.extend(self.reprice_pegged_requests());
+        Ok(QuoteResult {
+            book_events,
+            bid,
+            ask,
+        })
+    }
+}
@@ -0,0 +1,203 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+use crate::depth::*;
+use crate::matcher::*;
+
+pub const REPL_HELP: &str = "commands:
+  buy|sell SIZE @ PRICE [user ID] [id ID] [limit|ioc|fok] [hidden]
+                   enter a request, user 1 and the next free id by default
+  cancel ID        cancel a resting request
+  book             show the book, marking levels changed by the last command
+  trades           show every trade so far
+  undo             take back the last request or cancel
+  save PATH        save everything entered so far
+  load PATH        replace the book with the one saved in the file
+  help             show this
+  quit             leave";
+
+/// Something entered into the book, the book can be rebuilt by replaying them.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    Submit(Request),
+    Cancel(u64),
+}
+
+/// Line of the REPL once it is parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Step(Step),
+    Book,
+    Trades,
+    Undo,
+    Save(String),
+    Load(String),
+    Help,
+    Quit,
+}
+
+/// An order book driven by commands typed by hand, see `REPL_HELP`.
+#[derive(Default, Debug, Clone)]
+pub struct Repl {
+    book: OrderBook,
+    history: Vec<Step>,
+    trades: Vec<MarketAction>,
+    // depth before the last step, to highlight what it changed
+    previous: Depth,
+}
+
+impl Repl {
+    pub fn book(&self) -> &OrderBook {
+        &self.book
+    }
+
+    /// Runs a parsed line, returning what should be shown for it.
+    pub fn execute(&mut self, command: Command) -> Result<String, String> {
+        match command {
+            Command::Step(step) => self.apply(step),
+            Command::Book => Ok(self.render_book()),
+            Command::Trades if self.trades.is_empty() => Ok("There were no trades".to_string()),
+            Command::Trades => {
+                let trades: Vec<_> = self.trades.iter().map(ToString::to_string).collect();
+                Ok(trades.join("\n"))
+            }
+            Command::Undo => {
+                let step = self.history.pop().ok_or("There is nothing to undo")?;
+                let history = std::mem::take(&mut self.history);
+                self.replay(history);
+                match step {
+                    Step::Submit(request) => Ok(format!("Request #{} was taken back", request.id)),
+                    Step::Cancel(id) => Ok(format!("Request #{} is back in the book", id)),
+                }
+            }
+            Command::Save(path) => {
+                let data = serde_json::to_string_pretty(&self.history)
+                    .expect("steps are always serializable");
+                fs::write(&path, data).map_err(|error| format!("{}: {}", path, error))?;
+                Ok(format!("Saved {} steps to {}", self.history.len(), path))
+            }
+            Command::Load(path) => {
+                let data =
+                    fs::read_to_string(&path).map_err(|error| format!("{}: {}", path, error))?;
+                let history: Vec<Step> =
+                    serde_json::from_str(&data).map_err(|error| format!("{}: {}", path, error))?;
+                self.replay(history);
+                Ok(format!("Loaded {} steps from {}", self.history.len(), path))
+            }
+            Command::Help => Ok(REPL_HELP.to_string()),
+            Command::Quit => Ok(String::new()),
+        }
+    }
+
+    /// Parses a line; requests without an id get the next free one.
+    pub fn parse(&self, line: &str) -> Result<Command, String> {
+        let words: Vec<_> = line.split_whitespace().collect();
+        let command = match words.as_slice() {
+            ["buy", rest @ ..] => Command::Step(Step::Submit(self.parse_request(Side::Buy, rest)?)),
+            ["sell", rest @ ..] => {
+                Command::Step(Step::Submit(self.parse_request(Side::Sell, rest)?))
+            }
+            ["cancel", id] => Command::Step(Step::Cancel(number(id)?)),
+            ["book"] => Command::Book,
+            ["trades"] => Command::Trades,
+            ["undo"] => Command::Undo,
+            ["save", path] => Command::Save(path.to_string()),
+            ["load", path] => Command::Load(path.to_string()),
+            ["help"] => Command::Help,
+            ["quit"] | ["exit"] => Command::Quit,
+            [] => return Err("Type `help` to see the commands".to_string()),
+            [word, ..] => return Err(format!("Unknown command '{}', try `help`", word)),
+        };
+        Ok(command)
+    }
+
+    fn parse_request(&self, side: Side, words: &[&str]) -> Result<Request, String> {
+        let (size, price, options) = match words {
+            [size, "@", price, options @ ..] => (number(size)?, number(price)?, options),
+            _ => return Err("Expected SIZE @ PRICE".to_string()),
+        };
+        let next_id = self
+            .history
+            .iter()
+            .filter_map(request_id)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let mut request = Request {
+            id: next_id,
+            side,
+            price,
+            size,
+            user_id: 1,
+            request_type: Type::Limit,
+            ..Default::default()
+        };
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            match *option {
+                "user" | "id" => {
+                    let value = options
+                        .next()
+                        .ok_or_else(|| format!("Expected a number after '{}'", option))?;
+                    if *option == "user" {
+                        request.user_id = number(value)?;
+                    } else {
+                        request.id = number(value)?;
+                    }
+                }
+                "limit" => request.request_type = Type::Limit,
+                "ioc" => request.request_type = Type::ImmediateOrCancel,
+                "fok" => request.request_type = Type::FillOrKill,
+                "hidden" => request.hidden = true,
+                _ => return Err(format!("Unknown option '{}'", option)),
+            }
+        }
+        Ok(request)
+    }
+
+    fn apply(&mut self, step: Step) -> Result<String, String> {
+        let previous = self.book.depth(usize::MAX);
+        let output = match step {
+            Step::Submit(ref request) => {
+                let result = self.book.match_request(request);
+                self.trades.extend(result.market_actions.iter().cloned());
+                format!("#{} {}\n{}", request.id, request, result)
+            }
+            Step::Cancel(id) => {
+                if self.book.cancel_request(id).is_empty() {
+                    // nothing happened, so there is nothing to undo either
+                    return Err(format!("There is no request #{} in the book", id));
+                }
+                format!("Request #{} was cancelled", id)
+            }
+        };
+        self.history.push(step);
+        self.previous = previous;
+        Ok(output)
+    }
+
+    // Rebuilds the book from scratch, steps which do nothing are dropped.
+    fn replay(&mut self, history: Vec<Step>) {
+        *self = Repl::default();
+        for step in history {
+            let _ = self.apply(step);
+        }
+    }
+
+    fn render_book(&self) -> String {
+        let ladder = self.book.ladder(usize::MAX);
+        ladder.highlight_changes(self.previous.clone()).to_string()
+    }
+}
+
+fn request_id(step: &Step) -> Option<u64> {
+    match step {
+        Step::Submit(request) => Some(request.id),
+        Step::Cancel(_) => None,
+    }
+}
+
+fn number(word: &str) -> Result<u64, String> {
+    word.parse()
+        .map_err(|_| format!("'{}' is not a number", word))
+}
@@ -0,0 +1,136 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::matcher::*;
+
+/// Per-user limits checked before a request reaches a book, `None` means no limit.
+#[derive(Deserialize, Serialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RiskLimits {
+    pub max_order_size: Option<u64>,
+    /// Price times size of a single request.
+    pub max_notional: Option<u64>,
+    /// Requests resting in all books together.
+    pub max_open_orders: Option<u64>,
+    /// Absolute net position in one book if the request was filled completely.
+    pub max_net_position: Option<u64>,
+    pub max_orders_per_second: Option<u64>,
+}
+
+/// The limit a request would breach and the value it would bring the user to.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RiskBreach {
+    OrderSize { limit: u64, value: u64 },
+    Notional { limit: u64, value: u64 },
+    OpenOrders { limit: u64, value: u64 },
+    NetPosition { limit: u64, value: i64 },
+    OrdersPerSecond { limit: u64, value: u64 },
+}
+
+impl fmt::Display for RiskBreach {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RiskBreach::OrderSize { limit, value } => {
+                write!(f, "size {} is over the limit of {}", value, limit)
+            }
+            RiskBreach::Notional { limit, value } => {
+                write!(f, "notional {} is over the limit of {}", value, limit)
+            }
+            RiskBreach::OpenOrders { limit, value } => {
+                write!(f, "{} open requests are over the limit of {}", value, limit)
+            }
+            RiskBreach::NetPosition { limit, value } => {
+                write!(f, "net position {} is over the limit of {}", value, limit)
+            }
+            RiskBreach::OrdersPerSecond { limit, value } => {
+                write!(
+                    f,
+                    "{} requests per second are over the limit of {}",
+                    value, limit
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for RiskBreach {}
+
+const RATE_WINDOW: u64 = 1000;
+
+// Positions and recent requests of one user, counted since the limits were set.
+#[derive(Default, Debug, Clone)]
+pub(crate) struct RiskTracker {
+    positions: HashMap<String, i64>,
+    accepted_at: VecDeque<u64>,
+}
+
+impl RiskTracker {
+    pub(crate) fn check_rate(
+        &mut self,
+        limits: &RiskLimits,
+        timestamp: u64,
+    ) -> Result<(), RiskBreach> {
+        while let Some(&accepted_at) = self.accepted_at.front() {
+            if accepted_at + RATE_WINDOW > timestamp {
+                break;
+            }
+            self.accepted_at.pop_front();
+        }
+        let value = self.accepted_at.len() as u64 + 1;
+        match limits.max_orders_per_second {
+            Some(limit) if value > limit => Err(RiskBreach::OrdersPerSecond { limit, value }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks a single request priced at `price`, which is not necessarily its own
+    /// price for pegged requests.
+    pub(crate) fn check_request(
+        &self,
+        limits: &RiskLimits,
+        symbol: &str,
+        request: &Request,
+        price: u64,
+    ) -> Result<(), RiskBreach> {
+        if let Some(limit) = limits.max_order_size {
+            if request.size > limit {
+                return Err(RiskBreach::OrderSize {
+                    limit,
+                    value: request.size,
+                });
+            }
+        }
+        if let Some(limit) = limits.max_notional {
+            let value = price.saturating_mul(request.size);
+            if value > limit {
+                return Err(RiskBreach::Notional { limit, value });
+            }
+        }
+        if let Some(limit) = limits.max_net_position {
+            let position = self.positions.get(symbol).copied().unwrap_or(0);
+            let size = i64::try_from(request.size).unwrap_or(i64::MAX);
+            let value = match request.side {
+                Side::Buy => position.saturating_add(size),
+                Side::Sell => position.saturating_sub(size),
+            };
+            if value.unsigned_abs() > limit {
+                return Err(RiskBreach::NetPosition { limit, value });
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn record_request(&mut self, timestamp: u64) {
+        self.accepted_at.push_back(timestamp);
+    }
+
+    pub(crate) fn record_execution(&mut self, symbol: &str, side: Side, size: u64) {
+        let position = self.positions.entry(symbol.to_string()).or_insert(0);
+        let size = i64::try_from(size).unwrap_or(i64::MAX);
+        *position = match side {
+            Side::Buy => position.saturating_add(size),
+            Side::Sell => position.saturating_sub(size),
+        };
+    }
+}
@@ -1,4 +1,31 @@
+use crate::accounts::*;
+use crate::dark::*;
+use crate::decimal::*;
+use crate::depth::*;
+#[cfg(feature = "display")]
+use crate::displayers::*;
+use crate::exchange::*;
+use crate::fees::*;
+use crate::fix::*;
+use crate::fix_gateway::*;
+use crate::groups::*;
+use crate::http::*;
+use crate::input::*;
+use crate::itch::*;
 use crate::matcher::*;
+use crate::mmp::*;
+use crate::ouch::*;
+use crate::pegging::*;
+use crate::positions::*;
+use crate::price::*;
+use crate::quotes::*;
+#[cfg(feature = "display")]
+use crate::repl::*;
+use crate::risk::*;
+use crate::websocket::*;
+use proptest::prelude::*;
+use std::io::{Read, Write};
+use std::thread;
 
 #[test]
 fn test_adding_buy_limit_to_empty_book() {
@@ -9,16 +36,20 @@ fn test_adding_buy_limit_to_empty_book() {
         size: 1,
         user_id: 1,
         request_type: Type::Limit,
+        ..Default::default()
     };
     let matching_result = book.match_request(&limit_request);
     let expected = MatchingResult {
         market_actions: vec![],
         request_actions: vec![RequestAction::AddedToBook],
+        ..Default::default()
     };
     assert_eq!(matching_result, expected);
     assert_eq!(book.buyers.len(), 1);
     assert_eq!(book.sellers.len(), 0);
     assert_eq!(book.buyers[0], limit_request);
+    assert_eq!(book.buyers().collect::<Vec<_>>(), vec![&limit_request]);
+    assert_eq!(book.sellers().count(), 0);
 }
 
 #[test]
@@ -30,11 +61,13 @@ fn test_adding_sell_limit_to_empty_book() {
         size: 1,
         user_id: 1,
         request_type: Type::Limit,
+        ..Default::default()
     };
     let matching_result = book.match_request(&limit_request);
     let expected = MatchingResult {
         market_actions: vec![],
         request_actions: vec![RequestAction::AddedToBook],
+        ..Default::default()
     };
     assert_eq!(matching_result, expected);
     assert_eq!(book.buyers.len(), 0);
@@ -53,6 +86,7 @@ fn test_adding_buy_limit_to_non_empty_book() {
             size: 1,
             user_id: i,
             request_type: Type::Limit,
+            ..Default::default()
         })
     });
     for request in requests {
@@ -70,11 +104,13 @@ fn test_adding_buy_limit_to_non_empty_book() {
         size: 1,
         user_id: 24,
         request_type: Type::Limit,
+        ..Default::default()
     };
     let matching_result = book.match_request(&request);
     let expected = MatchingResult {
         market_actions: vec![],
         request_actions: vec![RequestAction::AddedToBook],
+        ..Default::default()
     };
     assert_eq!(matching_result, expected);
     assert_eq!(book.buyers[0], request);
@@ -85,6 +121,7 @@ fn test_adding_buy_limit_to_non_empty_book() {
         size: 1,
         user_id: 24,
         request_type: Type::Limit,
+        ..Default::default()
     };
     let matching_result = book.match_request(&request);
     assert_eq!(matching_result, expected);
@@ -102,6 +139,7 @@ fn test_adding_sell_limit_to_non_empty_book() {
             size: 1,
             user_id: i,
             request_type: Type::Limit,
+            ..Default::default()
         })
     });
     for request in requests {
@@ -120,11 +158,13 @@ fn test_adding_sell_limit_to_non_empty_book() {
         size: 1,
         user_id: 24,
         request_type: Type::Limit,
+        ..Default::default()
     };
     let matching_result = book.match_request(&request);
     let expected = MatchingResult {
         market_actions: vec![],
         request_actions: vec![RequestAction::AddedToBook],
+        ..Default::default()
     };
     assert_eq!(matching_result, expected);
     assert_eq!(book.sellers[0], request);
@@ -136,6 +176,7 @@ fn test_adding_sell_limit_to_non_empty_book() {
         size: 1,
         user_id: 24,
         request_type: Type::Limit,
+        ..Default::default()
     };
     let matching_result = book.match_request(&request);
     assert_eq!(matching_result, expected);
@@ -151,6 +192,7 @@ fn test_simple_limit_matching_one_to_one() {
         size: 1,
         user_id: 1,
         request_type: Type::Limit,
+        ..Default::default()
     };
     book.match_request(&limit_request.clone());
     limit_request.side = Side::Sell;
@@ -159,6 +201,7 @@ fn test_simple_limit_matching_one_to_one() {
     let expected = MatchingResult {
         market_actions: vec![],
         request_actions: vec![RequestAction::AddedToBook],
+        ..Default::default()
     };
     assert_eq!(matching_result, expected);
     assert_eq!(book.sellers.len(), 1);
@@ -173,8 +216,11 @@ fn test_simple_limit_matching_one_to_one() {
             price: 1,
             seller_user_id: 2,
             buyer_user_id: 1,
+            aggressor_side: Side::Sell,
+            ..Default::default()
         }],
         request_actions: vec![RequestAction::Filled],
+        ..Default::default()
     };
     assert_eq!(matching_result, expected);
     assert_eq!(book.sellers.len(), 1);
@@ -189,8 +235,10 @@ fn test_simple_limit_matching_one_to_one() {
             price: 1,
             seller_user_id: 1,
             buyer_user_id: 2,
+            ..Default::default()
         }],
         request_actions: vec![RequestAction::Filled],
+        ..Default::default()
     };
     assert_eq!(matching_result, expected);
     assert_eq!(book.sellers.len(), 0);
@@ -207,6 +255,7 @@ fn test_correct_logic_of_buying_matching() {
             size: 1,
             user_id: 1,
             request_type: Type::Limit,
+            ..Default::default()
         };
         book.match_request(&limit_request);
     }
@@ -217,6 +266,7 @@ fn test_correct_logic_of_buying_matching() {
             size: 1,
             user_id: 2,
             request_type: Type::Limit,
+            ..Default::default()
         };
         let matching_result = book.match_request(&limit_request);
         let expected = MatchingResult {
@@ -225,8 +275,10 @@ fn test_correct_logic_of_buying_matching() {
                 price: i - 4,
                 seller_user_id: 1,
                 buyer_user_id: 2,
+                ..Default::default()
             }],
             request_actions: vec![RequestAction::Filled],
+            ..Default::default()
         };
         book.flush_request_queues();
         assert_eq!(book.sellers.len(), (10 - i - 1) as usize);
@@ -245,6 +297,7 @@ fn test_correct_logic_of_selling_matching() {
             size: 1,
             user_id: 1,
             request_type: Type::Limit,
+            ..Default::default()
         };
         book.match_request(&limit_request);
     }
@@ -255,6 +308,7 @@ fn test_correct_logic_of_selling_matching() {
             size: 1,
             user_id: 2,
             request_type: Type::Limit,
+            ..Default::default()
         };
         let matching_result = book.match_request(&limit_request);
         book.flush_request_queues();
@@ -264,8 +318,11 @@ fn test_correct_logic_of_selling_matching() {
                 price: 10 - i + 1,
                 seller_user_id: 2,
                 buyer_user_id: 1,
+                aggressor_side: Side::Sell,
+                ..Default::default()
             }],
             request_actions: vec![RequestAction::Filled],
+            ..Default::default()
         };
         assert_eq!(book.buyers.len(), (5 - i) as usize);
         assert_eq!(book.sellers.len(), 0);
@@ -282,6 +339,7 @@ fn test_limit_matching_with_leftovers() {
         size: 1,
         user_id: 1,
         request_type: Type::Limit,
+        ..Default::default()
     };
     book.match_request(&limit_request);
     limit_request.side = Side::Sell;
@@ -306,6 +364,7 @@ fn test_spread_limit_matching() {
             size: 2,
             user_id: i,
             request_type: Type::Limit,
+            ..Default::default()
         };
         book.match_request(&limit_request);
     }
@@ -317,6 +376,7 @@ fn test_spread_limit_matching() {
             size: 2,
             user_id: i,
             request_type: Type::Limit,
+            ..Default::default()
         };
         book.match_request(&limit_request);
     }
@@ -328,6 +388,7 @@ fn test_spread_limit_matching() {
         size: 300,
         user_id: 1000,
         request_type: Type::Limit,
+        ..Default::default()
     };
     // let's cover all of the buy offers and leave 100 in a book
     book.match_request(&limit_request);
@@ -353,6 +414,7 @@ fn test_simple_fill_or_kill_selling_one_to_one() {
         size: 2,
         user_id: 1,
         request_type: Type::Limit,
+        ..Default::default()
     };
     book.match_request(&limit_request);
     let mut fk_request = Request {
@@ -361,6 +423,7 @@ fn test_simple_fill_or_kill_selling_one_to_one() {
         size: 2,
         user_id: 1,
         request_type: Type::FillOrKill,
+        ..Default::default()
     };
     // same user_id shouldn't sell to the book
     book.match_request(&fk_request);
@@ -389,6 +452,7 @@ fn test_simple_fill_or_kill_bying_one_to_one() {
         size: 2,
         user_id: 1,
         request_type: Type::Limit,
+        ..Default::default()
     };
     book.match_request(&limit_request);
     let mut fk_request = Request {
@@ -397,6 +461,7 @@ fn test_simple_fill_or_kill_bying_one_to_one() {
         size: 2,
         user_id: 1,
         request_type: Type::FillOrKill,
+        ..Default::default()
     };
     // same user_id shouldn't sell to the book
     book.match_request(&fk_request);
@@ -425,6 +490,7 @@ fn test_spread_fill_or_kill_selling() {
         size: 1,
         user_id: 1,
         request_type: Type::Limit,
+        ..Default::default()
     };
     for _ in 0..100 {
         book.match_request(&limit_request);
@@ -435,6 +501,7 @@ fn test_spread_fill_or_kill_selling() {
         size: 101,
         user_id: 2,
         request_type: Type::FillOrKill,
+        ..Default::default()
     };
     // shouldn't sell when is not satisfied
     book.match_request(&fk_request);
@@ -457,6 +524,7 @@ fn test_spread_fill_or_kill_buying() {
         size: 1,
         user_id: 1,
         request_type: Type::Limit,
+        ..Default::default()
     };
     for _ in 0..100 {
         book.match_request(&limit_request);
@@ -467,6 +535,7 @@ fn test_spread_fill_or_kill_buying() {
         size: 101,
         user_id: 2,
         request_type: Type::FillOrKill,
+        ..Default::default()
     };
     // shouldn't sell when is not satisfied
     book.match_request(&fk_request);
@@ -489,6 +558,7 @@ fn test_simple_immediate_or_cancel_selling_one_to_one() {
         size: 3,
         user_id: 1,
         request_type: Type::Limit,
+        ..Default::default()
     };
     book.match_request(&limit_request);
     let mut ic_request = Request {
@@ -497,6 +567,7 @@ fn test_simple_immediate_or_cancel_selling_one_to_one() {
         size: 1,
         user_id: 1,
         request_type: Type::ImmediateOrCancel,
+        ..Default::default()
     };
     // same user_id shouldn't sell to the book
     book.match_request(&ic_request);
@@ -526,6 +597,7 @@ fn test_simple_immediate_or_cancel_buying_one_to_one() {
         size: 3,
         user_id: 1,
         request_type: Type::Limit,
+        ..Default::default()
     };
     book.match_request(&limit_request);
     let mut ic_request = Request {
@@ -534,6 +606,7 @@ fn test_simple_immediate_or_cancel_buying_one_to_one() {
         size: 1,
         user_id: 1,
         request_type: Type::ImmediateOrCancel,
+        ..Default::default()
     };
     // same user_id shouldn't buy from the book
     book.match_request(&ic_request);
@@ -564,6 +637,7 @@ fn test_correct_removing_from_book_all() {
             size: 1,
             user_id: 1,
             request_type: Type::Limit,
+            ..Default::default()
         };
         book.match_request(&request);
     }
@@ -575,6 +649,7 @@ fn test_correct_removing_from_book_all() {
         size: 10,
         user_id: 2,
         request_type: Type::Limit,
+        ..Default::default()
     };
     book.match_request(&request);
     book.flush_request_queues();
@@ -591,6 +666,7 @@ fn test_correct_removing_from_book_except_user_3() {
             size: 1,
             user_id: i,
             request_type: Type::Limit,
+            ..Default::default()
         };
         book.match_request(&request);
     }
@@ -601,6 +677,7 @@ fn test_correct_removing_from_book_except_user_3() {
         size: 10,
         user_id: 3,
         request_type: Type::Limit,
+        ..Default::default()
     };
     book.match_request(&request);
     assert_eq!(book.sellers.len(), 1);
@@ -616,6 +693,7 @@ fn test_correct_removing_from_book_except_user_2() {
             size: 1,
             user_id: i,
             request_type: Type::Limit,
+            ..Default::default()
         };
         book.match_request(&request);
     }
@@ -626,7 +704,3550 @@ fn test_correct_removing_from_book_except_user_2() {
         size: 10,
         user_id: 2,
         request_type: Type::Limit,
+        ..Default::default()
     };
     book.match_request(&request);
     assert_eq!(book.sellers.len(), 1);
 }
+
+#[test]
+fn test_primary_peg_follows_best_bid() {
+    let mut book = OrderBook::default();
+    let mut limit_request = Request {
+        side: Side::Sell,
+        price: 10,
+        size: 1,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    book.match_request(&limit_request);
+    limit_request.side = Side::Buy;
+    limit_request.price = 5;
+    limit_request.user_id = 2;
+    book.match_request(&limit_request);
+    let pegged_request = Request {
+        side: Side::Buy,
+        price: 0,
+        size: 3,
+        user_id: 3,
+        request_type: Type::Limit,
+        peg: Some(Peg {
+            reference: PegReference::Primary,
+            offset: 0,
+            limit: None,
+        }),
+        ..Default::default()
+    };
+    let matching_result = book.match_request(&pegged_request);
+    let expected = MatchingResult {
+        request_actions: vec![RequestAction::AddedToBook],
+        ..Default::default()
+    };
+    assert_eq!(matching_result, expected);
+    assert_eq!(book.buyers[1].user_id, 3);
+    assert_eq!(book.buyers[1].price, 5);
+    // a better bid moves the pegged request behind it
+    limit_request.price = 7;
+    limit_request.user_id = 4;
+    let matching_result = book.match_request(&limit_request);
+    let expected = MatchingResult {
+        request_actions: vec![RequestAction::AddedToBook],
+        book_events: vec![BookEvent::Repriced {
+            id: 0,
+            side: Side::Buy,
+            user_id: 3,
+            size: 3,
+            old_price: 5,
+            new_price: 7,
+        }],
+        ..Default::default()
+    };
+    assert_eq!(matching_result, expected);
+    assert_eq!(book.buyers.len(), 3);
+    assert_eq!(book.buyers[0].user_id, 4);
+    assert_eq!(book.buyers[1].user_id, 3);
+    assert_eq!(book.buyers[1].price, 7);
+    // once the better bid is gone the pegged request goes back
+    limit_request.side = Side::Sell;
+    limit_request.user_id = 5;
+    limit_request.size = 1;
+    let matching_result = book.match_request(&limit_request);
+    assert_eq!(
+        matching_result.book_events,
+        vec![BookEvent::Repriced {
+            id: 0,
+            side: Side::Buy,
+            user_id: 3,
+            size: 3,
+            old_price: 7,
+            new_price: 5,
+        }]
+    );
+    assert_eq!(book.buyers.len(), 2);
+    assert_eq!(book.buyers[1].user_id, 3);
+    assert_eq!(book.buyers[1].price, 5);
+    // books without pegged requests are not scanned for them
+    assert_eq!(book.pegged, 1);
+    limit_request.size = 4;
+    limit_request.price = 5;
+    limit_request.user_id = 6;
+    book.match_request(&limit_request);
+    assert!(book.buyers.active().is_empty());
+    assert_eq!(book.pegged, 0);
+}
+
+#[test]
+fn test_midpoint_peg_with_limit() {
+    let mut book = OrderBook::default();
+    let mut limit_request = Request {
+        side: Side::Sell,
+        price: 9,
+        size: 1,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    book.match_request(&limit_request);
+    limit_request.side = Side::Buy;
+    limit_request.price = 4;
+    book.match_request(&limit_request);
+    let mut pegged_request = Request {
+        side: Side::Buy,
+        price: 0,
+        size: 1,
+        user_id: 2,
+        request_type: Type::Limit,
+        peg: Some(Peg {
+            reference: PegReference::Midpoint,
+            offset: 0,
+            limit: None,
+        }),
+        ..Default::default()
+    };
+    // the middle of 4 and 9 is rounded down for buyers
+    book.match_request(&pegged_request);
+    assert_eq!(book.buyers[0].price, 6);
+    // and up for sellers
+    pegged_request.side = Side::Sell;
+    pegged_request.user_id = 3;
+    book.match_request(&pegged_request);
+    assert_eq!(book.sellers[0].price, 7);
+    // the limit caps the price of a buyer
+    pegged_request.side = Side::Buy;
+    pegged_request.user_id = 4;
+    pegged_request.peg = Some(Peg {
+        reference: PegReference::Midpoint,
+        offset: 0,
+        limit: Some(5),
+    });
+    book.match_request(&pegged_request);
+    assert_eq!(book.buyers[1].price, 5);
+    assert_eq!(book.buyers[1].user_id, 4);
+}
+
+#[test]
+fn test_pegged_request_without_reference_is_cancelled() {
+    let mut book = OrderBook::default();
+    let pegged_request = Request {
+        side: Side::Sell,
+        price: 0,
+        size: 1,
+        user_id: 1,
+        request_type: Type::Limit,
+        peg: Some(Peg {
+            reference: PegReference::Market,
+            offset: 1,
+            limit: None,
+        }),
+        ..Default::default()
+    };
+    let matching_result = book.match_request(&pegged_request);
+    let expected = MatchingResult {
+        request_actions: vec![RequestAction::Cancelled],
+        ..Default::default()
+    };
+    assert_eq!(matching_result, expected);
+    assert_eq!(book.sellers.len(), 0);
+}
+
+#[test]
+fn test_repriced_request_does_not_cross() {
+    let mut book = OrderBook::default();
+    let mut limit_request = Request {
+        side: Side::Sell,
+        price: 10,
+        size: 1,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    book.match_request(&limit_request);
+    limit_request.side = Side::Buy;
+    limit_request.price = 5;
+    book.match_request(&limit_request);
+    let pegged_request = Request {
+        side: Side::Buy,
+        price: 0,
+        size: 1,
+        user_id: 2,
+        request_type: Type::Limit,
+        peg: Some(Peg {
+            reference: PegReference::Primary,
+            offset: 2,
+            limit: None,
+        }),
+        ..Default::default()
+    };
+    book.match_request(&pegged_request);
+    assert_eq!(book.buyers[0].price, 7);
+    // following the bid to 11 would cross the ask at 10
+    limit_request.price = 9;
+    let matching_result = book.match_request(&limit_request);
+    assert_eq!(
+        matching_result.book_events,
+        vec![BookEvent::Repriced {
+            id: 0,
+            side: Side::Buy,
+            user_id: 2,
+            size: 1,
+            old_price: 7,
+            new_price: 9,
+        }]
+    );
+    assert_eq!(book.sellers[0].price, 10);
+    assert_eq!(book.buyers[1].user_id, 2);
+    assert_eq!(book.buyers[1].price, 9);
+}
+
+#[test]
+fn test_hidden_request_queues_behind_displayed() {
+    let mut book = OrderBook::default();
+    let mut limit_request = Request {
+        side: Side::Sell,
+        price: 5,
+        size: 1,
+        user_id: 1,
+        request_type: Type::Limit,
+        hidden: true,
+        ..Default::default()
+    };
+    book.match_request(&limit_request);
+    limit_request.user_id = 2;
+    book.match_request(&limit_request);
+    limit_request.user_id = 3;
+    limit_request.hidden = false;
+    book.match_request(&limit_request);
+    // a displayed request at a worse price is still queued after the hidden ones
+    limit_request.user_id = 4;
+    limit_request.price = 6;
+    book.match_request(&limit_request);
+    let user_ids = book.sellers.iter().map(|r| r.user_id).collect::<Vec<_>>();
+    assert_eq!(user_ids, vec![3, 1, 2, 4]);
+    let displayed = book.sellers().map(|r| r.user_id).collect::<Vec<_>>();
+    assert_eq!(displayed, vec![3, 4]);
+    // hidden requests are matched like any other request
+    let request = Request {
+        side: Side::Buy,
+        price: 5,
+        size: 2,
+        user_id: 5,
+        request_type: Type::ImmediateOrCancel,
+        ..Default::default()
+    };
+    let matching_result = book.match_request(&request);
+    let expected = MatchingResult {
+        market_actions: vec![
+            MarketAction {
+                size: 1,
+                price: 5,
+                seller_user_id: 3,
+                buyer_user_id: 5,
+                ..Default::default()
+            },
+            MarketAction {
+                size: 1,
+                price: 5,
+                seller_user_id: 1,
+                buyer_user_id: 5,
+                ..Default::default()
+            },
+        ],
+        request_actions: vec![RequestAction::Filled],
+        ..Default::default()
+    };
+    assert_eq!(matching_result, expected);
+}
+
+#[test]
+fn test_depth_leaves_out_hidden_requests() {
+    let mut book = OrderBook::default();
+    for (price, hidden) in [(3, false), (3, true), (3, false), (2, true), (1, false)].iter() {
+        let request = Request {
+            side: Side::Buy,
+            price: *price,
+            size: 2,
+            user_id: 1,
+            request_type: Type::Limit,
+            hidden: *hidden,
+            ..Default::default()
+        };
+        book.match_request(&request);
+    }
+    let request = Request {
+        side: Side::Sell,
+        price: 4,
+        size: 1,
+        user_id: 1,
+        request_type: Type::Limit,
+        hidden: true,
+        ..Default::default()
+    };
+    book.match_request(&request);
+    let expected = Depth {
+        bids: vec![
+            PriceLevel {
+                price: 3,
+                size: 4,
+                orders: 2,
+            },
+            PriceLevel {
+                price: 1,
+                size: 2,
+                orders: 1,
+            },
+        ],
+        asks: vec![],
+    };
+    assert_eq!(book.depth(10), expected);
+    assert_eq!(book.depth(1).bids, expected.bids[..1].to_vec());
+    assert_eq!(
+        book.bbo(),
+        Bbo {
+            bid: Some(3),
+            ask: None
+        }
+    );
+}
+
+#[test]
+fn test_dark_book_executes_at_midpoint_of_lit_book() {
+    let mut lit_book = OrderBook::default();
+    let mut limit_request = Request {
+        side: Side::Sell,
+        price: 12,
+        size: 1,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    lit_book.match_request(&limit_request);
+    limit_request.side = Side::Buy;
+    limit_request.price = 8;
+    lit_book.match_request(&limit_request);
+
+    let mut dark_book = DarkBook::default();
+    assert!(dark_book.update_reference(lit_book.bbo()).is_empty());
+    assert_eq!(dark_book.midpoint(), Some(10));
+    let mut dark_request = Request {
+        side: Side::Sell,
+        price: 11,
+        size: 5,
+        user_id: 2,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    // the midpoint is below the limit of the seller
+    let matching_result = dark_book.match_request(&dark_request);
+    assert_eq!(
+        matching_result.request_actions,
+        vec![RequestAction::AddedToBook]
+    );
+    dark_request.price = 9;
+    dark_request.user_id = 3;
+    dark_book.match_request(&dark_request);
+    let dark_request = Request {
+        side: Side::Buy,
+        price: 10,
+        size: 7,
+        user_id: 4,
+        request_type: Type::ImmediateOrCancel,
+        ..Default::default()
+    };
+    let matching_result = dark_book.match_request(&dark_request);
+    let expected = MatchingResult {
+        market_actions: vec![MarketAction {
+            size: 5,
+            price: 10,
+            seller_user_id: 3,
+            buyer_user_id: 4,
+            ..Default::default()
+        }],
+        request_actions: vec![RequestAction::FilledPartially, RequestAction::Cancelled],
+        ..Default::default()
+    };
+    assert_eq!(matching_result, expected);
+    assert_eq!(dark_book.sellers.len(), 1);
+    assert_eq!(dark_book.sellers[0].request.user_id, 2);
+}
+
+#[test]
+fn test_dark_book_minimum_size() {
+    let mut dark_book = DarkBook::default();
+    dark_book.update_reference(Bbo {
+        bid: Some(9),
+        ask: Some(11),
+    });
+    let mut dark_request = Request {
+        side: Side::Buy,
+        price: 10,
+        size: 3,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    dark_book.match_request(&dark_request);
+    dark_request.size = 10;
+    dark_request.user_id = 2;
+    dark_book.match_request(&dark_request);
+    let mut dark_order = DarkOrder {
+        request: Request {
+            side: Side::Sell,
+            price: 10,
+            size: 20,
+            user_id: 3,
+            request_type: Type::FillOrKill,
+            ..Default::default()
+        },
+        min_size: 5,
+    };
+    // only the second buyer is big enough, which is not enough for fill or kill
+    let matching_result = dark_book.match_order(&dark_order);
+    assert_eq!(
+        matching_result.request_actions,
+        vec![RequestAction::Cancelled]
+    );
+    assert!(matching_result.market_actions.is_empty());
+    dark_order.request.request_type = Type::ImmediateOrCancel;
+    let matching_result = dark_book.match_order(&dark_order);
+    let expected = MatchingResult {
+        market_actions: vec![MarketAction {
+            size: 10,
+            price: 10,
+            seller_user_id: 3,
+            buyer_user_id: 2,
+            aggressor_side: Side::Sell,
+            ..Default::default()
+        }],
+        request_actions: vec![RequestAction::FilledPartially, RequestAction::Cancelled],
+        ..Default::default()
+    };
+    assert_eq!(matching_result, expected);
+    assert_eq!(dark_book.buyers.len(), 1);
+}
+
+#[test]
+fn test_dark_book_crosses_resting_requests_on_reference_update() {
+    let mut dark_book = DarkBook::default();
+    let mut dark_request = Request {
+        side: Side::Buy,
+        price: 10,
+        size: 2,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    // there is no reference yet
+    let matching_result = dark_book.match_request(&dark_request);
+    assert_eq!(
+        matching_result.request_actions,
+        vec![RequestAction::AddedToBook]
+    );
+    dark_request.side = Side::Sell;
+    dark_request.price = 8;
+    dark_request.user_id = 2;
+    dark_request.size = 1;
+    dark_book.match_request(&dark_request);
+    // the seller came later, so the midpoint of 9 and 12 is rounded down for the buyer
+    let market_actions = dark_book.update_reference(Bbo {
+        bid: Some(9),
+        ask: Some(12),
+    });
+    let expected = vec![MarketAction {
+        size: 1,
+        price: 10,
+        seller_user_id: 2,
+        buyer_user_id: 1,
+        aggressor_side: Side::Sell,
+        ..Default::default()
+    }];
+    assert_eq!(market_actions, expected);
+    assert_eq!(dark_book.sellers.len(), 0);
+    assert_eq!(dark_book.buyers[0].request.size, 1);
+}
+
+#[test]
+fn test_dark_book_rounds_odd_spread_for_passive_side() {
+    let mut dark_book = DarkBook::default();
+    dark_book.update_reference(Bbo {
+        bid: Some(9),
+        ask: Some(10),
+    });
+    assert_eq!(dark_book.midpoint(), Some(9));
+    assert_eq!(dark_book.execution_price(Side::Buy), Some(10));
+    assert_eq!(dark_book.execution_price(Side::Sell), Some(9));
+    let mut dark_request = Request {
+        side: Side::Sell,
+        price: 9,
+        size: 2,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    dark_book.match_request(&dark_request);
+    dark_request.side = Side::Buy;
+    dark_request.price = 10;
+    dark_request.size = 1;
+    dark_request.user_id = 2;
+    let matching_result = dark_book.match_request(&dark_request);
+    assert_eq!(matching_result.market_actions[0].price, 10);
+    // a buyer limited to 9 does not get the half tick from the resting seller
+    dark_request.price = 9;
+    let matching_result = dark_book.match_request(&dark_request);
+    assert!(matching_result.market_actions.is_empty());
+    dark_request.side = Side::Sell;
+    dark_request.user_id = 3;
+    let matching_result = dark_book.match_request(&dark_request);
+    let expected = vec![MarketAction {
+        size: 1,
+        price: 9,
+        seller_user_id: 3,
+        buyer_user_id: 2,
+        aggressor_side: Side::Sell,
+        ..Default::default()
+    }];
+    assert_eq!(matching_result.market_actions, expected);
+}
+
+#[test]
+fn test_dark_book_cancel_with_signed_prices() {
+    let mut dark_book = DarkBook::<i64, u64, u64>::new();
+    dark_book.update_reference(Bbo {
+        bid: Some(-3),
+        ask: Some(-1),
+    });
+    assert_eq!(dark_book.midpoint(), Some(-2));
+    let dark_request = Request {
+        id: 1,
+        side: Side::Sell,
+        price: -2,
+        size: 2,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    dark_book.match_request(&dark_request);
+    assert_eq!(dark_book.cancel_request(0), vec![]);
+    assert_eq!(
+        dark_book.cancel_request(1),
+        vec![BookEvent::Cancelled {
+            request: dark_request.clone()
+        }]
+    );
+    assert!(dark_book.sellers.is_empty());
+    let buyer = Request {
+        id: 2,
+        side: Side::Buy,
+        user_id: 2,
+        ..dark_request
+    };
+    let matching_result = dark_book.match_request(&buyer);
+    assert_eq!(
+        matching_result.request_actions,
+        vec![RequestAction::AddedToBook]
+    );
+}
+
+#[test]
+fn test_cancel_request() {
+    let mut book = OrderBook::default();
+    let mut limit_request = Request {
+        id: 1,
+        side: Side::Sell,
+        price: 2,
+        size: 1,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    book.match_request(&limit_request);
+    limit_request.id = 2;
+    book.match_request(&limit_request);
+    let events = book.cancel_request(1);
+    limit_request.id = 1;
+    assert_eq!(
+        events,
+        vec![BookEvent::Cancelled {
+            request: limit_request
+        }]
+    );
+    assert!(book.cancel_request(1).is_empty());
+    assert_eq!(book.sellers.len(), 1);
+    assert_eq!(book.sellers[0].id, 2);
+}
+
+#[test]
+fn test_oco_any_fill_cancels_other_leg() {
+    let mut book = OrderBook::default();
+    let first = Request {
+        id: 1,
+        side: Side::Sell,
+        price: 10,
+        size: 5,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    let second = Request {
+        id: 2,
+        side: Side::Buy,
+        price: 5,
+        size: 5,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    let (first_result, second_result) = book
+        .submit_oco(&first, &second, OcoTrigger::AnyFill)
+        .unwrap();
+    assert_eq!(
+        first_result.request_actions,
+        vec![RequestAction::AddedToBook]
+    );
+    assert_eq!(
+        second_result.unwrap().request_actions,
+        vec![RequestAction::AddedToBook]
+    );
+    let request = Request {
+        id: 3,
+        side: Side::Buy,
+        price: 10,
+        size: 1,
+        user_id: 2,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    let matching_result = book.match_request(&request);
+    let expected = MatchingResult {
+        market_actions: vec![MarketAction {
+            size: 1,
+            price: 10,
+            seller_user_id: 1,
+            buyer_user_id: 2,
+            seller_request_id: 1,
+            buyer_request_id: 3,
+            aggressor_side: Side::Buy,
+            ..Default::default()
+        }],
+        request_actions: vec![RequestAction::Filled],
+        book_events: vec![BookEvent::Cancelled { request: second }],
+    };
+    assert_eq!(matching_result, expected);
+    assert_eq!(book.buyers.len(), 0);
+    assert_eq!(book.sellers.len(), 1);
+    assert_eq!(book.sellers[0].size, 4);
+}
+
+#[test]
+fn test_oco_full_fill_and_immediate_trigger() {
+    let mut book = OrderBook::default();
+    let limit_request = Request {
+        id: 1,
+        side: Side::Sell,
+        price: 10,
+        size: 2,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    book.match_request(&limit_request);
+    let first = Request {
+        id: 2,
+        side: Side::Buy,
+        price: 10,
+        size: 3,
+        user_id: 2,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    let second = Request {
+        id: 3,
+        side: Side::Sell,
+        price: 20,
+        size: 3,
+        user_id: 2,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    // a partial fill of the first leg is not enough
+    let (first_result, second_result) = book
+        .submit_oco(&first, &second, OcoTrigger::FullFill)
+        .unwrap();
+    assert_eq!(
+        first_result.request_actions,
+        vec![RequestAction::FilledPartially, RequestAction::AddedToBook]
+    );
+    assert!(second_result.is_some());
+    assert_eq!(book.sellers.len(), 1);
+    assert_eq!(book.sellers[0].id, 3);
+    let request = Request {
+        id: 4,
+        side: Side::Sell,
+        price: 10,
+        size: 1,
+        user_id: 3,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    let matching_result = book.match_request(&request);
+    assert_eq!(
+        matching_result.book_events,
+        vec![BookEvent::Cancelled {
+            request: second.clone()
+        }]
+    );
+    assert_eq!(book.sellers.len(), 0);
+    assert_eq!(book.buyers.len(), 0);
+
+    // the second leg is never entered when the first one is filled right away
+    let request = Request {
+        id: 5,
+        side: Side::Sell,
+        price: 10,
+        size: 3,
+        user_id: 3,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    book.match_request(&request);
+    let first = Request { id: 6, ..first };
+    let second = Request { id: 7, ..second };
+    let (first_result, second_result) = book
+        .submit_oco(&first, &second, OcoTrigger::FullFill)
+        .unwrap();
+    assert_eq!(first_result.request_actions, vec![RequestAction::Filled]);
+    assert!(second_result.is_none());
+    assert_eq!(book.sellers.len(), 0);
+}
+
+#[test]
+fn test_bracket_take_profit() {
+    let mut book = OrderBook::default();
+    let bracket = Bracket {
+        entry: Request {
+            id: 1,
+            side: Side::Buy,
+            price: 10,
+            size: 2,
+            user_id: 1,
+            request_type: Type::Limit,
+            ..Default::default()
+        },
+        take_profit: Request {
+            id: 2,
+            side: Side::Sell,
+            price: 15,
+            size: 2,
+            user_id: 1,
+            request_type: Type::Limit,
+            ..Default::default()
+        },
+        stop_loss: Request {
+            id: 3,
+            side: Side::Sell,
+            price: 0,
+            size: 2,
+            user_id: 1,
+            request_type: Type::ImmediateOrCancel,
+            ..Default::default()
+        },
+        stop_price: 8,
+    };
+    let matching_result = book.submit_bracket(&bracket).unwrap();
+    assert_eq!(
+        matching_result.request_actions,
+        vec![RequestAction::AddedToBook]
+    );
+    // exits are not active before the entry is filled
+    assert_eq!(book.sellers.len(), 0);
+    let mut request = Request {
+        id: 4,
+        side: Side::Sell,
+        price: 10,
+        size: 2,
+        user_id: 2,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    let matching_result = book.match_request(&request);
+    assert_eq!(
+        matching_result.book_events,
+        vec![BookEvent::Activated {
+            request: bracket.take_profit.clone(),
+            request_actions: vec![RequestAction::AddedToBook],
+        }]
+    );
+    assert_eq!(book.sellers.len(), 1);
+    assert_eq!(book.sellers[0].id, 2);
+    // the take-profit is filled, the stop-loss is disarmed
+    request.side = Side::Buy;
+    request.price = 15;
+    request.id = 5;
+    book.match_request(&request);
+    assert_eq!(book.sellers.active().len(), 0);
+    assert!(book.links.is_empty());
+}
+
+#[test]
+fn test_bracket_stop_loss() {
+    let mut book = OrderBook::default();
+    for (id, price) in [(10, 9), (11, 7)].iter() {
+        let request = Request {
+            id: *id,
+            side: Side::Buy,
+            price: *price,
+            size: 2,
+            user_id: 3,
+            request_type: Type::Limit,
+            ..Default::default()
+        };
+        book.match_request(&request);
+    }
+    let limit_request = Request {
+        id: 4,
+        side: Side::Sell,
+        price: 10,
+        size: 2,
+        user_id: 2,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    book.match_request(&limit_request);
+    let bracket = Bracket {
+        entry: Request {
+            id: 1,
+            side: Side::Buy,
+            price: 10,
+            size: 2,
+            user_id: 1,
+            request_type: Type::Limit,
+            ..Default::default()
+        },
+        take_profit: Request {
+            id: 2,
+            side: Side::Sell,
+            price: 15,
+            size: 2,
+            user_id: 1,
+            request_type: Type::Limit,
+            ..Default::default()
+        },
+        stop_loss: Request {
+            id: 3,
+            side: Side::Sell,
+            price: 0,
+            size: 2,
+            user_id: 1,
+            request_type: Type::ImmediateOrCancel,
+            ..Default::default()
+        },
+        stop_price: 8,
+    };
+    // the entry is filled straight away and the exits are activated
+    let matching_result = book.submit_bracket(&bracket).unwrap();
+    assert_eq!(matching_result.request_actions, vec![RequestAction::Filled]);
+    assert_eq!(book.sellers.len(), 1);
+    assert_eq!(book.sellers[0].id, 2);
+    // a trade at 7 triggers the stop-loss, which sells to the best bid at 9
+    let request = Request {
+        id: 5,
+        side: Side::Sell,
+        price: 7,
+        size: 3,
+        user_id: 4,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    let matching_result = book.match_request(&request);
+    let expected = MatchingResult {
+        market_actions: vec![
+            MarketAction {
+                size: 2,
+                price: 9,
+                seller_user_id: 4,
+                buyer_user_id: 3,
+                seller_request_id: 5,
+                buyer_request_id: 10,
+                aggressor_side: Side::Sell,
+                ..Default::default()
+            },
+            MarketAction {
+                size: 1,
+                price: 7,
+                seller_user_id: 4,
+                buyer_user_id: 3,
+                seller_request_id: 5,
+                buyer_request_id: 11,
+                aggressor_side: Side::Sell,
+                ..Default::default()
+            },
+            MarketAction {
+                size: 1,
+                price: 7,
+                seller_user_id: 1,
+                buyer_user_id: 3,
+                seller_request_id: 3,
+                buyer_request_id: 11,
+                aggressor_side: Side::Sell,
+                ..Default::default()
+            },
+        ],
+        request_actions: vec![RequestAction::Filled],
+        book_events: vec![
+            BookEvent::Cancelled {
+                request: bracket.take_profit.clone(),
+            },
+            BookEvent::Activated {
+                request: bracket.stop_loss.clone(),
+                request_actions: vec![RequestAction::FilledPartially, RequestAction::Cancelled],
+            },
+        ],
+    };
+    assert_eq!(matching_result, expected);
+    assert_eq!(book.sellers.active().len(), 0);
+    assert_eq!(book.buyers.active().len(), 0);
+    assert!(book.links.is_empty());
+}
+
+#[test]
+fn test_bracket_ids_and_cancelled_entry() {
+    let mut book = OrderBook::default();
+    let mut bracket = Bracket {
+        entry: Request {
+            id: 1,
+            side: Side::Buy,
+            price: 10,
+            size: 4,
+            user_id: 1,
+            request_type: Type::Limit,
+            ..Default::default()
+        },
+        take_profit: Request {
+            id: 2,
+            side: Side::Sell,
+            price: 15,
+            size: 4,
+            user_id: 1,
+            request_type: Type::Limit,
+            ..Default::default()
+        },
+        stop_loss: Request {
+            id: 0,
+            side: Side::Sell,
+            price: 0,
+            size: 4,
+            user_id: 1,
+            request_type: Type::ImmediateOrCancel,
+            ..Default::default()
+        },
+        stop_price: 8,
+    };
+    assert_eq!(book.submit_bracket(&bracket), Err(GroupError::MissingId));
+    bracket.stop_loss.id = 2;
+    assert_eq!(book.submit_bracket(&bracket), Err(GroupError::DuplicateId));
+    bracket.stop_loss.id = 3;
+    book.submit_bracket(&bracket).unwrap();
+    assert_eq!(
+        book.submit_oco(&bracket.take_profit, &bracket.entry, OcoTrigger::AnyFill),
+        Err(GroupError::DuplicateId)
+    );
+    let mut request = Request {
+        id: 4,
+        side: Side::Sell,
+        price: 10,
+        size: 1,
+        user_id: 2,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    book.match_request(&request);
+    // exits are activated for the filled piece of the cancelled entry
+    let events = book.cancel_request(1);
+    let take_profit = Request {
+        size: 1,
+        ..bracket.take_profit.clone()
+    };
+    assert_eq!(
+        events,
+        vec![
+            BookEvent::Cancelled {
+                request: Request {
+                    size: 3,
+                    ..bracket.entry.clone()
+                }
+            },
+            BookEvent::Activated {
+                request: take_profit,
+                request_actions: vec![RequestAction::AddedToBook],
+            }
+        ]
+    );
+    assert_eq!(book.sellers[0].size, 1);
+    assert!(!book.links.is_empty());
+    request.side = Side::Buy;
+    request.price = 15;
+    request.id = 5;
+    book.match_request(&request);
+    assert!(book.links.is_empty());
+
+    // so are they for the filled piece of an immediate-or-cancel entry
+    let request = Request {
+        id: 6,
+        side: Side::Sell,
+        price: 10,
+        ..request
+    };
+    book.match_request(&request);
+    let bracket = Bracket {
+        entry: Request {
+            id: 7,
+            request_type: Type::ImmediateOrCancel,
+            ..bracket.entry
+        },
+        take_profit: Request {
+            id: 8,
+            ..bracket.take_profit
+        },
+        stop_loss: Request {
+            id: 9,
+            ..bracket.stop_loss
+        },
+        stop_price: 8,
+    };
+    let result = book.submit_bracket(&bracket).unwrap();
+    assert_eq!(result.market_actions.len(), 1);
+    assert_eq!(
+        result.book_events,
+        vec![BookEvent::Activated {
+            request: Request {
+                size: 1,
+                ..bracket.take_profit.clone()
+            },
+            request_actions: vec![RequestAction::AddedToBook],
+        }]
+    );
+    assert!(!book.links.is_empty());
+}
+
+#[test]
+fn test_cancel_user_requests_with_filter() {
+    let mut book = OrderBook::default();
+    for (id, side, price, user_id) in [
+        (1, Side::Buy, 1, 1),
+        (2, Side::Buy, 2, 1),
+        (3, Side::Buy, 3, 2),
+        (4, Side::Sell, 5, 1),
+        (5, Side::Sell, 6, 1),
+    ]
+    .iter()
+    {
+        let request = Request {
+            id: *id,
+            side: *side,
+            price: *price,
+            size: 1,
+            user_id: *user_id,
+            request_type: Type::Limit,
+            ..Default::default()
+        };
+        book.match_request(&request);
+    }
+    let filter = CancelFilter {
+        min_price: Some(2),
+        max_price: Some(5),
+        ..Default::default()
+    };
+    let events = book.cancel_user_requests(1, &filter);
+    let cancelled_ids = events
+        .iter()
+        .map(|event| match event {
+            BookEvent::Cancelled { request } => request.id,
+            _ => panic!("unexpected event {:?}", event),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(cancelled_ids, vec![2, 4]);
+    let filter = CancelFilter {
+        side: Some(Side::Sell),
+        ..Default::default()
+    };
+    assert_eq!(book.cancel_user_requests(1, &filter).len(), 1);
+    assert_eq!(book.buyers.len(), 2);
+    assert_eq!(book.sellers.len(), 0);
+}
+
+#[test]
+fn test_kill_switch() {
+    let mut exchange = Exchange::default();
+    exchange.add_book("AAA");
+    exchange.add_book("BBB");
+    let mut request = Request {
+        id: 1,
+        side: Side::Buy,
+        price: 1,
+        size: 1,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    exchange.submit("AAA", &request).unwrap();
+    request.id = 2;
+    exchange.submit("BBB", &request).unwrap();
+    request.id = 3;
+    request.user_id = 2;
+    exchange.submit("BBB", &request).unwrap();
+    assert_eq!(exchange.submit("CCC", &request), Err(Reject::UnknownBook));
+
+    let events = exchange.kill_switch(1);
+    assert_eq!(events.len(), 2);
+    assert_eq!(events["AAA"].len(), 1);
+    assert_eq!(events["BBB"].len(), 1);
+    assert_eq!(exchange.book("AAA").unwrap().buyers.len(), 0);
+    assert_eq!(exchange.book("BBB").unwrap().buyers.len(), 1);
+    request.user_id = 1;
+    assert_eq!(exchange.submit("AAA", &request), Err(Reject::UserBlocked));
+    exchange.enable_user(1);
+    assert!(exchange.submit("AAA", &request).is_ok());
+}
+
+#[test]
+fn test_cancel_on_disconnect() {
+    let mut exchange = Exchange::default();
+    exchange.add_book("AAA");
+    let cancelling_session = exchange.connect(1, true);
+    let keeping_session = exchange.connect(1, false);
+    let mut request = Request {
+        id: 1,
+        side: Side::Sell,
+        price: 10,
+        size: 2,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    exchange
+        .submit_from_session(cancelling_session, "AAA", &request)
+        .unwrap();
+    request.id = 2;
+    exchange
+        .submit_from_session(keeping_session, "AAA", &request)
+        .unwrap();
+    request.user_id = 2;
+    assert_eq!(
+        exchange.submit_from_session(keeping_session, "AAA", &request),
+        Err(Reject::UserMismatch)
+    );
+    let events = exchange.disconnect(cancelling_session);
+    assert_eq!(events["AAA"].len(), 1);
+    assert!(exchange.disconnect(keeping_session).is_empty());
+    let sellers = &exchange.book("AAA").unwrap().sellers;
+    assert_eq!(sellers.len(), 1);
+    assert_eq!(sellers[0].id, 2);
+    assert_eq!(
+        exchange.submit_from_session(cancelling_session, "AAA", &request),
+        Err(Reject::UnknownSession)
+    );
+}
+
+#[test]
+fn test_market_maker_protection() {
+    let mut exchange = Exchange::default();
+    exchange.add_book("AAA");
+    exchange.set_mmp(
+        1,
+        MmpConfig {
+            window: 1000,
+            volume_limit: 3,
+        },
+    );
+    let quote = Quote {
+        user_id: 1,
+        bid_id: 1,
+        bid_price: 5,
+        bid_size: 10,
+        ask_id: 2,
+        ask_price: 10,
+        ask_size: 10,
+    };
+    exchange.quote("AAA", &quote).unwrap();
+    let mut request = Request {
+        id: 3,
+        side: Side::Buy,
+        price: 10,
+        size: 2,
+        user_id: 2,
+        request_type: Type::ImmediateOrCancel,
+        ..Default::default()
+    };
+    exchange.set_time(0);
+    let matching_result = exchange.submit("AAA", &request).unwrap();
+    assert!(matching_result.book_events.is_empty());
+    // the first execution is out of the window by now
+    exchange.set_time(1000);
+    let matching_result = exchange.submit("AAA", &request).unwrap();
+    assert!(matching_result.book_events.is_empty());
+    exchange.set_time(1500);
+    let matching_result = exchange.submit("AAA", &request).unwrap();
+    assert_eq!(matching_result.market_actions.len(), 1);
+    assert_eq!(matching_result.book_events.len(), 2);
+    assert_eq!(exchange.book("AAA").unwrap().sellers.len(), 0);
+    assert_eq!(exchange.book("AAA").unwrap().buyers.len(), 0);
+
+    // only quotes are refused
+    assert_eq!(exchange.quote("AAA", &quote), Err(Reject::MmpTriggered));
+    request.user_id = 1;
+    request.side = Side::Sell;
+    assert!(exchange.submit("AAA", &request).is_ok());
+    exchange.reset_mmp("AAA", 1);
+    assert!(exchange.quote("AAA", &quote).is_ok());
+}
+
+#[test]
+fn test_quote_replaces_previous_quote() {
+    let mut book = OrderBook::default();
+    let limit_request = Request {
+        id: 1,
+        side: Side::Buy,
+        price: 5,
+        size: 1,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    book.match_request(&limit_request);
+    let quote = Quote {
+        user_id: 1,
+        bid_id: 2,
+        bid_price: 5,
+        bid_size: 10,
+        ask_id: 3,
+        ask_price: 7,
+        ask_size: 10,
+    };
+    let quote_result = book.quote(&quote).unwrap();
+    assert!(quote_result.book_events.is_empty());
+    assert_eq!(
+        quote_result.bid.unwrap().request_actions,
+        vec![RequestAction::AddedToBook]
+    );
+    assert_eq!(book.buyers[1].request_type, Type::Quote);
+    // the new quote takes the place of the old one, ordinary requests stay
+    let new_quote = Quote {
+        bid_id: 4,
+        ask_id: 5,
+        bid_price: 6,
+        ask_size: 0,
+        ..quote
+    };
+    let quote_result = book.quote(&new_quote).unwrap();
+    let cancelled_ids = quote_result
+        .book_events
+        .iter()
+        .map(|event| match event {
+            BookEvent::Cancelled { request } => request.id,
+            _ => panic!("unexpected event {:?}", event),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(cancelled_ids, vec![2, 3]);
+    assert!(quote_result.ask.is_none());
+    let buyer_ids = book.buyers.iter().map(|r| r.id).collect::<Vec<_>>();
+    assert_eq!(buyer_ids, vec![4, 1]);
+    assert_eq!(book.sellers.len(), 0);
+    // a quote may trade like a limit request
+    let request = Request {
+        id: 6,
+        side: Side::Sell,
+        price: 7,
+        size: 4,
+        user_id: 2,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    book.match_request(&request);
+    let quote = Quote {
+        user_id: 3,
+        bid_id: 7,
+        bid_price: 7,
+        bid_size: 1,
+        ..Default::default()
+    };
+    let quote_result = book.quote(&quote).unwrap();
+    assert_eq!(quote_result.market_actions().count(), 1);
+    assert_eq!(
+        quote_result.bid.unwrap().request_actions,
+        vec![RequestAction::Filled]
+    );
+}
+
+#[test]
+fn test_quote_checks() {
+    let mut book = OrderBook::default();
+    let quote = Quote {
+        user_id: 1,
+        bid_id: 1,
+        bid_price: 7,
+        bid_size: 10,
+        ask_id: 2,
+        ask_price: 7,
+        ask_size: 10,
+    };
+    assert_eq!(book.quote(&quote), Err(QuoteError::Crossed));
+    let mut exchange = Exchange::default();
+    exchange.add_book("AAA");
+    assert_eq!(exchange.quote("AAA", &quote), Err(Reject::CrossedQuote));
+    // legs need ids which no other request has
+    let order = Request {
+        id: 7,
+        side: Side::Buy,
+        price: 1,
+        size: 1,
+        user_id: 2,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    exchange.submit("AAA", &order).unwrap();
+    let ids = |bid_id, ask_id| Quote {
+        bid_id,
+        ask_id,
+        ask_price: 8,
+        ..quote.clone()
+    };
+    assert_eq!(exchange.quote("AAA", &ids(0, 2)), Err(Reject::MissingId));
+    assert_eq!(exchange.quote("AAA", &ids(2, 2)), Err(Reject::DuplicateId));
+    assert_eq!(exchange.quote("AAA", &ids(7, 2)), Err(Reject::DuplicateId));
+    exchange.quote("AAA", &ids(1, 2)).unwrap();
+    exchange.quote("AAA", &ids(2, 1)).unwrap();
+    let empty = Request {
+        id: 8,
+        size: 0,
+        ..order
+    };
+    assert_eq!(exchange.submit("AAA", &empty), Err(Reject::ZeroSize));
+    let error: Box<dyn std::error::Error> = Box::new(Reject::ZeroSize);
+    assert_eq!(
+        error.to_string(),
+        "Request was rejected: the request has no size"
+    );
+    // a leg of a group replaced by a new quote leaves the group
+    let leg = Request {
+        id: 3,
+        side: Side::Buy,
+        price: 5,
+        size: 1,
+        user_id: 1,
+        request_type: Type::Quote,
+        ..Default::default()
+    };
+    let other_leg = Request {
+        id: 4,
+        side: Side::Sell,
+        price: 9,
+        ..leg.clone()
+    };
+    book.submit_oco(&leg, &other_leg, OcoTrigger::AnyFill)
+        .unwrap();
+    let quote = Quote {
+        ask_size: 0,
+        ..quote
+    };
+    book.quote(&quote).unwrap();
+    assert!(book.links.is_empty());
+}
+
+#[test]
+fn test_risk_limits_on_single_request() {
+    let mut exchange = Exchange::default();
+    exchange.add_book("AAA");
+    exchange.set_risk_limits(
+        1,
+        RiskLimits {
+            max_order_size: Some(10),
+            max_notional: Some(50),
+            max_open_orders: Some(1),
+            ..Default::default()
+        },
+    );
+    let mut request = Request {
+        id: 1,
+        side: Side::Buy,
+        price: 5,
+        size: 11,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    assert_eq!(
+        exchange.submit("AAA", &request),
+        Err(Reject::Risk(RiskBreach::OrderSize {
+            limit: 10,
+            value: 11
+        }))
+    );
+    request.price = 6;
+    request.size = 9;
+    assert_eq!(
+        exchange.submit("AAA", &request),
+        Err(Reject::Risk(RiskBreach::Notional {
+            limit: 50,
+            value: 54
+        }))
+    );
+    assert_eq!(exchange.book("AAA").unwrap().buyers.len(), 0);
+    request.price = 5;
+    assert!(exchange.submit("AAA", &request).is_ok());
+    request.id = 2;
+    assert_eq!(
+        exchange.submit("AAA", &request),
+        Err(Reject::Risk(RiskBreach::OpenOrders { limit: 1, value: 2 }))
+    );
+    // a request which never rests is not an open order
+    request.request_type = Type::ImmediateOrCancel;
+    assert!(exchange.submit("AAA", &request).is_ok());
+    // other users are not limited
+    request.user_id = 2;
+    request.size = 100;
+    assert!(exchange.submit("AAA", &request).is_ok());
+}
+
+#[test]
+fn test_risk_limits_on_position_and_rate() {
+    let mut exchange = Exchange::default();
+    exchange.add_book("AAA");
+    exchange.add_book("BBB");
+    exchange.set_risk_limits(
+        1,
+        RiskLimits {
+            max_net_position: Some(5),
+            max_orders_per_second: Some(2),
+            ..Default::default()
+        },
+    );
+    let resting_request = Request {
+        id: 1,
+        side: Side::Sell,
+        price: 5,
+        size: 10,
+        user_id: 2,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    exchange.submit("AAA", &resting_request).unwrap();
+    let mut request = Request {
+        id: 2,
+        side: Side::Buy,
+        price: 5,
+        size: 4,
+        user_id: 1,
+        request_type: Type::ImmediateOrCancel,
+        ..Default::default()
+    };
+    exchange.set_time(0);
+    assert_eq!(
+        exchange
+            .submit("AAA", &request)
+            .unwrap()
+            .market_actions
+            .len(),
+        1
+    );
+    assert_eq!(
+        exchange.submit("AAA", &request),
+        Err(Reject::Risk(RiskBreach::NetPosition { limit: 5, value: 8 }))
+    );
+    // positions are kept per book
+    assert!(exchange.submit("BBB", &request).is_ok());
+    request.side = Side::Sell;
+    exchange.set_time(999);
+    assert_eq!(
+        exchange.submit("AAA", &request),
+        Err(Reject::Risk(RiskBreach::OrdersPerSecond {
+            limit: 2,
+            value: 3
+        }))
+    );
+    exchange.set_time(1000);
+    assert!(exchange.submit("AAA", &request).is_ok());
+}
+
+fn exchange_with_accounts() -> Exchange {
+    let mut accounts = Accounts::default();
+    accounts.add_instrument(
+        "BTCUSD",
+        Instrument {
+            base: "BTC".to_string(),
+            quote: "USD".to_string(),
+        },
+    );
+    accounts.deposit(1, "USD", 100);
+    accounts.deposit(2, "BTC", 10);
+    let mut exchange = Exchange::default();
+    exchange.add_book("BTCUSD");
+    exchange.set_accounts(accounts);
+    exchange
+}
+
+#[test]
+fn test_accounts_reserve_and_release() {
+    let mut exchange = exchange_with_accounts();
+    let mut request = Request {
+        id: 1,
+        side: Side::Buy,
+        price: 11,
+        size: 10,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    assert_eq!(
+        exchange.submit("BTCUSD", &request),
+        Err(Reject::InsufficientFunds)
+    );
+    assert!(exchange.book("BTCUSD").unwrap().buyers.is_empty());
+    request.price = 10;
+    exchange.submit("BTCUSD", &request).unwrap();
+    let accounts = exchange.accounts().unwrap();
+    assert_eq!(
+        accounts.balance(1, "USD"),
+        Balance {
+            available: 0,
+            reserved: 100
+        }
+    );
+    exchange.cancel_request("BTCUSD", 1).unwrap();
+    let accounts = exchange.accounts_mut().unwrap();
+    assert_eq!(accounts.balance(1, "USD").available, 100);
+    assert_eq!(accounts.balance(1, "USD").reserved, 0);
+    assert!(accounts.withdraw(1, "USD", 100));
+    assert!(!accounts.withdraw(1, "USD", 1));
+
+    request.id = 0;
+    assert_eq!(exchange.submit("BTCUSD", &request), Err(Reject::MissingId));
+}
+
+#[test]
+fn test_accounts_reject_duplicate_ids() {
+    let mut exchange = exchange_with_accounts();
+    let request = Request {
+        id: 1,
+        side: Side::Buy,
+        price: 10,
+        size: 5,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    exchange.submit("BTCUSD", &request).unwrap();
+    // a second reservation under the same id would never be released
+    assert_eq!(
+        exchange.submit("BTCUSD", &request),
+        Err(Reject::DuplicateId)
+    );
+    assert_eq!(exchange.cancel_request("BTCUSD", 1).unwrap().len(), 1);
+    assert!(exchange.cancel_request("BTCUSD", 1).unwrap().is_empty());
+    let accounts = exchange.accounts().unwrap();
+    assert_eq!(
+        accounts.balance(1, "USD"),
+        Balance {
+            available: 100,
+            reserved: 0
+        }
+    );
+    assert!(exchange.book("BTCUSD").unwrap().buyers.is_empty());
+}
+
+#[test]
+fn test_accounts_settle_trades() {
+    let mut exchange = exchange_with_accounts();
+    let sell_request = Request {
+        id: 1,
+        side: Side::Sell,
+        price: 8,
+        size: 10,
+        user_id: 2,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    exchange.submit("BTCUSD", &sell_request).unwrap();
+    let buy_request = Request {
+        id: 2,
+        side: Side::Buy,
+        price: 10,
+        size: 5,
+        user_id: 1,
+        request_type: Type::ImmediateOrCancel,
+        ..Default::default()
+    };
+    exchange.submit("BTCUSD", &buy_request).unwrap();
+    let accounts = exchange.accounts().unwrap();
+    // the buyer pays the execution price, not the reserved one
+    assert_eq!(accounts.balance(1, "USD").available, 60);
+    assert_eq!(accounts.balance(1, "USD").reserved, 0);
+    assert_eq!(accounts.balance(1, "BTC").available, 5);
+    assert_eq!(
+        accounts.balance(2, "BTC"),
+        Balance {
+            available: 0,
+            reserved: 5
+        }
+    );
+    assert_eq!(accounts.balance(2, "USD").available, 40);
+
+    let events = exchange.kill_switch(2);
+    assert_eq!(events["BTCUSD"].len(), 1);
+    let accounts = exchange.accounts().unwrap();
+    assert_eq!(accounts.balance(2, "BTC").available, 5);
+    assert_eq!(accounts.balance(2, "BTC").reserved, 0);
+}
+
+#[test]
+fn test_fee_schedule() {
+    let mut schedule = FeeSchedule {
+        default: FeeTier {
+            maker: 10,
+            taker: 20,
+        },
+        ..Default::default()
+    };
+    schedule.users.insert(
+        1,
+        FeeTier {
+            maker: -5,
+            taker: 15,
+        },
+    );
+    let mut action = MarketAction {
+        size: 3,
+        price: 1001,
+        seller_user_id: 1,
+        buyer_user_id: 2,
+        aggressor_side: Side::Buy,
+        ..Default::default()
+    };
+    schedule.apply(&mut action).unwrap();
+    // 3003 at 0.2% is 6.006 and at -0.05% is -1.5015
+    assert_eq!(action.buyer_fee, 7);
+    assert_eq!(action.seller_fee, -1);
+    action.aggressor_side = Side::Sell;
+    schedule.apply(&mut action).unwrap();
+    assert_eq!(action.buyer_fee, 4);
+    assert_eq!(action.seller_fee, 5);
+
+    action.price = 2;
+    action.size = u64::MAX;
+    assert_eq!(schedule.apply(&mut action), Err(Overflow::Notional));
+    action.price = 1;
+    schedule.default.maker = i64::MAX;
+    assert_eq!(schedule.apply(&mut action), Err(Overflow::Balance));
+    assert_eq!(action.buyer_fee, 4);
+}
+
+#[test]
+fn test_fees_are_paid_through_accounts() {
+    let mut exchange = exchange_with_accounts();
+    exchange.set_fee_schedule(FeeSchedule {
+        default: FeeTier {
+            maker: -10,
+            taker: 100,
+        },
+        ..Default::default()
+    });
+    let sell_request = Request {
+        id: 1,
+        side: Side::Sell,
+        price: 10,
+        size: 10,
+        user_id: 2,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    exchange.submit("BTCUSD", &sell_request).unwrap();
+    let mut buy_request = Request {
+        id: 2,
+        side: Side::Buy,
+        price: 10,
+        size: 10,
+        user_id: 1,
+        request_type: Type::ImmediateOrCancel,
+        ..Default::default()
+    };
+    // the taker fee has to be reserved as well
+    assert_eq!(
+        exchange.submit("BTCUSD", &buy_request),
+        Err(Reject::InsufficientFunds)
+    );
+    buy_request.size = 5;
+    let result = exchange.submit("BTCUSD", &buy_request).unwrap();
+    assert_eq!(result.market_actions[0].buyer_fee, 1);
+    assert_eq!(result.market_actions[0].seller_fee, 0);
+    let accounts = exchange.accounts().unwrap();
+    assert_eq!(accounts.balance(1, "USD").available, 49);
+    assert_eq!(accounts.balance(1, "USD").reserved, 0);
+    assert_eq!(accounts.balance(2, "USD").available, 50);
+    assert_eq!(accounts.collected_fees("USD"), 1);
+}
+
+#[test]
+fn test_unsettled_executions() {
+    let mut exchange = exchange_with_accounts();
+    exchange.set_fee_schedule(FeeSchedule {
+        default: FeeTier {
+            maker: 20000,
+            taker: 0,
+        },
+        ..Default::default()
+    });
+    let sell_request = Request {
+        id: 1,
+        side: Side::Sell,
+        price: 10,
+        size: 10,
+        user_id: 2,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    exchange.submit("BTCUSD", &sell_request).unwrap();
+    let buy_request = Request {
+        id: 2,
+        side: Side::Buy,
+        price: 10,
+        size: 2,
+        user_id: 1,
+        request_type: Type::ImmediateOrCancel,
+        ..Default::default()
+    };
+    let result = exchange.submit("BTCUSD", &buy_request).unwrap();
+    // the fee of the seller is twice the proceeds, which it has nothing to pay with
+    assert_eq!(result.market_actions[0].seller_fee, 40);
+    let unsettled = exchange.take_unsettled();
+    assert_eq!(
+        unsettled,
+        vec![UnsettledExecution {
+            symbol: "BTCUSD".to_string(),
+            action: result.market_actions[0].clone(),
+            error: SettlementError::InsufficientFunds {
+                user_id: 2,
+                asset: "USD".to_string()
+            },
+        }]
+    );
+    assert!(exchange.take_unsettled().is_empty());
+    let accounts = exchange.accounts().unwrap();
+    assert_eq!(accounts.balance(1, "USD").available, 100);
+    assert_eq!(accounts.balance(1, "BTC").available, 0);
+    assert_eq!(accounts.balance(2, "USD").available, 0);
+    assert_eq!(accounts.balance(2, "BTC").reserved, 10);
+    assert_eq!(accounts.collected_fees("USD"), 0);
+}
+
+#[test]
+fn test_position_keeper() {
+    let mut book = OrderBook::default();
+    let mut keeper = PositionKeeper::default();
+    let mut trade = |book: &mut OrderBook, side, price, size, user_id| {
+        let request = Request {
+            side,
+            price,
+            size,
+            user_id,
+            request_type: Type::Limit,
+            ..Default::default()
+        };
+        keeper.record_result(&book.match_request(&request)).unwrap();
+    };
+    trade(&mut book, Side::Sell, 10, 2, 2);
+    trade(&mut book, Side::Buy, 10, 2, 1);
+    trade(&mut book, Side::Sell, 14, 2, 2);
+    trade(&mut book, Side::Buy, 14, 2, 1);
+    trade(&mut book, Side::Buy, 16, 3, 3);
+    trade(&mut book, Side::Sell, 16, 3, 1);
+
+    // bought 2 at 10 and 2 at 14, then sold 3 at 16
+    let position = keeper.position(1);
+    assert_eq!(position.net, 1);
+    assert_eq!(position.average_price(), Some(12.0));
+    assert_eq!(position.realized_pnl, 12);
+    assert_eq!(keeper.mark_price(), Some(16));
+    assert_eq!(keeper.unrealized_pnl(1), Some(4));
+    let position = keeper.position(2);
+    assert_eq!(position.net, -4);
+    assert_eq!(position.average_price(), Some(12.0));
+    assert_eq!(position.realized_pnl, 0);
+    assert_eq!(keeper.unrealized_pnl(2), Some(-16));
+}
+
+#[test]
+fn test_position_flips_side_and_marks_to_midpoint() {
+    let mut keeper = PositionKeeper::new(Mark::Midpoint);
+    let mut action = MarketAction {
+        size: 2,
+        price: 10,
+        seller_user_id: 2,
+        buyer_user_id: 1,
+        ..Default::default()
+    };
+    keeper.record(&action).unwrap();
+    action.size = 5;
+    action.price = 12;
+    action.seller_user_id = 1;
+    action.buyer_user_id = 2;
+    keeper.record(&action).unwrap();
+    let position = keeper.position(1);
+    assert_eq!(position.net, -3);
+    assert_eq!(position.realized_pnl, 4);
+    assert_eq!(position.average_price(), Some(12.0));
+    assert_eq!(keeper.unrealized_pnl(1), None);
+    keeper.update_bbo(Bbo {
+        bid: Some(10),
+        ask: Some(13),
+    });
+    assert_eq!(keeper.mark_price(), Some(11));
+    assert_eq!(keeper.unrealized_pnl(1), Some(3));
+
+    let snapshot = keeper.snapshot();
+    assert_eq!(snapshot.positions.len(), 2);
+    assert_eq!(snapshot.positions[0].unrealized_pnl, Some(3));
+    let json = serde_json::to_string(&snapshot).unwrap();
+    assert_eq!(
+        serde_json::from_str::<PositionSnapshot>(&json).unwrap(),
+        snapshot
+    );
+}
+
+#[test]
+fn test_decimal_parsing_and_display() {
+    let decimal: Decimal = "-12.0340".parse().unwrap();
+    assert_eq!(decimal, Decimal::new(-120340, 4));
+    assert_eq!(decimal.to_string(), "-12.0340");
+    assert_eq!(Decimal::new(5, 8).to_string(), "0.00000005");
+    assert_eq!(decimal.rescale(3), Ok(Decimal::new(-12034, 3)));
+    assert_eq!(decimal.rescale(2), Err(DecimalError::TooPrecise));
+    assert_eq!(decimal.rescale(6), Ok(Decimal::new(-12034000, 6)));
+    for malformed in &["", "-", ".5", "1.", "1.2.3", "1e5", "+1"] {
+        assert_eq!(
+            malformed.parse::<Decimal>(),
+            Err(DecimalError::Malformed),
+            "{}",
+            malformed
+        );
+    }
+    let parsed: Vec<Decimal> = serde_json::from_str(r#"["1.50", 7]"#).unwrap();
+    assert_eq!(parsed, vec![Decimal::new(150, 2), Decimal::new(7, 0)]);
+    assert_eq!(serde_json::to_string(&parsed).unwrap(), r#"["1.50","7"]"#);
+    // equal by value whatever the scale
+    assert_eq!("1.0".parse::<Decimal>(), "1.00".parse());
+    assert_ne!(Decimal::new(101, 2), Decimal::new(1, 0));
+    let set: std::collections::HashSet<Decimal> = parsed.iter().copied().collect();
+    assert!(set.contains(&"1.5".parse().unwrap()));
+}
+
+#[test]
+fn test_decimal_requests() {
+    let scale = Scale { price: 4, size: 8 };
+    let mut exchange = Exchange::default();
+    exchange.add_book("ETHUSD");
+    exchange.set_scale("ETHUSD", scale);
+    let mut request: DecimalRequest = serde_json::from_str(
+        r#"{"side":"Sell","price":"2000.5","size":"0.25","user_id":1,"request_type":"Limit"}"#,
+    )
+    .unwrap();
+    exchange.submit_decimal("ETHUSD", &request).unwrap();
+    assert_eq!(exchange.book("ETHUSD").unwrap().sellers[0].price, 20005000);
+    assert_eq!(exchange.book("ETHUSD").unwrap().sellers[0].size, 25000000);
+
+    request.side = Side::Buy;
+    request.user_id = 2;
+    request.size = "0.000000001".parse().unwrap();
+    assert_eq!(
+        exchange.submit_decimal("ETHUSD", &request),
+        Err(Reject::Decimal(DecimalError::TooPrecise))
+    );
+    request.size = "0.1".parse().unwrap();
+    let result = exchange.submit_decimal("ETHUSD", &request).unwrap();
+    let action = scale.market_action(&result.market_actions[0]);
+    let notional = action.price.checked_mul(action.size).unwrap();
+    assert_eq!(notional.to_string(), "200.050000000000");
+    #[cfg(feature = "display")]
+    assert_eq!(
+        scale.display(&result.market_actions[0]).to_string(),
+        "User #1 sold 0.10000000 pieces at price point '2000.5000' to user #2"
+    );
+    request.peg = Some(Peg {
+        reference: PegReference::Primary,
+        offset: -2,
+        limit: Some("2000.25".parse().unwrap()),
+    });
+    let request = scale.request(&request).unwrap();
+    assert_eq!(request.peg.unwrap().limit, Some(20002500));
+    assert_eq!(scale.request(&scale.decimal_request(&request)), Ok(request));
+}
+
+#[test]
+fn test_decimal_book() {
+    let requests: Vec<Request<Decimal, Decimal>> = serde_json::from_str(
+        r#"[
+            {"id": 1, "side": "Sell", "price": 10.5, "size": "2", "user_id": 1,
+             "request_type": "Limit"},
+            {"id": 2, "side": "Sell", "price": "10.25", "size": 1.5, "user_id": 1,
+             "request_type": "Limit"},
+            {"id": 3, "side": "Buy", "price": 11, "size": 2.5, "user_id": 2,
+             "request_type": "Limit"}
+        ]"#,
+    )
+    .unwrap();
+    assert_eq!(requests[0].price, "10.50".parse().unwrap());
+    let mut book = OrderBook::<Decimal, Decimal>::new();
+    let mut results = Vec::new();
+    for request in requests.iter() {
+        results.push(book.match_request(request));
+    }
+    let actions = &results[2].market_actions;
+    assert_eq!(actions.len(), 2);
+    assert_eq!(actions[0].price, "10.25".parse().unwrap());
+    assert_eq!(actions[1].size, "1".parse().unwrap());
+    assert_eq!(
+        book.resting_request(1).unwrap().size,
+        "1.0".parse().unwrap()
+    );
+
+    let (low, high): (Decimal, Decimal) = ("10.25".parse().unwrap(), "10.5".parse().unwrap());
+    assert!(low < high && Decimal::new(i128::MAX, 0) > Decimal::new(1, 30));
+    assert_eq!(Decimal::middle(low, high, false).to_string(), "10.375");
+    assert_eq!(Decimal::middle(low, low, true).to_string(), "10.25");
+    assert_eq!(low.offset(-1).unwrap().to_string(), "10.24");
+}
+
+#[test]
+fn test_negative_prices() {
+    let mut book = OrderBook::<i64>::new();
+    let mut request = Request {
+        id: 1,
+        side: Side::Sell,
+        price: -3,
+        size: 2,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    book.match_request(&request);
+    request.id = 2;
+    request.price = -5;
+    book.match_request(&request);
+    request.id = 3;
+    request.side = Side::Buy;
+    request.price = -8;
+    request.user_id = 2;
+    book.match_request(&request);
+    assert_eq!(
+        book.sellers.iter().map(|r| r.price).collect::<Vec<_>>(),
+        vec![-5, -3]
+    );
+    assert_eq!(
+        book.bbo(),
+        Bbo {
+            bid: Some(-8),
+            ask: Some(-5)
+        }
+    );
+
+    request.id = 4;
+    request.peg = Some(Peg {
+        reference: PegReference::Midpoint,
+        offset: 0,
+        limit: None,
+    });
+    let result = book.match_request(&request);
+    assert_eq!(result.request_actions, vec![RequestAction::AddedToBook]);
+    assert_eq!(book.buyers[0].price, -7);
+
+    request.peg = None;
+    request.id = 5;
+    request.price = -4;
+    request.size = 3;
+    let result = book.match_request(&request);
+    assert_eq!(result.market_actions.len(), 1);
+    assert_eq!(result.market_actions[0].price, -5);
+    assert_eq!(book.buyers[0].price, -4);
+    #[cfg(feature = "display")]
+    assert_eq!(result.to_string().lines().count(), 2);
+}
+
+#[test]
+fn test_price_offsets_and_midpoints() {
+    assert_eq!(0u64.offset(-1), None);
+    assert_eq!(0i64.offset(-1), Some(-1));
+    assert_eq!(i64::MAX.offset(1), None);
+    assert_eq!(u64::middle(3, 6, false), 4);
+    assert_eq!(u64::middle(3, 6, true), 5);
+    assert_eq!(i64::middle(-6, -3, false), -5);
+    assert_eq!(i64::middle(-6, -3, true), -4);
+    assert_eq!(i64::middle(i64::MIN, i64::MAX, false), -1);
+}
+
+#[test]
+fn test_compact_book() {
+    let mut book = OrderBook::<u32, u32, u32>::new();
+    let mut request = Request {
+        id: 1,
+        side: Side::Sell,
+        price: 7,
+        size: 5,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    book.match_request(&request);
+    request.id = 2;
+    request.side = Side::Buy;
+    request.size = 3;
+    request.user_id = 2;
+    let result = book.match_request(&request);
+    assert_eq!(result.request_actions, vec![RequestAction::Filled]);
+    assert_eq!(result.market_actions[0].size, 3u32);
+    assert_eq!(book.sellers[0].size, 2u32);
+    assert_eq!(book.depth(1).asks[0].size, 2u32);
+    // what makes the compact book worth it: 56 bytes a request against 80
+    let compact_size = std::mem::size_of::<Request<u32, u32, u32>>();
+    assert!(compact_size + 24 <= std::mem::size_of::<Request>());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+struct Trader(u32);
+
+impl std::fmt::Display for Trader {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "T{}", self.0)
+    }
+}
+
+#[test]
+fn test_newtype_user_ids() {
+    let mut book = OrderBook::<u64, u64, Trader>::new();
+    let mut request = Request {
+        id: 1,
+        side: Side::Buy,
+        price: 10,
+        size: 1,
+        user_id: Trader(7),
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    book.match_request(&request);
+    request.id = 2;
+    request.side = Side::Sell;
+    request.size = 2;
+    request.user_id = Trader(9);
+    let result = book.match_request(&request);
+    assert_eq!(result.market_actions[0].buyer_user_id, Trader(7));
+    #[cfg(feature = "display")]
+    assert_eq!(
+        result.market_actions[0].to_string(),
+        "User #T9 sold 1 pieces at price point '10' to user #T7"
+    );
+    assert!(book
+        .cancel_user_requests(Trader(7), &CancelFilter::default())
+        .is_empty());
+    assert_eq!(
+        book.cancel_user_requests(Trader(9), &CancelFilter::default())
+            .len(),
+        1
+    );
+}
+
+#[test]
+fn test_overflowing_requests_are_rejected() {
+    let mut exchange = Exchange::default();
+    exchange.add_book("AAA");
+    let mut request = Request {
+        id: 1,
+        side: Side::Sell,
+        price: 1,
+        size: u64::MAX - 1,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    exchange.submit("AAA", &request).unwrap();
+    request.id = 2;
+    request.size = 2;
+    let reject = exchange.submit("AAA", &request).unwrap_err();
+    assert_eq!(reject, Reject::Overflow(Overflow::Volume));
+    assert_eq!(
+        reject.to_string(),
+        "Request was rejected: the size in the book would overflow"
+    );
+    request.size = 1;
+    exchange.submit("AAA", &request).unwrap();
+    assert_eq!(
+        exchange.book("AAA").unwrap().depth(1).asks[0].size,
+        u64::MAX
+    );
+
+    request.id = 3;
+    request.side = Side::Buy;
+    request.price = 2;
+    request.size = u64::MAX / 2 + 1;
+    request.user_id = 2;
+    assert_eq!(
+        exchange.submit("AAA", &request),
+        Err(Reject::Overflow(Overflow::Notional))
+    );
+    request.price = 1;
+    request.size = u64::MAX;
+    request.request_type = Type::ImmediateOrCancel;
+    let result = exchange.submit("AAA", &request).unwrap();
+    assert_eq!(result.market_actions[0].size, u64::MAX - 1);
+    assert_eq!(result.market_actions[1].size, 1);
+    assert_eq!(exchange.book("AAA").unwrap().volume(Side::Sell), Some(0));
+}
+
+proptest! {
+    #[test]
+    fn test_arithmetic_near_max(
+        first in u64::MAX - 5000..=u64::MAX,
+        second in 1..=5000u64,
+        size in u64::MAX / 8..=u64::MAX,
+        price in 1u64 << 20..=1 << 24,
+        amount in u64::MAX / 2 + 1..=u64::MAX,
+    ) {
+        let fits = u128::from(first) + u128::from(second) <= u128::from(u64::MAX);
+        let mut book = OrderBook::default();
+        let mut request = Request {
+            id: 1,
+            side: Side::Buy,
+            price: 1,
+            size: first,
+            user_id: 1,
+            request_type: Type::Limit,
+            ..Default::default()
+        };
+        book.match_request(&request);
+        request.size = second;
+        prop_assert_eq!(book.check_request(&request).is_ok(), fits);
+        prop_assert_eq!(Quantity::checked_add(first, second).is_some(), fits);
+        prop_assert_eq!(book.clone().try_match_request(&request).is_ok(), fits);
+        book.match_request(&request);
+        prop_assert_eq!(book.volume(Side::Buy).is_some(), fits);
+        let level_size = book.depth(1).bids[0].size;
+        prop_assert_eq!(level_size, first.saturating_add(second));
+        prop_assert_eq!(book.try_depth(1).is_ok(), fits);
+
+        let action = MarketAction {
+            size,
+            price,
+            seller_user_id: 2,
+            buyer_user_id: 1,
+            ..Default::default()
+        };
+        let mut keeper = PositionKeeper::default();
+        prop_assert_eq!(keeper.record(&action), Err(Overflow::Position));
+        prop_assert_eq!(keeper.position(1), Position::default());
+        let mut accounts = Accounts::default();
+        prop_assert!(accounts.deposit(1, "USD", amount));
+        prop_assert!(!accounts.deposit(1, "USD", amount));
+        prop_assert_eq!(accounts.balance(1, "USD").available, amount);
+    }
+}
+
+#[test]
+fn test_reading_json_lines_and_csv() {
+    let input = r#"{"side":"Buy","price":1,"size":5,"user_id":1,"request_type":"Limit"}
+
+{"side":"Sell","price":1,"size":2,"user_id":2,"request_type":"ImmediateOrCancel"}
+{"side":"Sell","price":1,"size":2,"user_id":2}
+{"side":"Sell","price":1,"size":3,"user_id":2,"request_type":"Limit"}
+"#;
+    let read: Vec<_> = RequestReader::new(input.as_bytes(), InputFormat::JsonLines).collect();
+    assert_eq!(read.len(), 3);
+    assert_eq!(read[1].as_ref().unwrap().size, 2);
+    let error = read[2].as_ref().unwrap_err();
+    assert_eq!(error.line, 4);
+    assert_eq!(error.to_string(), "line 4: missing field `request_type`");
+
+    let input = "side, price, size, user_id, request_type, hidden, id
+Buy, 10, 5, 1, Limit, true, 7
+Sell, 9, 1, 2, FillOrKill, ,
+Sell, 9, 1, 2
+";
+    let mut reader = RequestReader::new(input.as_bytes(), InputFormat::Csv);
+    let request = reader.next().unwrap().unwrap();
+    assert_eq!((request.id, request.price, request.hidden), (7, 10, true));
+    let request = reader.next().unwrap().unwrap();
+    assert_eq!(request.request_type, Type::FillOrKill);
+    assert_eq!(request.id, 0);
+    assert_eq!(
+        reader.next().unwrap().unwrap_err().to_string(),
+        "line 4: expected 7 fields, found 4"
+    );
+    assert!(reader.next().is_none());
+}
+
+#[test]
+fn test_reading_json_arrays() {
+    let input = r#"[
+  {"side":"Buy","price":1,"size":5,"user_id":1,"request_type":"Limit"},
+  {"side":"Sell","price":1,"size":5,"user_id":2,"request_type":"Limit"}
+]"#;
+    let requests: Result<Vec<_>, _> =
+        RequestReader::new(input.as_bytes(), InputFormat::Json).collect();
+    assert_eq!(requests.unwrap().len(), 2);
+    let input = input.replace("\"Sell\"", "Sell");
+    let mut reader = RequestReader::new(input.as_bytes(), InputFormat::Json);
+    assert_eq!(reader.next().unwrap().unwrap_err().line, 3);
+    assert!(reader.next().is_none());
+    assert_eq!(
+        InputFormat::from_path("flow.JSONL"),
+        Some(InputFormat::JsonLines)
+    );
+    assert_eq!(InputFormat::from_path("flow"), None);
+}
+
+#[test]
+fn test_results_round_trip_through_json() {
+    let mut book = OrderBook::default();
+    let mut request = Request {
+        id: 1,
+        side: Side::Sell,
+        price: 3,
+        size: 2,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    book.match_request(&request);
+    request.id = 2;
+    request.side = Side::Buy;
+    request.size = 5;
+    request.user_id = 2;
+    let result = book.match_request(&request);
+    let json = serde_json::to_string(&result).unwrap();
+    assert_eq!(
+        json,
+        "{\"market_actions\":[{\"size\":2,\"price\":3,\"seller_user_id\":1,\"buyer_user_id\":2,\
+         \"seller_request_id\":1,\"buyer_request_id\":2,\"aggressor_side\":\"Buy\",\
+         \"buyer_fee\":0,\"seller_fee\":0}],\
+         \"request_actions\":[\"FilledPartially\",\"AddedToBook\"],\"book_events\":[]}"
+    );
+    assert_eq!(
+        serde_json::from_str::<MatchingResult>(&json).unwrap(),
+        result
+    );
+
+    let events = book.cancel_request(2);
+    let json = serde_json::to_string(&events).unwrap();
+    assert!(json.starts_with("[{\"Cancelled\":{\"request\":{\"id\":2,"));
+    assert_eq!(
+        serde_json::from_str::<Vec<BookEvent>>(&json).unwrap(),
+        events
+    );
+}
+
+#[test]
+#[cfg(feature = "display")]
+fn test_repl_commands() {
+    let mut repl = Repl::default();
+    let mut run = |line: &str| {
+        let command = repl.parse(line)?;
+        repl.execute(command)
+    };
+    assert_eq!(
+        run("buy 10 @ 101 user 3").unwrap(),
+        "#1 Incoming Limit request from user #3 to buy 10 pieces at price point '101'\n\
+         Request was added to the market"
+    );
+    run("sell 4 @ 100 user 2 ioc").unwrap();
+    run("sell 2 @ 103 id 7 hidden").unwrap();
+    assert_eq!(
+        run("book").unwrap(),
+        "  side price size orders\n  bid    101    6      1 ####################"
+    );
+    assert_eq!(
+        run("trades").unwrap(),
+        "User #2 sold 4 pieces at price point '101' to user #3"
+    );
+    assert_eq!(run("cancel 1").unwrap(), "Request #1 was cancelled");
+    assert_eq!(
+        run("cancel 1").unwrap_err(),
+        "There is no request #1 in the book"
+    );
+    assert_eq!(run("undo").unwrap(), "Request #1 is back in the book");
+    assert_eq!(run("buy 1 @ x").unwrap_err(), "'x' is not a number");
+    assert_eq!(run("buy 1 101").unwrap_err(), "Expected SIZE @ PRICE");
+    assert!(run("sell 1 @ 1 gtc").is_err());
+    assert_eq!(repl.parse("quit"), Ok(Command::Quit));
+    assert_eq!(repl.book().sellers[0].id, 7);
+}
+
+#[test]
+#[cfg(feature = "display")]
+fn test_repl_undo_save_and_load() {
+    let mut repl = Repl::default();
+    for line in ["buy 5 @ 10", "sell 3 @ 10 user 2", "sell 1 @ 12 user 2"].iter() {
+        let command = repl.parse(line).unwrap();
+        repl.execute(command).unwrap();
+    }
+    repl.execute(Command::Undo).unwrap();
+    assert!(repl.book().sellers.is_empty());
+    assert_eq!(repl.book().buyers[0].size, 2);
+    let path = std::env::temp_dir().join("market_matcher_repl_test.json");
+    let path = path.to_str().unwrap().to_string();
+    repl.execute(Command::Save(path.clone())).unwrap();
+
+    let mut loaded = Repl::default();
+    assert_eq!(
+        loaded.execute(Command::Load(path.clone())).unwrap(),
+        format!("Loaded 2 steps from {}", path)
+    );
+    assert_eq!(loaded.book().buyers[0].size, 2);
+    loaded.execute(Command::Undo).unwrap();
+    loaded.execute(Command::Undo).unwrap();
+    assert!(loaded.execute(Command::Undo).is_err());
+    assert_eq!(loaded.execute(Command::Book).unwrap(), "The book is empty");
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+#[cfg(feature = "display")]
+fn test_ladder_rendering() {
+    let mut book = OrderBook::default();
+    assert_eq!(book.to_string(), "The book is empty");
+    let mut request = Request {
+        side: Side::Buy,
+        price: 99,
+        size: 10,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    book.match_request(&request);
+    request.price = 100;
+    request.size = 5;
+    book.match_request(&request);
+    book.match_request(&request);
+    request.side = Side::Sell;
+    request.price = 102;
+    request.size = 1;
+    request.user_id = 2;
+    book.match_request(&request);
+    let previous = book.depth(10);
+    request.price = 100;
+    request.size = 6;
+    book.match_request(&request);
+    request.price = 1000;
+    request.size = 20;
+    book.match_request(&request);
+
+    let ladder = book.ladder(10).highlight_changes(previous);
+    assert_eq!(
+        ladder.to_string(),
+        "  side price size orders
+* ask   1000   20      1 ####################
+  ask    102    1      1 #
+------------------------
+* bid    100    4      1 ####
+  bid     99   10      1 ##########"
+    );
+    let ladder = book.ladder(1);
+    assert_eq!(ladder.to_string().lines().count(), 4);
+    assert!(!ladder.to_string().contains('*'));
+}
+
+#[test]
+fn test_debug_leaves_out_removed_requests() {
+    let mut book = OrderBook::default();
+    let mut request = Request {
+        id: 1,
+        side: Side::Buy,
+        price: 1,
+        size: 1,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    book.match_request_quiet(&request);
+    request.id = 2;
+    book.match_request_quiet(&request);
+    request.side = Side::Sell;
+    request.user_id = 2;
+    book.match_request_quiet(&request);
+    assert_eq!(book.buyers.start_from, 1);
+    let debug = format!("{:?}", book.buyers);
+    assert!(debug.contains("Request { id: 2,") && !debug.contains("Request { id: 1,"));
+}
+
+#[test]
+#[cfg(feature = "display")]
+fn test_formatting_options() {
+    let mut book = OrderBook::default();
+    let mut request = Request {
+        id: 1,
+        side: Side::Sell,
+        price: 3,
+        size: 2,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    book.match_request(&request);
+    request.id = 2;
+    request.side = Side::Buy;
+    request.size = 5;
+    request.user_id = 2;
+    request.hidden = true;
+    let result = book.match_request(&request);
+
+    let russian = DisplayOptions {
+        language: Language::Russian,
+        verbosity: Verbosity::Verbose,
+    };
+    assert_eq!(
+        russian.format(&request).to_string(),
+        "Входящая скрытая заявка типа «лимитная» от пользователя #2 на покупку 5 шт. по цене '3'"
+    );
+    assert_eq!(
+        russian.format(&result).to_string(),
+        "Заявка исполнена частично, добавлена на рынок и на рынке были совершены \
+         следующие действия:\nПользователь #1 продал 2 шт. по цене '3' пользователю #2"
+    );
+    let compact = DisplayOptions {
+        language: Language::English,
+        verbosity: Verbosity::Compact,
+    };
+    assert_eq!(
+        compact.format(&request).to_string(),
+        "#2 buy 5 @ 3 limit user #2 hidden"
+    );
+    assert_eq!(
+        compact.format(&result).to_string(),
+        "partially filled, added; #1 -> #2 2 @ 3"
+    );
+    request.peg = Some(Peg {
+        reference: PegReference::Midpoint,
+        offset: -1,
+        limit: Some(4),
+    });
+    assert_eq!(
+        compact.format(&request).to_string(),
+        "#2 buy 5 @ peg midpoint-1 limit 4 limit user #2 hidden"
+    );
+    assert_eq!(
+        DisplayOptions::default().format(&result).to_string(),
+        result.to_string()
+    );
+}
+
+#[test]
+#[cfg(feature = "display")]
+fn test_formatting_the_book() {
+    let mut book = OrderBook::default();
+    let mut request = Request {
+        side: Side::Buy,
+        price: 9,
+        size: 4,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    book.match_request(&request);
+    request.side = Side::Sell;
+    request.price = 11;
+    request.size = 2;
+    book.match_request(&request);
+
+    let russian = DisplayOptions {
+        language: Language::Russian,
+        verbosity: Verbosity::Verbose,
+    };
+    assert_eq!(
+        russian.format(&book).to_string(),
+        "  сторона цена объём заявки
+  прод.     11     2      1 ##########
+---------------------------
+  пок.       9     4      1 ####################"
+    );
+    let compact = DisplayOptions {
+        language: Language::English,
+        verbosity: Verbosity::Compact,
+    };
+    assert_eq!(compact.format(&book).to_string(), "bids 4@9; asks 2@11");
+    let ladder = book.ladder(5).highlight_changes(Depth::default());
+    assert_eq!(compact.format(&ladder).to_string(), "bids *4@9; asks *2@11");
+    assert_eq!(
+        compact.format(&OrderBook::default()).to_string(),
+        "The book is empty"
+    );
+}
+
+fn decoded(bytes: &[u8]) -> FixMessage {
+    FixMessage::decode(bytes).unwrap().unwrap().0
+}
+
+#[test]
+fn test_fix_session_recovers_sequence_gaps() {
+    let encoded = FixMessage::new(msg_type::HEARTBEAT)
+        .with(tag::MSG_SEQ_NUM, 1)
+        .encode();
+    assert_eq!(
+        String::from_utf8(encoded.clone()).unwrap(),
+        "8=FIX.4.4\u{1}9=10\u{1}35=0\u{1}34=1\u{1}10=165\u{1}"
+    );
+    assert_eq!(
+        FixMessage::decode(&encoded),
+        Ok(Some((
+            FixMessage::new(msg_type::HEARTBEAT).with(tag::MSG_SEQ_NUM, 1),
+            encoded.len()
+        )))
+    );
+    assert_eq!(FixMessage::decode(&encoded[..20]), Ok(None));
+    let mut corrupted = encoded.clone();
+    corrupted[15] = b'1';
+    assert!(matches!(
+        FixMessage::decode(&corrupted),
+        Err(FixError::BadChecksum { .. })
+    ));
+    let huge = b"8=FIX.4.4\x019=18446744073709551615\x0135=0\x01";
+    assert!(matches!(
+        FixMessage::decode(huge),
+        Err(FixError::Malformed(_))
+    ));
+
+    let mut session = FixSession::new("MATCHER", "CLIENT");
+    let from_client = |msg_type: &str, seq: u64| {
+        FixMessage::new(msg_type)
+            .with(tag::SENDER_COMP_ID, "CLIENT")
+            .with(tag::TARGET_COMP_ID, "MATCHER")
+            .with(tag::MSG_SEQ_NUM, seq)
+    };
+    session.receive(
+        from_client(msg_type::LOGON, 1).with(tag::HEART_BT_INT, 10),
+        0,
+    );
+    assert!(session.is_logged_on());
+    let logon = decoded(&session.take_outgoing()[0]);
+    assert_eq!(logon.get(tag::HEART_BT_INT), Some("10"));
+
+    // 2 and 4 are application messages, 3 is a heartbeat
+    session.send(
+        FixMessage::new(msg_type::EXECUTION_REPORT).with(tag::ORDER_ID, 1),
+        0,
+    );
+    session.send(FixMessage::new(msg_type::HEARTBEAT), 0);
+    session.send(
+        FixMessage::new(msg_type::EXECUTION_REPORT).with(tag::ORDER_ID, 2),
+        0,
+    );
+    session.take_outgoing();
+    let resend = from_client(msg_type::RESEND_REQUEST, 2)
+        .with(tag::BEGIN_SEQ_NO, 1)
+        .with(tag::END_SEQ_NO, 0);
+    assert_eq!(session.receive(resend, 1000), None);
+    let resent: Vec<_> = session.take_outgoing().iter().map(|m| decoded(m)).collect();
+    let summary: Vec<_> = resent
+        .iter()
+        .map(|m| {
+            (
+                m.msg_type(),
+                m.get(tag::MSG_SEQ_NUM).unwrap(),
+                m.get(tag::POSS_DUP_FLAG),
+                m.get(tag::NEW_SEQ_NO).or(m.get(tag::ORDER_ID)),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("4", "1", Some("Y"), Some("2")),
+            ("8", "2", Some("Y"), Some("1")),
+            ("4", "3", Some("Y"), Some("4")),
+            ("8", "4", Some("Y"), Some("2")),
+        ]
+    );
+    assert_eq!(
+        resent[1].get(tag::ORIG_SENDING_TIME),
+        Some("19700101-00:00:00.000")
+    );
+    assert_eq!(
+        resent[1].get(tag::SENDING_TIME),
+        Some("19700101-00:00:01.000")
+    );
+    assert_eq!(session.next_outgoing(), 5);
+
+    // 3 went missing, so 4 is dropped until a resend fills the gap
+    let order = from_client(msg_type::NEW_ORDER_SINGLE, 4);
+    assert_eq!(session.receive(order.clone(), 2000), None);
+    let request = decoded(&session.take_outgoing()[0]);
+    assert_eq!(request.msg_type(), msg_type::RESEND_REQUEST);
+    assert_eq!(request.get(tag::BEGIN_SEQ_NO), Some("3"));
+    let gap_fill = from_client(msg_type::SEQUENCE_RESET, 3)
+        .with(tag::GAP_FILL_FLAG, "Y")
+        .with(tag::NEW_SEQ_NO, 4);
+    assert_eq!(session.receive(gap_fill, 2000), None);
+    let resent = order.with(tag::POSS_DUP_FLAG, "Y");
+    assert_eq!(session.receive(resent.clone(), 2000), Some(resent.clone()));
+    assert_eq!(session.receive(resent, 2000), None);
+    assert_eq!(session.next_incoming(), 5);
+
+    // a silent counterparty gets a test request, then is logged out
+    assert!(session.take_outgoing().is_empty());
+    session.on_timer(14_000);
+    let sent: Vec<_> = session.take_outgoing().iter().map(|m| decoded(m)).collect();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].msg_type(), msg_type::TEST_REQUEST);
+    session.on_timer(22_000);
+    assert!(session.should_disconnect());
+}
+
+#[test]
+fn test_fix_orders_over_tcp() {
+    let mut exchange = Exchange::default();
+    exchange.add_book("AAA");
+    let mut gateway = FixGateway::new("MATCHER", exchange);
+    gateway.add_client("SELLER", 1);
+    gateway.add_client("BUYER", 2);
+    let acceptor = FixAcceptor::bind("127.0.0.1:0", gateway).unwrap();
+    let address = acceptor.local_addr().unwrap();
+    let gateway = acceptor.gateway();
+    thread::spawn(move || acceptor.run());
+
+    let mut seller = FixClient::connect(address, "SELLER", "MATCHER").unwrap();
+    let mut buyer = FixClient::connect(address, "BUYER", "MATCHER").unwrap();
+    assert!(FixClient::connect(address, "BUYER", "MATCHER").is_err());
+    let order = |cl_ord_id: &str, side: &str, size: u64, price: u64| {
+        FixMessage::new(msg_type::NEW_ORDER_SINGLE)
+            .with(tag::CL_ORD_ID, cl_ord_id)
+            .with(tag::SYMBOL, "AAA")
+            .with(tag::SIDE, side)
+            .with(tag::ORDER_QTY, size)
+            .with(tag::ORD_TYPE, 2)
+            .with(tag::PRICE, price)
+    };
+    let status = |report: &FixMessage| {
+        [
+            tag::EXEC_TYPE,
+            tag::ORD_STATUS,
+            tag::CUM_QTY,
+            tag::LEAVES_QTY,
+        ]
+        .iter()
+        .map(|tag| report.get(*tag).unwrap_or("-"))
+        .collect::<Vec<_>>()
+        .join(" ")
+    };
+
+    seller.send(order("s1", "2", 5, 10)).unwrap();
+    let new = seller.receive().unwrap();
+    assert_eq!(new.msg_type(), msg_type::EXECUTION_REPORT);
+    assert_eq!(new.get(tag::CL_ORD_ID), Some("s1"));
+    assert_eq!(status(&new), "0 0 0 5");
+
+    buyer
+        .send(order("b1", "1", 3, 10).with(tag::TIME_IN_FORCE, 3))
+        .unwrap();
+    assert_eq!(status(&buyer.receive().unwrap()), "0 0 0 3");
+    let trade = buyer.receive().unwrap();
+    assert_eq!(status(&trade), "F 2 3 0");
+    assert_eq!(trade.get(tag::LAST_PX), Some("10"));
+    assert_eq!(status(&seller.receive().unwrap()), "F 1 3 2");
+
+    let replace = FixMessage::new(msg_type::ORDER_CANCEL_REPLACE_REQUEST)
+        .with(tag::CL_ORD_ID, "s2")
+        .with(tag::ORIG_CL_ORD_ID, "s1")
+        .with(tag::SYMBOL, "AAA")
+        .with(tag::SIDE, 2)
+        .with(tag::ORDER_QTY, 4)
+        .with(tag::ORD_TYPE, 2)
+        .with(tag::PRICE, 11);
+    seller.send(replace).unwrap();
+    let replaced = seller.receive().unwrap();
+    assert_eq!(status(&replaced), "5 1 3 1");
+    assert_eq!(replaced.get(tag::PRICE), Some("11"));
+    assert_eq!(replaced.get(tag::ORDER_ID), new.get(tag::ORDER_ID));
+
+    let cancel = FixMessage::new(msg_type::ORDER_CANCEL_REQUEST)
+        .with(tag::CL_ORD_ID, "s3")
+        .with(tag::ORIG_CL_ORD_ID, "s2")
+        .with(tag::SYMBOL, "AAA")
+        .with(tag::SIDE, 2);
+    seller.send(cancel.clone()).unwrap();
+    let cancelled = seller.receive().unwrap();
+    assert_eq!(status(&cancelled), "4 4 3 0");
+    assert_eq!(cancelled.get(tag::ORIG_CL_ORD_ID), Some("s2"));
+    seller.send(cancel).unwrap();
+    let rejected = seller.receive().unwrap();
+    assert_eq!(rejected.msg_type(), msg_type::ORDER_CANCEL_REJECT);
+    assert_eq!(rejected.get(tag::CXL_REJ_REASON), Some("1"));
+
+    buyer
+        .send(order("b2", "1", 1, 10).with(tag::ORD_TYPE, 1))
+        .unwrap();
+    let rejected = buyer.receive().unwrap();
+    assert_eq!(status(&rejected), "8 8 0 0");
+    assert_eq!(rejected.get(tag::ORD_REJ_REASON), Some("11"));
+    buyer
+        .send(FixMessage::new(msg_type::NEW_ORDER_SINGLE).with(tag::CL_ORD_ID, "b3"))
+        .unwrap();
+    let rejected = buyer.receive().unwrap();
+    assert_eq!(rejected.msg_type(), msg_type::REJECT);
+    assert_eq!(rejected.get(tag::REF_TAG_ID), Some("55"));
+
+    // orders of a client leave the books with its connection
+    buyer.send(order("b4", "1", 2, 9)).unwrap();
+    assert_eq!(status(&buyer.receive().unwrap()), "0 0 0 2");
+    drop(buyer);
+    let bids = || {
+        gateway
+            .lock()
+            .unwrap()
+            .exchange()
+            .book("AAA")
+            .unwrap()
+            .depth(5)
+            .bids
+    };
+    for _ in 0..100 {
+        if bids().is_empty() {
+            break;
+        }
+        thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert!(bids().is_empty());
+
+    let gateway = gateway.lock().unwrap();
+    assert!(gateway
+        .exchange()
+        .book("AAA")
+        .unwrap()
+        .depth(5)
+        .asks
+        .is_empty());
+    assert_eq!(gateway.session("SELLER").unwrap().next_incoming(), 6);
+}
+
+#[test]
+fn test_ouch_messages_round_trip() {
+    let order = EnterOrder {
+        token: 7,
+        side: Side::Sell,
+        size: 300,
+        symbol: wire_symbol("AAA").unwrap(),
+        price: 1015,
+        time_in_force: Type::ImmediateOrCancel,
+        hidden: true,
+    };
+    let mut buffer = [0; OUCH_MAX_LEN];
+    let len = OuchInbound::Enter(order).encode(&mut buffer);
+    assert_eq!(len, 36);
+    assert_eq!(&buffer[..10], b"O\0\0\0\0\0\0\0\x07S");
+    assert_eq!(&buffer[18..26], b"AAA     ");
+    assert_eq!(&buffer[34..36], b"IN");
+    assert_eq!(
+        OuchInbound::decode(&buffer[..len]),
+        Ok(Some((OuchInbound::Enter(order), 36)))
+    );
+    assert_eq!(order.request(3).size, 300);
+    assert_eq!(order.request(3).user_id, 3);
+    // a view reads the fields from the buffer
+    let view = match OuchInboundView::decode(&buffer[..len]) {
+        Ok(Some((OuchInboundView::Enter(view), 36))) => view,
+        decoded => panic!("{:?}", decoded),
+    };
+    assert_eq!(view.symbol(), &order.symbol);
+    assert_eq!(view.request(3), order.request(3));
+    assert_eq!(view.to_order(), order);
+    assert_eq!(OuchInbound::decode(&buffer[..35]), Ok(None));
+    buffer[9] = b'Z';
+    assert_eq!(
+        OuchInbound::decode(&buffer),
+        Err(WireError::InvalidField("side"))
+    );
+    assert_eq!(OuchInbound::decode(b"Q"), Err(WireError::UnknownType(b'Q')));
+
+    let messages = [
+        OuchOutbound::Accepted {
+            timestamp: 1,
+            order,
+            resting: true,
+        },
+        OuchOutbound::Replaced {
+            timestamp: 2,
+            previous_token: 6,
+            order,
+            resting: false,
+        },
+        OuchOutbound::Executed {
+            timestamp: 3,
+            token: 7,
+            size: 100,
+            price: 1015,
+            liquidity: Liquidity::Removed,
+            match_number: 9,
+        },
+        OuchOutbound::Cancelled {
+            timestamp: 4,
+            token: 7,
+            size: 200,
+            reason: CancelReason::Immediate,
+        },
+        OuchOutbound::Rejected {
+            timestamp: 5,
+            token: 8,
+            reason: RejectReason::DuplicateToken,
+        },
+    ];
+    // messages are read back from one stream
+    let mut stream = Vec::new();
+    for message in messages.iter() {
+        let len = message.encode(&mut buffer);
+        stream.extend_from_slice(&buffer[..len]);
+    }
+    assert_eq!(stream.len(), 45 + 53 + 42 + 26 + 18);
+    let mut decoded = Vec::new();
+    let mut rest = &stream[..];
+    while let Some((message, len)) = OuchOutbound::decode(rest).unwrap() {
+        decoded.push(message);
+        rest = &rest[len..];
+    }
+    assert_eq!(decoded, messages);
+}
+
+#[test]
+fn test_ouch_gateway() {
+    let mut exchange = Exchange::default();
+    exchange.add_book("AAA");
+    let mut gateway = OuchGateway::new(exchange);
+    let order = EnterOrder {
+        token: 1,
+        side: Side::Sell,
+        size: 5,
+        symbol: wire_symbol("AAA").unwrap(),
+        price: 10,
+        time_in_force: Type::Limit,
+        hidden: false,
+    };
+    let responses = gateway.handle(1, &OuchInbound::Enter(order), 100);
+    assert_eq!(
+        responses,
+        vec![(
+            1,
+            OuchOutbound::Accepted {
+                timestamp: 100,
+                order,
+                resting: true
+            }
+        )]
+    );
+    let duplicate = gateway.handle(1, &OuchInbound::Enter(order), 100);
+    assert_eq!(
+        duplicate,
+        vec![(
+            1,
+            OuchOutbound::Rejected {
+                timestamp: 100,
+                token: 1,
+                reason: RejectReason::DuplicateToken
+            }
+        )]
+    );
+
+    let buy = EnterOrder {
+        token: 2,
+        side: Side::Buy,
+        size: 3,
+        time_in_force: Type::ImmediateOrCancel,
+        price: 11,
+        ..order
+    };
+    let responses = gateway.handle(2, &OuchInbound::Enter(buy), 200);
+    let executed = |token, liquidity| OuchOutbound::Executed {
+        timestamp: 200,
+        token,
+        size: 3,
+        price: 10,
+        liquidity,
+        match_number: 1,
+    };
+    assert_eq!(
+        responses,
+        vec![
+            (
+                2,
+                OuchOutbound::Accepted {
+                    timestamp: 200,
+                    order: buy,
+                    resting: false
+                }
+            ),
+            (2, executed(2, Liquidity::Removed)),
+            (1, executed(1, Liquidity::Added)),
+        ]
+    );
+    let zero = EnterOrder { token: 0, ..buy };
+    let zero = gateway.handle(2, &OuchInbound::Enter(zero), 250);
+    assert!(matches!(
+        zero[0].1,
+        OuchOutbound::Rejected {
+            reason: RejectReason::InvalidToken,
+            ..
+        }
+    ));
+
+    // only the owner replaces or cancels
+    let replace = ReplaceOrder {
+        existing_token: 1,
+        replacement_token: 3,
+        size: 4,
+        price: 12,
+    };
+    let responses = gateway.handle(2, &OuchInbound::Replace(replace), 300);
+    assert!(matches!(
+        responses[0].1,
+        OuchOutbound::Rejected {
+            reason: RejectReason::UnknownToken,
+            ..
+        }
+    ));
+    let responses = gateway.handle(1, &OuchInbound::Replace(replace), 300);
+    let replaced = EnterOrder {
+        token: 3,
+        size: 4,
+        price: 12,
+        ..order
+    };
+    assert_eq!(
+        responses,
+        vec![(
+            1,
+            OuchOutbound::Replaced {
+                timestamp: 300,
+                previous_token: 1,
+                order: replaced,
+                resting: true
+            }
+        )]
+    );
+    let responses = gateway.handle(1, &OuchInbound::Cancel(CancelOrder { token: 3 }), 400);
+    assert_eq!(
+        responses,
+        vec![(
+            1,
+            OuchOutbound::Cancelled {
+                timestamp: 400,
+                token: 3,
+                size: 4,
+                reason: CancelReason::User
+            }
+        )]
+    );
+    assert!(gateway
+        .exchange()
+        .book("AAA")
+        .unwrap()
+        .depth(5)
+        .asks
+        .is_empty());
+
+    // tokens are free once their requests are gone, and every user has tokens of its own
+    let bid = EnterOrder {
+        price: 9,
+        time_in_force: Type::Limit,
+        ..buy
+    };
+    let ask = EnterOrder { token: 2, ..order };
+    for (user_id, order) in [(2, bid), (1, ask)].iter() {
+        let responses = gateway.handle(*user_id, &OuchInbound::Enter(*order), 500);
+        assert_eq!(
+            responses,
+            vec![(
+                *user_id,
+                OuchOutbound::Accepted {
+                    timestamp: 500,
+                    order: *order,
+                    resting: true
+                }
+            )]
+        );
+    }
+    let responses = gateway.handle(1, &OuchInbound::Cancel(CancelOrder { token: 2 }), 600);
+    assert!(matches!(
+        responses[..],
+        [(
+            1,
+            OuchOutbound::Cancelled {
+                token: 2,
+                size: 5,
+                ..
+            }
+        )]
+    ));
+    assert_eq!(
+        gateway.exchange().book("AAA").unwrap().depth(5).bids[0].size,
+        3
+    );
+}
+
+#[test]
+fn test_itch_replica_follows_book() {
+    let symbol = wire_symbol("AAA").unwrap();
+    let mut book = OrderBook::default();
+    let mut publisher = ItchPublisher::new();
+    let mut messages = vec![publisher.system_event(SystemEvent::StartOfMessages, 0)];
+    let sell = Request {
+        id: 1,
+        side: Side::Sell,
+        price: 10,
+        size: 5,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    let hidden = Request {
+        id: 2,
+        size: 4,
+        hidden: true,
+        ..sell.clone()
+    };
+    let higher = Request {
+        id: 3,
+        price: 11,
+        size: 2,
+        ..sell.clone()
+    };
+    let bid = Request {
+        id: 4,
+        side: Side::Buy,
+        price: 8,
+        size: 3,
+        user_id: 2,
+        ..sell.clone()
+    };
+    let pegged = Request {
+        id: 5,
+        price: 0,
+        peg: Some(Peg {
+            reference: PegReference::Primary,
+            offset: 0,
+            limit: None,
+        }),
+        ..bid.clone()
+    };
+    let better_bid = Request {
+        id: 6,
+        price: 9,
+        size: 1,
+        ..bid.clone()
+    };
+    let taker = Request {
+        id: 0,
+        price: 10,
+        size: 7,
+        ..bid.clone()
+    };
+    for (timestamp, request) in [sell, hidden, higher, bid, pegged, better_bid, taker]
+        .iter()
+        .enumerate()
+    {
+        let result = book.match_request(request);
+        let published =
+            publisher.publish_result(&symbol, &book, request, &result, timestamp as u64);
+        messages.extend(published);
+    }
+    let events = book.cancel_request(3);
+    messages.extend(publisher.publish_events(&symbol, &book, &events, 10));
+    let types: Vec<u8> = messages
+        .iter()
+        .map(|message| {
+            let mut buffer = [0; ITCH_MAX_LEN];
+            message.encode(&mut buffer);
+            buffer[0]
+        })
+        .collect();
+    // the pegged request is published again at its new price, the hidden one trades anonymously
+    assert_eq!(types, b"SAAAAXAAEPX".to_vec());
+    assert_eq!(
+        messages[8].body,
+        ItchBody::OrderExecuted {
+            symbol,
+            order_id: 1,
+            size: 5,
+            match_number: 1
+        }
+    );
+    assert_eq!(
+        messages[9].body,
+        ItchBody::Trade {
+            symbol,
+            side: Side::Sell,
+            size: 2,
+            price: 10,
+            match_number: 2
+        }
+    );
+
+    let mut writer = ItchWriter::new(Vec::new());
+    writer.write(&messages).unwrap();
+    let mut replica = ItchReplica::new();
+    let mut replayed = Vec::new();
+    for message in ItchReader::new(&writer.into_inner()[..]) {
+        let message = message.unwrap();
+        replica.apply(&message).unwrap();
+        replayed.push(message);
+    }
+    assert_eq!(replayed, messages);
+    assert_eq!(replica.depth("AAA", 10), book.depth(10));
+    assert_eq!(replica.volume("AAA"), 7);
+    // a repeated message is ignored, a missed one is reported
+    assert_eq!(replica.apply(&messages[3]), Ok(()));
+    let mut later = messages[0];
+    later.sequence = replica.next_sequence() + 1;
+    assert_eq!(
+        replica.apply(&later),
+        Err(ItchGap {
+            expected: 12,
+            found: 13
+        })
+    );
+}
+
+#[test]
+fn test_itch_trades_against_requests_without_ids() {
+    let symbol = wire_symbol("AAA").unwrap();
+    let mut book = OrderBook::default();
+    let mut publisher = ItchPublisher::new();
+    let sell = Request {
+        side: Side::Sell,
+        price: 10,
+        size: 5,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    let buy = Request {
+        side: Side::Buy,
+        size: 2,
+        user_id: 2,
+        id: 1,
+        ..sell.clone()
+    };
+    let mut messages = Vec::new();
+    for request in [sell, buy].iter() {
+        let result = book.match_request(request);
+        messages.extend(publisher.publish_result(&symbol, &book, request, &result, 0));
+    }
+    assert_eq!(
+        messages
+            .iter()
+            .map(|message| message.body)
+            .collect::<Vec<_>>(),
+        vec![ItchBody::Trade {
+            symbol,
+            side: Side::Sell,
+            size: 2,
+            price: 10,
+            match_number: 1
+        }]
+    );
+}
+
+#[test]
+fn test_itch_over_udp() {
+    let receiver = ItchUdpReceiver::bind("127.0.0.1:0").unwrap();
+    receiver
+        .socket()
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+    let sender = ItchUdpSender::connect(receiver.local_addr().unwrap()).unwrap();
+    let mut publisher = ItchPublisher::new();
+    let mut book = OrderBook::default();
+    let request = Request {
+        id: 1,
+        side: Side::Buy,
+        price: 10,
+        size: 5,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    let result = book.match_request(&request);
+    let symbol = wire_symbol("AAA").unwrap();
+    let mut messages = vec![publisher.system_event(SystemEvent::StartOfMessages, 1)];
+    messages.extend(publisher.publish_result(&symbol, &book, &request, &result, 2));
+    // enough messages for more than one datagram
+    for _ in 0..40 {
+        messages.push(publisher.system_event(SystemEvent::EndOfMessages, 3));
+    }
+    sender.send(&messages).unwrap();
+
+    let mut replica = ItchReplica::new();
+    let mut received = Vec::new();
+    while received.len() < messages.len() {
+        received.extend(receiver.receive().unwrap());
+    }
+    assert_eq!(received, messages);
+    for message in received.iter() {
+        replica.apply(message).unwrap();
+    }
+    assert_eq!(replica.depth("AAA", 1), book.depth(1));
+}
+
+fn http_request(method: &str, path: &str, body: &str) -> HttpRequest {
+    let raw = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        body.len(),
+        body
+    );
+    HttpRequest::read(&mut raw.as_bytes()).unwrap().unwrap()
+}
+
+#[test]
+fn test_matching_engine_api() {
+    let mut exchange = Exchange::default();
+    exchange.add_book("AAA");
+    let mut engine = MatchingEngine::new(exchange);
+    let api_keys = vec![("one".to_string(), 1), ("two".to_string(), 2)]
+        .into_iter()
+        .collect();
+    // the API key the calls are made with
+    let key = std::cell::Cell::new(None);
+    let mut call = |method, path, body| {
+        let mut request = http_request(method, path, body);
+        if let Some(key) = key.get() {
+            let authorization = format!("Bearer {}", key);
+            request
+                .headers
+                .push(("authorization".to_string(), authorization));
+        }
+        match route(&request, &api_keys) {
+            Ok(command) => engine.execute(command, 100),
+            Err(response) => response,
+        }
+    };
+    let sell = r#"{"id":1,"side":"Sell","price":10,"size":5,"user_id":1,"request_type":"Limit"}"#;
+    let response = call("POST", "/books/AAA/requests", sell);
+    assert_eq!(response.status, 200);
+    assert_eq!(
+        response.body["request_actions"],
+        serde_json::json!(["AddedToBook"])
+    );
+    let buy = r#"{"side":"Buy","price":10,"size":2,"user_id":2,"request_type":"Limit"}"#;
+    let response = call("POST", "/books/AAA/requests", buy);
+    assert_eq!(response.body["market_actions"][0]["size"], 2);
+
+    let response = call("GET", "/books/AAA?levels=1", "");
+    let depth: Depth = serde_json::from_value(response.body).unwrap();
+    assert_eq!(
+        depth.asks,
+        vec![PriceLevel {
+            price: 10,
+            size: 3,
+            orders: 1
+        }]
+    );
+    let response = call("GET", "/books/AAA/trades", "");
+    assert_eq!(response.body[0]["sequence"], 1);
+    assert_eq!(response.body[0]["timestamp"], 100);
+    assert_eq!(response.body[0]["buyer_user_id"], 2);
+    let response = call("GET", "/users/1/requests", "");
+    assert_eq!(response.body[0]["symbol"], "AAA");
+    assert_eq!(response.body[0]["request"]["size"], 3);
+
+    // only the owner cancels
+    let cancel = "/books/AAA/requests/1";
+    assert_eq!(call("DELETE", cancel, "").status, 401);
+    key.set(Some("three"));
+    assert_eq!(call("DELETE", cancel, "").status, 401);
+    key.set(Some("two"));
+    assert_eq!(call("DELETE", cancel, "").status, 404);
+    key.set(Some("one"));
+    assert_eq!(call("DELETE", cancel, "").status, 200);
+    assert_eq!(call("DELETE", cancel, "").status, 404);
+    assert_eq!(
+        call("GET", "/users/1/requests", "").body,
+        serde_json::json!([])
+    );
+    let response = call("POST", "/books/BBB/requests", sell);
+    assert_eq!(response.status, 404);
+    assert_eq!(response.body["reject"], "UnknownBook");
+    assert_eq!(call("POST", "/books/AAA/requests", "{").status, 400);
+    assert_eq!(call("PUT", "/books/AAA", "").status, 405);
+    assert_eq!(call("GET", "/nothing", "").status, 404);
+}
+
+#[test]
+fn test_http_server() {
+    let mut exchange = Exchange::default();
+    exchange.add_book("AAA");
+    let server = HttpServer::bind("127.0.0.1:0", MatchingEngine::new(exchange).spawn()).unwrap();
+    let address = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+    let call = |method: &str, path: &str, body: &str| {
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let sell = r#"{"id":1,"side":"Sell","price":10,"size":5,"user_id":1,"request_type":"Limit"}"#;
+    let response = call("POST", "/books/AAA/requests", sell);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    let response = call("GET", "/books", "");
+    assert!(response.ends_with("\r\n\r\n[\"AAA\"]"));
+    let response = call("GET", "/books/AAA", "");
+    assert!(response.ends_with(r#"{"asks":[{"orders":1,"price":10,"size":5}],"bids":[]}"#));
+    let response = call("OPTIONS", "/books/AAA/requests", "");
+    assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+    assert!(response.contains("Access-Control-Allow-Origin: *\r\n"));
+}
+
+#[test]
+fn test_websocket_codec_and_book_stream() {
+    let digest: String = sha1(b"abc")
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    assert_eq!(digest, "a9993e364706816aba3e25717850c26c9cd0d89d");
+    let encoded: Vec<String> = ["", "f", "fo", "foo", "foob"]
+        .iter()
+        .map(|text| base64(text.as_bytes()))
+        .collect();
+    assert_eq!(encoded, vec!["", "Zg==", "Zm8=", "Zm9v", "Zm9vYg=="]);
+    // the example of RFC 6455
+    assert_eq!(
+        accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+        "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+    );
+    let frame = WsFrame::text(&"x".repeat(200));
+    let mut bytes = Vec::new();
+    frame.write(&mut bytes, Some([1, 2, 3, 4])).unwrap();
+    assert_eq!(&bytes[..4], &[0x81, 0x80 | 126, 0, 200]);
+    assert_eq!(WsFrame::read(&mut &bytes[..]).unwrap(), frame);
+
+    let mut book = OrderBook::default();
+    let mut stream = BookStream::default();
+    let sell = Request {
+        id: 1,
+        side: Side::Sell,
+        price: 10,
+        size: 5,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    let result = book.match_request(&sell);
+    stream.updates("AAA", &book, Some(&sell), &result, 1);
+    let snapshot = stream.snapshot("AAA", &book);
+    assert_eq!(
+        snapshot,
+        StreamMessage::Snapshot {
+            symbol: "AAA".to_string(),
+            sequence: 1,
+            bids: vec![],
+            asks: vec![PriceLevel {
+                price: 10,
+                size: 5,
+                orders: 1
+            }],
+        }
+    );
+    let buy = Request {
+        id: 2,
+        side: Side::Buy,
+        size: 7,
+        user_id: 2,
+        ..sell.clone()
+    };
+    let result = book.match_request(&buy);
+    let level = |sequence, side, size, orders| StreamMessage::Level {
+        symbol: "AAA".to_string(),
+        sequence,
+        side,
+        price: 10,
+        size,
+        orders,
+    };
+    assert_eq!(
+        stream.updates("AAA", &book, Some(&buy), &result, 2),
+        vec![
+            StreamMessage::Trade {
+                symbol: "AAA".to_string(),
+                sequence: 2,
+                timestamp: 2,
+                price: 10,
+                size: 5,
+                aggressor_side: Side::Buy,
+            },
+            level(3, Side::Sell, 0, 0),
+            level(4, Side::Buy, 2, 1),
+        ]
+    );
+    // a hidden request changes nothing which is displayed
+    let hidden = Request {
+        id: 3,
+        hidden: true,
+        ..buy
+    };
+    let result = book.match_request(&hidden);
+    assert!(stream
+        .updates("AAA", &book, Some(&hidden), &result, 3)
+        .is_empty());
+    let cancelled = MatchingResult {
+        book_events: book.cancel_request(3),
+        ..Default::default()
+    };
+    assert_eq!(cancelled.book_events.len(), 1);
+    assert!(stream.updates("AAA", &book, None, &cancelled, 4).is_empty());
+}
+
+#[test]
+fn test_book_stream_of_engine() {
+    let mut exchange = Exchange::default();
+    exchange.add_book("AAA");
+    let mut engine = MatchingEngine::new(exchange);
+    let (frames, received) = std::sync::mpsc::sync_channel(10);
+    assert!(engine.subscribe("AAA", frames));
+    let (slow_frames, slow_received) = std::sync::mpsc::sync_channel(1);
+    assert!(engine.subscribe("AAA", slow_frames));
+    let bid = Request {
+        id: 1,
+        side: Side::Buy,
+        price: 10,
+        size: 2,
+        user_id: 1,
+        request_type: Type::Limit,
+        ..Default::default()
+    };
+    // the pegged buyer without an id joins the bid
+    let pegged = Request {
+        id: 0,
+        price: 0,
+        size: 3,
+        peg: Some(Peg {
+            reference: PegReference::Primary,
+            offset: 0,
+            limit: None,
+        }),
+        ..bid.clone()
+    };
+    for request in [bid, pegged].iter() {
+        let command = EngineCommand::Submit {
+            symbol: "AAA".to_string(),
+            request: request.clone(),
+        };
+        assert_eq!(engine.execute(command, 1).status, 200);
+    }
+    let cancel = EngineCommand::Cancel {
+        symbol: "AAA".to_string(),
+        id: 1,
+        owner: 1,
+    };
+    assert_eq!(engine.execute(cancel, 2).status, 200);
+    let levels: Vec<(u64, u64)> = received
+        .try_iter()
+        .skip(1)
+        .map(|StreamFrame::Text(text)| {
+            let message: serde_json::Value = serde_json::from_str(&text).unwrap();
+            let field = |name: &str| message[name].as_u64().unwrap();
+            (field("price"), field("size"))
+        })
+        .collect();
+    assert_eq!(levels, vec![(10, 2), (10, 5), (10, 3)]);
+    // the subscriber which did not read its snapshot is dropped
+    assert!(slow_received.recv().is_ok());
+    assert!(slow_received.recv().is_err());
+}
+
+#[test]
+fn test_websocket_stream_over_tcp() {
+    let mut exchange = Exchange::default();
+    exchange.add_book("AAA");
+    let server = HttpServer::bind("127.0.0.1:0", MatchingEngine::new(exchange).spawn()).unwrap();
+    let address = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let mut socket = std::net::TcpStream::connect(address).unwrap();
+    socket
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+    write!(
+        socket,
+        "GET /books/AAA/stream HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+         Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+         Sec-WebSocket-Version: 13\r\n\r\n"
+    )
+    .unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        socket.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap();
+    assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+    assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    let mut next_message = || {
+        let frame = WsFrame::read(&mut socket).unwrap();
+        serde_json::from_slice::<serde_json::Value>(&frame.payload).unwrap()
+    };
+    let snapshot = next_message();
+    assert_eq!(snapshot["type"], "snapshot");
+    assert_eq!(snapshot["sequence"], 0);
+
+    let submit = |body: &str| {
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        let request = format!(
+            "POST /books/AAA/requests HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        stream.read_to_end(&mut Vec::new()).unwrap();
+    };
+    submit(r#"{"id":1,"side":"Sell","price":10,"size":5,"user_id":1,"request_type":"Limit"}"#);
+    submit(r#"{"side":"Buy","price":10,"size":2,"user_id":2,"request_type":"Limit"}"#);
+    let messages: Vec<serde_json::Value> = (0..3).map(|_| next_message()).collect();
+    let sequences: Vec<u64> = messages
+        .iter()
+        .map(|message| message["sequence"].as_u64().unwrap())
+        .collect();
+    assert_eq!(sequences, vec![1, 2, 3]);
+    assert_eq!(messages[0]["type"], "level");
+    assert_eq!(messages[0]["size"], 5);
+    assert_eq!(messages[1]["type"], "trade");
+    assert_eq!(messages[1]["size"], 2);
+    assert_eq!(messages[2]["size"], 3);
+
+    let ping = WsFrame {
+        fin: true,
+        opcode: opcode::PING,
+        payload: b"hi".to_vec(),
+    };
+    ping.write(&mut socket, Some([9, 8, 7, 6])).unwrap();
+    let pong = WsFrame::read(&mut socket).unwrap();
+    assert_eq!((pong.opcode, pong.payload), (opcode::PONG, b"hi".to_vec()));
+}
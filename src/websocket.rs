@@ -0,0 +1,502 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::depth::*;
+use crate::http::*;
+use crate::matcher::*;
+
+// Appended to the key of the client to make the accept key, see RFC 6455.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_FRAME_SIZE: u64 = 64 * 1024;
+/// Messages queued for a connection of a stream, a connection which falls further
+/// behind is dropped.
+pub const STREAM_BUFFER: usize = 1024;
+// How often a connection waiting for messages looks whether its client has gone.
+const CLOSED_POLL: Duration = Duration::from_millis(100);
+
+pub mod opcode {
+    pub const CONTINUATION: u8 = 0x0;
+    pub const TEXT: u8 = 0x1;
+    pub const BINARY: u8 = 0x2;
+    pub const CLOSE: u8 = 0x8;
+    pub const PING: u8 = 0x9;
+    pub const PONG: u8 = 0xA;
+}
+
+/// A frame of a WebSocket connection; fragmented messages are not joined.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WsFrame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+impl WsFrame {
+    pub fn text(text: &str) -> WsFrame {
+        WsFrame {
+            fin: true,
+            opcode: opcode::TEXT,
+            payload: text.as_bytes().to_vec(),
+        }
+    }
+
+    /// Reads the next frame, unmasking its payload.
+    pub fn read(input: &mut impl Read) -> io::Result<WsFrame> {
+        let mut head = [0; 2];
+        input.read_exact(&mut head)?;
+        let len = match head[1] & 0x7F {
+            126 => {
+                let mut len = [0; 2];
+                input.read_exact(&mut len)?;
+                u16::from_be_bytes(len) as u64
+            }
+            127 => {
+                let mut len = [0; 8];
+                input.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            }
+            len => len as u64,
+        };
+        if len > MAX_FRAME_SIZE {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "the frame is too long",
+            ));
+        }
+        let mut mask = [0; 4];
+        let masked = head[1] & 0x80 != 0;
+        if masked {
+            input.read_exact(&mut mask)?;
+        }
+        let mut payload = vec![0; len as usize];
+        input.read_exact(&mut payload)?;
+        if masked {
+            apply_mask(&mut payload, mask);
+        }
+        Ok(WsFrame {
+            fin: head[0] & 0x80 != 0,
+            opcode: head[0] & 0x0F,
+            payload,
+        })
+    }
+
+    /// Writes the frame, masked with the key if it is given; clients have to mask
+    /// their frames, servers must not.
+    pub fn write(&self, output: &mut impl Write, mask: Option<[u8; 4]>) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(self.payload.len() + 14);
+        frame.push(if self.fin { 0x80 } else { 0 } | self.opcode);
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        match self.payload.len() {
+            len if len < 126 => frame.push(mask_bit | len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        let start = frame.len();
+        if let Some(mask) = mask {
+            frame.extend_from_slice(&mask);
+        }
+        frame.extend_from_slice(&self.payload);
+        if let Some(mask) = mask {
+            apply_mask(&mut frame[start + 4..], mask);
+        }
+        output.write_all(&frame)?;
+        output.flush()
+    }
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+/// Value of `Sec-WebSocket-Accept` for the `Sec-WebSocket-Key` of a client.
+pub fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, HANDSHAKE_GUID).as_bytes()))
+}
+
+/// SHA-1 digest, only used by the handshake.
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [
+        0x6745_2301,
+        0xEFCD_AB89,
+        0x98BA_DCFE,
+        0x1032_5476,
+        0xC3D2_E1F0,
+    ];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut words = [0u32; 80];
+        for (word, bytes) in words.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in words.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+            let next = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = next;
+        }
+        for (value, added) in state.iter_mut().zip([a, b, c, d, e].iter()) {
+            *value = value.wrapping_add(*added);
+        }
+    }
+    let mut digest = [0; 20];
+    for (bytes, value) in digest.chunks_mut(4).zip(state.iter()) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+/// Standard base64 with padding.
+pub fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let byte = |i: usize| chunk.get(i).copied().unwrap_or(0) as u32;
+        let bits = byte(0) << 16 | byte(1) << 8 | byte(2);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// A message of the public stream of a book; sequence numbers of a book have no gaps,
+/// updates follow the snapshot with the sequence number they were sent after.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamMessage {
+    /// Every displayed level of the book, sent on subscribing.
+    Snapshot {
+        symbol: String,
+        sequence: u64,
+        bids: Vec<PriceLevel>,
+        asks: Vec<PriceLevel>,
+    },
+    Trade {
+        symbol: String,
+        sequence: u64,
+        timestamp: u64,
+        price: u64,
+        size: u64,
+        aggressor_side: Side,
+    },
+    /// The level as it is now, it is gone when it has no orders.
+    Level {
+        symbol: String,
+        sequence: u64,
+        side: Side,
+        price: u64,
+        size: u64,
+        orders: usize,
+    },
+}
+
+/// What the matching thread queues for a WebSocket connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamFrame {
+    Text(String),
+}
+
+/// Public stream of a book and the connections subscribed to it.
+#[derive(Debug, Default)]
+pub struct BookStream {
+    sequence: u64,
+    subscribers: Vec<SyncSender<StreamFrame>>,
+    // displayed levels as the subscribers last saw them
+    levels: HashMap<(Side, u64), PriceLevel>,
+}
+
+impl BookStream {
+    /// Sequence number of the last update.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn snapshot(&self, symbol: &str, book: &OrderBook) -> StreamMessage {
+        let depth = book.depth(usize::MAX);
+        StreamMessage::Snapshot {
+            symbol: symbol.to_string(),
+            sequence: self.sequence,
+            bids: depth.bids,
+            asks: depth.asks,
+        }
+    }
+
+    /// Sends the snapshot to the connection and then every update; the connection is
+    /// dropped as soon as its queue is full.
+    pub fn subscribe(&mut self, symbol: &str, book: &OrderBook, frames: SyncSender<StreamFrame>) {
+        let snapshot = self.snapshot(symbol, book);
+        if self.subscribers.is_empty() {
+            // levels are not followed while nobody is subscribed
+            let depth = book.depth(usize::MAX);
+            let bids = depth.bids.into_iter().map(|level| (Side::Buy, level));
+            let asks = depth.asks.into_iter().map(|level| (Side::Sell, level));
+            self.levels = bids
+                .chain(asks)
+                .map(|(side, level)| ((side, level.price), level))
+                .collect();
+        }
+        if frames
+            .try_send(StreamFrame::Text(to_json(&snapshot)))
+            .is_ok()
+        {
+            self.subscribers.push(frames);
+        }
+    }
+
+    /// Trades and the levels they changed, read from the book after matching;
+    /// `request` is the one matched, if the result is not of a cancel.
+    pub fn updates(
+        &mut self,
+        symbol: &str,
+        book: &OrderBook,
+        request: Option<&Request>,
+        result: &MatchingResult,
+        timestamp: u64,
+    ) -> Vec<StreamMessage> {
+        let mut messages = Vec::new();
+        let mut levels = Vec::new();
+        for action in result.market_actions.iter() {
+            self.sequence += 1;
+            messages.push(StreamMessage::Trade {
+                symbol: symbol.to_string(),
+                sequence: self.sequence,
+                timestamp,
+                price: action.price,
+                size: action.size,
+                aggressor_side: action.aggressor_side,
+            });
+            let resting_side = match action.aggressor_side {
+                Side::Buy => Side::Sell,
+                Side::Sell => Side::Buy,
+            };
+            levels.push((resting_side, action.price));
+        }
+        let mut added = Vec::new();
+        if result.request_actions.contains(&RequestAction::AddedToBook) {
+            added.extend(request);
+        }
+        for event in result.book_events.iter() {
+            match event {
+                BookEvent::Repriced {
+                    side,
+                    old_price,
+                    new_price,
+                    ..
+                } => levels.extend(&[(*side, *old_price), (*side, *new_price)]),
+                BookEvent::Cancelled { request } => levels.push((request.side, request.price)),
+                BookEvent::Activated {
+                    request,
+                    request_actions,
+                } => {
+                    if request_actions.contains(&RequestAction::AddedToBook) {
+                        added.push(request);
+                    }
+                }
+            }
+        }
+        for request in added.into_iter().filter(|request| !request.hidden) {
+            // pegged requests rest at a price of their own
+            let price = match (book.resting_request(request.id), request.peg) {
+                (Some(resting), _) if request.id != 0 => resting.price,
+                (_, Some(peg)) => match peg.price(request.side, book.reference_bbo()) {
+                    Some(price) => price,
+                    None => continue,
+                },
+                _ => request.price,
+            };
+            levels.push((request.side, price));
+        }
+        for (side, price) in levels {
+            let level = book.level(side, price);
+            let seen = self
+                .levels
+                .get(&(side, price))
+                .map_or((0, 0), |seen| (seen.size, seen.orders));
+            // e.g. a hidden request has left the level, or the level is published already
+            if (level.size, level.orders) == seen {
+                continue;
+            }
+            if level.orders == 0 {
+                self.levels.remove(&(side, price));
+            } else {
+                self.levels.insert((side, price), level.clone());
+            }
+            self.sequence += 1;
+            messages.push(StreamMessage::Level {
+                symbol: symbol.to_string(),
+                sequence: self.sequence,
+                side,
+                price,
+                size: level.size,
+                orders: level.orders,
+            });
+        }
+        messages
+    }
+
+    /// Sends the updates to the subscribers, forgetting the ones which are gone or do not
+    /// keep up. Nothing is worked out while nobody is subscribed.
+    pub fn publish(
+        &mut self,
+        symbol: &str,
+        book: &OrderBook,
+        request: Option<&Request>,
+        result: &MatchingResult,
+        timestamp: u64,
+    ) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+        let frames: Vec<String> = self
+            .updates(symbol, book, request, result, timestamp)
+            .iter()
+            .map(to_json)
+            .collect();
+        self.subscribers.retain(|subscriber| {
+            frames.iter().all(|frame| {
+                subscriber
+                    .try_send(StreamFrame::Text(frame.clone()))
+                    .is_ok()
+            })
+        });
+        if self.subscribers.is_empty() {
+            self.levels.clear();
+        }
+    }
+}
+
+fn to_json(message: &StreamMessage) -> String {
+    serde_json::to_string(message).expect("stream messages are always serializable")
+}
+
+/// Symbol of the book if the request asks for its stream, `GET /books/{symbol}/stream`.
+pub fn stream_symbol(request: &HttpRequest) -> Option<&str> {
+    let upgrade = request.header("upgrade")?;
+    let segments: Vec<&str> = request.path.split('/').filter(|s| !s.is_empty()).collect();
+    match segments.as_slice() {
+        ["books", symbol, "stream"]
+            if request.method == "GET" && upgrade.eq_ignore_ascii_case("websocket") =>
+        {
+            Some(symbol)
+        }
+        _ => None,
+    }
+}
+
+/// Completes the handshake and streams the book until either side closes the connection.
+pub fn serve_stream(
+    mut stream: TcpStream,
+    request: &HttpRequest,
+    symbol: &str,
+    engine: &EngineHandle,
+) -> io::Result<()> {
+    let key = match request.header("sec-websocket-key") {
+        Some(key) if request.header("sec-websocket-version") == Some("13") => key,
+        _ => return HttpResponse::error(400, "expected a WebSocket handshake").write(&mut stream),
+    };
+    let (frames, outgoing) = mpsc::sync_channel(STREAM_BUFFER);
+    if !engine.subscribe(symbol, frames) {
+        return HttpResponse::error(404, "no book with the symbol").write(&mut stream);
+    }
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    )?;
+    stream.set_read_timeout(None)?;
+    let mut input = stream.try_clone()?;
+    let control = stream.try_clone()?;
+    // pongs are written by the reader, so the matching thread holds the only sender
+    // and the connection is closed once it drops it
+    let output = Arc::new(Mutex::new(stream));
+    let pongs = Arc::clone(&output);
+    let reader = thread::spawn(move || {
+        while let Ok(frame) = WsFrame::read(&mut input) {
+            let pong = WsFrame {
+                fin: true,
+                opcode: opcode::PONG,
+                payload: frame.payload,
+            };
+            let written = match frame.opcode {
+                opcode::PING => write_frame(&pongs, &pong),
+                opcode::CLOSE => break,
+                // the stream takes no messages
+                _ => Ok(()),
+            };
+            if written.is_err() {
+                break;
+            }
+        }
+        let _ = input.shutdown(Shutdown::Both);
+    });
+    loop {
+        let text = match outgoing.recv_timeout(CLOSED_POLL) {
+            Ok(StreamFrame::Text(text)) => text,
+            // the reader has seen the client close the connection or go away
+            Err(RecvTimeoutError::Timeout) if reader.is_finished() => return Ok(()),
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if let Err(error) = write_frame(&output, &WsFrame::text(&text)) {
+            let _ = control.shutdown(Shutdown::Both);
+            return Err(error);
+        }
+    }
+    let close = WsFrame {
+        fin: true,
+        opcode: opcode::CLOSE,
+        payload: Vec::new(),
+    };
+    let _ = write_frame(&output, &close);
+    control.shutdown(Shutdown::Both)
+}
+
+fn write_frame(output: &Mutex<TcpStream>, frame: &WsFrame) -> io::Result<()> {
+    let mut output = output
+        .lock()
+        .map_err(|_| io::Error::other("a writer of the connection panicked"))?;
+    frame.write(&mut *output, None)
+}